@@ -0,0 +1,42 @@
+// diesel 1.x derives (used by `table!`) emit non-local impls
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate diesel;
+
+use actix_threadpool_diesel::*;
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, Pool},
+    sql_query,
+    sql_types::{BigInt, Text},
+};
+use std::error::Error;
+
+// A reporting row that doesn't correspond to any table
+#[derive(QueryableByName)]
+struct Report {
+    #[sql_type = "Text"]
+    label: String,
+    #[sql_type = "BigInt"]
+    total: i64,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Connect
+    let manager =
+        ConnectionManager::<PgConnection>::new("postgres://postgres@localhost/tokio_diesel__test");
+    let pool = Pool::builder().build(manager)?;
+
+    // Raw SQL with bind parameters, loaded by column name
+    let reports: Vec<Report> = sql_query("SELECT $1 AS label, COUNT(*) AS total FROM users")
+        .bind::<Text, _>("users")
+        .load_async(&pool)
+        .await?;
+    for report in reports {
+        println!("{}: {}", report.label, report.total);
+    }
+
+    Ok(())
+}
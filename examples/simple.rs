@@ -1,3 +1,6 @@
+// diesel 1.x derives (used by `table!`) emit non-local impls
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 
@@ -16,7 +19,7 @@ table! {
     }
 }
 
-#[actix_rt::main]
+#[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Connect
     let manager =
@@ -0,0 +1,913 @@
+#[cfg(feature = "postgres")]
+use crate::libpq::quote_identifier;
+use crate::{
+    with_connection_label, with_deadline, AsyncConnection, AsyncError, AsyncHealthCheck,
+    AsyncSimpleConnection, AsyncTransaction, Database, DatabaseErrorClass, PoolConfig, PoolStats,
+    QueryMetrics,
+};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Server, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use async_trait::async_trait;
+#[cfg(feature = "postgres")]
+use diesel::PgConnection;
+use diesel::{result::Error as DieselError, Connection};
+use futures::future::{ready, Ready};
+use serde::Serialize;
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    marker::PhantomData,
+    ops::Deref,
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The application's `Database`, extracted in handlers.
+///
+/// Looks for a `Database<Conn>` registered with `App::app_data`, either
+/// directly or wrapped in `web::Data`. Implements the async traits itself, so
+/// `query.load_async(&db)` works on the extractor.
+pub struct Db<Conn>(Database<Conn>)
+where
+    Conn: 'static + Connection;
+
+// Lets handlers return `AsyncError` with `?`. The body is only the status's reason
+// phrase, so database messages don't leak to clients.
+impl ResponseError for AsyncError<DieselError> {
+    fn status_code(&self) -> StatusCode {
+        if let AsyncError::Closed = *self {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        match self.class() {
+            DatabaseErrorClass::NotFound => StatusCode::NOT_FOUND,
+            DatabaseErrorClass::UniqueViolation => StatusCode::CONFLICT,
+            DatabaseErrorClass::ConnectionUnavailable | DatabaseErrorClass::Timeout => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        HttpResponse::build(status).body(status.canonical_reason().unwrap_or_default())
+    }
+}
+
+impl<Conn> Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// The database registered under `name` in the app's `DatabaseRegistry`.
+    ///
+    /// Call it from handlers that take the `HttpRequest`, e.g.
+    /// `Db::<PgConnection>::named(&req, "analytics")?`.
+    pub fn named(req: &HttpRequest, name: &str) -> Result<Db<Conn>, actix_web::Error> {
+        let registry = req.app_data::<DatabaseRegistry>().or_else(|| {
+            req.app_data::<web::Data<DatabaseRegistry>>()
+                .map(|data| data.get_ref())
+        });
+
+        let registry = registry.ok_or_else(|| {
+            log::error!("no `DatabaseRegistry` registered with `App::app_data`");
+            ErrorInternalServerError("database not configured")
+        })?;
+
+        registry.get(name).map(Db).ok_or_else(|| {
+            log::error!(
+                "no database named {:?} of this type in the `DatabaseRegistry`",
+                name
+            );
+            ErrorInternalServerError("database not configured")
+        })
+    }
+
+    pub fn into_inner(self) -> Database<Conn> {
+        self.0
+    }
+}
+
+impl<Conn> Clone for Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        Db(self.0.clone())
+    }
+}
+
+impl<Conn> Deref for Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Target = Database<Conn>;
+
+    fn deref(&self) -> &Database<Conn> {
+        &self.0
+    }
+}
+
+impl<Conn> FromRequest for Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(registered_database(req).map(Db))
+    }
+}
+
+// The `Database<Conn>` registered as app data, directly or in `web::Data`
+fn registered_database<Conn>(req: &HttpRequest) -> Result<Database<Conn>, actix_web::Error>
+where
+    Conn: 'static + Connection,
+{
+    let db = req.app_data::<Database<Conn>>().cloned().or_else(|| {
+        req.app_data::<web::Data<Database<Conn>>>()
+            .map(|data| data.get_ref().clone())
+    });
+
+    db.ok_or_else(|| {
+        log::error!("no `Database` registered with `App::app_data`");
+        ErrorInternalServerError("database not configured")
+    })
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        self.0.batch_execute_async(query).await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for Db<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.run(f).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.transaction(f).await
+    }
+
+    #[inline]
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.run_with_timeout(timeout, f).await
+    }
+}
+
+/// Databases registered under names such as "primary" and "analytics", for
+/// services that talk to several of them.
+///
+/// Build it once, before `HttpServer::new`, register it with `App::app_data`
+/// and look databases up in handlers with `Db::named`. Each name may use its
+/// own connection type.
+#[derive(Clone, Default)]
+pub struct DatabaseRegistry {
+    databases: HashMap<Cow<'static, str>, Arc<dyn Any + Send + Sync>>,
+}
+
+impl DatabaseRegistry {
+    pub fn new() -> DatabaseRegistry {
+        DatabaseRegistry::default()
+    }
+
+    /// Register `db` under `name`, replacing any database already there.
+    pub fn register<Conn, N>(mut self, name: N, db: Database<Conn>) -> DatabaseRegistry
+    where
+        Conn: 'static + Connection,
+        N: Into<Cow<'static, str>>,
+    {
+        self.databases.insert(name.into(), Arc::new(db));
+        self
+    }
+
+    /// The database registered under `name`, if it uses `Conn` connections.
+    pub fn get<Conn>(&self, name: &str) -> Option<Database<Conn>>
+    where
+        Conn: 'static + Connection,
+    {
+        self.databases
+            .get(name)?
+            .downcast_ref::<Database<Conn>>()
+            .cloned()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.databases.keys().map(|name| &**name)
+    }
+}
+
+impl fmt::Debug for DatabaseRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DatabaseRegistry")
+            .field("names", &self.databases.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A `Database` ready to be registered with actix apps.
+///
+/// Build it once, before `HttpServer::new`, and call `configure` from the app
+/// factory, so every worker shares the one pool:
+/// `App::new().configure(|cfg| db_config.configure(cfg))`. After the server
+/// stops, `serve` drains outstanding work.
+pub struct DatabaseConfig<Conn>
+where
+    Conn: 'static + Connection,
+{
+    db: Database<Conn>,
+    health_path: Option<Cow<'static, str>>,
+    shutdown_grace: Duration,
+}
+
+impl<Conn> DatabaseConfig<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Create the pool for `database_url`; connections open in the
+    /// background, so this doesn't block.
+    pub fn new(database_url: &str, pool_config: PoolConfig) -> DatabaseConfig<Conn> {
+        let pool = pool_config.build_pool(database_url);
+        DatabaseConfig::from_database(Database::builder().database_url(database_url).build(pool))
+    }
+
+    /// Register a database built some other way, e.g. with
+    /// `DatabaseBuilder::connect`.
+    pub fn from_database(db: Database<Conn>) -> DatabaseConfig<Conn> {
+        DatabaseConfig {
+            db,
+            health_path: None,
+            shutdown_grace: Duration::from_secs(30),
+        }
+    }
+
+    /// Also register `db_health_handler` for GET requests to `path`.
+    pub fn health_route<S: Into<Cow<'static, str>>>(mut self, path: S) -> DatabaseConfig<Conn> {
+        self.health_path = Some(path.into());
+        self
+    }
+
+    /// How long `serve` lets database work still running after the server
+    /// stopped finish (default 30 seconds).
+    pub fn shutdown_grace(mut self, grace: Duration) -> DatabaseConfig<Conn> {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Run `server` until it stops, then shut the database down.
+    ///
+    /// Stopping the server, by signal or `ServerHandle::stop`, first lets its
+    /// workers finish in-flight requests, transactions included. Then the
+    /// database refuses new work and waits up to the shutdown grace period
+    /// for what remains, such as rollbacks of abandoned transactions and
+    /// tasks spawned by handlers.
+    pub async fn serve(&self, server: Server) -> io::Result<()> {
+        let result = server.await;
+        if self.db.shutdown(self.shutdown_grace).await.is_err() {
+            log::warn!(
+                "database work still running {:?} after the server stopped",
+                self.shutdown_grace
+            );
+        }
+        result
+    }
+
+    pub fn database(&self) -> &Database<Conn> {
+        &self.db
+    }
+
+    /// Register the database as app data, for `Db` and `Transactional`, and
+    /// the health route if one was set.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.db.clone());
+        if let Some(ref path) = self.health_path {
+            cfg.route(path, web::get().to(db_health_handler::<Conn>));
+        }
+    }
+}
+
+impl<Conn> Clone for DatabaseConfig<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        DatabaseConfig {
+            db: self.db.clone(),
+            health_path: self.health_path.clone(),
+            shutdown_grace: self.shutdown_grace,
+        }
+    }
+}
+
+// How long `db_health_handler` waits for its ping, checkout included
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct HealthReport {
+    healthy: bool,
+    pool: PoolStats,
+}
+
+/// A readiness route: pings the database within a second and responds 200,
+/// or 503 if that fails, with the pool's statistics as JSON:
+/// `{"healthy": true, "pool": {"idle": 2, "in_use": 1, ...}}`.
+///
+/// Register it with e.g. `web::get().to(db_health_handler::<PgConnection>)`.
+pub async fn db_health_handler<Conn>(db: Db<Conn>) -> HttpResponse
+where
+    Conn: 'static + Connection,
+{
+    let healthy = db.is_healthy(HEALTH_CHECK_TIMEOUT).await;
+    let report = HealthReport {
+        healthy,
+        pool: db.stats(),
+    };
+
+    if healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Middleware running each request in one transaction.
+///
+/// The transaction begins before the handler runs, on the `Database` that
+/// `Db` would extract, and handlers reach it through the `Tx` extractor. It is
+/// committed when the response is a success or redirect and rolled back
+/// otherwise. A failed commit replaces the response with the commit's error.
+pub struct Transactional<Conn> {
+    session_vars: Rc<Vec<SessionVar>>,
+    _conn: PhantomData<fn() -> Conn>,
+}
+
+pub struct TransactionalMiddleware<S, Conn> {
+    service: Rc<S>,
+    session_vars: Rc<Vec<SessionVar>>,
+    _conn: PhantomData<fn() -> Conn>,
+}
+
+type LocalFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+type ExtractValue =
+    Box<dyn Fn(&mut ServiceRequest) -> LocalFuture<Result<String, actix_web::Error>>>;
+
+// A setting applied with `SET LOCAL`, its name quoted, and the extraction of its value
+struct SessionVar {
+    name: String,
+    extract: ExtractValue,
+}
+
+/// Middleware collecting the `QueryMetrics` of each request.
+///
+/// The metrics are stored in the response's extensions and reported in
+/// headers: `X-DB-Queries` with the number of queries, `X-DB-Time` with their
+/// total time and `X-DB-Slowest` with the slowest one's, both in
+/// milliseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DbMetrics;
+
+pub struct DbMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for DbMetrics
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = DbMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DbMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for DbMetricsMiddleware<S>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let (res, metrics) = QueryMetrics::collect(service.call(req)).await;
+            let mut res = res?;
+
+            let headers = res.headers_mut();
+            headers.insert(
+                HeaderName::from_static("x-db-queries"),
+                metrics.queries.into(),
+            );
+            headers.insert(HeaderName::from_static("x-db-time"), millis(metrics.total));
+            headers.insert(
+                HeaderName::from_static("x-db-slowest"),
+                millis(metrics.slowest),
+            );
+            res.response_mut().extensions_mut().insert(metrics);
+            Ok(res)
+        })
+    }
+}
+
+fn millis(duration: Duration) -> HeaderValue {
+    let millis = format!("{:.3}", duration.as_secs_f64() * 1000.0);
+    HeaderValue::from_str(&millis).expect("a number is a valid header value")
+}
+
+/// Serves the metrics of the `prometheus::Registry` registered as app data,
+/// directly or in `web::Data`, or else of the default registry, in the text
+/// exposition format. Route it with `web::get().to(prometheus_handler)`.
+#[cfg(feature = "prometheus")]
+pub async fn prometheus_handler(req: HttpRequest) -> HttpResponse {
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    let families = match req.app_data::<Registry>().or_else(|| {
+        req.app_data::<web::Data<Registry>>()
+            .map(|data| data.get_ref())
+    }) {
+        Some(registry) => registry.gather(),
+        None => prometheus::gather(),
+    };
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&families, &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(err) => {
+            log::error!("encoding metrics failed: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Middleware giving each request's database work a deadline, as in
+/// `with_deadline`.
+///
+/// Wrap a scope or resource to set a deadline for its routes only. Queries
+/// that overrun it fail with `AsyncError::Timeout`, answered with
+/// `503 Service Unavailable` when returned from the handler.
+#[derive(Clone, Copy, Debug)]
+pub struct DbDeadline {
+    timeout: Duration,
+}
+
+pub struct DbDeadlineMiddleware<S> {
+    service: Rc<S>,
+    timeout: Duration,
+}
+
+impl DbDeadline {
+    pub fn new(timeout: Duration) -> DbDeadline {
+        DbDeadline { timeout }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for DbDeadline
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = DbDeadlineMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DbDeadlineMiddleware {
+            service: Rc::new(service),
+            timeout: self.timeout,
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for DbDeadlineMiddleware<S>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        Box::pin(with_deadline(self.timeout, self.service.call(req)))
+    }
+}
+
+/// Middleware labelling the connections each request checks out with the
+/// service name and the request id, as in `with_connection_label`.
+///
+/// The request id is taken from the `X-Request-Id` header, or generated and
+/// returned in that header. Databases must be built with
+/// `DatabaseBuilder::label_connections`; on Postgres the label is then the
+/// connection's `application_name`, so `pg_stat_activity` shows which request
+/// a slow query belongs to.
+#[derive(Clone, Debug)]
+pub struct LabelConnections {
+    service_name: Rc<str>,
+}
+
+pub struct LabelConnectionsMiddleware<S> {
+    service: Rc<S>,
+    service_name: Rc<str>,
+}
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Numbers requests that arrive without an id
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+impl LabelConnections {
+    pub fn new<S: Into<Rc<str>>>(service_name: S) -> LabelConnections {
+        LabelConnections {
+            service_name: service_name.into(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LabelConnections
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = LabelConnectionsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LabelConnectionsMiddleware {
+            service: Rc::new(service),
+            service_name: self.service_name.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for LabelConnectionsMiddleware<S>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let given = req
+            .headers()
+            .get(&REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string);
+        let generated = given.is_none();
+        let request_id = given
+            .unwrap_or_else(|| format!("{:016x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)));
+        let label = format!("{} {}", self.service_name, request_id);
+
+        Box::pin(async move {
+            let mut res = with_connection_label(label, service.call(req)).await?;
+            if generated {
+                let id = HeaderValue::from_str(&request_id).expect("hex is a valid header value");
+                res.headers_mut().insert(REQUEST_ID, id);
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// The request's transaction, extracted in handlers wrapped by
+/// `Transactional`.
+pub struct Tx<Conn>(Arc<AsyncTransaction<Conn>>)
+where
+    Conn: 'static + Connection;
+
+impl<Conn> Transactional<Conn> {
+    pub fn new() -> Transactional<Conn> {
+        Transactional {
+            session_vars: Rc::new(Vec::new()),
+            _conn: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Transactional<PgConnection> {
+    /// Set the Postgres setting `name` for the request's transaction, with
+    /// `SET LOCAL`, to the value of the extractor `T`, e.g. the authenticated
+    /// user's id as `app.user_id` for row-level security policies to read
+    /// with `current_setting('app.user_id')`.
+    ///
+    /// `T` is extracted before the transaction begins; if it fails, so does
+    /// the request, with the extractor's error.
+    ///
+    /// Panics unless `name` is one identifier or several joined by dots.
+    pub fn set_local<T>(mut self, name: &str) -> Transactional<PgConnection>
+    where
+        T: 'static + FromRequest + fmt::Display,
+        T::Future: 'static,
+    {
+        assert!(
+            name.split('.').all(is_identifier),
+            "invalid setting name {:?}",
+            name
+        );
+        let name = name
+            .split('.')
+            .map(quote_identifier)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        let extract = move |req: &mut ServiceRequest| -> LocalFuture<_> {
+            let value = req.extract::<T>();
+            Box::pin(async move {
+                value
+                    .await
+                    .map(|value| value.to_string())
+                    .map_err(Into::into)
+            })
+        };
+        Rc::get_mut(&mut self.session_vars)
+            .expect("`set_local` called on a middleware in use")
+            .push(SessionVar {
+                name,
+                extract: Box::new(extract),
+            });
+        self
+    }
+}
+
+// An unquoted SQL identifier: a letter or underscore, then letters, digits, underscores
+#[cfg(feature = "postgres")]
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl<Conn> Default for Transactional<Conn> {
+    fn default() -> Self {
+        Transactional::new()
+    }
+}
+
+impl<S, B, Conn> Transform<S, ServiceRequest> for Transactional<Conn>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+    Conn: 'static + Connection,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = TransactionalMiddleware<S, Conn>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TransactionalMiddleware {
+            service: Rc::new(service),
+            session_vars: self.session_vars.clone(),
+            _conn: PhantomData,
+        }))
+    }
+}
+
+impl<S, B, Conn> Service<ServiceRequest> for TransactionalMiddleware<S, Conn>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: 'static,
+    Conn: 'static + Connection,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let session_vars = self.session_vars.clone();
+        Box::pin(async move {
+            let tx = match begin_request::<Conn>(&mut req, &session_vars).await {
+                Ok(tx) => Arc::new(tx),
+                Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+            };
+            req.extensions_mut().insert(Tx(tx.clone()));
+
+            // On an error the transaction rolls back once the request, which holds
+            // the last reference to it, is dropped
+            let res = service.call(req).await?;
+            res.request().extensions_mut().remove::<Tx<Conn>>();
+
+            if !(res.status().is_success() || res.status().is_redirection()) {
+                // Otherwise the last reference rolls back when dropped
+                if let Ok(tx) = Arc::try_unwrap(tx) {
+                    if let Err(err) = tx.rollback().await {
+                        log::warn!("rolling back the request's transaction failed: {}", err);
+                    }
+                }
+                return Ok(res.map_into_left_body());
+            }
+
+            let committed = match Arc::try_unwrap(tx) {
+                Ok(tx) => tx.commit().await.map_err(actix_web::Error::from),
+                Err(_) => Err(ErrorInternalServerError(
+                    "the request's transaction outlived the request",
+                )),
+            };
+            match committed {
+                Ok(()) => Ok(res.map_into_left_body()),
+                Err(err) => {
+                    let (req, _) = res.into_parts();
+                    Ok(ServiceResponse::from_err(err, req).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+// Begin the request's transaction and apply its settings
+async fn begin_request<Conn>(
+    req: &mut ServiceRequest,
+    session_vars: &[SessionVar],
+) -> Result<AsyncTransaction<Conn>, actix_web::Error>
+where
+    Conn: 'static + Connection,
+{
+    let db = registered_database::<Conn>(req.request())?;
+
+    let mut settings = String::new();
+    for var in session_vars {
+        let value = (var.extract)(req).await?;
+        settings += &format!("SET LOCAL {} = {};", var.name, quote_literal(&value));
+    }
+
+    let tx = db.begin().await?;
+    if !settings.is_empty() {
+        tx.batch_execute_async(&settings).await?;
+    }
+    Ok(tx)
+}
+
+// A Postgres string literal (with `standard_conforming_strings`, the default since 9.1)
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+impl<Conn> Clone for Tx<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        Tx(self.0.clone())
+    }
+}
+
+impl<Conn> Deref for Tx<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Target = AsyncTransaction<Conn>;
+
+    fn deref(&self) -> &AsyncTransaction<Conn> {
+        &self.0
+    }
+}
+
+impl<Conn> FromRequest for Tx<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let tx = req.extensions().get::<Tx<Conn>>().cloned();
+        ready(tx.ok_or_else(|| {
+            log::error!("`Tx` extracted outside the `Transactional` middleware");
+            ErrorInternalServerError("no transaction for this request")
+        }))
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for Tx<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        self.0.batch_execute_async(query).await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for Tx<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.run(f).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.transaction(f).await
+    }
+
+    #[inline]
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.0.run_with_timeout(timeout, f).await
+    }
+}
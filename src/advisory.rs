@@ -0,0 +1,119 @@
+use crate::{AsyncConnection, AsyncConnectionGuard, AsyncError, Database};
+use diesel::{
+    dsl::sql,
+    pg::PgConnection,
+    result::{Error as DieselError, QueryResult},
+    sql_query,
+    sql_types::Bool,
+    RunQueryDsl,
+};
+
+/// A session level Postgres advisory lock, held on a connection checked out
+/// for it until the guard is dropped or `unlock` is called.
+///
+/// Created by `Database::advisory_lock` and `try_advisory_lock`. Advisory
+/// locks are shared by every client of the database, so they can make sure
+/// only one instance of a service runs a job at a time.
+pub struct AdvisoryLockGuard {
+    key: i64,
+    // Taken by `unlock`, or on drop to unlock off the async thread
+    conn: Option<AsyncConnectionGuard<PgConnection>>,
+}
+
+impl Database<PgConnection> {
+    /// Wait for the advisory lock `key` (`pg_advisory_lock`).
+    ///
+    /// The wait holds a connection and a blocking thread; bound it with
+    /// `with_deadline`, or use `try_advisory_lock`. A lock obtained after the
+    /// caller stopped waiting is released right away.
+    pub async fn advisory_lock(
+        &self,
+        key: i64,
+    ) -> Result<AdvisoryLockGuard, AsyncError<DieselError>> {
+        // Built first, so the lock is released however the wait ends
+        let guard = AdvisoryLockGuard {
+            key,
+            conn: Some(self.acquire().await?),
+        };
+        guard
+            .conn()
+            .run(move |conn| sql_query(format!("SELECT pg_advisory_lock({})", key)).execute(conn))
+            .await?;
+        Ok(guard)
+    }
+
+    /// Take the advisory lock `key` if no one else holds it
+    /// (`pg_try_advisory_lock`), or return `Ok(None)` right away.
+    pub async fn try_advisory_lock(
+        &self,
+        key: i64,
+    ) -> Result<Option<AdvisoryLockGuard>, AsyncError<DieselError>> {
+        let mut guard = AdvisoryLockGuard {
+            key,
+            conn: Some(self.acquire().await?),
+        };
+        let locked = guard
+            .conn()
+            .run(move |conn| {
+                diesel::select(sql::<Bool>(&format!("pg_try_advisory_lock({})", key)))
+                    .get_result::<bool>(conn)
+            })
+            .await?;
+
+        if locked {
+            Ok(Some(guard))
+        } else {
+            // Nothing to release
+            guard.conn.take();
+            Ok(None)
+        }
+    }
+}
+
+impl AdvisoryLockGuard {
+    pub fn key(&self) -> i64 {
+        self.key
+    }
+
+    fn conn(&self) -> &AsyncConnectionGuard<PgConnection> {
+        self.conn.as_ref().expect("connection taken before drop")
+    }
+
+    /// Release the lock, reporting a failure to do so, which dropping the
+    /// guard only logs.
+    pub async fn unlock(mut self) -> Result<(), AsyncError<DieselError>> {
+        let key = self.key;
+        let conn = self.conn.take().expect("connection taken before unlock");
+        conn.run(move |conn| unlock(conn, key)).await
+    }
+}
+
+impl Drop for AdvisoryLockGuard {
+    fn drop(&mut self) {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        let key = self.key;
+        let pinned = conn.pinned_conn();
+        let db = conn.db().clone();
+        // Waits for a `pg_advisory_lock` still running on the connection
+        db.spawn_detached(move || {
+            if let Err(err) = unlock(&pinned.lock().unwrap(), key) {
+                log::warn!("releasing advisory lock {} failed: {}", key, err);
+            }
+            drop(conn);
+        });
+    }
+}
+
+fn unlock(conn: &PgConnection, key: i64) -> QueryResult<()> {
+    let held = diesel::select(sql::<Bool>(&format!("pg_advisory_unlock({})", key)))
+        .get_result::<bool>(conn)?;
+    // As when the wait for the lock failed
+    if !held {
+        log::debug!("advisory lock {} was not held when released", key);
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use crate::{
+    checkout::{Checkout, CheckoutPool},
+    classify::{self, DatabaseErrorClass},
+    label,
+    thread_pool::Canceled,
+    AsyncError, CheckoutError,
+};
+use actix_rt::Arbiter;
+use diesel::{connection::TransactionManager, Connection};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::oneshot;
+
+thread_local! {
+    // The connection the current arbiter keeps for each database, by `Database::id`
+    static CONNS: RefCell<HashMap<usize, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// A fixed set of actix arbiters dedicated to database work, for users of
+/// actix's single-threaded runtime in place of Tokio's blocking pool; see
+/// `DatabaseBuilder::arbiters`.
+///
+/// Each arbiter is a thread of the current actix `System` running one call
+/// at a time, and calls go to the arbiter with the fewest queued. An arbiter
+/// keeps the pooled connection it checked out for a database and runs that
+/// database's calls on it; it checks out a new one after a call panicked,
+/// lost the connection or left a transaction open. Size the pool to at
+/// least the number of arbiters, as their connections stay checked out.
+///
+/// The database's `on_acquire` and `on_release` hooks still run around every
+/// call, as they would if each call checked a connection out of the pool.
+///
+/// Cloning an `ArbiterPool` is cheap and yields a handle to the same
+/// arbiters; they stop, closing their connections, once every handle is
+/// dropped.
+#[derive(Clone)]
+pub struct ArbiterPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    arbiter: Arbiter,
+    queued: Arc<AtomicUsize>,
+}
+
+// Counts a call as queued on its arbiter until it has run, or was dropped unrun
+struct Queued(Arc<AtomicUsize>);
+
+impl ArbiterPool {
+    /// Start `size` arbiters in the current actix `System`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no `System` is running on this thread.
+    pub fn new(size: usize) -> ArbiterPool {
+        assert!(size > 0, "arbiter pool size must be positive");
+        let workers = (0..size)
+            .map(|_| Worker {
+                arbiter: Arbiter::new(),
+                queued: Arc::new(AtomicUsize::new(0)),
+            })
+            .collect();
+        ArbiterPool {
+            inner: Arc::new(Inner { workers }),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.workers.len()
+    }
+
+    /// The calls queued or running on each arbiter.
+    pub fn queued(&self) -> Vec<usize> {
+        self.inner
+            .workers
+            .iter()
+            .map(|worker| worker.queued.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub(crate) async fn spawn<F, R>(&self, f: F) -> Result<R, Canceled>
+    where
+        F: 'static + FnOnce() -> R + Send,
+        R: 'static + Send,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.execute(move || {
+            let _ = tx.send(f());
+        });
+        rx.await.map_err(|_| Canceled)
+    }
+
+    // Queue a job without waiting for its result
+    pub(crate) fn execute<F>(&self, f: F)
+    where
+        F: 'static + FnOnce() + Send,
+    {
+        let worker = self.least_busy();
+        let queued = Queued::new(&worker.queued);
+        // A panicking job drops its result sender, which the caller sees as `Canceled`,
+        // instead of taking the arbiter down
+        worker.arbiter.spawn_fn(move || {
+            let _queued = queued;
+            let _ = panic::catch_unwind(AssertUnwindSafe(f));
+        });
+    }
+
+    // Closes the connections the arbiters keep for the database `db`
+    pub(crate) fn forget(&self, db: usize) {
+        for worker in &self.inner.workers {
+            worker.arbiter.spawn_fn(move || {
+                let conn = CONNS.with(|conns| conns.borrow_mut().remove(&db));
+                drop(conn);
+            });
+        }
+    }
+
+    fn least_busy(&self) -> &Worker {
+        self.inner
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.queued.load(Ordering::Relaxed))
+            .expect("arbiter pool is not empty")
+    }
+}
+
+impl fmt::Debug for ArbiterPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArbiterPool")
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.arbiter.stop();
+        }
+    }
+}
+
+impl Queued {
+    fn new(queued: &Arc<AtomicUsize>) -> Queued {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Queued(queued.clone())
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The connection the current arbiter keeps for the database `db`, or a new checkout.
+// Labels are set per checkout, so labeled calls get a checkout of their own
+pub(crate) fn checkout<Conn>(
+    db: usize,
+    pool: &CheckoutPool<Conn>,
+) -> Result<Checkout<Conn>, CheckoutError>
+where
+    Conn: 'static + Connection,
+{
+    if label::current().is_some() {
+        return pool.get();
+    }
+    let kept = CONNS.with(|conns| conns.borrow_mut().remove(&db));
+    match kept.and_then(|conn| conn.downcast::<Checkout<Conn>>().ok()) {
+        Some(conn) => pool.reuse(*conn),
+        None => pool.get(),
+    }
+}
+
+// Keeps `conn` for the next call of the database `db`, after its release hooks, unless
+// the call that used it might have left it unusable, in which case it goes back to the pool
+pub(crate) fn keep<Conn, R, E>(
+    db: usize,
+    mut conn: Checkout<Conn>,
+    result: &Result<R, AsyncError<E>>,
+) where
+    Conn: 'static + Connection,
+    E: 'static + fmt::Debug,
+{
+    let healthy = match *result {
+        Ok(_) => true,
+        Err(AsyncError::Panicked(_)) => false,
+        Err(ref err) => classify::class_of(err) != DatabaseErrorClass::ConnectionLost,
+    };
+    let idle = TransactionManager::<Conn>::get_transaction_depth(conn.transaction_manager()) == 0;
+    if healthy && idle && label::current().is_none() {
+        conn.release();
+        CONNS.with(|conns| conns.borrow_mut().insert(db, Box::new(conn)));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{classify, trace, AsyncError, DatabaseErrorClass};
+use std::{cell::RefCell, fmt, future::Future, sync::Arc, time::SystemTime};
+
+/// Receives an `AuditEvent` for every write made through a `Database`, its
+/// transactions and guards, for compliance logging.
+///
+/// Added with `DatabaseBuilder::audit_sink`. Events are recorded on the async
+/// thread once the write finished, so `record` should be quick.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: AuditEvent);
+}
+
+/// A finished write: an `execute_async`, a `transaction` or the commit of an
+/// `AsyncTransaction`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AuditEvent {
+    /// When the write finished
+    pub timestamp: SystemTime,
+    /// `execute`, `transaction` or `commit`
+    pub operation: &'static str,
+    /// The tag given with `with_tag` or `Database::run_tagged`
+    pub tag: Option<String>,
+    /// Rows affected, for `execute`
+    pub rows_affected: Option<usize>,
+    /// The actor given with `with_actor`
+    pub actor: Option<String>,
+    /// The class of the error the write failed with, if it did
+    pub error: Option<DatabaseErrorClass>,
+}
+
+tokio::task_local! {
+    static ACTOR: Arc<str>;
+
+    // Where the database running the write being audited leaves its sink
+    static SINK: RefCell<Option<Arc<dyn AuditSink>>>;
+}
+
+/// Run `fut` with `actor`, such as the id of the user the request is made
+/// for, recorded in the audit events of the writes it makes.
+pub async fn with_actor<F, A>(actor: A, fut: F) -> F::Output
+where
+    F: Future,
+    A: Into<Arc<str>>,
+{
+    ACTOR.scope(actor.into(), fut).await
+}
+
+// Runs the write `fut`, then records it with the sink of the database it ran on,
+// if that has one; `rows` counts the rows affected in its result
+pub(crate) async fn audited<T, E, F>(
+    operation: &'static str,
+    rows: fn(&T) -> Option<usize>,
+    fut: F,
+) -> Result<T, AsyncError<E>>
+where
+    E: 'static + fmt::Debug,
+    F: Future<Output = Result<T, AsyncError<E>>>,
+{
+    let (result, sink) = SINK
+        .scope(RefCell::new(None), async {
+            let result = fut.await;
+            (result, SINK.with(|sink| sink.borrow_mut().take()))
+        })
+        .await;
+
+    if let Some(sink) = sink {
+        let (rows_affected, error) = match result {
+            Ok(ref value) => (rows(value), None),
+            Err(ref err) => (None, Some(classify::class_of(err))),
+        };
+        sink.record(AuditEvent {
+            timestamp: SystemTime::now(),
+            operation,
+            tag: trace::tag().map(|tag| tag.to_string()),
+            rows_affected,
+            actor: ACTOR.try_with(|actor| actor.to_string()).ok(),
+            error,
+        });
+    }
+    result
+}
+
+// Called by a database starting a call, so a write being audited reaches its sink
+pub(crate) fn offer(sink: &Arc<dyn AuditSink>) {
+    let _ = SINK.try_with(|offered| *offered.borrow_mut() = Some(sink.clone()));
+}
@@ -0,0 +1,106 @@
+use crate::{AsyncError, AsyncPool, CheckoutError};
+use async_trait::async_trait;
+use diesel::{r2d2::Error, result::ConnectionError, Connection};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::task;
+
+/// A bb8 connection manager for diesel connections.
+///
+/// Connections are established and validated on blocking threads, so
+/// `bb8::Pool<Bb8ConnectionManager<Conn>>` never blocks the async runtime.
+pub struct Bb8ConnectionManager<Conn> {
+    database_url: String,
+    _marker: PhantomData<fn() -> Conn>,
+}
+
+/// A connection owned by a bb8 pool.
+// Shared so it can be validated on a blocking thread while bb8 holds a `&mut`
+pub struct Bb8Connection<Conn>(Arc<Mutex<Conn>>);
+
+impl<Conn> Bb8ConnectionManager<Conn> {
+    pub fn new<S: Into<String>>(database_url: S) -> Bb8ConnectionManager<Conn> {
+        Bb8ConnectionManager {
+            database_url: database_url.into(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for Bb8ConnectionManager<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bb8ConnectionManager").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> bb8::ManageConnection for Bb8ConnectionManager<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    type Connection = Bb8Connection<Conn>;
+    type Error = Error;
+
+    async fn connect(&self) -> Result<Bb8Connection<Conn>, Error> {
+        let database_url = self.database_url.clone();
+        task::spawn_blocking(move || Conn::establish(&database_url))
+            .await
+            .map_err(|_| {
+                Error::ConnectionError(ConnectionError::BadConnection(
+                    "connecting was cancelled".to_string(),
+                ))
+            })?
+            .map(|conn| Bb8Connection(Arc::new(Mutex::new(conn))))
+            .map_err(Error::ConnectionError)
+    }
+
+    async fn is_valid(&self, conn: &mut Bb8Connection<Conn>) -> Result<(), Error> {
+        let conn = conn.0.clone();
+        task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+            conn.execute("SELECT 1").map(|_| ())
+        })
+        .await
+        .map_err(|_| {
+            Error::ConnectionError(ConnectionError::BadConnection(
+                "validation was cancelled".to_string(),
+            ))
+        })?
+        .map_err(Error::QueryError)
+    }
+
+    fn has_broken(&self, conn: &mut Bb8Connection<Conn>) -> bool {
+        // Only a panic while the connection was in use poisons it
+        conn.0.is_poisoned()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncPool for bb8::Pool<Bb8ConnectionManager<Conn>>
+where
+    Conn: 'static + Connection + Send,
+{
+    type Connection = Conn;
+
+    #[inline]
+    async fn with_connection<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, AsyncError<E>> + Send,
+    {
+        let pooled = self
+            .get_owned()
+            .await
+            .map_err(|e| AsyncError::Checkout(CheckoutError::new(e)))?;
+        task::spawn_blocking(move || {
+            let conn = pooled.0.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&*conn)
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{label, stats::CheckoutStats, CheckoutError};
+use diesel::{
+    connection::TransactionManager,
+    r2d2::{ConnectionManager, Pool, PooledConnection},
+    result::QueryResult,
+    Connection,
+};
+#[cfg(feature = "postgres")]
+use std::sync::OnceLock;
+use std::{ops::Deref, sync::Arc, time::Instant};
+
+pub(crate) type Hook<Conn> = Box<dyn Fn(&Conn) -> QueryResult<()> + Send + Sync>;
+
+// Callbacks run around every checkout made by a `Database`
+pub(crate) struct Hooks<Conn> {
+    pub(crate) on_acquire: Vec<Hook<Conn>>,
+    pub(crate) on_release: Vec<Hook<Conn>>,
+    pub(crate) labeler: Option<Labeler<Conn>>,
+}
+
+// How a database sets `with_connection_label` labels on its connections
+pub(crate) struct Labeler<Conn> {
+    pub(crate) set: fn(&Conn, &str) -> QueryResult<()>,
+    pub(crate) clear: fn(&Conn) -> QueryResult<()>,
+}
+
+// A database's pool, with checkouts counted in its statistics and run through its hooks
+pub(crate) struct CheckoutPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pool: Pool<ConnectionManager<Conn>>,
+    stats: Arc<CheckoutStats>,
+    hooks: Arc<Hooks<Conn>>,
+}
+
+// A checked out connection; runs the release hooks before going back to the pool
+pub(crate) struct Checkout<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: PooledConnection<ConnectionManager<Conn>>,
+    hooks: Arc<Hooks<Conn>>,
+    labeled: bool,
+    // Set once the release hooks ran for a connection kept rather than given back
+    released: bool,
+    #[cfg(feature = "postgres")]
+    session: Arc<Session>,
+}
+
+// What the crate learns about a pooled connection's session, kept in the pool along
+// with the connection so it is only read once
+#[cfg(feature = "postgres")]
+#[derive(Default)]
+pub(crate) struct Session {
+    // Postgres's `pg_backend_pid()`
+    pub(crate) backend_pid: OnceLock<i32>,
+}
+
+impl<Conn> Default for Hooks<Conn> {
+    fn default() -> Self {
+        Hooks {
+            on_acquire: Vec::new(),
+            on_release: Vec::new(),
+            labeler: None,
+        }
+    }
+}
+
+impl<Conn> CheckoutPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(
+        pool: Pool<ConnectionManager<Conn>>,
+        stats: Arc<CheckoutStats>,
+        hooks: Arc<Hooks<Conn>>,
+    ) -> Self {
+        CheckoutPool { pool, stats, hooks }
+    }
+
+    // A connection whose acquire hook fails goes back to the pool unused, and the
+    // hook's error is reported as the checkout's
+    pub(crate) fn get(&self) -> Result<Checkout<Conn>, CheckoutError> {
+        let start = Instant::now();
+        let result = self
+            .pool
+            .get()
+            .map_err(CheckoutError::from)
+            .and_then(|conn| {
+                for hook in &self.hooks.on_acquire {
+                    hook(&*conn).map_err(CheckoutError::new)?;
+                }
+                let labeled = match (&self.hooks.labeler, label::current()) {
+                    (Some(labeler), Some(label)) => {
+                        (labeler.set)(&*conn, &label).map_err(CheckoutError::new)?;
+                        true
+                    }
+                    _ => false,
+                };
+                #[cfg(feature = "postgres")]
+                let (conn, session) = with_session(conn);
+                Ok(Checkout {
+                    conn,
+                    hooks: self.hooks.clone(),
+                    labeled,
+                    released: false,
+                    #[cfg(feature = "postgres")]
+                    session,
+                })
+            });
+        self.stats.record(start.elapsed(), result.is_ok());
+        if result.is_ok() {
+            self.stats.record_acquire();
+        }
+        result
+    }
+
+    // Runs the acquire hooks again on a connection kept since an earlier call, counted
+    // like a checkout; a failing hook drops the connection without its release hooks
+    #[cfg(feature = "actix-rt")]
+    pub(crate) fn reuse(&self, mut conn: Checkout<Conn>) -> Result<Checkout<Conn>, CheckoutError> {
+        let start = Instant::now();
+        let result = match self
+            .hooks
+            .on_acquire
+            .iter()
+            .try_for_each(|hook| hook(&conn))
+        {
+            Ok(()) => {
+                conn.released = false;
+                Ok(conn)
+            }
+            Err(err) => Err(CheckoutError::new(err)),
+        };
+        self.stats.record(start.elapsed(), result.is_ok());
+        if result.is_ok() {
+            self.stats.record_acquire();
+        }
+        result
+    }
+}
+
+impl<Conn> Checkout<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Runs the release hooks on a connection about to be kept for a later call, which
+    // `CheckoutPool::reuse` acquires again
+    #[cfg(feature = "actix-rt")]
+    pub(crate) fn release(&mut self) {
+        self.released = true;
+        self.run_release_hooks();
+    }
+
+    fn run_release_hooks(&self) {
+        for hook in &self.hooks.on_release {
+            if let Err(err) = hook(&self.conn) {
+                log::warn!("connection release hook failed: {}", err);
+            }
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    pub(crate) fn session(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl<Conn> Clone for CheckoutPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        CheckoutPool {
+            pool: self.pool.clone(),
+            stats: self.stats.clone(),
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+impl<Conn> Deref for Checkout<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        &self.conn
+    }
+}
+
+impl<Conn> Drop for Checkout<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        // diesel keeps counting a transaction whose `COMMIT` failed, even in its own
+        // `Connection::transaction`; the next user of the connection must not inherit it
+        let manager = self.conn.transaction_manager();
+        while TransactionManager::<Conn>::get_transaction_depth(manager) > 0 {
+            if let Err(err) = manager.rollback_transaction(&*self.conn) {
+                log::warn!("rolling back a transaction left open failed: {}", err);
+                break;
+            }
+        }
+        if let Some(labeler) = self.hooks.labeler.as_ref().filter(|_| self.labeled) {
+            if let Err(err) = (labeler.clear)(&self.conn) {
+                log::warn!("clearing connection label failed: {}", err);
+            }
+        }
+        if !self.released {
+            self.run_release_hooks();
+        }
+    }
+}
+
+// The session kept with `conn` in the pool, added by its first checkout
+#[cfg(feature = "postgres")]
+fn with_session<Conn>(
+    mut conn: PooledConnection<ConnectionManager<Conn>>,
+) -> (PooledConnection<ConnectionManager<Conn>>, Arc<Session>)
+where
+    Conn: 'static + Connection,
+{
+    let extensions = PooledConnection::extensions_mut(&mut conn);
+    let session = match extensions.get::<Arc<Session>>() {
+        Some(session) => session.clone(),
+        None => {
+            let session = Arc::new(Session::default());
+            extensions.insert(session.clone());
+            session
+        }
+    };
+    (conn, session)
+}
@@ -0,0 +1,304 @@
+use crate::AsyncError;
+use diesel::result::{
+    ConnectionError, DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError,
+};
+use std::{any::Any, fmt};
+
+/// What kind of failure an error represents, for deciding whether to retry
+/// and how to report it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DatabaseErrorClass {
+    /// No connection could be checked out or established
+    ConnectionUnavailable,
+    /// The connection broke while in use
+    ConnectionLost,
+    SerializationFailure,
+    Deadlock,
+    /// A lock could not be acquired in time (or at all, with `NOWAIT`)
+    LockTimeout,
+    /// The client or server side time limit was exceeded
+    Timeout,
+    UniqueViolation,
+    ForeignKeyViolation,
+    NotNullViolation,
+    CheckViolation,
+    NotFound,
+    Other,
+}
+
+impl DatabaseErrorClass {
+    /// The class of `error`: by its diesel error kind, by its error number
+    /// for MySQL errors of the crate's own libmysqlclient calls, and otherwise
+    /// by the backend's message. diesel 1.4 keeps no SQLSTATE it would give
+    /// out, so classes it has no kind for, such as `Deadlock`, come from the
+    /// message; `of_sqlstate` classifies codes obtained elsewhere.
+    pub fn of(error: &DieselError) -> DatabaseErrorClass {
+        match *error {
+            DieselError::NotFound => DatabaseErrorClass::NotFound,
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                DatabaseErrorClass::UniqueViolation
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                DatabaseErrorClass::ForeignKeyViolation
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => {
+                DatabaseErrorClass::SerializationFailure
+            }
+            // diesel's Postgres backend reports a failure to reach the server this way
+            DieselError::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => {
+                DatabaseErrorClass::ConnectionLost
+            }
+            DieselError::DatabaseError(_, ref info) => {
+                class_of_code(&**info).unwrap_or_else(|| class_of_message(info.message()))
+            }
+            _ => DatabaseErrorClass::Other,
+        }
+    }
+
+    /// The class of the SQLSTATE `code`, such as Postgres's `40P01`.
+    pub fn of_sqlstate(code: &str) -> DatabaseErrorClass {
+        match code {
+            "40001" => DatabaseErrorClass::SerializationFailure,
+            "40P01" => DatabaseErrorClass::Deadlock,
+            // lock_not_available, raised by `lock_timeout` and `NOWAIT`
+            "55P03" => DatabaseErrorClass::LockTimeout,
+            // query_canceled, raised by `statement_timeout`
+            "57014" => DatabaseErrorClass::Timeout,
+            "23505" => DatabaseErrorClass::UniqueViolation,
+            "23503" => DatabaseErrorClass::ForeignKeyViolation,
+            "23502" => DatabaseErrorClass::NotNullViolation,
+            "23514" => DatabaseErrorClass::CheckViolation,
+            // Connections refused, and too many connections
+            "08001" | "08004" | "53300" | "57P03" => DatabaseErrorClass::ConnectionUnavailable,
+            // Connections the server terminated, by shutting down or for being idle
+            "25P03" | "57P01" | "57P02" | "57P05" => DatabaseErrorClass::ConnectionLost,
+            _ if code.starts_with("08") => DatabaseErrorClass::ConnectionLost,
+            _ => DatabaseErrorClass::Other,
+        }
+    }
+
+    /// The class of the MySQL error number `errno`, such as 1213.
+    pub fn of_mysql_errno(errno: u32) -> DatabaseErrorClass {
+        match errno {
+            1213 => DatabaseErrorClass::Deadlock,
+            // Lock wait timeout, and locks not granted with `NOWAIT`
+            1205 | 3572 => DatabaseErrorClass::LockTimeout,
+            // `max_execution_time` exceeded
+            3024 => DatabaseErrorClass::Timeout,
+            1062 | 1586 | 1859 => DatabaseErrorClass::UniqueViolation,
+            1216 | 1217 | 1451 | 1452 | 1830 | 1834 => DatabaseErrorClass::ForeignKeyViolation,
+            1048 => DatabaseErrorClass::NotNullViolation,
+            3819 => DatabaseErrorClass::CheckViolation,
+            // Too many connections, and the client's "can't connect"
+            1040 | 2002 | 2003 => DatabaseErrorClass::ConnectionUnavailable,
+            // The client's "server has gone away" and "lost connection"
+            2006 | 2013 | 2055 => DatabaseErrorClass::ConnectionLost,
+            _ => DatabaseErrorClass::Other,
+        }
+    }
+
+    /// The class's name in snake case, as used in serialized errors and
+    /// metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DatabaseErrorClass::ConnectionUnavailable => "connection_unavailable",
+            DatabaseErrorClass::ConnectionLost => "connection_lost",
+            DatabaseErrorClass::SerializationFailure => "serialization_failure",
+            DatabaseErrorClass::Deadlock => "deadlock",
+            DatabaseErrorClass::LockTimeout => "lock_timeout",
+            DatabaseErrorClass::Timeout => "timeout",
+            DatabaseErrorClass::UniqueViolation => "unique_violation",
+            DatabaseErrorClass::ForeignKeyViolation => "foreign_key_violation",
+            DatabaseErrorClass::NotNullViolation => "not_null_violation",
+            DatabaseErrorClass::CheckViolation => "check_violation",
+            DatabaseErrorClass::NotFound => "not_found",
+            DatabaseErrorClass::Other => "other",
+        }
+    }
+
+    /// Whether the same work may succeed if simply tried again.
+    pub fn is_transient(self) -> bool {
+        match self {
+            DatabaseErrorClass::ConnectionUnavailable
+            | DatabaseErrorClass::ConnectionLost
+            | DatabaseErrorClass::SerializationFailure
+            | DatabaseErrorClass::Deadlock
+            | DatabaseErrorClass::LockTimeout
+            | DatabaseErrorClass::Timeout => true,
+            DatabaseErrorClass::UniqueViolation
+            | DatabaseErrorClass::ForeignKeyViolation
+            | DatabaseErrorClass::NotNullViolation
+            | DatabaseErrorClass::CheckViolation
+            | DatabaseErrorClass::NotFound
+            | DatabaseErrorClass::Other => false,
+        }
+    }
+}
+
+// The class from the backend's own error code, when it can be had: the error number
+// of a MySQL error raised by the crate's own libmysqlclient calls
+fn class_of_code(
+    info: &(dyn DatabaseErrorInformation + Send + Sync),
+) -> Option<DatabaseErrorClass> {
+    #[cfg(feature = "mysql")]
+    if let Some(errno) = crate::mysqlclient::errno(info) {
+        return Some(DatabaseErrorClass::of_mysql_errno(errno));
+    }
+    let _ = info;
+    None
+}
+
+// Without a code, as for diesel's errors, which only keep messages, errors are
+// recognized by the backends' English messages. SQLite's are never
+// translated; MySQL's are unless the server's `lc_messages` says otherwise
+fn class_of_message(message: &str) -> DatabaseErrorClass {
+    let message = message.to_lowercase();
+    let mentions = |patterns: &[&str]| patterns.iter().any(|p| message.contains(p));
+
+    // Postgres 40P01, MySQL 1213
+    if mentions(&["deadlock detected", "deadlock found"]) {
+        DatabaseErrorClass::Deadlock
+    // Postgres 55P03, MySQL 1205, SQLite SQLITE_BUSY
+    } else if mentions(&[
+        "lock timeout",
+        "could not obtain lock",
+        "lock wait timeout exceeded",
+        "database is locked",
+    ]) {
+        DatabaseErrorClass::LockTimeout
+    // Postgres 57014, MySQL 3024, and `AsyncError::Timeout` converted to a diesel error
+    } else if mentions(&[
+        "statement timeout",
+        "maximum statement execution time",
+        "operation timed out",
+    ]) {
+        DatabaseErrorClass::Timeout
+    // Postgres 40001 raised outside a serializable transaction
+    } else if mentions(&["could not serialize access"]) {
+        DatabaseErrorClass::SerializationFailure
+    // Postgres 57P01 and libpq, MySQL 2006/2013
+    } else if mentions(&[
+        "terminating connection",
+        "server closed the connection",
+        "no connection to the server",
+        "could not receive data from server",
+        "connection reset",
+        "server has gone away",
+        "lost connection to mysql server",
+    ]) {
+        DatabaseErrorClass::ConnectionLost
+    // Postgres 23502, SQLite, MySQL 1048
+    } else if mentions(&[
+        "not-null constraint",
+        "not null constraint",
+        "cannot be null",
+    ]) {
+        DatabaseErrorClass::NotNullViolation
+    // Postgres 23514, SQLite, MySQL 3819
+    } else if mentions(&["check constraint"]) {
+        DatabaseErrorClass::CheckViolation
+    } else {
+        DatabaseErrorClass::Other
+    }
+}
+
+impl AsyncError<DieselError> {
+    pub fn class(&self) -> DatabaseErrorClass {
+        class_of(self)
+    }
+
+    /// Whether the same work may succeed if simply tried again, e.g. after a
+    /// deadlock or a serialization failure.
+    pub fn is_transient(&self) -> bool {
+        self.class().is_transient()
+    }
+
+    pub fn is_unique_violation(&self) -> bool {
+        self.class() == DatabaseErrorClass::UniqueViolation
+    }
+
+    pub fn is_foreign_key_violation(&self) -> bool {
+        self.class() == DatabaseErrorClass::ForeignKeyViolation
+    }
+
+    /// The constraint a unique violation tripped, when the backend names it.
+    pub fn unique_violation_constraint(&self) -> Option<&str> {
+        self.violated_constraint(DatabaseErrorClass::UniqueViolation)
+    }
+
+    /// The constraint a foreign key violation tripped, when the backend names it.
+    pub fn foreign_key_violation_constraint(&self) -> Option<&str> {
+        self.violated_constraint(DatabaseErrorClass::ForeignKeyViolation)
+    }
+
+    /// The constraint any violation tripped, when the backend names it.
+    pub fn constraint_name(&self) -> Option<&str> {
+        self.database_error()?.constraint_name()
+    }
+
+    /// The backend's primary message for a database error.
+    pub fn database_message(&self) -> Option<&str> {
+        Some(self.database_error()?.message())
+    }
+
+    /// The backend's explanation of a database error, e.g. Postgres's
+    /// `Key (email)=(a@b.c) already exists.`
+    pub fn details(&self) -> Option<&str> {
+        self.database_error()?.details()
+    }
+
+    /// The backend's suggestion for fixing a database error.
+    pub fn hint(&self) -> Option<&str> {
+        self.database_error()?.hint()
+    }
+
+    /// The table a database error concerns, when the backend names it.
+    pub fn table_name(&self) -> Option<&str> {
+        self.database_error()?.table_name()
+    }
+
+    /// The column a database error concerns, when the backend names it.
+    pub fn column_name(&self) -> Option<&str> {
+        self.database_error()?.column_name()
+    }
+
+    fn violated_constraint(&self, class: DatabaseErrorClass) -> Option<&str> {
+        if self.class() == class {
+            self.constraint_name()
+        } else {
+            None
+        }
+    }
+
+    fn database_error(&self) -> Option<&(dyn DatabaseErrorInformation + Send + Sync)> {
+        match *self {
+            AsyncError::Error(DieselError::DatabaseError(_, ref info)) => Some(&**info),
+            _ => None,
+        }
+    }
+}
+
+// The class of any `AsyncError`; query errors of other types than diesel's are `Other`
+pub(crate) fn class_of<E: 'static + fmt::Debug>(err: &AsyncError<E>) -> DatabaseErrorClass {
+    match *err {
+        AsyncError::Checkout(_) | AsyncError::Connect(ConnectionError::BadConnection(_)) => {
+            DatabaseErrorClass::ConnectionUnavailable
+        }
+        // A bad URL or configuration won't fix itself
+        AsyncError::Connect(_) => DatabaseErrorClass::Other,
+        AsyncError::Error(ref err) => match (err as &dyn Any).downcast_ref::<DieselError>() {
+            Some(err) => DatabaseErrorClass::of(err),
+            None => DatabaseErrorClass::Other,
+        },
+        AsyncError::Timeout => DatabaseErrorClass::Timeout,
+        AsyncError::Canceled
+        | AsyncError::Panicked(_)
+        | AsyncError::Closed
+        | AsyncError::Rejected(_) => DatabaseErrorClass::Other,
+    }
+}
@@ -0,0 +1,107 @@
+use diesel::{
+    r2d2::{Builder, ConnectionManager, Pool},
+    Connection,
+};
+use std::time::Duration;
+
+/// Settings for the r2d2 pool created by `DatabaseBuilder::connect`.
+///
+/// The defaults are r2d2's except for the checkout timeout: 5 seconds rather
+/// than 30, so a starved pool fails a request well before a typical HTTP
+/// client or proxy gives up on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolConfig {
+    max_size: u32,
+    min_idle: Option<u32>,
+    connection_timeout: Duration,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    test_on_checkout: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(5),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            test_on_checkout: true,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn new() -> PoolConfig {
+        PoolConfig::default()
+    }
+
+    /// Most connections the pool opens (default 10).
+    pub fn max_size(mut self, max_size: u32) -> PoolConfig {
+        assert!(max_size > 0, "max_size must be positive");
+        self.max_size = max_size;
+        self
+    }
+
+    /// Idle connections the pool tries to keep open (default `max_size`).
+    pub fn min_idle(mut self, min_idle: Option<u32>) -> PoolConfig {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// How long a checkout waits for a connection before failing with
+    /// `AsyncError::Checkout` (default 5 seconds).
+    pub fn connection_timeout(mut self, timeout: Duration) -> PoolConfig {
+        assert!(
+            timeout > Duration::from_secs(0),
+            "connection_timeout must be positive"
+        );
+        self.connection_timeout = timeout;
+        self
+    }
+
+    /// Age after which a connection is closed (default 30 minutes).
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> PoolConfig {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Idle time after which a connection is closed (default 10 minutes).
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> PoolConfig {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Check connections with `SELECT 1` before handing them out (default true).
+    pub fn test_on_checkout(mut self, test_on_checkout: bool) -> PoolConfig {
+        self.test_on_checkout = test_on_checkout;
+        self
+    }
+
+    /// Create a pool for `database_url` with these settings.
+    ///
+    /// Connections are opened in the background, so this never blocks;
+    /// connection problems surface on checkout.
+    pub fn build_pool<Conn>(&self, database_url: &str) -> Pool<ConnectionManager<Conn>>
+    where
+        Conn: 'static + Connection,
+    {
+        self.builder()
+            .build_unchecked(ConnectionManager::new(database_url))
+    }
+
+    // An r2d2 builder with these settings, for `DatabaseBuilder::connect` to add to
+    pub(crate) fn builder<Conn>(&self) -> Builder<ConnectionManager<Conn>>
+    where
+        Conn: 'static + Connection,
+    {
+        Pool::builder()
+            .max_size(self.max_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(self.connection_timeout)
+            .max_lifetime(self.max_lifetime)
+            .idle_timeout(self.idle_timeout)
+            .test_on_check_out(self.test_on_checkout)
+    }
+}
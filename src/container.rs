@@ -0,0 +1,388 @@
+use crate::{AsyncError, Database, DatabaseBuilder};
+use diesel::{result::Error as DieselError, Connection};
+use std::{
+    fmt, fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::task;
+
+// How often a starting container is tried
+const READY_POLL: Duration = Duration::from_millis(250);
+
+/// Connections whose server `TestContainer` can run in a Docker container.
+pub trait ContainerBackend: Connection {
+    /// The image run unless `TestContainer::image` says otherwise.
+    const IMAGE: &'static str;
+
+    /// The port the server listens on inside the container.
+    const PORT: u16;
+
+    /// The environment setting the server up for `url`.
+    fn environment() -> Vec<(String, String)>;
+
+    /// The URL of the server, once its port is published on `host:port`.
+    fn url(host: &str, port: u16) -> String;
+}
+
+/// Starts a throwaway database server in a Docker container for integration
+/// tests: `start` runs the container, waits until the server accepts
+/// connections, runs the migrations and connects a pool to it.
+///
+/// Needs the `docker` command and a daemon it can reach. The container is
+/// removed when the `ContainerDatabase` is dropped or stopped.
+pub struct TestContainer<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    image: String,
+    env: Vec<(String, String)>,
+    migrations: Option<PathBuf>,
+    startup_timeout: Duration,
+    builder: DatabaseBuilder<Conn>,
+}
+
+/// A database server running in a container, and a pool connected to it,
+/// which the `ContainerDatabase` derefs to.
+///
+/// Dropping it removes the container, blocking the thread while it does, so
+/// prefer `stop` in async code.
+pub struct ContainerDatabase<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    db: Database<Conn>,
+    id: String,
+    url: String,
+    stopped: bool,
+}
+
+/// Why a container database could not be started.
+#[derive(Debug, Error)]
+pub enum ContainerError {
+    // Running `docker` failed, or it reported an error; holds its message
+    #[error("docker: {0}")]
+    Docker(String),
+
+    #[error("reading {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    // The server did not accept connections in time; holds the last error
+    #[error("database not ready within the startup timeout: {0}")]
+    Timeout(String),
+
+    #[error("migration {name} failed: {source}")]
+    Migration {
+        name: String,
+        #[source]
+        source: DieselError,
+    },
+
+    #[error("{0}")]
+    Connect(#[from] AsyncError<DieselError>),
+}
+
+impl<Conn> TestContainer<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    pub fn new() -> TestContainer<Conn> {
+        TestContainer {
+            image: Conn::IMAGE.to_string(),
+            env: Conn::environment(),
+            migrations: None,
+            startup_timeout: Duration::from_secs(60),
+            builder: Database::builder(),
+        }
+    }
+
+    /// Run `image` instead of the backend's default, such as `postgres:13`
+    /// to test against an older server.
+    pub fn image<S: Into<String>>(mut self, image: S) -> TestContainer<Conn> {
+        self.image = image.into();
+        self
+    }
+
+    /// Set the environment variable `name` of the container, for the image's
+    /// own settings.
+    pub fn env<K, V>(mut self, name: K, value: V) -> TestContainer<Conn>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let (name, value) = (name.into(), value.into());
+        self.env.retain(|(n, _)| *n != name);
+        self.env.push((name, value));
+        self
+    }
+
+    /// Run the migrations of `dir` before connecting the pool, in diesel's
+    /// layout: a directory per migration whose name starts with its version,
+    /// holding an `up.sql`.
+    ///
+    /// They run in version order, each in a transaction, and are recorded in
+    /// `__diesel_schema_migrations` as diesel's own runner does.
+    pub fn migrations<P: Into<PathBuf>>(mut self, dir: P) -> TestContainer<Conn> {
+        self.migrations = Some(dir.into());
+        self
+    }
+
+    /// How long to wait for the server to accept connections (default 60
+    /// seconds, as images pulled for the first time take a while).
+    pub fn startup_timeout(mut self, timeout: Duration) -> TestContainer<Conn> {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// Build the database with `builder`, such as for its `PoolConfig`.
+    pub fn database_builder(mut self, builder: DatabaseBuilder<Conn>) -> TestContainer<Conn> {
+        self.builder = builder;
+        self
+    }
+
+    /// Run the container and connect a pool to it once it is ready and
+    /// migrated.
+    pub async fn start(self) -> Result<ContainerDatabase<Conn>, ContainerError> {
+        let TestContainer {
+            image,
+            env,
+            migrations,
+            startup_timeout,
+            builder,
+        } = self;
+
+        let (id, url) = task::spawn_blocking(move || {
+            let id = run_container(&image, &env, Conn::PORT)?;
+            let url = wait_ready::<Conn>(&id, startup_timeout).and_then(|(conn, url)| {
+                if let Some(dir) = migrations {
+                    run_migrations(&conn, &dir)?;
+                }
+                Ok(url)
+            });
+            match url {
+                Ok(url) => Ok((id, url)),
+                Err(err) => {
+                    remove_container(&id);
+                    Err(err)
+                }
+            }
+        })
+        .await
+        .map_err(|_| ContainerError::Connect(AsyncError::Canceled))??;
+
+        match builder.connect(url.clone()).await {
+            Ok(db) => Ok(ContainerDatabase {
+                db,
+                id,
+                url,
+                stopped: false,
+            }),
+            Err(err) => {
+                let _ = task::spawn_blocking(move || remove_container(&id)).await;
+                Err(err.into())
+            }
+        }
+    }
+}
+
+impl<Conn> Default for TestContainer<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    fn default() -> Self {
+        TestContainer::new()
+    }
+}
+
+impl<Conn> ContainerDatabase<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    /// The pool connected to the server.
+    pub fn database(&self) -> &Database<Conn> {
+        &self.db
+    }
+
+    /// The id of the container.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The URL the pool connects to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Shut the pool down, giving in-flight calls a moment to finish, and
+    /// remove the container.
+    pub async fn stop(mut self) {
+        let _ = self.db.shutdown(Duration::from_secs(1)).await;
+        self.stopped = true;
+        let id = self.id.clone();
+        let _ = task::spawn_blocking(move || remove_container(&id)).await;
+    }
+}
+
+impl<Conn> Deref for ContainerDatabase<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    type Target = Database<Conn>;
+
+    fn deref(&self) -> &Database<Conn> {
+        &self.db
+    }
+}
+
+impl<Conn> fmt::Debug for ContainerDatabase<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ContainerDatabase")
+            .field("id", &self.id)
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+impl<Conn> Drop for ContainerDatabase<Conn>
+where
+    Conn: 'static + ContainerBackend,
+{
+    fn drop(&mut self) {
+        if !self.stopped {
+            self.db.close_now();
+            remove_container(&self.id);
+        }
+    }
+}
+
+// Runs `args` with `docker`, returning what it printed
+fn docker(args: &[&str]) -> Result<String, ContainerError> {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .map_err(|err| ContainerError::Docker(err.to_string()))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(ContainerError::Docker(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+// Starts the container with `port` published on a free port of the host, returning its id
+fn run_container(
+    image: &str,
+    env: &[(String, String)],
+    port: u16,
+) -> Result<String, ContainerError> {
+    let port = port.to_string();
+    let env: Vec<String> = env
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    let mut args = vec!["run", "--detach", "--rm", "--publish", &port];
+    for var in &env {
+        args.extend(["--env", var.as_str()]);
+    }
+    args.push(image);
+    docker(&args)
+}
+
+// Only a warning, as a container left behind doesn't fail the test
+fn remove_container(id: &str) {
+    if let Err(err) = docker(&["rm", "--force", "--volumes", id]) {
+        log::warn!("failed to remove container {}: {}", id, err);
+    }
+}
+
+// Connects until the server answers, returning the connection and its URL
+fn wait_ready<Conn>(id: &str, timeout: Duration) -> Result<(Conn, String), ContainerError>
+where
+    Conn: ContainerBackend,
+{
+    // `0.0.0.0:49153`, and a line for IPv6 too
+    let published = docker(&["port", id, &Conn::PORT.to_string()])?;
+    let port = published
+        .lines()
+        .filter_map(|line| line.rsplit(':').next())
+        .find_map(|port| port.parse().ok())
+        .ok_or_else(|| ContainerError::Docker(format!("no published port in {:?}", published)))?;
+    let url = Conn::url("127.0.0.1", port);
+
+    // Servers restart once they have set up their data directory, so a
+    // connection only counts once a statement has run on it
+    let deadline = Instant::now() + timeout;
+    loop {
+        let err = match Conn::establish(&url) {
+            Ok(conn) => match conn.batch_execute("SELECT 1") {
+                Ok(()) => return Ok((conn, url)),
+                Err(err) => err.to_string(),
+            },
+            Err(err) => err.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(ContainerError::Timeout(err));
+        }
+        thread::sleep(READY_POLL);
+    }
+}
+
+// Returns how many migrations ran
+fn run_migrations<Conn: Connection>(conn: &Conn, dir: &Path) -> Result<usize, ContainerError> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| ContainerError::Io { path, source }
+    };
+    let mut migrations = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error(dir))? {
+        let path = entry.map_err(io_error(dir))?.path();
+        if path.join("up.sql").is_file() {
+            migrations.push(path);
+        }
+    }
+    migrations.sort();
+
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (\
+             version VARCHAR(50) PRIMARY KEY NOT NULL, \
+             run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP\
+         )",
+    )
+    .map_err(|source| ContainerError::Migration {
+        name: "__diesel_schema_migrations".to_string(),
+        source,
+    })?;
+
+    for path in &migrations {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // `2021-01-01-000000_create_users` is version `20210101000000`
+        let version = name.split('_').next().unwrap_or_default().replace('-', "");
+        let up = path.join("up.sql");
+        let sql = fs::read_to_string(&up).map_err(io_error(&up))?;
+
+        conn.transaction(|| {
+            conn.batch_execute(&sql)?;
+            conn.batch_execute(&format!(
+                "INSERT INTO __diesel_schema_migrations (version) VALUES ('{}')",
+                version.replace('\'', "''")
+            ))
+        })
+        .map_err(|source| ContainerError::Migration { name, source })?;
+    }
+    Ok(migrations.len())
+}
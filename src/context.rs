@@ -0,0 +1,119 @@
+use crate::AsyncError;
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::{borrow::Cow, fmt, time::Duration};
+use thiserror::Error;
+
+/// An `AsyncError` together with what was being done when it happened.
+///
+/// Created with `ErrorContextExt::context` or returned by
+/// `Database::run_tagged`. The original error is its `source`.
+#[derive(Debug, Error)]
+#[error("{context}: {error}")]
+pub struct ContextError<E: fmt::Debug> {
+    #[source]
+    error: AsyncError<E>,
+    // Boxed to keep results carrying this error small
+    context: Box<ErrorContext>,
+}
+
+#[derive(Debug, Default)]
+struct ErrorContext {
+    tag: Option<Cow<'static, str>>,
+    elapsed: Option<Duration>,
+    attempts: Option<u32>,
+    #[cfg(feature = "backtrace")]
+    backtrace: Option<Backtrace>,
+}
+
+/// Adds `context` to the results of the async methods.
+pub trait ErrorContextExt<T, E: fmt::Debug> {
+    /// Name the failed operation, e.g. `"load users"`, in the error.
+    fn context<S>(self, tag: S) -> Result<T, ContextError<E>>
+    where
+        S: Into<Cow<'static, str>>;
+}
+
+impl<T, E: fmt::Debug> ErrorContextExt<T, E> for Result<T, AsyncError<E>> {
+    fn context<S>(self, tag: S) -> Result<T, ContextError<E>>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.map_err(|error| ContextError::new(error).tag(tag))
+    }
+}
+
+impl<E: fmt::Debug> ContextError<E> {
+    pub(crate) fn new(error: AsyncError<E>) -> ContextError<E> {
+        ContextError {
+            error,
+            context: Box::new(ErrorContext {
+                #[cfg(feature = "backtrace")]
+                backtrace: Some(Backtrace::capture()),
+                ..ErrorContext::default()
+            }),
+        }
+    }
+
+    pub(crate) fn tag<S: Into<Cow<'static, str>>>(mut self, tag: S) -> ContextError<E> {
+        self.context.tag = Some(tag.into());
+        self
+    }
+
+    pub(crate) fn elapsed(mut self, elapsed: Duration) -> ContextError<E> {
+        self.context.elapsed = Some(elapsed);
+        self
+    }
+
+    pub(crate) fn attempts(mut self, attempts: u32) -> ContextError<E> {
+        self.context.attempts = Some(attempts);
+        self
+    }
+
+    pub fn error(&self) -> &AsyncError<E> {
+        &self.error
+    }
+
+    pub fn into_error(self) -> AsyncError<E> {
+        self.error
+    }
+
+    /// The name given to the failed operation.
+    pub fn query_tag(&self) -> Option<&str> {
+        self.context.tag.as_deref()
+    }
+
+    /// How long the operation ran before failing, when measured.
+    pub fn query_elapsed(&self) -> Option<Duration> {
+        self.context.elapsed
+    }
+
+    /// How many times a connection was checked out for the operation, when
+    /// counted.
+    pub fn query_attempts(&self) -> Option<u32> {
+        self.context.attempts
+    }
+
+    /// Where the error was given its context; only captured when
+    /// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` enables it.
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.context.backtrace.as_ref()
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.tag {
+            Some(ref tag) => write!(f, "query `{}` failed", tag)?,
+            None => write!(f, "query failed")?,
+        }
+        if let Some(elapsed) = self.elapsed {
+            write!(f, " after {:?}", elapsed)?;
+        }
+        match self.attempts {
+            Some(attempts) if attempts > 1 => write!(f, " ({} attempts)", attempts),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{
+    libpq::RawConnection,
+    limiter::Priority,
+    stream::{self, INPUT_BUFFER, STREAM_BUFFER},
+    AsyncError, Database, LoadStream,
+};
+use bytes::Bytes;
+use diesel::{pg::PgConnection, result::Error as DieselError};
+use futures::future;
+use tokio::{io::AsyncRead, sync::mpsc};
+
+impl Database<PgConnection> {
+    /// Run `sql`, a `COPY ... FROM STDIN` statement, with `source` as its
+    /// input, returning the number of rows copied.
+    ///
+    /// `source` is read on the current task and handed to a blocking thread
+    /// through a bounded buffer, so a large file never sits in memory at
+    /// once. A read error aborts the copy, which fails with the error's
+    /// message. Runs on a connection of its own, established outside the pool
+    /// and closed afterwards; needs the database URL, see
+    /// `DatabaseBuilder::database_url`.
+    pub async fn copy_in_async<R>(
+        &self,
+        sql: &str,
+        source: R,
+    ) -> Result<u64, AsyncError<DieselError>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let in_flight = self.admit()?;
+        let url = self.database_url()?;
+        let sql = sql.to_string();
+        let (tx, mut rx) = mpsc::channel(INPUT_BUFFER);
+
+        let copy = self.spawn_job(Priority::Normal, move || {
+            let _in_flight = in_flight;
+            let conn = RawConnection::establish(&url).map_err(AsyncError::Connect)?;
+            conn.copy_in(&sql, std::iter::from_fn(|| rx.blocking_recv()))
+                .map_err(AsyncError::Error)
+        });
+        let (_, copied) = future::join(stream::feed_input("COPY", source, tx), copy).await;
+        copied.map_err(|_| AsyncError::Canceled)?
+    }
+
+    /// Run `sql`, a `COPY ... TO STDOUT` statement, streaming its output as
+    /// it is produced, one row per item in text and CSV formats, so exports
+    /// can be piped straight into an HTTP response body.
+    ///
+    /// Runs on a connection of its own, like `copy_in_async`; dropping the
+    /// stream closes it, which stops the copy.
+    pub fn copy_out_async(&self, sql: &str) -> LoadStream<'_, Bytes> {
+        let sql = sql.to_string();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let feed = async move {
+            let in_flight = self.admit()?;
+            let url = self.database_url()?;
+            self.spawn_job(Priority::Normal, move || {
+                let _in_flight = in_flight;
+                let conn = RawConnection::establish(&url).map_err(AsyncError::Connect)?;
+                conn.copy_out(&sql, |chunk| tx.blocking_send(Bytes::from(chunk)).is_ok())
+                    .map(|_| ())
+                    .map_err(AsyncError::Error)
+            })
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+        };
+
+        LoadStream::new(Box::pin(feed), rx)
+    }
+}
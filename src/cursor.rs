@@ -0,0 +1,116 @@
+use crate::{stream::STREAM_BUFFER, AsyncConnection, Database, LoadStream};
+use diesel::{
+    pg::{Pg, PgConnection},
+    query_builder::{AsQuery, AstPass, Query, QueryFragment, QueryId},
+    result::QueryResult,
+    sql_types::HasSqlType,
+    Queryable, RunQueryDsl,
+};
+use std::marker::PhantomData;
+use tokio::sync::mpsc;
+
+// Each stream declares its cursor in a transaction of its own, so one name does
+const CURSOR: &str = "db_cursor";
+
+// `DECLARE ... CURSOR FOR` the wrapped query, binds included
+struct DeclareCursor<Q>(Q);
+
+// `FETCH` the next rows of the cursor, typed as the declared query's rows
+struct Fetch<ST> {
+    count: i64,
+    sql_type: PhantomData<fn() -> ST>,
+}
+
+impl Database<PgConnection> {
+    /// Stream the rows of `query` through a server side cursor, fetching
+    /// `batch_size` rows at a time, so memory use stays bounded by the batch
+    /// size rather than the size of the result.
+    ///
+    /// The cursor lives in a transaction on a connection held for the life of
+    /// the stream, which is rolled back once the rows run out or the stream is
+    /// dropped. Each `FETCH` is a job of its own, so the blocking thread is
+    /// given back between batches.
+    pub fn load_cursor_async<U, Q>(&self, query: Q, batch_size: i64) -> LoadStream<'_, U>
+    where
+        Q: AsQuery,
+        Q::Query: 'static + Send + QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<Q::SqlType>,
+        U: 'static + Send + Queryable<Q::SqlType, Pg>,
+    {
+        assert!(batch_size > 0, "batch size must be positive");
+
+        let declare = DeclareCursor(query.as_query());
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let feed = async move {
+            let cursor = self.begin().await?;
+            cursor.run(move |conn| declare.execute(conn)).await?;
+            loop {
+                let rows: Vec<U> = cursor
+                    .run(move |conn| Fetch::<Q::SqlType>::new(batch_size).load(conn))
+                    .await?;
+                let last = (rows.len() as i64) < batch_size;
+                for row in rows {
+                    // The stream was dropped; dropping the transaction closes the cursor
+                    if tx.send(row).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                if last {
+                    return cursor.rollback().await;
+                }
+            }
+        };
+
+        LoadStream::new(Box::pin(feed), rx)
+    }
+}
+
+impl<ST> Fetch<ST> {
+    fn new(count: i64) -> Self {
+        Fetch {
+            count,
+            sql_type: PhantomData,
+        }
+    }
+}
+
+impl<Q> QueryFragment<Pg> for DeclareCursor<Q>
+where
+    Q: QueryFragment<Pg>,
+{
+    fn walk_ast(&self, mut out: AstPass<'_, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("DECLARE ");
+        out.push_identifier(CURSOR)?;
+        out.push_sql(" NO SCROLL CURSOR FOR ");
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> QueryId for DeclareCursor<Q> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> RunQueryDsl<PgConnection> for DeclareCursor<Q> {}
+
+impl<ST> QueryFragment<Pg> for Fetch<ST> {
+    fn walk_ast(&self, mut out: AstPass<'_, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql(&format!("FETCH {} FROM ", self.count));
+        out.push_identifier(CURSOR)
+    }
+}
+
+impl<ST> Query for Fetch<ST> {
+    type SqlType = ST;
+}
+
+impl<ST> QueryId for Fetch<ST> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<ST> RunQueryDsl<PgConnection> for Fetch<ST> {}
@@ -0,0 +1,995 @@
+#[cfg(feature = "actix-rt")]
+use crate::arbiter::{self, ArbiterPool};
+#[cfg(feature = "otel")]
+use crate::otel::{self, OtelConfig};
+use crate::{
+    audit::{self, AuditSink},
+    checkout::{Checkout, CheckoutPool, Hook, Hooks, Labeler},
+    classify,
+    config::PoolConfig,
+    context::ContextError,
+    deadline,
+    intercept::{QueryCall, QueryInterceptor},
+    label::{self, SessionLabel},
+    limiter::{Limiter, Priority},
+    metrics,
+    retry::RetryPolicy,
+    run_guarded,
+    stats::{self, CheckoutStats, PoolStats},
+    thread_pool::{Canceled, ThreadPool},
+    trace,
+    writer::{self, WriteLock, Writer},
+    AsyncConnection, AsyncError, AsyncSimpleConnection,
+};
+use async_trait::async_trait;
+use diesel::{
+    r2d2::{ConnectionManager, CustomizeConnection, Error as R2D2Error, Pool},
+    result::{ConnectionError, Error as DieselError, QueryResult},
+    Connection,
+};
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{runtime, sync::watch, task, time};
+#[cfg(feature = "prometheus")]
+use {crate::prometheus_metrics::QueryCollectors, std::sync::OnceLock};
+
+/// A connection pool together with the configuration used to run work on it.
+///
+/// `Database` implements the same async traits as a bare r2d2 `Pool`, so the
+/// `*_async` DSL methods accept either. Clones share the same pool and
+/// configuration.
+pub struct Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    shared: Arc<Shared<Conn>>,
+}
+
+pub struct DatabaseBuilder<Conn>
+where
+    Conn: 'static + Connection,
+{
+    executor: Executor,
+    max_concurrent_queries: Option<usize>,
+    database_url: Option<String>,
+    checkout_retry: Option<RetryPolicy>,
+    slow_query_threshold: Option<Duration>,
+    redact_bind_values: bool,
+    pool_config: PoolConfig,
+    pub(crate) connection_customizer: Option<Box<dyn CustomizeConnection<Conn, R2D2Error>>>,
+    pub(crate) single_writer: bool,
+    hooks: Hooks<Conn>,
+    interceptors: Vec<Box<dyn QueryInterceptor>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    #[cfg(feature = "otel")]
+    otel: OtelConfig,
+}
+
+struct Shared<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Taken on shutdown so the connections close once in-flight work lets go of them
+    pool: RwLock<Option<Pool<ConnectionManager<Conn>>>>,
+    executor: Executor,
+    limit: Option<Arc<Limiter>>,
+    lifecycle: watch::Sender<Lifecycle>,
+    // Used to establish connections outside the pool
+    database_url: Option<String>,
+    checkout_retry: Option<RetryPolicy>,
+    slow_query_threshold: Option<Duration>,
+    redact_bind_values: bool,
+    stats: Arc<CheckoutStats>,
+    // Set in single-writer mode, for every call but reads
+    writer: Option<Writer<Conn>>,
+    hooks: Arc<Hooks<Conn>>,
+    interceptors: Vec<Box<dyn QueryInterceptor>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    #[cfg(feature = "prometheus")]
+    prometheus: OnceLock<QueryCollectors>,
+    #[cfg(feature = "otel")]
+    otel: OtelConfig,
+}
+
+#[derive(Clone, Copy)]
+struct Lifecycle {
+    closed: bool,
+    in_flight: usize,
+}
+
+// Counts one admitted call as in flight until dropped
+pub(crate) struct InFlight(watch::Sender<Lifecycle>);
+
+// Where the blocking diesel calls are run
+#[derive(Clone)]
+enum Executor {
+    // Tokio's blocking thread pool, shared with the rest of the runtime
+    Blocking,
+
+    // Threads owned by the crate
+    ThreadPool(ThreadPool),
+
+    // Actix arbiters, each keeping a connection of the database
+    #[cfg(feature = "actix-rt")]
+    Arbiters(ArbiterPool),
+}
+
+impl Executor {
+    async fn spawn<F, R>(&self, priority: Priority, name: Arc<str>, f: F) -> Result<R, Canceled>
+    where
+        F: 'static + FnOnce() -> R + Send,
+        R: 'static + Send,
+    {
+        match *self {
+            // Task names are only kept, for tokio-console, with tokio's unstable tracing
+            #[cfg(all(tokio_unstable, feature = "tracing"))]
+            Executor::Blocking => task::Builder::new()
+                .name(&name)
+                .spawn_blocking(f)
+                .map_err(|_| Canceled)?
+                .await
+                .map_err(|_| Canceled),
+            #[cfg(not(all(tokio_unstable, feature = "tracing")))]
+            Executor::Blocking => {
+                let _ = name;
+                task::spawn_blocking(f).await.map_err(|_| Canceled)
+            }
+            Executor::ThreadPool(ref pool) => pool.spawn(priority, Some(name), f).await,
+            #[cfg(feature = "actix-rt")]
+            Executor::Arbiters(ref arbiters) => arbiters.spawn(f).await,
+        }
+    }
+
+    // Fire and forget, for cleanup that has to happen from `Drop`
+    fn spawn_detached<F>(&self, f: F)
+    where
+        F: 'static + FnOnce() + Send,
+    {
+        match *self {
+            Executor::Blocking => match runtime::Handle::try_current() {
+                Ok(handle) => drop(handle.spawn_blocking(f)),
+                Err(_) => f(),
+            },
+            Executor::ThreadPool(ref pool) => pool.execute(f),
+            #[cfg(feature = "actix-rt")]
+            Executor::Arbiters(ref arbiters) => arbiters.execute(f),
+        }
+    }
+
+    // Closes the connections kept for the database `db`
+    fn forget(&self, db: usize) {
+        #[cfg(feature = "actix-rt")]
+        if let Executor::Arbiters(ref arbiters) = *self {
+            arbiters.forget(db);
+        }
+        let _ = db;
+    }
+}
+
+impl<Conn> Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub fn new(pool: Pool<ConnectionManager<Conn>>) -> Database<Conn> {
+        Database::builder().build(pool)
+    }
+
+    /// Create a pool for `database_url` with the default `PoolConfig`, after
+    /// checking the database is reachable; see `DatabaseBuilder::connect`.
+    pub async fn connect<S: Into<String>>(
+        database_url: S,
+    ) -> Result<Database<Conn>, AsyncError<DieselError>> {
+        Database::builder().connect(database_url).await
+    }
+
+    pub fn builder() -> DatabaseBuilder<Conn> {
+        DatabaseBuilder {
+            executor: Executor::Blocking,
+            max_concurrent_queries: None,
+            database_url: None,
+            checkout_retry: None,
+            slow_query_threshold: None,
+            redact_bind_values: true,
+            pool_config: PoolConfig::default(),
+            connection_customizer: None,
+            single_writer: false,
+            hooks: Hooks::default(),
+            interceptors: Vec::new(),
+            audit_sink: None,
+            #[cfg(feature = "otel")]
+            otel: OtelConfig::default(),
+        }
+    }
+
+    /// The underlying r2d2 pool, or `None` once the database has been shut down.
+    pub fn pool(&self) -> Option<Pool<ConnectionManager<Conn>>> {
+        self.shared.pool.read().unwrap().clone()
+    }
+
+    /// Connection counts and checkout statistics for the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.shared
+            .stats
+            .snapshot(self.pool().map(|pool| pool.state()))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lifecycle.borrow().closed
+    }
+
+    /// Open and validate `n` connections (at most the pool's maximum size)
+    /// ahead of time, so the first requests after startup don't wait for
+    /// connections to be established.
+    ///
+    /// The connections are checked out together, which makes the pool open
+    /// new ones instead of reusing idle ones, then each runs `SELECT 1`.
+    pub async fn warm_up(&self, n: u32) -> Result<(), AsyncError<DieselError>> {
+        let in_flight = self.admit()?;
+        let pool = self.pool().ok_or(AsyncError::Closed)?;
+        let n = n.min(pool.max_size());
+
+        self.spawn_job(Priority::Normal, move || {
+            let _in_flight = in_flight;
+            let mut conns = Vec::with_capacity(n as usize);
+            for _ in 0..n {
+                let conn = pool.get().map_err(|e| AsyncError::Checkout(e.into()))?;
+                conn.execute("SELECT 1").map_err(AsyncError::Error)?;
+                conns.push(conn);
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+
+    /// Stop accepting new work, wait up to `grace` for in-flight calls to
+    /// finish, then release the pool.
+    ///
+    /// Calls made after shutdown begins fail with `AsyncError::Closed`. Returns
+    /// `AsyncError::Timeout` if work was still running when the grace period
+    /// ran out; that work keeps its connection until it completes.
+    pub async fn shutdown(&self, grace: Duration) -> Result<(), AsyncError<DieselError>> {
+        self.shared
+            .lifecycle
+            .send_modify(|lifecycle| lifecycle.closed = true);
+
+        let mut lifecycle = self.shared.lifecycle.subscribe();
+        let drained = time::timeout(grace, lifecycle.wait_for(|l| l.in_flight == 0)).await;
+
+        self.release();
+
+        match drained {
+            Ok(_) => Ok(()),
+            Err(_) => Err(AsyncError::Timeout),
+        }
+    }
+
+    // Shutdown without waiting, for `Drop` impls that can't await it
+    pub(crate) fn close_now(&self) {
+        self.shared
+            .lifecycle
+            .send_modify(|lifecycle| lifecycle.closed = true);
+        self.release();
+    }
+
+    // Lets go of the pool, whose idle connections close once in-flight calls are done
+    fn release(&self) {
+        self.shared.pool.write().unwrap().take();
+        self.shared.executor.forget(self.id());
+        if let Some(conn) = self.shared.writer.as_ref().and_then(Writer::take) {
+            self.spawn_detached(move || drop(conn));
+        }
+    }
+
+    /// Like `run`, but when the database is saturated higher priority callers
+    /// are admitted ahead of lower priority ones.
+    pub async fn run_with_priority<R, E, Func>(
+        &self,
+        priority: Priority,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.dispatch(priority, f).await
+    }
+
+    /// Like `run`, but a failure is reported as a `ContextError` naming `tag`
+    /// and recording how long the call took and how many checkouts it made.
+    pub async fn run_tagged<R, E, Func, S>(&self, tag: S, f: Func) -> Result<R, ContextError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+        S: Into<Cow<'static, str>>,
+    {
+        let tag = tag.into();
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = match self.start_call(Some(&tag)) {
+            Ok(()) => {
+                let result = trace::with_tag(
+                    &*tag,
+                    self.dispatch_counting(Priority::Normal, move |conn| f(conn), &mut attempts),
+                )
+                .await;
+                self.finish_call(start, Some(&tag), &result);
+                result
+            }
+            Err(err) => Err(err),
+        };
+
+        result.map_err(|err| {
+            ContextError::new(err)
+                .tag(tag)
+                .elapsed(start.elapsed())
+                .attempts(attempts)
+        })
+    }
+
+    /// Run `f` on a pooled connection of the concrete backend type, for
+    /// backend specific APIs not covered by the generic traits.
+    ///
+    /// Unlike `run`, the closure's error type need not be convertible from a
+    /// diesel error; checkout failures are still reported as
+    /// `AsyncError::Checkout`.
+    pub async fn with_raw<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.dispatch(Priority::Normal, f).await
+    }
+
+    /// Run `f` on a brand new connection, established outside the pool and
+    /// closed afterwards, for work that must not share session state with
+    /// pooled connections (`CREATE DATABASE`, long maintenance DDL, ...).
+    ///
+    /// Needs the database URL, see `DatabaseBuilder::database_url`.
+    pub async fn run_isolated<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let in_flight = self.admit()?;
+        let url = self.database_url()?;
+
+        self.spawn_job(Priority::Normal, move || {
+            let _in_flight = in_flight;
+            let conn = Conn::establish(&url).map_err(AsyncError::Connect)?;
+            run_guarded(&conn, f)
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+
+    async fn dispatch<R, E, Func>(&self, priority: Priority, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.dispatch_checkout(priority, move |conn| f(conn)).await
+    }
+
+    // Like `dispatch`, handing `f` the checkout, for what the crate keeps about its session
+    pub(crate) async fn dispatch_checkout<R, E, Func>(
+        &self,
+        priority: Priority,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Checkout<Conn>) -> Result<R, E> + Send,
+    {
+        let start = Instant::now();
+        self.start_call(None)?;
+        let result = self.dispatch_counting(priority, f, &mut 0).await;
+        self.finish_call(start, None, &result);
+        result
+    }
+
+    // Like `dispatch`, counting the checkout attempts made into `attempts`
+    async fn dispatch_counting<R, E, Func>(
+        &self,
+        priority: Priority,
+        f: Func,
+        attempts: &mut u32,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Checkout<Conn>) -> Result<R, E> + Send,
+    {
+        if let Some(conn) = self.pinned() {
+            *attempts = 1;
+            return self.run_pinned(conn, priority, f).await;
+        }
+        if let Some(writer) = self.shared.writer.clone().filter(|_| !writer::is_read()) {
+            *attempts = 1;
+            return self.run_on_writer(writer, priority, f).await;
+        }
+
+        let pool = self.checkout_pool()?;
+        let in_flight = self.admit()?;
+        let owner = self.owner();
+        deadline::enforced(async {
+            let mut pending = (f, in_flight);
+            loop {
+                *attempts += 1;
+                let (f, in_flight) = pending;
+                let pool = pool.clone();
+                // A failed checkout hands the closure back so it can be retried
+                let outcome = self
+                    .spawn_job(priority, move || {
+                        let conn = match owner {
+                            #[cfg(feature = "actix-rt")]
+                            Some(db) => arbiter::checkout(db, &pool),
+                            _ => pool.get(),
+                        };
+                        match conn {
+                            Ok(conn) => {
+                                let _in_flight = in_flight;
+                                let result = run_guarded(&*conn, |_| f(&conn));
+                                #[cfg(feature = "actix-rt")]
+                                if let Some(db) = owner {
+                                    arbiter::keep(db, conn, &result);
+                                }
+                                Ok(result)
+                            }
+                            Err(err) => Err((f, in_flight, err)),
+                        }
+                    })
+                    .await
+                    .map_err(|_| AsyncError::Canceled)?;
+
+                match outcome {
+                    Ok(result) => return result,
+                    Err((f, in_flight, err)) => match self.shared.checkout_retry {
+                        Some(ref policy) if policy.should_retry(*attempts) => {
+                            time::sleep(policy.delay(*attempts)).await;
+                            pending = (f, in_flight);
+                        }
+                        _ => return Err(AsyncError::Checkout(err)),
+                    },
+                }
+            }
+        })
+        .await
+    }
+
+    // The writer's turn, held by a transaction or guard of a single-writer database
+    pub(crate) async fn write_lock(&self) -> Option<WriteLock<Conn>> {
+        match self.shared.writer {
+            Some(ref writer) => Some(writer.lock().await),
+            None => None,
+        }
+    }
+
+    // The database whose connections the executor's workers keep, when they do
+    fn owner(&self) -> Option<usize> {
+        match self.shared.executor {
+            #[cfg(feature = "actix-rt")]
+            Executor::Arbiters(_) => Some(self.id()),
+            _ => None,
+        }
+    }
+
+    // The pool, with checkouts counted in `stats` and run through the hooks
+    pub(crate) fn checkout_pool<E: fmt::Debug>(&self) -> Result<CheckoutPool<Conn>, AsyncError<E>> {
+        let pool = self.pool().ok_or(AsyncError::Closed)?;
+        Ok(CheckoutPool::new(
+            pool,
+            self.shared.stats.clone(),
+            self.shared.hooks.clone(),
+        ))
+    }
+
+    // Run `job` on the executor, subject to the concurrency limit
+    pub(crate) async fn spawn_job<F, R>(&self, priority: Priority, job: F) -> Result<R, Canceled>
+    where
+        F: 'static + FnOnce() -> R + Send,
+        R: 'static + Send,
+    {
+        let called_at = Instant::now();
+        // Excess callers queue here instead of occupying blocking threads; the permit
+        // moves into the job so it is held until the query itself finishes
+        let permit = match self.shared.limit {
+            Some(ref limit) => Some(limit.acquire(priority).await),
+            None => None,
+        };
+
+        let job = stats::called_at(called_at, label::labeled(metrics::measured(job)));
+        self.shared
+            .executor
+            .spawn(priority, self.task_name(), move || {
+                let _permit = permit;
+                job()
+            })
+            .await
+    }
+
+    pub(crate) fn spawn_detached<F>(&self, job: F)
+    where
+        F: 'static + FnOnce() + Send,
+    {
+        self.shared.executor.spawn_detached(job)
+    }
+
+    // Runs the interceptors' `before_query` for a `run`, `transaction` or `*_async`
+    // call about to start
+    pub(crate) fn start_call<E: fmt::Debug>(&self, tag: Option<&str>) -> Result<(), AsyncError<E>> {
+        self.offer_audit();
+        if self.shared.interceptors.is_empty() {
+            return Ok(());
+        }
+
+        let statement = self.statement();
+        let call = QueryCall {
+            tag,
+            statement: statement.as_deref(),
+        };
+        for interceptor in &self.shared.interceptors {
+            interceptor
+                .before_query(&call)
+                .map_err(AsyncError::Rejected)?;
+        }
+        Ok(())
+    }
+
+    // Reports a finished `run`, `transaction` or `*_async` call to the interceptors,
+    // the slow query log and the metrics
+    pub(crate) fn finish_call<R, E>(
+        &self,
+        start: Instant,
+        tag: Option<&str>,
+        result: &Result<R, AsyncError<E>>,
+    ) where
+        E: 'static + fmt::Debug,
+    {
+        let elapsed = start.elapsed();
+        let statement = self.statement();
+        if let Err(ref err) = *result {
+            self.shared.stats.record_error(err);
+        }
+        match self.shared.slow_query_threshold {
+            Some(threshold) if elapsed >= threshold => log::warn!(
+                "slow query took {:?} (threshold {:?}): {}",
+                elapsed,
+                threshold,
+                tag.or(statement.as_deref()).unwrap_or("untagged")
+            ),
+            _ => {}
+        }
+        if let (Err(ref err), Some(ref sql)) = (result, &statement) {
+            log::debug!(
+                "database call failed ({}): {}",
+                classify::class_of(err).as_str(),
+                sql
+            );
+        }
+
+        if !self.shared.interceptors.is_empty() {
+            let call = QueryCall {
+                tag,
+                statement: statement.as_deref(),
+            };
+            let outcome = match *result {
+                Ok(_) => Ok(()),
+                Err(ref err) => Err(classify::class_of(err)),
+            };
+            for interceptor in &self.shared.interceptors {
+                interceptor.after_query(&call, outcome, elapsed);
+            }
+        }
+
+        #[cfg(feature = "prometheus")]
+        if let Some(collectors) = self.shared.prometheus.get() {
+            collectors.observe(elapsed, result);
+        }
+        #[cfg(feature = "otel")]
+        otel::record_span(
+            self,
+            &self.shared.otel,
+            elapsed,
+            tag,
+            statement.as_deref(),
+            result,
+        );
+    }
+
+    // For connections established outside the pool
+    pub(crate) fn database_url<E: fmt::Debug>(&self) -> Result<String, AsyncError<E>> {
+        self.shared.database_url.clone().ok_or_else(|| {
+            AsyncError::Connect(ConnectionError::InvalidConnectionUrl(
+                "no database URL configured".to_string(),
+            ))
+        })
+    }
+
+    // Lets a write being audited know where to record its event
+    pub(crate) fn offer_audit(&self) {
+        if let Some(ref sink) = self.shared.audit_sink {
+            audit::offer(sink);
+        }
+    }
+
+    // Names the blocking task of the current call after its tag, or else its statement
+    fn task_name(&self) -> Arc<str> {
+        const MAX_LEN: usize = 100;
+
+        let name = match trace::tag().or_else(|| self.statement()) {
+            Some(name) => name,
+            None => return "db".into(),
+        };
+        match name.char_indices().nth(MAX_LEN) {
+            Some((end, _)) => format!("db: {}...", &name[..end]).into(),
+            None => format!("db: {}", name).into(),
+        }
+    }
+
+    // The statement of the current call, its bind values dropped unless configured not to
+    fn statement(&self) -> Option<Arc<str>> {
+        let sql = trace::statement()?;
+        if self.shared.redact_bind_values {
+            Some(trace::redact_binds(&sql).into())
+        } else {
+            Some(sql)
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn set_query_collectors(
+        &self,
+        collectors: QueryCollectors,
+    ) -> Result<(), QueryCollectors> {
+        let acquire_wait = collectors.acquire_wait.clone();
+        self.shared.prometheus.set(collectors)?;
+        self.shared.stats.set_acquire_histogram(acquire_wait);
+        Ok(())
+    }
+
+    // Identifies the shared state, so clones of one database compare equal
+    pub(crate) fn id(&self) -> usize {
+        Arc::as_ptr(&self.shared) as *const () as usize
+    }
+
+    pub(crate) fn admit<E: fmt::Debug>(&self) -> Result<InFlight, AsyncError<E>> {
+        let admitted = self.shared.lifecycle.send_if_modified(|lifecycle| {
+            if lifecycle.closed {
+                return false;
+            }
+            lifecycle.in_flight += 1;
+            true
+        });
+
+        if admitted {
+            Ok(InFlight(self.shared.lifecycle.clone()))
+        } else {
+            Err(AsyncError::Closed)
+        }
+    }
+}
+
+impl<Conn> Drop for Shared<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        // `Database::id` of the last clone, which workers may still keep a connection for
+        self.executor
+            .forget(self as *const Shared<Conn> as *const () as usize);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.send_modify(|lifecycle| lifecycle.in_flight -= 1);
+    }
+}
+
+impl<Conn> Clone for Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        Database {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Conn> From<Pool<ConnectionManager<Conn>>> for Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn from(pool: Pool<ConnectionManager<Conn>>) -> Self {
+        Database::new(pool)
+    }
+}
+
+impl<Conn> DatabaseBuilder<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Run queries on a dedicated thread pool instead of Tokio's blocking pool.
+    pub fn thread_pool(mut self, thread_pool: ThreadPool) -> DatabaseBuilder<Conn> {
+        self.executor = Executor::ThreadPool(thread_pool);
+        self
+    }
+
+    /// Run queries on actix arbiters, each keeping a connection, instead of
+    /// Tokio's blocking pool; see `ArbiterPool`.
+    #[cfg(feature = "actix-rt")]
+    pub fn arbiters(mut self, arbiters: ArbiterPool) -> DatabaseBuilder<Conn> {
+        self.executor = Executor::Arbiters(arbiters);
+        self
+    }
+
+    /// Limit how many queries may be running or waiting on a blocking thread
+    /// at once; further callers wait asynchronously (default unlimited).
+    pub fn max_concurrent_queries(mut self, max: usize) -> DatabaseBuilder<Conn> {
+        assert!(max > 0, "max_concurrent_queries must be positive");
+        self.max_concurrent_queries = Some(max);
+        self
+    }
+
+    /// The URL the pool connects to, needed by `Database::run_isolated`.
+    pub fn database_url<S: Into<String>>(mut self, database_url: S) -> DatabaseBuilder<Conn> {
+        self.database_url = Some(database_url.into());
+        self
+    }
+
+    /// Retry failed checkouts in `run`, `transaction` and the `*_async`
+    /// methods according to `policy` before reporting `AsyncError::Checkout`
+    /// (default no retries).
+    pub fn checkout_retry(mut self, policy: RetryPolicy) -> DatabaseBuilder<Conn> {
+        self.checkout_retry = Some(policy);
+        self
+    }
+
+    /// Log a warning for every `run`, `transaction` or `*_async` call taking
+    /// `threshold` or longer, checkout included (default off).
+    ///
+    /// The warning names the `run_tagged` tag, or else the statement when it
+    /// is known, as for `batch_execute_async` or inside `with_sql`.
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> DatabaseBuilder<Conn> {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Drop the bind values `debug_query` appends to statements given to
+    /// `with_sql` before they reach logs, interceptors and spans (default on),
+    /// so they show the query's shape without the data it was run with.
+    pub fn redact_bind_values(mut self, redact: bool) -> DatabaseBuilder<Conn> {
+        self.redact_bind_values = redact;
+        self
+    }
+
+    /// Create a pool for `database_url` with the configured `PoolConfig` and
+    /// build the database on it.
+    ///
+    /// Before returning, one connection is established and runs `SELECT 1`
+    /// on a blocking thread, so a wrong URL or an unreachable server fails
+    /// here with `AsyncError::Connect` instead of at the first query.
+    pub async fn connect<S: Into<String>>(
+        mut self,
+        database_url: S,
+    ) -> Result<Database<Conn>, AsyncError<DieselError>> {
+        let database_url = database_url.into();
+        let mut pool = self.pool_config.builder();
+        if let Some(customizer) = self.connection_customizer.take() {
+            pool = pool.connection_customizer(customizer);
+        }
+        // The pool fills in the background; the check below stands in for r2d2's own
+        let pool = pool.build_unchecked(ConnectionManager::new(database_url.clone()));
+        let db = self.database_url(database_url.clone()).build(pool);
+
+        db.spawn_job(Priority::Normal, move || {
+            let conn = Conn::establish(&database_url).map_err(AsyncError::Connect)?;
+            conn.execute("SELECT 1").map_err(AsyncError::Error)
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)??;
+
+        Ok(db)
+    }
+
+    /// Run `hook` on every connection the database checks out, before it is
+    /// used, e.g. to `SET` the time zone or `search_path`.
+    ///
+    /// A failing hook fails the checkout with `AsyncError::Checkout`. Hooks run
+    /// in the order they were added, on the blocking thread that checked out.
+    pub fn on_acquire<F>(mut self, hook: F) -> DatabaseBuilder<Conn>
+    where
+        F: 'static + Fn(&Conn) -> QueryResult<()> + Send + Sync,
+    {
+        self.hooks.on_acquire.push(Box::new(hook) as Hook<Conn>);
+        self
+    }
+
+    /// Run `hook` on every connection the database checks out, just before it
+    /// goes back to the pool, e.g. to `RESET` session settings.
+    ///
+    /// A failing hook is logged and the connection returned anyway.
+    pub fn on_release<F>(mut self, hook: F) -> DatabaseBuilder<Conn>
+    where
+        F: 'static + Fn(&Conn) -> QueryResult<()> + Send + Sync,
+    {
+        self.hooks.on_release.push(Box::new(hook) as Hook<Conn>);
+        self
+    }
+
+    /// How calls are reported as OpenTelemetry spans (default
+    /// `OtelConfig::default()`).
+    #[cfg(feature = "otel")]
+    pub fn otel(mut self, config: OtelConfig) -> DatabaseBuilder<Conn> {
+        self.otel = config;
+        self
+    }
+
+    /// Pass every `run`, `transaction` and `*_async` call through
+    /// `interceptor`, after the interceptors already added.
+    pub fn interceptor<I>(mut self, interceptor: I) -> DatabaseBuilder<Conn>
+    where
+        I: 'static + QueryInterceptor,
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Record an `AuditEvent` with `sink` for every `execute_async`,
+    /// `transaction` and `AsyncTransaction::commit`.
+    pub fn audit_sink<S>(mut self, sink: S) -> DatabaseBuilder<Conn>
+    where
+        S: 'static + AuditSink,
+    {
+        self.audit_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Settings for the pool created by `connect` (default `PoolConfig::default()`).
+    pub fn pool_config(mut self, pool_config: PoolConfig) -> DatabaseBuilder<Conn> {
+        self.pool_config = pool_config;
+        self
+    }
+
+    /// Have the pool created by `connect` run `customizer` on every
+    /// connection it opens, as r2d2's `Builder::connection_customizer`.
+    ///
+    /// Unlike `on_acquire` hooks, which run on every checkout, it runs once
+    /// per connection.
+    pub fn connection_customizer<C>(mut self, customizer: C) -> DatabaseBuilder<Conn>
+    where
+        C: CustomizeConnection<Conn, R2D2Error>,
+    {
+        self.connection_customizer = Some(Box::new(customizer));
+        self
+    }
+
+    pub fn build(self, pool: Pool<ConnectionManager<Conn>>) -> Database<Conn> {
+        let (lifecycle, _) = watch::channel(Lifecycle {
+            closed: false,
+            in_flight: 0,
+        });
+
+        Database {
+            shared: Arc::new(Shared {
+                pool: RwLock::new(Some(pool)),
+                executor: self.executor,
+                limit: self.max_concurrent_queries.map(Limiter::new),
+                lifecycle,
+                database_url: self.database_url,
+                checkout_retry: self.checkout_retry,
+                slow_query_threshold: self.slow_query_threshold,
+                redact_bind_values: self.redact_bind_values,
+                stats: Arc::default(),
+                writer: if self.single_writer {
+                    Some(Writer::new())
+                } else {
+                    None
+                },
+                hooks: Arc::new(self.hooks),
+                interceptors: self.interceptors,
+                audit_sink: self.audit_sink,
+                #[cfg(feature = "prometheus")]
+                prometheus: OnceLock::new(),
+                #[cfg(feature = "otel")]
+                otel: self.otel,
+            }),
+        }
+    }
+}
+
+impl<Conn> DatabaseBuilder<Conn>
+where
+    Conn: 'static + SessionLabel,
+{
+    /// Set the label of an enclosing `with_connection_label` on the
+    /// connections checked out for it, e.g. as Postgres's `application_name`.
+    ///
+    /// Costs a round trip at checkout and one at release, only for work run
+    /// with a label.
+    pub fn label_connections(mut self) -> DatabaseBuilder<Conn> {
+        self.hooks.labeler = Some(Labeler {
+            set: Conn::set_label,
+            clear: Conn::clear_label,
+        });
+        self
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        let sql = query.to_string();
+        let dispatch = self.dispatch(Priority::Normal, move |conn| conn.batch_execute(&sql));
+        trace::instrument(
+            "batch_execute",
+            trace::no_rows,
+            trace::with_sql(query, dispatch),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        trace::instrument("run", trace::no_rows, self.dispatch(Priority::Normal, f)).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let transaction = trace::instrument(
+            "transaction",
+            trace::no_rows,
+            self.dispatch(Priority::Normal, |conn| {
+                conn.transaction::<R, E, _>(|| f(conn))
+            }),
+        );
+        audit::audited("transaction", trace::no_rows, transaction).await
+    }
+
+    // As a deadline, so the timeout is enforced inside the call and counted in its stats
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let dispatch = deadline::with_deadline(timeout, self.dispatch(Priority::Normal, f));
+        trace::instrument("run", trace::no_rows, dispatch).await
+    }
+}
@@ -0,0 +1,35 @@
+use crate::AsyncError;
+use std::{fmt, future::Future, time::Duration};
+use tokio::time::{self, Instant};
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Run `fut` with a deadline on its database work: once `timeout` has
+/// passed, every query it runs on the current task, through a database or a
+/// transaction or guard from one, fails with `AsyncError::Timeout`.
+///
+/// A deadline inside another can only shorten it. A query that overruns
+/// keeps running on its blocking thread; only the caller stops waiting.
+pub async fn with_deadline<F: Future>(timeout: Duration, fut: F) -> F::Output {
+    let deadline = Instant::now() + timeout;
+    let deadline = DEADLINE
+        .try_with(|outer| deadline.min(*outer))
+        .unwrap_or(deadline);
+    DEADLINE.scope(deadline, fut).await
+}
+
+// Runs `fut`, failing it with `AsyncError::Timeout` at the enclosing deadline, if any
+pub(crate) async fn enforced<T, E, F>(fut: F) -> Result<T, AsyncError<E>>
+where
+    E: fmt::Debug,
+    F: Future<Output = Result<T, AsyncError<E>>>,
+{
+    match DEADLINE.try_with(|deadline| *deadline) {
+        Ok(deadline) => time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| AsyncError::Timeout)?,
+        Err(_) => fut.await,
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{panic_message, AsyncError, AsyncPool, CheckoutError};
+use async_trait::async_trait;
+use deadpool_diesel::{InteractError, Manager, Pool};
+use diesel::Connection;
+use std::fmt;
+
+// deadpool-diesel already runs each interaction on a blocking thread, with the
+// connection behind a mutex, so checking out is the only thing left to adapt
+#[async_trait]
+impl<Conn> AsyncPool for Pool<Manager<Conn>>
+where
+    Conn: 'static + Connection,
+{
+    type Connection = Conn;
+
+    #[inline]
+    async fn with_connection<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, AsyncError<E>> + Send,
+    {
+        let conn = self
+            .get()
+            .await
+            .map_err(|e| AsyncError::Checkout(CheckoutError::new(e)))?;
+        conn.interact(move |conn| f(conn))
+            .await
+            .map_err(|e| match e {
+                InteractError::Panic(payload) => AsyncError::Panicked(panic_message(payload)),
+                InteractError::Aborted => AsyncError::Canceled,
+            })?
+    }
+}
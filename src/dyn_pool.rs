@@ -0,0 +1,185 @@
+use crate::{AsyncConnection, AsyncError, AsyncSimpleConnection};
+use async_trait::async_trait;
+use diesel::{result::Error as DieselError, Connection};
+use std::{any::Any, fmt, sync::Arc};
+
+/// A closure given to `AsyncDatabase`, its result boxed so the trait needs no
+/// type parameters.
+pub type DynCall<Conn> = Box<dyn FnOnce(&Conn) -> Result<Box<dyn Any + Send>, DynCallError> + Send>;
+
+/// The error of a `DynCall`: a diesel error, or the caller's own error boxed.
+pub enum DynCallError {
+    Diesel(DieselError),
+    Other(Box<dyn Any + Send>),
+}
+
+/// The object-safe subset of `AsyncConnection`, so services can hold an
+/// `Arc<dyn AsyncDatabase<Conn>>` and be given a real database or a test
+/// double.
+///
+/// Implemented for everything implementing `AsyncConnection`. Wrap a trait
+/// object in `DynAsyncPool` to use the `*_async` DSL methods and the rest of
+/// `AsyncConnection` with it again.
+#[async_trait]
+pub trait AsyncDatabase<Conn>: AsyncSimpleConnection<Conn> + Send + Sync
+where
+    Conn: 'static + Connection,
+{
+    /// `AsyncConnection::run`, with the closure and its result boxed.
+    async fn run_dyn(
+        &self,
+        f: DynCall<Conn>,
+    ) -> Result<Box<dyn Any + Send>, AsyncError<DynCallError>>;
+
+    /// `AsyncConnection::transaction`, with the closure and its result boxed.
+    async fn transaction_dyn(
+        &self,
+        f: DynCall<Conn>,
+    ) -> Result<Box<dyn Any + Send>, AsyncError<DynCallError>>;
+}
+
+/// An `Arc<dyn AsyncDatabase<Conn>>` implementing `AsyncConnection`, for the
+/// `*_async` DSL methods. Clones share the database.
+pub struct DynAsyncPool<Conn> {
+    db: Arc<dyn AsyncDatabase<Conn>>,
+}
+
+#[async_trait]
+impl<Conn, P> AsyncDatabase<Conn> for P
+where
+    Conn: 'static + Connection,
+    P: AsyncConnection<Conn> + Send + Sync,
+{
+    async fn run_dyn(
+        &self,
+        f: DynCall<Conn>,
+    ) -> Result<Box<dyn Any + Send>, AsyncError<DynCallError>> {
+        self.run(f).await
+    }
+
+    async fn transaction_dyn(
+        &self,
+        f: DynCall<Conn>,
+    ) -> Result<Box<dyn Any + Send>, AsyncError<DynCallError>> {
+        self.transaction(f).await
+    }
+}
+
+impl<Conn> DynAsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub fn new<P>(db: P) -> DynAsyncPool<Conn>
+    where
+        P: 'static + AsyncDatabase<Conn>,
+    {
+        DynAsyncPool { db: Arc::new(db) }
+    }
+
+    /// The database as a trait object.
+    pub fn inner(&self) -> &Arc<dyn AsyncDatabase<Conn>> {
+        &self.db
+    }
+}
+
+impl<Conn> From<Arc<dyn AsyncDatabase<Conn>>> for DynAsyncPool<Conn> {
+    fn from(db: Arc<dyn AsyncDatabase<Conn>>) -> Self {
+        DynAsyncPool { db }
+    }
+}
+
+impl<Conn> Clone for DynAsyncPool<Conn> {
+    fn clone(&self) -> Self {
+        DynAsyncPool {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for DynAsyncPool<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynAsyncPool").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for DynAsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        self.db.batch_execute_async(query).await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for DynAsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        unbox(self.db.run_dyn(boxed(f)).await)
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        unbox(self.db.transaction_dyn(boxed(f)).await)
+    }
+}
+
+impl From<DieselError> for DynCallError {
+    fn from(err: DieselError) -> DynCallError {
+        DynCallError::Diesel(err)
+    }
+}
+
+impl fmt::Debug for DynCallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DynCallError::Diesel(ref err) => f.debug_tuple("Diesel").field(err).finish(),
+            DynCallError::Other(_) => f.debug_tuple("Other").finish(),
+        }
+    }
+}
+
+// Errors stay errors, so a transaction given the closure still rolls back on them
+fn boxed<Conn, R, E, Func>(f: Func) -> DynCall<Conn>
+where
+    R: 'static + Send,
+    E: 'static + Send,
+    Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+{
+    Box::new(move |conn| match f(conn) {
+        Ok(value) => Ok(Box::new(value) as Box<dyn Any + Send>),
+        Err(err) => Err(DynCallError::Other(Box::new(err))),
+    })
+}
+
+// Undoes `boxed`, for its result once run
+fn unbox<R, E>(
+    result: Result<Box<dyn Any + Send>, AsyncError<DynCallError>>,
+) -> Result<R, AsyncError<E>>
+where
+    R: 'static,
+    E: 'static + From<DieselError> + fmt::Debug,
+{
+    match result {
+        Ok(value) => Ok(*value.downcast().expect("result of a boxed call")),
+        Err(err) => Err(err.map(|err| match err {
+            DynCallError::Diesel(err) => E::from(err),
+            DynCallError::Other(err) => *err.downcast().expect("error of a boxed call"),
+        })),
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{AsyncError, DatabaseErrorClass};
+use diesel::result::Error as DieselError;
+use serde::{Serialize, Serializer};
+
+/// A serializable summary of an `AsyncError`, for structured API error bodies.
+///
+/// diesel 1.x doesn't expose SQLSTATE codes; `kind` carries the
+/// classification derived from them instead.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ErrorBody {
+    pub kind: DatabaseErrorClass,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constraint: Option<String>,
+}
+
+impl AsyncError<DieselError> {
+    pub fn to_body(&self) -> ErrorBody {
+        ErrorBody {
+            kind: self.class(),
+            message: self.to_string(),
+            constraint: self.constraint_name().map(str::to_string),
+        }
+    }
+}
+
+impl Serialize for AsyncError<DieselError> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_body().serialize(serializer)
+    }
+}
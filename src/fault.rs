@@ -0,0 +1,233 @@
+use crate::{AsyncConnection, AsyncError, AsyncSimpleConnection, CheckoutError};
+use async_trait::async_trait;
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    Connection,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time;
+
+/// A wrapper around a database that makes calls fail or stall at random, so
+/// retry and timeout handling can be tested without breaking a real server.
+///
+/// For every call, in this order: with the `checkout_failures` probability it
+/// fails with `AsyncError::Checkout` before reaching the database; with the
+/// `latency` probability it waits first; with the `disconnects` probability
+/// its closure runs but the call then fails as if the connection was lost,
+/// rolling a `transaction` back. Injected errors are transient, so retrying
+/// callers retry them.
+///
+/// The faults come from a generator seeded with `seed` (default 0), so the
+/// same calls made in the same order meet the same faults on every run.
+/// Clones share the generator.
+pub struct FaultInjector<Conn, P> {
+    db: P,
+    faults: Faults,
+    rng: Arc<Mutex<u64>>,
+    _conn: PhantomData<fn() -> Conn>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Faults {
+    checkout_failure: f64,
+    latency: f64,
+    delay: Duration,
+    disconnect: f64,
+}
+
+// The faults drawn for one call
+struct Draw {
+    checkout_failure: bool,
+    delay: Option<Duration>,
+    disconnect: bool,
+}
+
+impl<Conn, P> FaultInjector<Conn, P>
+where
+    Conn: 'static + Connection,
+    P: AsyncConnection<Conn>,
+{
+    /// Wrap `db`, injecting nothing until configured to.
+    pub fn new(db: P) -> FaultInjector<Conn, P> {
+        FaultInjector {
+            db,
+            faults: Faults::default(),
+            rng: Arc::new(Mutex::new(0)),
+            _conn: PhantomData,
+        }
+    }
+
+    pub fn seed(self, seed: u64) -> FaultInjector<Conn, P> {
+        *self.rng.lock().unwrap() = seed;
+        self
+    }
+
+    /// Fail calls with `AsyncError::Checkout` with `probability`.
+    pub fn checkout_failures(mut self, probability: f64) -> FaultInjector<Conn, P> {
+        self.faults.checkout_failure = check_probability(probability);
+        self
+    }
+
+    /// Delay calls by `delay` with `probability`, as a saturated pool or a
+    /// slow server would.
+    pub fn latency(mut self, probability: f64, delay: Duration) -> FaultInjector<Conn, P> {
+        self.faults.latency = check_probability(probability);
+        self.faults.delay = delay;
+        self
+    }
+
+    /// Fail calls with a lost connection after their closure ran with
+    /// `probability`; a transaction is rolled back.
+    pub fn disconnects(mut self, probability: f64) -> FaultInjector<Conn, P> {
+        self.faults.disconnect = check_probability(probability);
+        self
+    }
+
+    /// The wrapped database.
+    pub fn inner(&self) -> &P {
+        &self.db
+    }
+
+    // Draws every fault for each call, so which are configured doesn't shift the others
+    fn draw(&self) -> Draw {
+        let mut rng = self.rng.lock().unwrap();
+        let mut happens = |probability: f64| next_f64(&mut rng) < probability;
+        Draw {
+            checkout_failure: happens(self.faults.checkout_failure),
+            delay: Some(self.faults.delay).filter(|_| happens(self.faults.latency)),
+            disconnect: happens(self.faults.disconnect),
+        }
+    }
+
+    // Injects the faults that come before the call reaches the database
+    async fn before<E>(&self, draw: &Draw) -> Result<(), AsyncError<E>>
+    where
+        E: fmt::Debug,
+    {
+        if draw.checkout_failure {
+            return Err(AsyncError::Checkout(CheckoutError::new(
+                "injected checkout failure",
+            )));
+        }
+        if let Some(delay) = draw.delay {
+            time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
+
+impl<Conn, P: Clone> Clone for FaultInjector<Conn, P> {
+    fn clone(&self) -> Self {
+        FaultInjector {
+            db: self.db.clone(),
+            faults: self.faults,
+            rng: self.rng.clone(),
+            _conn: PhantomData,
+        }
+    }
+}
+
+impl<Conn, P: fmt::Debug> fmt::Debug for FaultInjector<Conn, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FaultInjector")
+            .field("db", &self.db)
+            .field("faults", &self.faults)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<Conn, P> AsyncSimpleConnection<Conn> for FaultInjector<Conn, P>
+where
+    Conn: 'static + Connection,
+    P: AsyncConnection<Conn> + Send + Sync,
+{
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        let draw = self.draw();
+        self.before(&draw).await?;
+        self.db.batch_execute_async(query).await?;
+        if draw.disconnect {
+            return Err(AsyncError::Error(connection_lost()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Conn, P> AsyncConnection<Conn> for FaultInjector<Conn, P>
+where
+    Conn: 'static + Connection,
+    P: AsyncConnection<Conn> + Send + Sync,
+{
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let draw = self.draw();
+        self.before(&draw).await?;
+        let disconnect = draw.disconnect;
+        self.db
+            .run(move |conn| {
+                let result = f(conn)?;
+                if disconnect {
+                    return Err(E::from(connection_lost()));
+                }
+                Ok(result)
+            })
+            .await
+    }
+
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let draw = self.draw();
+        self.before(&draw).await?;
+        let disconnect = draw.disconnect;
+        // Failing inside the transaction rolls it back, as a lost connection would
+        self.db
+            .transaction(move |conn| {
+                let result = f(conn)?;
+                if disconnect {
+                    return Err(E::from(connection_lost()));
+                }
+                Ok(result)
+            })
+            .await
+    }
+}
+
+// Worded like libpq's, so it is classified as `ConnectionLost`
+fn connection_lost() -> DieselError {
+    DieselError::DatabaseError(
+        DatabaseErrorKind::UnableToSendCommand,
+        Box::new("injected fault: server closed the connection unexpectedly".to_string()),
+    )
+}
+
+fn check_probability(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "probability must be between 0 and 1"
+    );
+    probability
+}
+
+// SplitMix64, mapped to [0, 1)
+fn next_f64(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
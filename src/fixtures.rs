@@ -0,0 +1,324 @@
+use crate::{AsyncConnection, AsyncError};
+use diesel::{
+    backend::Backend,
+    query_builder::{AstPass, QueryFragment, QueryId},
+    result::{Error as DieselError, QueryResult},
+    Connection, RunQueryDsl,
+};
+use serde_yaml::{Mapping, Value};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Test data to insert before a test, from YAML and SQL fixture files.
+///
+/// A YAML file maps table names to lists of rows, each mapping column names
+/// to values (strings, numbers, booleans or null); strings are sent as SQL
+/// literals, so the database converts them to the column's type, such as a
+/// UUID or a timestamp. An SQL file holds statements for the table it is
+/// named after, `users.sql` for `users`.
+///
+/// `load` inserts everything in one transaction, table by table: in the order
+/// they were added, except that a table comes after those it depends on.
+#[derive(Clone, Debug, Default)]
+pub struct Fixtures {
+    tables: Vec<Table>,
+}
+
+/// Why fixtures could not be read.
+#[derive(Debug, Error)]
+pub enum FixtureError {
+    #[error("reading {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    // The YAML parsed, but is not a map of tables to lists of rows
+    #[error("invalid fixtures: {0}")]
+    Invalid(String),
+}
+
+#[derive(Clone, Debug)]
+struct Table {
+    name: String,
+    depends_on: Vec<String>,
+    rows: Vec<Row>,
+    sql: Vec<String>,
+}
+
+type Row = Vec<(String, Literal)>;
+
+#[derive(Clone, Debug)]
+enum Literal {
+    Null,
+    Bool(bool),
+    Number(String),
+    Text(String),
+}
+
+// An `INSERT` of one row, with the table and columns quoted for the backend
+struct InsertRow<'a> {
+    table: &'a str,
+    row: &'a Row,
+}
+
+impl Fixtures {
+    pub fn new() -> Fixtures {
+        Fixtures::default()
+    }
+
+    /// Read every `.yaml`, `.yml` and `.sql` file of `dir`, in file name
+    /// order.
+    pub fn dir<P: AsRef<Path>>(self, dir: P) -> Result<Fixtures, FixtureError> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir).map_err(|source| FixtureError::Io {
+            path: dir.to_path_buf(),
+            source,
+        })?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|source| FixtureError::Io {
+                path: dir.to_path_buf(),
+                source,
+            })?;
+            paths.push(entry.path());
+        }
+        paths.sort();
+
+        paths
+            .into_iter()
+            .filter(|path| matches!(extension(path), Some("yaml" | "yml" | "sql")))
+            .try_fold(self, Fixtures::file)
+    }
+
+    /// Read the fixture file `path`: YAML for a `.yaml` or `.yml` extension,
+    /// otherwise SQL.
+    pub fn file<P: AsRef<Path>>(self, path: P) -> Result<Fixtures, FixtureError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|source| FixtureError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        match extension(path) {
+            Some("yaml" | "yml") => self.yaml(&contents),
+            _ => {
+                let table = path.file_stem().map(|stem| stem.to_string_lossy());
+                let table = table.ok_or_else(|| {
+                    FixtureError::Invalid(format!("no table name in {}", path.display()))
+                })?;
+                Ok(self.sql(table.into_owned(), contents))
+            }
+        }
+    }
+
+    /// Add the rows of the YAML document `yaml`.
+    pub fn yaml(mut self, yaml: &str) -> Result<Fixtures, FixtureError> {
+        let tables: Mapping = serde_yaml::from_str(yaml)?;
+        for (table, rows) in tables {
+            let table = match table {
+                Value::String(table) => table,
+                other => return Err(invalid("table name", &other)),
+            };
+            let rows = match rows {
+                Value::Sequence(rows) => rows,
+                Value::Null => Vec::new(),
+                other => return Err(invalid(&format!("rows of {}", table), &other)),
+            };
+            let rows = rows
+                .into_iter()
+                .map(|row| parse_row(&table, row))
+                .collect::<Result<Vec<_>, _>>()?;
+            self.table(table).rows.extend(rows);
+        }
+        Ok(self)
+    }
+
+    /// Add the SQL statements `sql` for `table`.
+    pub fn sql<T, S>(mut self, table: T, sql: S) -> Fixtures
+    where
+        T: Into<String>,
+        S: Into<String>,
+    {
+        self.table(table.into()).sql.push(sql.into());
+        self
+    }
+
+    /// Load `table` after `dependency`, such as a table after the one its
+    /// foreign keys refer to.
+    ///
+    /// Panics if `dependency` already has to come after `table`.
+    pub fn depends_on<T, D>(mut self, table: T, dependency: D) -> Fixtures
+    where
+        T: Into<String>,
+        D: Into<String>,
+    {
+        let (table, dependency) = (table.into(), dependency.into());
+        assert!(
+            table != dependency && !self.depends(&dependency, &table),
+            "fixture dependency cycle: {} and {} depend on each other",
+            table,
+            dependency
+        );
+        self.table(dependency.clone());
+        self.table(table).depends_on.push(dependency);
+        self
+    }
+
+    /// Insert the fixtures in one transaction, returning the number of rows
+    /// inserted from YAML.
+    pub async fn load<Conn, A>(&self, db: &A) -> Result<usize, AsyncError<DieselError>>
+    where
+        Conn: 'static + Connection,
+        A: AsyncConnection<Conn>,
+    {
+        let tables = self.ordered();
+        db.transaction(move |conn| {
+            let mut inserted = 0;
+            for table in &tables {
+                for row in &table.rows {
+                    inserted += InsertRow {
+                        table: &table.name,
+                        row,
+                    }
+                    .execute(conn)?;
+                }
+                for sql in &table.sql {
+                    conn.batch_execute(sql)?;
+                }
+            }
+            Ok(inserted)
+        })
+        .await
+    }
+
+    fn table(&mut self, name: String) -> &mut Table {
+        let i = match self.tables.iter().position(|table| table.name == name) {
+            Some(i) => i,
+            None => {
+                self.tables.push(Table {
+                    name,
+                    depends_on: Vec::new(),
+                    rows: Vec::new(),
+                    sql: Vec::new(),
+                });
+                self.tables.len() - 1
+            }
+        };
+        &mut self.tables[i]
+    }
+
+    // Whether `table` has to come after `dependency`, directly or not
+    fn depends(&self, table: &str, dependency: &str) -> bool {
+        let table = match self.tables.iter().find(|t| t.name == table) {
+            Some(table) => table,
+            None => return false,
+        };
+        table
+            .depends_on
+            .iter()
+            .any(|d| d == dependency || self.depends(d, dependency))
+    }
+
+    // The tables in the order they were added, each moved after its dependencies
+    fn ordered(&self) -> Vec<Table> {
+        fn visit(fixtures: &Fixtures, table: &Table, ordered: &mut Vec<Table>) {
+            if ordered.iter().any(|t| t.name == table.name) {
+                return;
+            }
+            for dependency in &table.depends_on {
+                if let Some(dependency) = fixtures.tables.iter().find(|t| t.name == *dependency) {
+                    visit(fixtures, dependency, ordered);
+                }
+            }
+            ordered.push(table.clone());
+        }
+
+        let mut ordered = Vec::with_capacity(self.tables.len());
+        for table in &self.tables {
+            visit(self, table, &mut ordered);
+        }
+        ordered
+    }
+}
+
+impl<'a, DB: Backend> QueryFragment<DB> for InsertRow<'a> {
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("INSERT INTO ");
+        // `schema.table`
+        for (i, part) in self.table.split('.').enumerate() {
+            if i > 0 {
+                out.push_sql(".");
+            }
+            out.push_identifier(part)?;
+        }
+
+        out.push_sql(" (");
+        for (i, (column, _)) in self.row.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            out.push_identifier(column)?;
+        }
+        out.push_sql(") VALUES (");
+        for (i, (_, value)) in self.row.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            match *value {
+                Literal::Null => out.push_sql("NULL"),
+                Literal::Bool(b) => out.push_sql(if b { "TRUE" } else { "FALSE" }),
+                Literal::Number(ref n) => out.push_sql(n),
+                Literal::Text(ref text) => out.push_sql(&format!("'{}'", text.replace('\'', "''"))),
+            }
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for InsertRow<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn: Connection> RunQueryDsl<Conn> for InsertRow<'a> {}
+
+fn parse_row(table: &str, row: Value) -> Result<Row, FixtureError> {
+    let row = match row {
+        Value::Mapping(row) => row,
+        other => return Err(invalid(&format!("row of {}", table), &other)),
+    };
+    row.into_iter()
+        .map(|(column, value)| {
+            let column = match column {
+                Value::String(column) => column,
+                other => return Err(invalid(&format!("column of {}", table), &other)),
+            };
+            let value = match value {
+                Value::Null => Literal::Null,
+                Value::Bool(b) => Literal::Bool(b),
+                Value::Number(n) => Literal::Number(n.to_string()),
+                Value::String(text) => Literal::Text(text),
+                other => return Err(invalid(&format!("{}.{}", table, column), &other)),
+            };
+            Ok((column, value))
+        })
+        .collect()
+}
+
+fn invalid(what: &str, value: &Value) -> FixtureError {
+    FixtureError::Invalid(format!("{} is not valid: {:?}", what, value))
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
@@ -0,0 +1,260 @@
+use crate::{
+    audit, checkout::Checkout, database::InFlight, deadline, limiter::Priority, run_guarded, trace,
+    writer::WriteLock, AsyncConnection, AsyncError, AsyncSimpleConnection, Database,
+};
+use async_trait::async_trait;
+use diesel::{connection::TransactionManager, result::Error as DieselError, Connection};
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// One pooled connection held across awaits.
+///
+/// Created by `Database::acquire`. Every call made through the guard runs on
+/// the same connection, so session state such as `SET`, temporary tables and
+/// advisory locks persists between them. The connection goes back to the
+/// pool when the guard is dropped.
+pub struct AsyncConnectionGuard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    db: Database<Conn>,
+    // Both taken on drop, to be released off the async thread
+    conn: Option<Pinned<Conn>>,
+    in_flight: Option<InFlight>,
+    // The writer's turn, held until the connection is back on a single-writer database
+    write_lock: Option<WriteLock<Conn>>,
+    // Set by `begin_test_transaction_async`, to roll it back on drop
+    test_transaction: AtomicBool,
+}
+
+type Pinned<Conn> = Arc<Mutex<Checkout<Conn>>>;
+
+tokio::task_local! {
+    // Connections pinned by `Database::with_pinned`, keyed by `Database::id`
+    static PINNED: Vec<(usize, Arc<dyn Any + Send + Sync>)>;
+}
+
+impl<Conn> Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub async fn acquire(&self) -> Result<AsyncConnectionGuard<Conn>, AsyncError<DieselError>> {
+        let write_lock = self.write_lock().await;
+        let in_flight = self.admit()?;
+        let pool = self.checkout_pool()?;
+
+        let conn = self
+            .spawn_job(Priority::Normal, move || pool.get())
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+            .map_err(AsyncError::Checkout)?;
+
+        Ok(AsyncConnectionGuard {
+            db: self.clone(),
+            conn: Some(Arc::new(Mutex::new(conn))),
+            in_flight: Some(in_flight),
+            write_lock,
+            test_transaction: AtomicBool::new(false),
+        })
+    }
+
+    /// Run `fut` with one connection pinned to it: every `run`, `transaction`
+    /// and `*_async` call made on this database from within `fut` uses that
+    /// connection, so session settings like `SET application_name` apply to
+    /// all of them.
+    ///
+    /// Pinning follows the current task only; work moved to other tasks with
+    /// `tokio::spawn` checks out connections as usual.
+    pub async fn with_pinned<F, T>(&self, fut: F) -> Result<T, AsyncError<DieselError>>
+    where
+        F: Future<Output = T>,
+    {
+        let guard = self.acquire().await?;
+
+        let mut pinned = PINNED.try_with(Clone::clone).unwrap_or_default();
+        pinned.push((self.id(), guard.pinned_conn() as Arc<dyn Any + Send + Sync>));
+
+        Ok(PINNED.scope(pinned, fut).await)
+    }
+
+    // The connection pinned to this database by an enclosing `with_pinned`, if any
+    pub(crate) fn pinned(&self) -> Option<Pinned<Conn>> {
+        PINNED
+            .try_with(|pinned| {
+                let (_, conn) = pinned.iter().rev().find(|(id, _)| *id == self.id())?;
+                conn.clone().downcast().ok()
+            })
+            .ok()
+            .flatten()
+    }
+
+    pub(crate) async fn run_pinned<R, E, Func>(
+        &self,
+        conn: Pinned<Conn>,
+        priority: Priority,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Checkout<Conn>) -> Result<R, E> + Send,
+    {
+        deadline::enforced(async {
+            self.spawn_job(priority, move || {
+                let conn = conn.lock().unwrap();
+                run_guarded(&**conn, |_| f(&conn))
+            })
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+        })
+        .await
+    }
+}
+
+impl<Conn> AsyncConnectionGuard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Begin a transaction that is never committed, as diesel's
+    /// `begin_test_transaction` does, for tests written against the same
+    /// calls as production code.
+    ///
+    /// Everything run through the guard afterwards is rolled back when the
+    /// guard is dropped, before the connection goes back to the pool;
+    /// `transaction` calls become savepoints.
+    pub async fn begin_test_transaction_async(&self) -> Result<(), AsyncError<DieselError>> {
+        self.with_conn(|conn| conn.begin_test_transaction()).await?;
+        self.test_transaction.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn pinned_conn(&self) -> Pinned<Conn> {
+        self.conn.clone().expect("connection taken before drop")
+    }
+
+    #[cfg(feature = "postgres")]
+    pub(crate) fn db(&self) -> &Database<Conn> {
+        &self.db
+    }
+
+    async fn with_conn<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let start = Instant::now();
+        self.db.start_call(None)?;
+        let result = self
+            .db
+            .run_pinned(self.pinned_conn(), Priority::Normal, move |conn| f(conn))
+            .await;
+        self.db.finish_call(start, None, &result);
+        result
+    }
+}
+
+impl<Conn> Drop for AsyncConnectionGuard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        // Release hooks are blocking work; keep them off the async thread
+        let conn = self.conn.take();
+        let in_flight = self.in_flight.take();
+        let write_lock = self.write_lock.take();
+        let test_transaction = *self.test_transaction.get_mut();
+        self.db.spawn_detached(move || {
+            if let Some(conn) = conn.as_ref().filter(|_| test_transaction) {
+                if let Ok(conn) = conn.lock() {
+                    roll_back_all(&**conn);
+                }
+            }
+            drop(conn);
+            drop(in_flight);
+            drop(write_lock);
+        });
+    }
+}
+
+// Rolls back the test transaction, and any savepoint left open inside it
+fn roll_back_all<Conn: Connection>(conn: &Conn) {
+    let manager = conn.transaction_manager();
+    while TransactionManager::<Conn>::get_transaction_depth(manager) > 0 {
+        if let Err(err) = manager.rollback_transaction(conn) {
+            log::warn!("rolling back the test transaction failed: {}", err);
+            break;
+        }
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncConnectionGuard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        let sql = query.to_string();
+        trace::instrument(
+            "batch_execute",
+            trace::no_rows,
+            trace::with_sql(query, self.with_conn(move |conn| conn.batch_execute(&sql))),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncConnectionGuard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        trace::instrument("run", trace::no_rows, self.with_conn(f)).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let transaction = trace::instrument(
+            "transaction",
+            trace::no_rows,
+            self.with_conn(|conn| conn.transaction::<R, E, _>(|| f(conn))),
+        );
+        audit::audited("transaction", trace::no_rows, transaction).await
+    }
+
+    // As a deadline, so the timeout is enforced inside the call and counted in its stats
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let call = deadline::with_deadline(timeout, self.with_conn(f));
+        trace::instrument("run", trace::no_rows, call).await
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{AsyncConnection, AsyncError};
+use async_trait::async_trait;
+use diesel::{result::Error as DieselError, Connection};
+use std::time::Duration;
+
+/// Liveness checks for readiness probes, run through the same connections
+/// as the application's queries.
+#[async_trait]
+pub trait AsyncHealthCheck<Conn>: AsyncConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Run `SELECT 1`, which every supported backend accepts.
+    async fn ping_async(&self) -> Result<(), AsyncError<DieselError>> {
+        self.run(|conn| conn.execute("SELECT 1").map(|_| ())).await
+    }
+
+    /// Whether a ping succeeds within `timeout`, checkout included.
+    async fn is_healthy(&self, timeout: Duration) -> bool {
+        self.run_with_timeout(timeout, |conn| conn.execute("SELECT 1"))
+            .await
+            .is_ok()
+    }
+}
+
+impl<Conn, T> AsyncHealthCheck<Conn> for T
+where
+    Conn: 'static + Connection,
+    T: AsyncConnection<Conn>,
+{
+}
@@ -0,0 +1,47 @@
+use crate::DatabaseErrorClass;
+use std::time::Duration;
+
+/// Hooks a `Database` runs around every `run`, `transaction` and `*_async`
+/// call, including those made through its transactions and guards, for
+/// custom logging, metrics, auditing or policy checks.
+///
+/// Added with `DatabaseBuilder::interceptor` and run in the order they were
+/// added. Both methods run on the async thread, so they should be quick.
+pub trait QueryInterceptor: Send + Sync {
+    /// Called before the call is dispatched; an `Err` refuses it, failing it
+    /// with `AsyncError::Rejected` carrying the message.
+    fn before_query(&self, call: &QueryCall<'_>) -> Result<(), String> {
+        let _ = call;
+        Ok(())
+    }
+
+    /// Called once the call finished, with the class of its error, if any,
+    /// and how long it took, checkout included. Not called for refused calls.
+    fn after_query(
+        &self,
+        call: &QueryCall<'_>,
+        result: Result<(), DatabaseErrorClass>,
+        duration: Duration,
+    ) {
+        let _ = (call, result, duration);
+    }
+}
+
+/// What is known about a call passed to a `QueryInterceptor`.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryCall<'a> {
+    pub(crate) tag: Option<&'a str>,
+    pub(crate) statement: Option<&'a str>,
+}
+
+impl<'a> QueryCall<'a> {
+    /// The tag given to `Database::run_tagged`.
+    pub fn tag(&self) -> Option<&'a str> {
+        self.tag
+    }
+
+    /// The SQL, when known, as for `batch_execute_async`.
+    pub fn statement(&self) -> Option<&'a str> {
+        self.statement
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{AsyncConnection, AsyncError};
+use diesel::{
+    backend::Backend,
+    dsl::{Filter, Limit, Order},
+    expression::{AppearsOnTable, Expression, NonAggregate},
+    query_builder::{AstPass, QueryFragment, QueryId},
+    query_dsl::{
+        methods::{FilterDsl, LimitDsl, LoadQuery, OrderDsl},
+        RunQueryDsl,
+    },
+    result::{Error as DieselError, QueryResult},
+    serialize::ToSql,
+    sql_types::{Bool, HasSqlType},
+    Connection,
+};
+use std::{fmt, str::FromStr};
+
+/// Adds `keyset` to queries.
+pub trait KeysetPaginate: Sized {
+    /// Page through the query in ascending order of `key`, which must be
+    /// unique, seeking past the previous page instead of skipping rows with
+    /// `OFFSET`.
+    fn keyset<K>(self, key: K) -> Keyset<Self, K>;
+}
+
+/// A keyset paginated query, created by `KeysetPaginate::keyset`.
+#[derive(Debug, Clone)]
+pub struct Keyset<T, K> {
+    query: T,
+    key: K,
+    after: Option<Cursor>,
+    limit: i64,
+}
+
+/// One page of a keyset paginated query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeysetPage<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, or `None` if this was the last one
+    pub next: Option<Cursor>,
+}
+
+/// An opaque position in a keyset paginated query, safe to hand to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cursor(String);
+
+/// `key > value`, or always true when there is no value
+#[derive(Debug, Clone, Copy)]
+pub struct After<K, V> {
+    key: K,
+    value: Option<V>,
+}
+
+impl<T> KeysetPaginate for T {
+    fn keyset<K>(self, key: K) -> Keyset<Self, K> {
+        Keyset {
+            query: self,
+            key,
+            after: None,
+            limit: 20,
+        }
+    }
+}
+
+impl<T, K> Keyset<T, K> {
+    /// Start after `cursor`, as returned in `KeysetPage::next`; `None` starts
+    /// at the beginning.
+    pub fn after(mut self, cursor: Option<Cursor>) -> Self {
+        self.after = cursor;
+        self
+    }
+
+    /// Number of rows per page (default 20).
+    pub fn limit(mut self, limit: i64) -> Self {
+        assert!(limit > 0, "limit must be positive");
+        self.limit = limit;
+        self
+    }
+
+    /// Load the page; `key_of` extracts the key from a loaded row to build
+    /// the next cursor. A cursor that does not decode is reported as a
+    /// `DeserializationError`.
+    pub async fn load_keyset_async<U, V, F, Conn, AsyncConn>(
+        self,
+        asc: &AsyncConn,
+        key_of: F,
+    ) -> Result<KeysetPage<U>, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        V: 'static + Send + FromStr + fmt::Display,
+        F: 'static + FnOnce(&U) -> V + Send,
+        K: Clone + Expression,
+        T: FilterDsl<After<K, V>>,
+        Filter<T, After<K, V>>: OrderDsl<K>,
+        Order<Filter<T, After<K, V>>, K>: LimitDsl,
+        Limit<Order<Filter<T, After<K, V>>, K>>: 'static + Send + LoadQuery<Conn, U>,
+        Conn: 'static + Connection,
+        AsyncConn: AsyncConnection<Conn>,
+    {
+        let value = match self.after {
+            Some(ref cursor) => Some(cursor.decode::<V>().ok_or_else(|| {
+                AsyncError::Error(DieselError::DeserializationError("invalid cursor".into()))
+            })?),
+            None => None,
+        };
+
+        let limit = self.limit;
+        // One extra row tells whether there is a next page
+        let query = self
+            .query
+            .filter(After {
+                key: self.key.clone(),
+                value,
+            })
+            .order(self.key)
+            .limit(limit + 1);
+
+        asc.run(move |conn| {
+            let mut items: Vec<U> = query.load(conn)?;
+            let next = if items.len() as i64 > limit {
+                items.truncate(limit as usize);
+                items.last().map(|last| Cursor::encode(&key_of(last)))
+            } else {
+                None
+            };
+            Ok(KeysetPage { items, next })
+        })
+        .await
+    }
+}
+
+impl Cursor {
+    pub fn encode<V: fmt::Display>(value: &V) -> Cursor {
+        let hex = value
+            .to_string()
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Cursor(hex)
+    }
+
+    pub fn decode<V: FromStr>(&self) -> Option<V> {
+        // An odd length leaves a trailing half byte, which `get` rejects
+        let bytes = (0..self.0.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(self.0.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        String::from_utf8(bytes).ok()?.parse().ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Cursor {
+    fn from(cursor: String) -> Cursor {
+        Cursor(cursor)
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<K, V> Expression for After<K, V> {
+    type SqlType = Bool;
+}
+
+impl<K: NonAggregate, V> NonAggregate for After<K, V> {}
+
+impl<K: AppearsOnTable<QS>, V, QS> AppearsOnTable<QS> for After<K, V> {}
+
+impl<K, V> QueryId for After<K, V> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<K, V, DB> QueryFragment<DB> for After<K, V>
+where
+    DB: Backend + HasSqlType<K::SqlType>,
+    K: Expression + QueryFragment<DB>,
+    V: ToSql<K::SqlType, DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        match self.value {
+            Some(ref value) => {
+                self.key.walk_ast(out.reborrow())?;
+                out.push_sql(" > ");
+                out.push_bind_param::<K::SqlType, _>(value)
+            }
+            None => {
+                out.push_sql("1 = 1");
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use diesel::{result::QueryResult, Connection};
+use std::{cell::RefCell, future::Future, sync::Arc};
+
+/// Connections that can carry a label naming the work they run, such as
+/// Postgres's `application_name`, so it shows up in server-side views like
+/// `pg_stat_activity`.
+pub trait SessionLabel: Connection {
+    fn set_label(&self, label: &str) -> QueryResult<()>;
+
+    fn clear_label(&self) -> QueryResult<()>;
+}
+
+tokio::task_local! {
+    static LABEL: Arc<str>;
+}
+
+thread_local! {
+    // The label of the job running on this blocking thread, read at checkout
+    static CURRENT: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Run `fut` with `label` set on every connection checked out for it on the
+/// current task, by databases built with `DatabaseBuilder::label_connections`.
+///
+/// The label is set when the connection is checked out and cleared before it
+/// goes back to the pool.
+pub async fn with_connection_label<F, L>(label: L, fut: F) -> F::Output
+where
+    F: Future,
+    L: Into<Arc<str>>,
+{
+    LABEL.scope(label.into(), fut).await
+}
+
+// Wraps `job` so checkouts it makes see the label of the task it was spawned from;
+// called on the task, like `metrics::measured`
+pub(crate) fn labeled<F, R>(job: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let label = LABEL.try_with(Clone::clone).ok();
+    move || {
+        // Every job sets its own, so one left behind by a panic is never read
+        CURRENT.with(|current| *current.borrow_mut() = label);
+        let result = job();
+        CURRENT.with(|current| current.borrow_mut().take());
+        result
+    }
+}
+
+pub(crate) fn current() -> Option<Arc<str>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
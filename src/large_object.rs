@@ -0,0 +1,209 @@
+use crate::{AsyncConnection, AsyncError, AsyncTransaction};
+use diesel::{
+    dsl::sql,
+    pg::PgConnection,
+    result::Error as DieselError,
+    sql_types::{Binary, Integer, Oid},
+    RunQueryDsl,
+};
+use std::{
+    cmp,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Bytes moved per `loread`/`lowrite` call, i.e. per blocking job
+const CHUNK: usize = 64 * 1024;
+
+// `lo_open` modes, from libpq's `libpq-fs.h`
+const INV_WRITE: i32 = 0x0002_0000;
+const INV_READ: i32 = 0x0004_0000;
+
+type Pending<'a, T> = Pin<Box<dyn Future<Output = Result<T, AsyncError<DieselError>>> + Send + 'a>>;
+
+/// Reads a large object, opened with `AsyncTransaction::read_large_object`.
+///
+/// Each read that finds nothing buffered fetches up to 64KiB with `loread`.
+pub struct LargeObjectReader<'a> {
+    tx: &'a AsyncTransaction<PgConnection>,
+    fd: i32,
+    pending: Option<Pending<'a, Vec<u8>>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+/// Writes a large object, opened with `AsyncTransaction::write_large_object`.
+///
+/// A write is accepted as soon as the previous one reached the server, so
+/// call `flush` or `shutdown` to wait for the last. `shutdown` also closes
+/// the object; otherwise it closes with the transaction.
+pub struct LargeObjectWriter<'a> {
+    tx: &'a AsyncTransaction<PgConnection>,
+    fd: i32,
+    pending: Option<Pending<'a, i32>>,
+    closed: bool,
+}
+
+impl AsyncTransaction<PgConnection> {
+    /// Create an empty large object (`lo_create`), returning its OID.
+    pub async fn create_large_object(&self) -> Result<u32, AsyncError<DieselError>> {
+        self.run(|conn| diesel::select(sql::<Oid>("lo_create(0)")).get_result(conn))
+            .await
+    }
+
+    /// Open the large object `oid` for reading as an `AsyncRead`.
+    ///
+    /// Large objects can only be used inside a transaction; the object is
+    /// closed when the transaction ends.
+    pub async fn read_large_object(
+        &self,
+        oid: u32,
+    ) -> Result<LargeObjectReader<'_>, AsyncError<DieselError>> {
+        Ok(LargeObjectReader {
+            tx: self,
+            fd: self.open_large_object(oid, INV_READ).await?,
+            pending: None,
+            buffer: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Open the large object `oid` for writing as an `AsyncWrite`, from its
+    /// start.
+    pub async fn write_large_object(
+        &self,
+        oid: u32,
+    ) -> Result<LargeObjectWriter<'_>, AsyncError<DieselError>> {
+        Ok(LargeObjectWriter {
+            tx: self,
+            fd: self.open_large_object(oid, INV_WRITE).await?,
+            pending: None,
+            closed: false,
+        })
+    }
+
+    /// Delete the large object `oid` (`lo_unlink`).
+    pub async fn unlink_large_object(&self, oid: u32) -> Result<(), AsyncError<DieselError>> {
+        self.run(move |conn| {
+            diesel::select(sql::<Integer>("lo_unlink(").bind::<Oid, _>(oid).sql(")")).execute(conn)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn open_large_object(&self, oid: u32, mode: i32) -> Result<i32, AsyncError<DieselError>> {
+        self.run(move |conn| {
+            diesel::select(
+                sql::<Integer>("lo_open(")
+                    .bind::<Oid, _>(oid)
+                    .sql(", ")
+                    .bind::<Integer, _>(mode)
+                    .sql(")"),
+            )
+            .get_result(conn)
+        })
+        .await
+    }
+}
+
+impl<'a> AsyncRead for LargeObjectReader<'a> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos == this.buffer.len() {
+            let (tx, fd) = (this.tx, this.fd);
+            let pending = this.pending.get_or_insert_with(|| {
+                tx.run(move |conn| {
+                    diesel::select(
+                        sql::<Binary>("loread(")
+                            .bind::<Integer, _>(fd)
+                            .sql(", ")
+                            .bind::<Integer, _>(CHUNK as i32)
+                            .sql(")"),
+                    )
+                    .get_result::<Vec<u8>>(conn)
+                })
+            });
+            let chunk = futures::ready!(pending.as_mut().poll(cx)).map_err(io_error);
+            this.pending = None;
+            this.buffer = chunk?;
+            this.pos = 0;
+        }
+
+        // An empty chunk is the end of the object
+        let n = cmp::min(buf.remaining(), this.buffer.len() - this.pos);
+        buf.put_slice(&this.buffer[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> LargeObjectWriter<'a> {
+    // Waits for the write in progress, if any
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(ref mut pending) = self.pending {
+            let written = futures::ready!(pending.as_mut().poll(cx)).map_err(io_error);
+            self.pending = None;
+            written?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> AsyncWrite for LargeObjectWriter<'a> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_pending(cx))?;
+        if this.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let chunk = buf[..cmp::min(buf.len(), CHUNK)].to_vec();
+        let n = chunk.len();
+        let fd = this.fd;
+        this.pending = Some(this.tx.run(move |conn| {
+            diesel::select(
+                sql::<Integer>("lowrite(")
+                    .bind::<Integer, _>(fd)
+                    .sql(", ")
+                    .bind::<Binary, _>(chunk)
+                    .sql(")"),
+            )
+            .get_result(conn)
+        }));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_pending(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_pending(cx))?;
+        if !this.closed {
+            this.closed = true;
+            let fd = this.fd;
+            this.pending = Some(this.tx.run(move |conn| {
+                diesel::select(sql::<Integer>("lo_close(").bind::<Integer, _>(fd).sql(")"))
+                    .get_result(conn)
+            }));
+            futures::ready!(this.poll_pending(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn io_error(err: AsyncError<DieselError>) -> io::Error {
+    io::Error::other(err)
+}
@@ -1,64 +1,346 @@
 use async_trait::async_trait;
 use diesel::{
     connection::SimpleConnection,
-    dsl::Limit,
+    connection::TransactionManager,
+    dsl::{count_star, exists, Limit, Offset, Select},
+    expression::{exists::Exists, Expression},
+    insertable::Insertable,
+    query_builder::{InsertStatement, SelectStatement},
     query_dsl::{
-        methods::{ExecuteDsl, LimitDsl, LoadQuery},
+        methods::{ExecuteDsl, LimitDsl, LoadQuery, OffsetDsl, SelectDsl},
         RunQueryDsl,
     },
-    r2d2::{ConnectionManager, Pool},
-    result::Error as DieselError,
+    result::{ConnectionError, DatabaseErrorKind, Error as DieselError},
     Connection,
 };
-use std::{error::Error as StdError, fmt};
-use tokio::task;
+use std::{
+    any::Any,
+    error::Error as StdError,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{sync::mpsc, time};
+
+#[cfg(feature = "actix-web")]
+mod actix;
+#[cfg(feature = "postgres")]
+mod advisory;
+#[cfg(feature = "actix-rt")]
+mod arbiter;
+mod audit;
+#[cfg(feature = "bb8")]
+mod bb8_pool;
+mod checkout;
+mod classify;
+mod config;
+#[cfg(feature = "testcontainers")]
+mod container;
+mod context;
+#[cfg(feature = "postgres")]
+mod copy;
+#[cfg(feature = "postgres")]
+mod cursor;
+mod database;
+mod deadline;
+#[cfg(feature = "deadpool")]
+mod deadpool;
+mod dyn_pool;
+#[cfg(feature = "serde")]
+mod error_body;
+mod fault;
+#[cfg(feature = "fixtures")]
+mod fixtures;
+mod guard;
+mod health;
+mod intercept;
+mod keyset;
+mod label;
+#[cfg(feature = "postgres")]
+mod large_object;
+#[cfg(feature = "postgres")]
+mod libpq;
+#[cfg(feature = "sqlite")]
+mod libsqlite3;
+mod limiter;
+#[cfg(feature = "postgres")]
+mod listen;
+mod loadtest;
+mod metrics;
+#[cfg(feature = "mobc")]
+mod mobc_pool;
+mod mock;
+#[cfg(feature = "mysql")]
+mod mysql;
+#[cfg(feature = "mysql")]
+mod mysqlclient;
+#[cfg(feature = "otel")]
+mod otel;
+mod paginate;
+#[cfg(feature = "postgres")]
+mod pg;
+mod pool;
+#[cfg(feature = "prometheus")]
+mod prometheus_metrics;
+mod retry;
+mod single;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statement_timeout;
+mod stats;
+mod stream;
+mod temp_database;
+mod test_pool;
+mod thread_pool;
+mod trace;
+mod transaction;
+mod truncate;
+mod writer;
+
+#[cfg(all(feature = "actix-web", feature = "prometheus"))]
+pub use actix::prometheus_handler;
+#[cfg(feature = "actix-web")]
+pub use actix::{
+    db_health_handler, DatabaseConfig, DatabaseRegistry, Db, DbDeadline, DbDeadlineMiddleware,
+    DbMetrics, DbMetricsMiddleware, LabelConnections, LabelConnectionsMiddleware, Transactional,
+    TransactionalMiddleware, Tx,
+};
+#[cfg(feature = "postgres")]
+pub use advisory::AdvisoryLockGuard;
+#[cfg(feature = "actix-rt")]
+pub use arbiter::ArbiterPool;
+pub use audit::{with_actor, AuditEvent, AuditSink};
+#[cfg(feature = "bb8")]
+pub use bb8_pool::{Bb8Connection, Bb8ConnectionManager};
+pub use classify::DatabaseErrorClass;
+pub use config::PoolConfig;
+#[cfg(feature = "testcontainers")]
+pub use container::{ContainerBackend, ContainerDatabase, ContainerError, TestContainer};
+pub use context::{ContextError, ErrorContextExt};
+pub use database::{Database, DatabaseBuilder};
+pub use deadline::with_deadline;
+pub use dyn_pool::{AsyncDatabase, DynAsyncPool, DynCall, DynCallError};
+#[cfg(feature = "serde")]
+pub use error_body::ErrorBody;
+pub use fault::FaultInjector;
+#[cfg(feature = "fixtures")]
+pub use fixtures::{FixtureError, Fixtures};
+pub use guard::AsyncConnectionGuard;
+pub use health::AsyncHealthCheck;
+pub use intercept::{QueryCall, QueryInterceptor};
+pub use keyset::{After, Cursor, Keyset, KeysetPage, KeysetPaginate};
+pub use label::{with_connection_label, SessionLabel};
+#[cfg(feature = "postgres")]
+pub use large_object::{LargeObjectReader, LargeObjectWriter};
+pub use limiter::Priority;
+#[cfg(feature = "postgres")]
+pub use listen::{Notification, Notifications};
+pub use loadtest::{LoadReport, LoadTest};
+pub use metrics::QueryMetrics;
+#[cfg(feature = "mobc")]
+pub use mobc_pool::MobcConnectionManager;
+pub use mock::{MockCall, MockDatabase};
+#[cfg(feature = "mysql")]
+pub use mysql::{AsyncInsertIdDsl, MysqlSessionConfig};
+#[cfg(feature = "otel")]
+pub use otel::OtelConfig;
+pub use paginate::{Page, Paginate, Paginated};
+#[cfg(feature = "postgres")]
+pub use pg::{ApplicationName, AsyncTransactionBuilder, IsolationLevel};
+pub use pool::AsyncPool;
+pub use retry::RetryPolicy;
+pub use single::AsyncSingleConnection;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{
+    BackupProgress, CacheSize, PragmaValue, SqlitePoolConfig, Synchronous, TempStore,
+};
+pub use statement_timeout::StatementTimeout;
+pub use stats::{ErrorCounts, PoolStats, WaitHistogram};
+pub use stream::LoadStream;
+pub use temp_database::{TempDatabase, TempDatabaseBackend};
+pub use test_pool::TestPool;
+pub use thread_pool::{ThreadPool, ThreadPoolBuilder};
+pub use trace::{with_sql, with_tag};
+pub use transaction::{AsyncSavepoint, AsyncTransaction};
+pub use truncate::TruncateTables;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum AsyncError<E: fmt::Debug> {
     // Failed to checkout a connection
-    Checkout(r2d2::Error),
+    #[error("{0}")]
+    Checkout(#[source] CheckoutError),
+
+    // Failed to establish a connection outside the pool
+    #[error("{0}")]
+    Connect(#[source] ConnectionError),
 
     // The query failed in some way
-    Error(E),
+    #[error("{0}")]
+    Error(#[source] E),
 
     // The task was cancelled
+    #[error("task was cancelled")]
     Canceled,
+
+    // The checkout and query did not finish within the allotted time
+    #[error("operation timed out")]
+    Timeout,
+
+    // The closure panicked; holds the panic message
+    #[error("task panicked: {0}")]
+    Panicked(String),
+
+    // The database has been shut down and accepts no new work
+    #[error("database is shut down")]
+    Closed,
+
+    // A `QueryInterceptor` refused the call; holds its reason
+    #[error("query rejected: {0}")]
+    Rejected(String),
+}
+
+/// Why a connection could not be checked out of a pool.
+///
+/// Holds the pool's own error, so integrations with pools other than r2d2 can
+/// report through `AsyncError::Checkout` too.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct CheckoutError(Box<dyn StdError + Send + Sync>);
+
+impl CheckoutError {
+    pub fn new<E: Into<Box<dyn StdError + Send + Sync>>>(err: E) -> CheckoutError {
+        CheckoutError(err.into())
+    }
+
+    /// The pool's error, if it is a `T`.
+    pub fn downcast_ref<T: StdError + 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn StdError + Send + Sync> {
+        self.0
+    }
+}
+
+impl From<r2d2::Error> for CheckoutError {
+    fn from(err: r2d2::Error) -> CheckoutError {
+        CheckoutError::new(err)
+    }
 }
 
 pub trait OptionalExtension<T, E: fmt::Debug> {
     fn optional(self) -> Result<Option<T>, AsyncError<E>>;
 }
 
-impl<T> OptionalExtension<T, DieselError> for Result<T, AsyncError<DieselError>> {
-    fn optional(self) -> Result<Option<T>, AsyncError<DieselError>> {
+/// Errors that can report a query finding no rows, so `optional` works with
+/// application error types and not only diesel's.
+pub trait IsNotFound {
+    fn is_not_found(&self) -> bool;
+}
+
+impl IsNotFound for DieselError {
+    fn is_not_found(&self) -> bool {
+        matches!(*self, DieselError::NotFound)
+    }
+}
+
+impl<T, E: IsNotFound + fmt::Debug> OptionalExtension<T, E> for Result<T, AsyncError<E>> {
+    fn optional(self) -> Result<Option<T>, AsyncError<E>> {
         match self {
             Ok(value) => Ok(Some(value)),
-            Err(AsyncError::Error(diesel::result::Error::NotFound)) => Ok(None),
+            Err(AsyncError::Error(ref e)) if e.is_not_found() => Ok(None),
             Err(e) => Err(e),
         }
     }
 }
 
-impl<E: fmt::Display + fmt::Debug> fmt::Display for AsyncError<E> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            AsyncError::Checkout(ref err) => fmt::Display::fmt(&err, f),
-            AsyncError::Error(ref err) => fmt::Display::fmt(&err, f),
-            AsyncError::Canceled => write!(f, "task was cancelled"),
+impl<E: fmt::Debug> AsyncError<E> {
+    /// Convert the query error, leaving the other variants as they are.
+    pub fn map<E2, F>(self, f: F) -> AsyncError<E2>
+    where
+        E2: fmt::Debug,
+        F: FnOnce(E) -> E2,
+    {
+        match self {
+            AsyncError::Checkout(err) => AsyncError::Checkout(err),
+            AsyncError::Connect(err) => AsyncError::Connect(err),
+            AsyncError::Error(err) => AsyncError::Error(f(err)),
+            AsyncError::Canceled => AsyncError::Canceled,
+            AsyncError::Timeout => AsyncError::Timeout,
+            AsyncError::Panicked(msg) => AsyncError::Panicked(msg),
+            AsyncError::Closed => AsyncError::Closed,
+            AsyncError::Rejected(reason) => AsyncError::Rejected(reason),
+        }
+    }
+
+    /// The query error, or `None` if the failure happened in the async layer.
+    pub fn into_inner(self) -> Option<E> {
+        match self {
+            AsyncError::Error(err) => Some(err),
+            _ => None,
         }
     }
 }
 
-impl<E: 'static + StdError> StdError for AsyncError<E> {
-    fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        match *self {
-            AsyncError::Checkout(ref err) => Some(err),
-            AsyncError::Error(ref err) => Some(err),
-            AsyncError::Canceled => None,
+// Lossy: failures of the async layer itself become a `DatabaseError` carrying only
+// their message, of a kind that keeps them transient only if they were
+impl From<AsyncError<DieselError>> for DieselError {
+    fn from(err: AsyncError<DieselError>) -> DieselError {
+        let kind = match err {
+            AsyncError::Error(err) => return err,
+            AsyncError::Checkout(_) | AsyncError::Connect(ConnectionError::BadConnection(_)) => {
+                DatabaseErrorKind::UnableToSendCommand
+            }
+            // Classified by the message, as timeouts have no kind of their own
+            _ => DatabaseErrorKind::__Unknown,
+        };
+        DieselError::DatabaseError(kind, Box::new(err.to_string()))
+    }
+}
+
+// Runs `f` on a checked out connection, turning a panic into `AsyncError::Panicked`.
+// A panic skips diesel's own rollback, so any transaction the closure opened is
+// rolled back here before the connection is used again.
+pub(crate) fn run_guarded<Conn, R, E, Func>(conn: &Conn, f: Func) -> Result<R, AsyncError<E>>
+where
+    Conn: Connection,
+    E: fmt::Debug,
+    Func: FnOnce(&Conn) -> Result<R, E>,
+{
+    let manager = conn.transaction_manager();
+    let depth = manager.get_transaction_depth();
+
+    match panic::catch_unwind(AssertUnwindSafe(|| f(conn))) {
+        Ok(result) => result.map_err(AsyncError::Error),
+        Err(payload) => {
+            while manager.get_transaction_depth() > depth {
+                if manager.rollback_transaction(conn).is_err() {
+                    break;
+                }
+            }
+
+            Err(AsyncError::Panicked(panic_message(payload)))
         }
     }
 }
 
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "Box<dyn Any>".to_string(),
+        },
+    }
+}
+
+// Paging or batching by chunks of no rows would never finish
+fn zero_chunk_size() -> DieselError {
+    DieselError::QueryBuilderError("chunk size must be positive".into())
+}
+
 #[async_trait]
 pub trait AsyncSimpleConnection<Conn>
 where
@@ -68,20 +350,19 @@ where
 }
 
 #[async_trait]
-impl<Conn> AsyncSimpleConnection<Conn> for Pool<ConnectionManager<Conn>>
+impl<P> AsyncSimpleConnection<P::Connection> for P
 where
-    Conn: 'static + Connection,
+    P: AsyncPool,
 {
     #[inline]
     async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
-        let self_ = self.clone();
         let query = query.to_string();
-        task::spawn_blocking(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            conn.batch_execute(&query).map_err(AsyncError::Error)
-        })
+        trace::instrument(
+            "batch_execute",
+            trace::no_rows,
+            self.with_connection(move |conn| run_guarded(conn, |conn| conn.batch_execute(&query))),
+        )
         .await
-        .map_err(|_| AsyncError::Canceled)?
     }
 }
 
@@ -101,27 +382,143 @@ where
         R: 'static + Send,
         E: 'static + From<DieselError> + fmt::Debug + Send,
         Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send;
+
+    // The query keeps running on its blocking thread after a timeout; only the
+    // caller stops waiting for it
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        time::timeout(timeout, self.run(f))
+            .await
+            .map_err(|_| AsyncError::Timeout)?
+    }
+
+    /// Like `run`, but the server cancels any statement of `f` that runs
+    /// longer than `timeout`, failing it with an error of class `Timeout`.
+    async fn run_with_statement_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        Conn: StatementTimeout,
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.run(move |conn| conn.with_statement_timeout(timeout, f))
+            .await
+    }
+
+    /// Empty every table of the database but those in `except` and diesel's
+    /// migrations table, returning the tables emptied, for test suites that
+    /// can't isolate tests in rolled back transactions.
+    ///
+    /// Tables are found through the catalog, so new ones need no listing.
+    /// Foreign keys between emptied tables are no obstacle and identity and
+    /// auto-increment counters start over; a kept table referencing an
+    /// emptied one makes the call fail.
+    async fn truncate_all(&self, except: &[&str]) -> Result<Vec<String>, AsyncError<DieselError>>
+    where
+        Conn: TruncateTables,
+    {
+        let except: Vec<String> = except.iter().map(|name| name.to_string()).collect();
+        self.run(move |conn| {
+            let except: Vec<&str> = except.iter().map(String::as_str).collect();
+            conn.truncate_all(&except)
+        })
+        .await
+    }
+
+    // Runs `f`, running it again after transient failures (deadlocks, serialization
+    // failures, lost connections, ...) as `policy` allows; each attempt checks out anew
+    async fn run_retrying<R, Func>(
+        &self,
+        policy: RetryPolicy,
+        f: Func,
+    ) -> Result<R, AsyncError<DieselError>>
+    where
+        R: 'static + Send,
+        Func: 'static + FnMut(&Conn) -> Result<R, DieselError> + Send,
+    {
+        let f = Arc::new(Mutex::new(f));
+        retry::retrying(&policy, AsyncError::is_transient, || {
+            let f = f.clone();
+            self.run(move |conn| (f.lock().unwrap())(conn))
+        })
+        .await
+    }
+
+    // Runs every closure on one connection in a single blocking call; each closure's
+    // result is returned in order, independent of the others
+    async fn run_batch<R, E, Func>(&self, fs: Vec<Func>) -> Result<Vec<Result<R, E>>, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.run(move |conn| Ok(fs.into_iter().map(|f| f(conn)).collect()))
+            .await
+    }
+
+    // Inserts `records` into `table` with one multi-row INSERT per `chunk_size` records,
+    // all in one transaction; keeps each statement under the backend's bind parameter limit.
+    // A `chunk_size` of zero fails with a `QueryBuilderError`
+    async fn insert_batched_async<Tab, V>(
+        &self,
+        table: Tab,
+        records: Vec<V>,
+        chunk_size: usize,
+    ) -> Result<usize, AsyncError<DieselError>>
+    where
+        Tab: 'static + Copy + Send,
+        V: 'static + Send,
+        Vec<V>: Insertable<Tab>,
+        InsertStatement<Tab, <Vec<V> as Insertable<Tab>>::Values>: ExecuteDsl<Conn>,
+    {
+        if chunk_size == 0 {
+            return Err(AsyncError::Error(zero_chunk_size()));
+        }
+
+        self.transaction(move |conn| {
+            let mut records = records;
+            let mut inserted = 0;
+            while !records.is_empty() {
+                let rest = records.split_off(chunk_size.min(records.len()));
+                inserted += diesel::insert_into(table).values(records).execute(conn)?;
+                records = rest;
+            }
+            Ok(inserted)
+        })
+        .await
+    }
 }
 
 #[async_trait]
-impl<Conn> AsyncConnection<Conn> for Pool<ConnectionManager<Conn>>
+impl<P> AsyncConnection<P::Connection> for P
 where
-    Conn: 'static + Connection,
+    P: AsyncPool,
 {
     #[inline]
     async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
     where
         R: 'static + Send,
         E: 'static + From<DieselError> + fmt::Debug + Send,
-        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+        Func: 'static + FnOnce(&P::Connection) -> Result<R, E> + Send,
     {
-        let self_ = self.clone();
-        task::spawn_blocking(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            f(&*conn).map_err(AsyncError::Error)
-        })
+        trace::instrument(
+            "run",
+            trace::no_rows,
+            self.with_connection(move |conn| run_guarded(conn, f)),
+        )
         .await
-        .map_err(|_| AsyncError::Canceled)?
     }
 
     #[inline]
@@ -129,16 +526,16 @@ where
     where
         R: 'static + Send,
         E: 'static + From<DieselError> + fmt::Debug + Send,
-        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+        Func: 'static + FnOnce(&P::Connection) -> Result<R, E> + Send,
     {
-        let self_ = self.clone();
-        task::spawn_blocking(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            conn.transaction::<R, E, _>(|| f(&*conn))
-                .map_err(AsyncError::Error)
-        })
+        trace::instrument(
+            "transaction",
+            trace::no_rows,
+            self.with_connection(move |conn| {
+                run_guarded(conn, |conn| conn.transaction::<R, E, _>(|| f(conn)))
+            }),
+        )
         .await
-        .map_err(|_| AsyncError::Canceled)?
     }
 }
 
@@ -151,16 +548,51 @@ where
     where
         Self: ExecuteDsl<Conn>;
 
+    async fn execute_async_timeout(
+        self,
+        asc: &AsyncConn,
+        timeout: Duration,
+    ) -> Result<usize, AsyncError<DieselError>>
+    where
+        Self: ExecuteDsl<Conn>;
+
     async fn load_async<U>(self, asc: &AsyncConn) -> Result<Vec<U>, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LoadQuery<Conn, U>;
 
+    /// Page through the results `chunk_size` rows at a time with
+    /// `LIMIT`/`OFFSET`, checking out a connection per chunk. The query should
+    /// have an `ORDER BY` that makes the paging stable.
+    ///
+    /// A `chunk_size` below one fails the stream with a
+    /// `QueryBuilderError`.
+    fn load_chunked_async<'a, U>(
+        self,
+        asc: &'a AsyncConn,
+        chunk_size: i64,
+    ) -> LoadStream<'a, Vec<U>>
+    where
+        U: 'static + Send,
+        Self: Clone + LimitDsl,
+        Limit<Self>: OffsetDsl,
+        Offset<Limit<Self>>: 'static + Send + LoadQuery<Conn, U>;
+
     async fn get_result_async<U>(self, asc: &AsyncConn) -> Result<U, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LoadQuery<Conn, U>;
 
+    /// Like `get_result_async`, but no row is `Ok(None)` rather than a
+    /// `NotFound` error.
+    async fn get_optional_async<U>(
+        self,
+        asc: &AsyncConn,
+    ) -> Result<Option<U>, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        Self: LoadQuery<Conn, U>;
+
     async fn get_results_async<U>(self, asc: &AsyncConn) -> Result<Vec<U>, AsyncError<DieselError>>
     where
         U: 'static + Send,
@@ -171,66 +603,198 @@ where
         U: 'static + Send,
         Self: LimitDsl,
         Limit<Self>: LoadQuery<Conn, U>;
+
+    /// Like `first_async`, but no row is `Ok(None)` rather than a `NotFound`
+    /// error.
+    async fn first_optional_async<U>(
+        self,
+        asc: &AsyncConn,
+    ) -> Result<Option<U>, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        Self: LimitDsl,
+        Limit<Self>: LoadQuery<Conn, U>;
+
+    /// `SELECT EXISTS (query)`: whether the query returns any rows.
+    async fn exists_async(self, asc: &AsyncConn) -> Result<bool, AsyncError<DieselError>>
+    where
+        Self: Sized,
+        Exists<Self>: Expression,
+        SelectStatement<()>: SelectDsl<Exists<Self>>,
+        Select<SelectStatement<()>, Exists<Self>>: LoadQuery<Conn, bool>;
+
+    /// `SELECT COUNT(*)` with the query's own select clause replaced, as
+    /// `QueryDsl::count` does.
+    async fn count_async(self, asc: &AsyncConn) -> Result<i64, AsyncError<DieselError>>
+    where
+        Self: SelectDsl<count_star>,
+        Select<Self, count_star>: LoadQuery<Conn, i64>;
 }
 
 #[async_trait]
-impl<T, Conn> AsyncRunQueryDsl<Conn, Pool<ConnectionManager<Conn>>> for T
+impl<T, Conn, AsyncConn> AsyncRunQueryDsl<Conn, AsyncConn> for T
 where
     T: 'static + Send + RunQueryDsl<Conn>,
     Conn: 'static + Connection,
+    AsyncConn: Send + Sync + AsyncConnection<Conn>,
 {
-    async fn execute_async(
+    async fn execute_async(self, asc: &AsyncConn) -> Result<usize, AsyncError<DieselError>>
+    where
+        Self: ExecuteDsl<Conn>,
+    {
+        let execute = trace::instrument(
+            "execute",
+            |rows| Some(*rows),
+            asc.run(|conn| self.execute(conn)),
+        );
+        audit::audited("execute", |rows| Some(*rows), execute).await
+    }
+
+    async fn execute_async_timeout(
         self,
-        asc: &Pool<ConnectionManager<Conn>>,
+        asc: &AsyncConn,
+        timeout: Duration,
     ) -> Result<usize, AsyncError<DieselError>>
     where
         Self: ExecuteDsl<Conn>,
     {
-        asc.run(|conn| self.execute(&*conn)).await
+        let execute = trace::instrument(
+            "execute",
+            |rows| Some(*rows),
+            asc.run_with_timeout(timeout, |conn| self.execute(conn)),
+        );
+        audit::audited("execute", |rows| Some(*rows), execute).await
     }
 
-    async fn load_async<U>(
-        self,
-        asc: &Pool<ConnectionManager<Conn>>,
-    ) -> Result<Vec<U>, AsyncError<DieselError>>
+    async fn load_async<U>(self, asc: &AsyncConn) -> Result<Vec<U>, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LoadQuery<Conn, U>,
     {
-        asc.run(|conn| self.load(&*conn)).await
+        trace::instrument(
+            "load",
+            |rows| Some(rows.len()),
+            writer::read(asc.run(|conn| self.load(conn))),
+        )
+        .await
     }
 
-    async fn get_result_async<U>(
+    fn load_chunked_async<'a, U>(
         self,
-        asc: &Pool<ConnectionManager<Conn>>,
-    ) -> Result<U, AsyncError<DieselError>>
+        asc: &'a AsyncConn,
+        chunk_size: i64,
+    ) -> LoadStream<'a, Vec<U>>
+    where
+        U: 'static + Send,
+        Self: Clone + LimitDsl,
+        Limit<Self>: OffsetDsl,
+        Offset<Limit<Self>>: 'static + Send + LoadQuery<Conn, U>,
+    {
+        // Only the next chunk is fetched ahead of the consumer
+        let (tx, rx) = mpsc::channel(1);
+        let feed = Box::pin(async move {
+            if chunk_size <= 0 {
+                return Err(AsyncError::Error(zero_chunk_size()));
+            }
+            let mut offset = 0;
+            loop {
+                let page = self.clone().limit(chunk_size).offset(offset);
+                let rows: Vec<U> = writer::read(asc.run(move |conn| page.load(conn))).await?;
+                let last = (rows.len() as i64) < chunk_size;
+
+                if rows.is_empty() || tx.send(rows).await.is_err() || last {
+                    return Ok(());
+                }
+                offset += chunk_size;
+            }
+        });
+
+        LoadStream::new(feed, rx)
+    }
+
+    async fn get_result_async<U>(self, asc: &AsyncConn) -> Result<U, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LoadQuery<Conn, U>,
     {
-        asc.run(|conn| self.get_result(&*conn)).await
+        trace::instrument(
+            "get_result",
+            |_| Some(1),
+            writer::read(asc.run(|conn| self.get_result(conn))),
+        )
+        .await
     }
 
-    async fn get_results_async<U>(
+    async fn get_optional_async<U>(
         self,
-        asc: &Pool<ConnectionManager<Conn>>,
-    ) -> Result<Vec<U>, AsyncError<DieselError>>
+        asc: &AsyncConn,
+    ) -> Result<Option<U>, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        Self: LoadQuery<Conn, U>,
+    {
+        self.get_result_async(asc).await.optional()
+    }
+
+    async fn get_results_async<U>(self, asc: &AsyncConn) -> Result<Vec<U>, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LoadQuery<Conn, U>,
     {
-        asc.run(|conn| self.get_results(&*conn)).await
+        trace::instrument(
+            "get_results",
+            |rows| Some(rows.len()),
+            writer::read(asc.run(|conn| self.get_results(conn))),
+        )
+        .await
+    }
+
+    async fn first_async<U>(self, asc: &AsyncConn) -> Result<U, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        Self: LimitDsl,
+        Limit<Self>: LoadQuery<Conn, U>,
+    {
+        let first = writer::read(asc.run(|conn| self.first(conn)));
+        trace::instrument("first", |_| Some(1), first).await
     }
 
-    async fn first_async<U>(
+    async fn first_optional_async<U>(
         self,
-        asc: &Pool<ConnectionManager<Conn>>,
-    ) -> Result<U, AsyncError<DieselError>>
+        asc: &AsyncConn,
+    ) -> Result<Option<U>, AsyncError<DieselError>>
     where
         U: 'static + Send,
         Self: LimitDsl,
         Limit<Self>: LoadQuery<Conn, U>,
     {
-        asc.run(|conn| self.first(&*conn)).await
+        self.first_async(asc).await.optional()
+    }
+
+    async fn exists_async(self, asc: &AsyncConn) -> Result<bool, AsyncError<DieselError>>
+    where
+        Exists<Self>: Expression,
+        SelectStatement<()>: SelectDsl<Exists<Self>>,
+        Select<SelectStatement<()>, Exists<Self>>: LoadQuery<Conn, bool>,
+    {
+        trace::instrument(
+            "exists",
+            |_| Some(1),
+            writer::read(asc.run(|conn| diesel::select(exists(self)).get_result(conn))),
+        )
+        .await
+    }
+
+    async fn count_async(self, asc: &AsyncConn) -> Result<i64, AsyncError<DieselError>>
+    where
+        Self: SelectDsl<count_star>,
+        Select<Self, count_star>: LoadQuery<Conn, i64>,
+    {
+        trace::instrument(
+            "count",
+            |_| Some(1),
+            writer::read(asc.run(|conn| self.select(count_star()).get_result(conn))),
+        )
+        .await
     }
 }
@@ -0,0 +1,290 @@
+use crate::Notification;
+use diesel::result::{
+    ConnectionError, DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError, QueryResult,
+};
+use pq_sys::*;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    ptr::{self, NonNull},
+};
+
+// A libpq connection of the crate's own, for the parts of the protocol diesel 1.x
+// does not expose, such as notifications and COPY
+pub(crate) struct RawConnection {
+    conn: NonNull<PGconn>,
+}
+
+// libpq connections may move between threads as long as only one uses them at a time
+unsafe impl Send for RawConnection {}
+
+// The error fields libpq has no constants for, from `postgres_ext.h`
+const PG_DIAG_SQLSTATE: c_int = b'C' as c_int;
+const PG_DIAG_MESSAGE_PRIMARY: c_int = b'M' as c_int;
+const PG_DIAG_MESSAGE_DETAIL: c_int = b'D' as c_int;
+const PG_DIAG_MESSAGE_HINT: c_int = b'H' as c_int;
+const PG_DIAG_TABLE_NAME: c_int = b't' as c_int;
+const PG_DIAG_COLUMN_NAME: c_int = b'c' as c_int;
+const PG_DIAG_CONSTRAINT_NAME: c_int = b'n' as c_int;
+
+// The result of a failed command, kept by the error made from it
+struct ResultError(NonNull<PGresult>);
+
+impl RawConnection {
+    pub(crate) fn establish(database_url: &str) -> Result<RawConnection, ConnectionError> {
+        let url = CString::new(database_url)?;
+        let conn = unsafe { PQconnectdb(url.as_ptr()) };
+        let conn = NonNull::new(conn).ok_or_else(|| {
+            ConnectionError::BadConnection("libpq could not allocate a connection".to_string())
+        })?;
+
+        let raw = RawConnection { conn };
+        if unsafe { PQstatus(raw.conn.as_ptr()) } != CONNECTION_OK {
+            return Err(ConnectionError::BadConnection(raw.last_error()));
+        }
+        Ok(raw)
+    }
+
+    // Runs `sql`, which must not return rows
+    pub(crate) fn execute(&self, sql: &str) -> QueryResult<()> {
+        let sql = CString::new(sql)?;
+        unsafe {
+            let result = PQexec(self.conn.as_ptr(), sql.as_ptr());
+            let ok = !result.is_null() && PQresultStatus(result) == PGRES_COMMAND_OK;
+            PQclear(result);
+            if ok {
+                Ok(())
+            } else {
+                Err(self.error())
+            }
+        }
+    }
+
+    // Reads what the server sent without blocking, then takes the notifications
+    // received so far; `Err` once the connection is lost
+    pub(crate) fn notifications(&self) -> QueryResult<Vec<Notification>> {
+        let mut notifications = Vec::new();
+        unsafe {
+            if PQconsumeInput(self.conn.as_ptr()) == 0 {
+                return Err(self.error());
+            }
+            loop {
+                let notify = PQnotifies(self.conn.as_ptr());
+                if notify.is_null() {
+                    break;
+                }
+                notifications.push(Notification {
+                    channel: string_at((*notify).relname),
+                    payload: string_at((*notify).extra),
+                    process_id: (*notify).be_pid,
+                });
+                PQfreemem(notify as *mut c_void);
+            }
+        }
+        Ok(notifications)
+    }
+
+    // Runs the `COPY ... FROM STDIN` in `sql`, sending it the chunks `data` yields;
+    // an `Err` chunk aborts the copy with its message. Returns the rows copied
+    pub(crate) fn copy_in<I>(&self, sql: &str, data: I) -> QueryResult<u64>
+    where
+        I: IntoIterator<Item = Result<Vec<u8>, String>>,
+    {
+        let sql = CString::new(sql)?;
+        let conn = self.conn.as_ptr();
+        unsafe {
+            let result = PQexec(conn, sql.as_ptr());
+            if result.is_null() || PQresultStatus(result) != PGRES_COPY_IN {
+                return Err(self.result_error(result));
+            }
+            PQclear(result);
+
+            let mut abort = None;
+            for chunk in data {
+                match chunk {
+                    Ok(chunk) => {
+                        // Larger chunks than libpq takes at once are sent in parts
+                        for part in chunk.chunks(c_int::MAX as usize) {
+                            let sent = PQputCopyData(
+                                conn,
+                                part.as_ptr() as *const c_char,
+                                part.len() as c_int,
+                            );
+                            if sent != 1 {
+                                return Err(self.error());
+                            }
+                        }
+                    }
+                    Err(message) => {
+                        abort = Some(CString::new(message.replace('\0', " "))?);
+                        break;
+                    }
+                }
+            }
+            let abort = abort
+                .as_ref()
+                .map_or(ptr::null(), |message| message.as_ptr());
+            if PQputCopyEnd(conn, abort) != 1 {
+                return Err(self.error());
+            }
+
+            self.finish_command()
+        }
+    }
+
+    // Runs the `COPY ... TO STDOUT` in `sql`, handing each row of output to `sink`
+    // until it returns `false`. Returns the rows copied, or 0 if `sink` stopped it
+    pub(crate) fn copy_out<F>(&self, sql: &str, mut sink: F) -> QueryResult<u64>
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        let sql = CString::new(sql)?;
+        let conn = self.conn.as_ptr();
+        unsafe {
+            let result = PQexec(conn, sql.as_ptr());
+            if result.is_null() || PQresultStatus(result) != PGRES_COPY_OUT {
+                return Err(self.result_error(result));
+            }
+            PQclear(result);
+
+            loop {
+                let mut buffer = ptr::null_mut();
+                match PQgetCopyData(conn, &mut buffer, 0) {
+                    // Done; the command's result follows
+                    -1 => return self.finish_command(),
+                    -2 => return Err(self.error()),
+                    len => {
+                        let chunk = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+                        let chunk = chunk.to_vec();
+                        PQfreemem(buffer as *mut c_void);
+                        // Closing the connection is all that stops a COPY TO early
+                        if !sink(chunk) {
+                            return Ok(0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Collects the results of the command just sent, returning the rows it affected
+    unsafe fn finish_command(&self) -> QueryResult<u64> {
+        let mut outcome = Ok(0);
+        loop {
+            let result = PQgetResult(self.conn.as_ptr());
+            if result.is_null() {
+                return outcome;
+            }
+            if outcome.is_err() || PQresultStatus(result) == PGRES_COMMAND_OK {
+                outcome = outcome.map(|_| string_at(PQcmdTuples(result)).parse().unwrap_or(0));
+                PQclear(result);
+            } else {
+                outcome = Err(self.result_error(result));
+            }
+        }
+    }
+
+    // Takes `result`, which the error keeps for its fields, as diesel's own errors do
+    unsafe fn result_error(&self, result: *mut PGresult) -> DieselError {
+        match NonNull::new(result) {
+            Some(result)
+                if PQresultErrorField(result.as_ptr(), PG_DIAG_MESSAGE_PRIMARY).is_null() =>
+            {
+                PQclear(result.as_ptr());
+                self.error()
+            }
+            Some(result) => {
+                // The only place the SQLSTATE can be had; diesel's kinds are what survives
+                let kind = match result_field(result.as_ptr(), PG_DIAG_SQLSTATE) {
+                    Some("23505") => DatabaseErrorKind::UniqueViolation,
+                    Some("23503") => DatabaseErrorKind::ForeignKeyViolation,
+                    Some("40001") => DatabaseErrorKind::SerializationFailure,
+                    _ => DatabaseErrorKind::__Unknown,
+                };
+                DieselError::DatabaseError(kind, Box::new(ResultError(result)))
+            }
+            None => self.error(),
+        }
+    }
+
+    fn error(&self) -> DieselError {
+        DieselError::DatabaseError(DatabaseErrorKind::__Unknown, Box::new(self.last_error()))
+    }
+
+    fn last_error(&self) -> String {
+        unsafe { string_at(PQerrorMessage(self.conn.as_ptr())) }
+            .trim_end()
+            .to_string()
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        unsafe { PQfinish(self.conn.as_ptr()) }
+    }
+}
+
+// A failed result is only read from, so it may be shared between threads
+unsafe impl Send for ResultError {}
+unsafe impl Sync for ResultError {}
+
+impl DatabaseErrorInformation for ResultError {
+    fn message(&self) -> &str {
+        self.field(PG_DIAG_MESSAGE_PRIMARY).unwrap_or_default()
+    }
+
+    fn details(&self) -> Option<&str> {
+        self.field(PG_DIAG_MESSAGE_DETAIL)
+    }
+
+    fn hint(&self) -> Option<&str> {
+        self.field(PG_DIAG_MESSAGE_HINT)
+    }
+
+    fn table_name(&self) -> Option<&str> {
+        self.field(PG_DIAG_TABLE_NAME)
+    }
+
+    fn column_name(&self) -> Option<&str> {
+        self.field(PG_DIAG_COLUMN_NAME)
+    }
+
+    fn constraint_name(&self) -> Option<&str> {
+        self.field(PG_DIAG_CONSTRAINT_NAME)
+    }
+}
+
+impl ResultError {
+    fn field(&self, field: c_int) -> Option<&str> {
+        unsafe { result_field(self.0.as_ptr(), field) }
+    }
+}
+
+impl Drop for ResultError {
+    fn drop(&mut self) {
+        unsafe { PQclear(self.0.as_ptr()) }
+    }
+}
+
+// A field of the error in `result`, which outlives the returned string
+unsafe fn result_field<'a>(result: *const PGresult, field: c_int) -> Option<&'a str> {
+    let ptr = PQresultErrorField(result, field);
+    if ptr.is_null() {
+        None
+    } else {
+        CStr::from_ptr(ptr).to_str().ok()
+    }
+}
+
+// Quotes `name` as an SQL identifier
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+unsafe fn string_at(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy().into_owned()
+    }
+}
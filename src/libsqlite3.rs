@@ -0,0 +1,117 @@
+use crate::BackupProgress;
+use diesel::result::{ConnectionError, DatabaseErrorKind, Error as DieselError, QueryResult};
+use libsqlite3_sys::*;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+    thread,
+    time::Duration,
+};
+
+// Pages copied per `sqlite3_backup_step`; the source is only locked during a step
+const BACKUP_STEP_PAGES: c_int = 256;
+
+// How long a backup step waits before trying again when the source is locked
+const BACKUP_BUSY_WAIT: Duration = Duration::from_millis(10);
+
+// An SQLite connection of the crate's own, for the parts of the library diesel 1.x
+// does not expose, such as the backup API
+pub(crate) struct RawConnection {
+    conn: NonNull<sqlite3>,
+}
+
+// SQLite connections may move between threads as long as only one uses them at a time
+unsafe impl Send for RawConnection {}
+
+impl RawConnection {
+    // Opens `database_url` as diesel does, also taking `file:` URIs
+    pub(crate) fn establish(database_url: &str) -> Result<RawConnection, ConnectionError> {
+        let url = CString::new(database_url)?;
+        let mut conn = ptr::null_mut();
+        let status = unsafe {
+            sqlite3_open_v2(
+                url.as_ptr(),
+                &mut conn,
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_URI,
+                ptr::null(),
+            )
+        };
+        let conn = NonNull::new(conn).ok_or_else(|| {
+            ConnectionError::BadConnection("SQLite could not allocate a connection".to_string())
+        })?;
+
+        // A connection that failed to open still has to be closed
+        let raw = RawConnection { conn };
+        if status != SQLITE_OK {
+            return Err(ConnectionError::BadConnection(raw.last_error()));
+        }
+        Ok(raw)
+    }
+
+    // Copies this connection's main database over `dest`'s, a few pages at a time,
+    // reporting the pages left to `progress` after every step. Steps the source is
+    // locked for are tried again after a pause. A panic of `progress` is resumed once
+    // the backup is finished, as the connections can't be closed before it is
+    pub(crate) fn backup_to<F>(&self, dest: &RawConnection, mut progress: F) -> QueryResult<()>
+    where
+        F: FnMut(BackupProgress),
+    {
+        let main = b"main\0".as_ptr() as *const c_char;
+        unsafe {
+            let backup = sqlite3_backup_init(dest.conn.as_ptr(), main, self.conn.as_ptr(), main);
+            if backup.is_null() {
+                return Err(dest.error());
+            }
+            let mut panicked = None;
+            loop {
+                let step = sqlite3_backup_step(backup, BACKUP_STEP_PAGES);
+                match step {
+                    SQLITE_OK | SQLITE_DONE => {
+                        let report = BackupProgress {
+                            remaining: sqlite3_backup_remaining(backup) as u32,
+                            total: sqlite3_backup_pagecount(backup) as u32,
+                        };
+                        if let Err(payload) =
+                            panic::catch_unwind(AssertUnwindSafe(|| progress(report)))
+                        {
+                            panicked = Some(payload);
+                            break;
+                        }
+                    }
+                    SQLITE_BUSY | SQLITE_LOCKED => thread::sleep(BACKUP_BUSY_WAIT),
+                    // `sqlite3_backup_finish` reports the error
+                    _ => break,
+                }
+                if step == SQLITE_DONE {
+                    break;
+                }
+            }
+            let finished = sqlite3_backup_finish(backup);
+            if let Some(payload) = panicked {
+                panic::resume_unwind(payload);
+            }
+            if finished != SQLITE_OK {
+                return Err(dest.error());
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self) -> DieselError {
+        DieselError::DatabaseError(DatabaseErrorKind::__Unknown, Box::new(self.last_error()))
+    }
+
+    fn last_error(&self) -> String {
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.conn.as_ptr())) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.conn.as_ptr()) };
+    }
+}
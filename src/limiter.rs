@@ -0,0 +1,97 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+/// Scheduling priority for work waiting on a saturated `Database`.
+///
+/// Higher priorities are admitted first; callers of equal priority are
+/// admitted in the order they arrived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+const PRIORITIES: usize = 3;
+
+// A counting semaphore that wakes waiters by priority rather than strictly FIFO
+pub(crate) struct Limiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    available: usize,
+    // Indexed by `Priority as usize`
+    waiters: [VecDeque<oneshot::Sender<Permit>>; PRIORITIES],
+}
+
+pub(crate) struct Permit {
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(permits: usize) -> Arc<Limiter> {
+        Arc::new(Limiter {
+            state: Mutex::new(State {
+                available: permits,
+                waiters: Default::default(),
+            }),
+        })
+    }
+
+    pub(crate) async fn acquire(self: &Arc<Self>, priority: Priority) -> Permit {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit {
+                    limiter: Some(self.clone()),
+                };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.waiters[priority as usize].push_back(tx);
+            rx
+        };
+
+        // Waiters are only ever removed by `release`, which sends before dropping them
+        rx.await.expect("limiter waiter dropped without a permit")
+    }
+
+    fn release(self: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                match state.waiters.iter_mut().rev().find_map(VecDeque::pop_front) {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                }
+            };
+
+            // A waiter whose future was dropped hands the permit back; try the next one
+            match waiter.send(Permit {
+                limiter: Some(self.clone()),
+            }) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    permit.limiter = None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            limiter.release();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{
+    libpq::{self, RawConnection},
+    stream::STREAM_BUFFER,
+    AsyncError, Database,
+};
+use diesel::{pg::PgConnection, result::Error as DieselError};
+use futures::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+// How often the listening connection is checked for notifications
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A message sent with `NOTIFY` to a channel listened to with
+/// `Database::listen`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    pub channel: String,
+    pub payload: String,
+    /// The server process of the session that sent it
+    pub process_id: i32,
+}
+
+/// The notifications sent to a channel, returned by `Database::listen`.
+///
+/// Ends if the listening connection is lost. Dropping it closes the
+/// connection.
+pub struct Notifications {
+    rx: mpsc::Receiver<Notification>,
+}
+
+impl Database<PgConnection> {
+    /// `LISTEN` to `channel` on a connection of its own, established outside
+    /// the pool, and stream the notifications sent to it.
+    ///
+    /// `channel` is quoted, so it is case sensitive: `NOTIFY events` reaches
+    /// `listen("events")` but not `listen("Events")`. The connection is
+    /// polled for notifications every 50ms on a dedicated thread.
+    ///
+    /// Needs the database URL, see `DatabaseBuilder::database_url`.
+    pub async fn listen(&self, channel: &str) -> Result<Notifications, AsyncError<DieselError>> {
+        let url = self.database_url()?;
+        let listen = format!("LISTEN {}", libpq::quote_identifier(channel));
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        thread::Builder::new()
+            .name("db-listen".to_string())
+            .spawn(move || {
+                let conn = match RawConnection::establish(&url) {
+                    Ok(conn) => conn,
+                    Err(err) => return drop(ready_tx.send(Err(AsyncError::Connect(err)))),
+                };
+                if let Err(err) = conn.execute(&listen) {
+                    return drop(ready_tx.send(Err(AsyncError::Error(err))));
+                }
+                let _ = ready_tx.send(Ok(()));
+                forward(&conn, &tx);
+            })
+            .map_err(|_| AsyncError::Canceled)?;
+
+        ready_rx.await.map_err(|_| AsyncError::Canceled)??;
+        Ok(Notifications { rx })
+    }
+}
+
+// Hands notifications over until the stream is dropped or the connection is lost
+fn forward(conn: &RawConnection, tx: &mpsc::Sender<Notification>) {
+    while !tx.is_closed() {
+        let notifications = match conn.notifications() {
+            Ok(notifications) => notifications,
+            Err(err) => {
+                log::warn!("listening connection lost: {}", err);
+                return;
+            }
+        };
+        for notification in notifications {
+            if tx.blocking_send(notification).is_err() {
+                return;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Stream for Notifications {
+    type Item = Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Notification>> {
+        self.rx.poll_recv(cx)
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{AsyncSimpleConnection, Database, ErrorCounts, WaitHistogram};
+use diesel::Connection;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+
+/// Runs a synthetic query through a database from many callers at once, to
+/// size `PoolConfig::max_size` and the blocking limits by measurement.
+///
+/// `concurrency` callers each run the statement one call after another until
+/// `duration` has passed or `queries` calls were made in total. Run it
+/// against a staging server: the load is real.
+#[derive(Clone, Debug)]
+pub struct LoadTest {
+    concurrency: usize,
+    duration: Duration,
+    queries: Option<u64>,
+    statement: String,
+}
+
+/// What a `LoadTest` measured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Calls made, failed ones included
+    pub queries: u64,
+    /// Calls that failed
+    pub errors: u64,
+    /// The failed calls by category
+    pub errors_by_category: ErrorCounts,
+    /// From the first call to the last one finishing
+    pub elapsed: Duration,
+    /// Call latency percentiles, checkout wait included
+    pub latency_p50: Duration,
+    pub latency_p90: Duration,
+    pub latency_p99: Duration,
+    /// Time from each call being made to it holding a connection
+    pub checkout_wait: WaitHistogram,
+}
+
+impl LoadTest {
+    /// 16 callers running `SELECT 1` for 10 seconds.
+    pub fn new() -> LoadTest {
+        LoadTest {
+            concurrency: 16,
+            duration: Duration::from_secs(10),
+            queries: None,
+            statement: "SELECT 1".to_string(),
+        }
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> LoadTest {
+        assert!(concurrency > 0, "concurrency must be positive");
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> LoadTest {
+        self.duration = duration;
+        self
+    }
+
+    /// Stop after `queries` calls in total, if `duration` hasn't passed first.
+    pub fn queries(mut self, queries: u64) -> LoadTest {
+        self.queries = Some(queries);
+        self
+    }
+
+    /// The statement every call runs, such as `SELECT pg_sleep(0.005)` to
+    /// stand for the service's typical query.
+    pub fn statement<S: Into<String>>(mut self, statement: S) -> LoadTest {
+        self.statement = statement.into();
+        self
+    }
+
+    /// Run the load against `db` and report on it.
+    pub async fn run<Conn>(&self, db: &Database<Conn>) -> LoadReport
+    where
+        Conn: 'static + Connection,
+    {
+        let before = db.stats();
+        let statement: Arc<str> = self.statement.as_str().into();
+        let remaining = Arc::new(AtomicU64::new(self.queries.unwrap_or(u64::MAX)));
+        let start = Instant::now();
+        let deadline = start + self.duration;
+
+        let mut callers = JoinSet::new();
+        for _ in 0..self.concurrency {
+            let (db, statement, remaining) = (db.clone(), statement.clone(), remaining.clone());
+            callers.spawn(async move {
+                let mut latencies = Vec::new();
+                let mut errors = 0;
+                while Instant::now() < deadline && take_one(&remaining) {
+                    let called = Instant::now();
+                    if db.batch_execute_async(&statement).await.is_err() {
+                        errors += 1;
+                    }
+                    latencies.push(called.elapsed());
+                }
+                (latencies, errors)
+            });
+        }
+
+        let (mut latencies, mut errors) = (Vec::new(), 0);
+        while let Some(caller) = callers.join_next().await {
+            if let Ok((caller_latencies, caller_errors)) = caller {
+                latencies.extend(caller_latencies);
+                errors += caller_errors;
+            }
+        }
+        let elapsed = start.elapsed();
+        let after = db.stats();
+
+        latencies.sort_unstable();
+        LoadReport {
+            queries: latencies.len() as u64,
+            errors,
+            errors_by_category: errors_since(&after.errors, &before.errors),
+            elapsed,
+            latency_p50: percentile(&latencies, 50),
+            latency_p90: percentile(&latencies, 90),
+            latency_p99: percentile(&latencies, 99),
+            checkout_wait: waits_since(&after.acquire_wait, &before.acquire_wait),
+        }
+    }
+}
+
+impl Default for LoadTest {
+    fn default() -> Self {
+        LoadTest::new()
+    }
+}
+
+impl LoadReport {
+    /// Calls per second.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.queries as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The share of calls that failed, from 0 to 1.
+    pub fn error_rate(&self) -> f64 {
+        if self.queries == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.queries as f64
+    }
+
+    /// The mean time calls waited for a connection.
+    pub fn mean_checkout_wait(&self) -> Duration {
+        match self.checkout_wait.count {
+            0 => Duration::ZERO,
+            count => self.checkout_wait.sum / count.min(u32::MAX as u64) as u32,
+        }
+    }
+}
+
+// Claims one of the calls left to make
+fn take_one(remaining: &AtomicU64) -> bool {
+    remaining
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+// The `p`th percentile of the sorted `latencies`
+fn percentile(latencies: &[Duration], p: usize) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    latencies[(latencies.len() - 1) * p / 100]
+}
+
+// The database's counts are cumulative, so the run's are the difference
+fn errors_since(after: &ErrorCounts, before: &ErrorCounts) -> ErrorCounts {
+    ErrorCounts {
+        checkout: after.checkout - before.checkout,
+        canceled: after.canceled - before.canceled,
+        timeout: after.timeout - before.timeout,
+        serialization: after.serialization - before.serialization,
+        constraint: after.constraint - before.constraint,
+        other: after.other - before.other,
+    }
+}
+
+fn waits_since(after: &WaitHistogram, before: &WaitHistogram) -> WaitHistogram {
+    WaitHistogram {
+        buckets: after
+            .buckets
+            .iter()
+            .zip(&before.buckets)
+            .map(|(&(bound, after), &(_, before))| (bound, after - before))
+            .collect(),
+        count: after.count - before.count,
+        sum: after.sum - before.sum,
+    }
+}
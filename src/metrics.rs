@@ -0,0 +1,59 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Database work done while a future ran, collected by
+/// `QueryMetrics::collect`.
+///
+/// Counts every blocking job a `Database`, or a transaction or guard from
+/// one, runs for the future: queries, but also `BEGIN` and `COMMIT`. Many
+/// queries for one request usually point at an N+1 pattern.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryMetrics {
+    pub queries: u32,
+    /// Time spent running them, connection checkouts included but not waits
+    /// for a blocking thread
+    pub total: Duration,
+    pub slowest: Duration,
+}
+
+type Collector = Arc<Mutex<QueryMetrics>>;
+
+tokio::task_local! {
+    static COLLECTOR: Collector;
+}
+
+impl QueryMetrics {
+    /// Run `fut`, collecting the database work it does on the current task.
+    pub async fn collect<F: Future>(fut: F) -> (F::Output, QueryMetrics) {
+        let collector = Collector::default();
+        let output = COLLECTOR.scope(collector.clone(), fut).await;
+        let metrics = *collector.lock().unwrap();
+        (output, metrics)
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        self.queries += 1;
+        self.total += elapsed;
+        self.slowest = self.slowest.max(elapsed);
+    }
+}
+
+// Wraps `job` to record its run time for the enclosing `QueryMetrics::collect`, if any;
+// called on the task, so the job can report from its blocking thread
+pub(crate) fn measured<F, R>(job: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let collector = COLLECTOR.try_with(Clone::clone).ok();
+    move || {
+        let start = Instant::now();
+        let result = job();
+        if let Some(collector) = collector {
+            collector.lock().unwrap().record(start.elapsed());
+        }
+        result
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{AsyncError, AsyncPool, CheckoutError};
+use async_trait::async_trait;
+use diesel::{r2d2::Error, result::ConnectionError, Connection};
+use std::{fmt, marker::PhantomData};
+use tokio::task;
+
+/// A mobc connection manager for diesel connections.
+///
+/// Connections are established and checked on blocking threads, so
+/// `mobc::Pool<MobcConnectionManager<Conn>>` never blocks the async runtime.
+pub struct MobcConnectionManager<Conn> {
+    database_url: String,
+    _marker: PhantomData<fn() -> Conn>,
+}
+
+impl<Conn> MobcConnectionManager<Conn> {
+    pub fn new<S: Into<String>>(database_url: S) -> MobcConnectionManager<Conn> {
+        MobcConnectionManager {
+            database_url: database_url.into(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for MobcConnectionManager<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MobcConnectionManager").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> mobc::Manager for MobcConnectionManager<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    type Connection = Conn;
+    type Error = Error;
+
+    async fn connect(&self) -> Result<Conn, Error> {
+        let database_url = self.database_url.clone();
+        task::spawn_blocking(move || Conn::establish(&database_url))
+            .await
+            .map_err(|_| {
+                Error::ConnectionError(ConnectionError::BadConnection(
+                    "connecting was cancelled".to_string(),
+                ))
+            })?
+            .map_err(Error::ConnectionError)
+    }
+
+    // mobc hands over the connection itself, so it can be moved to a blocking thread
+    async fn check(&self, conn: Conn) -> Result<Conn, Error> {
+        task::spawn_blocking(move || conn.execute("SELECT 1").map(|_| conn))
+            .await
+            .map_err(|_| {
+                Error::ConnectionError(ConnectionError::BadConnection(
+                    "validation was cancelled".to_string(),
+                ))
+            })?
+            .map_err(Error::QueryError)
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncPool for mobc::Pool<MobcConnectionManager<Conn>>
+where
+    Conn: 'static + Connection + Send,
+{
+    type Connection = Conn;
+
+    #[inline]
+    async fn with_connection<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, AsyncError<E>> + Send,
+    {
+        let conn = self
+            .get()
+            .await
+            .map_err(|e| AsyncError::Checkout(CheckoutError::new(e)))?;
+        task::spawn_blocking(move || f(&*conn))
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+    }
+}
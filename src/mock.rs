@@ -0,0 +1,201 @@
+use crate::{trace, AsyncConnection, AsyncError, AsyncSimpleConnection};
+use async_trait::async_trait;
+use diesel::{result::Error as DieselError, Connection};
+use std::{
+    any::{self, Any, TypeId},
+    collections::VecDeque,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+/// A stand-in for a database in unit tests, which runs nothing: each call
+/// records a `MockCall` and gets the next response queued with `returns` or
+/// `fails`.
+///
+/// Responses are taken in order by every `run`, `transaction`,
+/// `batch_execute_async` and `*_async` call, whose closures are dropped
+/// without being called; a `()` result is made up when none is queued.
+/// Taking a response of another type than the call's panics, as does a call
+/// finding no response for another type. Clones share the queue and the
+/// calls.
+pub struct MockDatabase<Conn> {
+    state: Arc<Mutex<MockState>>,
+    _conn: PhantomData<fn() -> Conn>,
+}
+
+/// A call made to a `MockDatabase`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockCall {
+    /// `run`, `transaction` or `batch_execute`
+    pub operation: &'static str,
+    /// The query given to `batch_execute_async`, or else the statement given
+    /// with `with_sql`
+    pub sql: Option<String>,
+    /// The tag given with `with_tag`
+    pub tag: Option<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    responses: VecDeque<Response>,
+    calls: Vec<MockCall>,
+}
+
+enum Response {
+    Value(Box<dyn Any + Send>, &'static str),
+    Error(AsyncError<DieselError>),
+}
+
+impl<Conn> MockDatabase<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub fn new() -> MockDatabase<Conn> {
+        MockDatabase {
+            state: Arc::default(),
+            _conn: PhantomData,
+        }
+    }
+
+    /// Queue `value` as the result of a call, such as the rows affected
+    /// (`usize`) for `execute_async` or a `Vec` of records for `load_async`.
+    pub fn returns<R>(&self, value: R) -> &MockDatabase<Conn>
+    where
+        R: 'static + Send,
+    {
+        self.push(Response::Value(Box::new(value), any::type_name::<R>()))
+    }
+
+    /// Queue `err` as the error of a call, e.g. `DieselError::NotFound`.
+    pub fn fails(&self, err: DieselError) -> &MockDatabase<Conn> {
+        self.fails_with(AsyncError::Error(err))
+    }
+
+    /// Like `fails`, for errors other than a query's own, such as
+    /// `AsyncError::Timeout`.
+    pub fn fails_with(&self, err: AsyncError<DieselError>) -> &MockDatabase<Conn> {
+        self.push(Response::Error(err))
+    }
+
+    /// The calls made so far, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// How many queued responses are left.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().responses.len()
+    }
+
+    fn push(&self, response: Response) -> &MockDatabase<Conn> {
+        self.state.lock().unwrap().responses.push_back(response);
+        self
+    }
+
+    // Records the call and takes its response
+    fn respond<R, E>(
+        &self,
+        operation: &'static str,
+        sql: Option<String>,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static,
+        E: From<DieselError> + fmt::Debug,
+    {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(MockCall {
+            operation,
+            sql: sql.or_else(|| trace::statement().map(|sql| sql.to_string())),
+            tag: trace::tag().map(|tag| tag.to_string()),
+        });
+
+        let response = match state.responses.pop_front() {
+            Some(response) => response,
+            None if TypeId::of::<R>() == TypeId::of::<()>() => {
+                Response::Value(Box::new(()), any::type_name::<()>())
+            }
+            None => panic!(
+                "MockDatabase: no response queued for a {} call returning {}",
+                operation,
+                any::type_name::<R>()
+            ),
+        };
+        // Unlock before panicking, so the mock stays usable
+        drop(state);
+        match response {
+            Response::Value(value, queued) => match value.downcast() {
+                Ok(value) => Ok(*value),
+                Err(_) => panic!(
+                    "MockDatabase: a {} call returning {} found a {} queued",
+                    operation,
+                    any::type_name::<R>(),
+                    queued
+                ),
+            },
+            Response::Error(err) => Err(err.map(E::from)),
+        }
+    }
+}
+
+impl<Conn> Default for MockDatabase<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn default() -> Self {
+        MockDatabase::new()
+    }
+}
+
+impl<Conn> Clone for MockDatabase<Conn> {
+    fn clone(&self) -> Self {
+        MockDatabase {
+            state: self.state.clone(),
+            _conn: PhantomData,
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for MockDatabase<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MockDatabase")
+            .field("pending", &state.responses.len())
+            .field("calls", &state.calls)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for MockDatabase<Conn>
+where
+    Conn: 'static + Connection,
+{
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        self.respond("batch_execute", Some(query.to_string()))
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for MockDatabase<Conn>
+where
+    Conn: 'static + Connection,
+{
+    async fn run<R, E, Func>(&self, _f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.respond("run", None)
+    }
+
+    async fn transaction<R, E, Func>(&self, _f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.respond("transaction", None)
+    }
+}
@@ -0,0 +1,383 @@
+use crate::{
+    audit,
+    limiter::Priority,
+    mysqlclient::RawConnection,
+    panic_message, retry, statement_timeout,
+    stream::{self, INPUT_BUFFER},
+    temp_database, trace, truncate, AsyncConnection, AsyncError, Database, DatabaseBuilder,
+    DatabaseErrorClass, RetryPolicy, StatementTimeout, TempDatabaseBackend, TruncateTables,
+};
+use async_trait::async_trait;
+use diesel::{
+    connection::SimpleConnection,
+    dsl::sql,
+    mysql::MysqlConnection,
+    query_dsl::methods::ExecuteDsl,
+    result::{Error as DieselError, QueryResult},
+    sql_types::{BigInt, Text, Unsigned},
+    Connection, RunQueryDsl,
+};
+use futures::future;
+use std::{
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{io::AsyncRead, sync::mpsc};
+
+/// Inserts returning the `AUTO_INCREMENT` id they generated, for MySQL,
+/// which has no `RETURNING`.
+#[async_trait]
+pub trait AsyncInsertIdDsl<AsyncConn> {
+    /// Execute the insert, then read `LAST_INSERT_ID()` in the same blocking
+    /// call, so both run on the same connection; as separate calls through a
+    /// pool they may not.
+    ///
+    /// For a multi-row insert this is the id of the first row.
+    async fn insert_and_get_id_async(self, asc: &AsyncConn) -> Result<u64, AsyncError<DieselError>>
+    where
+        Self: ExecuteDsl<MysqlConnection>;
+}
+
+#[async_trait]
+impl<T, AsyncConn> AsyncInsertIdDsl<AsyncConn> for T
+where
+    T: 'static + Send + RunQueryDsl<MysqlConnection>,
+    AsyncConn: Send + Sync + AsyncConnection<MysqlConnection>,
+{
+    async fn insert_and_get_id_async(self, asc: &AsyncConn) -> Result<u64, AsyncError<DieselError>>
+    where
+        Self: ExecuteDsl<MysqlConnection>,
+    {
+        let insert = trace::instrument(
+            "execute",
+            |&(rows, _)| Some(rows),
+            asc.run(|conn| {
+                let rows = self.execute(conn)?;
+                let id = diesel::select(sql::<Unsigned<BigInt>>("LAST_INSERT_ID()"))
+                    .get_result::<u64>(conn)?;
+                Ok((rows, id))
+            }),
+        );
+        let (_, id) = audit::audited("execute", |&(rows, _)| Some(rows), insert).await?;
+        Ok(id)
+    }
+}
+
+/// Session variables set on every connection a MySQL database checks out,
+/// with one `SET SESSION` statement run by an `on_acquire` hook.
+///
+/// Added with `DatabaseBuilder::session_variables`. Setting a variable twice
+/// keeps the last value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MysqlSessionConfig {
+    variables: BTreeMap<String, SessionValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SessionValue {
+    Text(String),
+    Number(i64),
+}
+
+impl MysqlSessionConfig {
+    pub fn new() -> MysqlSessionConfig {
+        MysqlSessionConfig::default()
+    }
+
+    /// Set the variable `name` to the string `value`.
+    ///
+    /// Panics if `name` is not made of ASCII letters, digits and underscores.
+    pub fn set<N: Into<String>, V: Into<String>>(self, name: N, value: V) -> MysqlSessionConfig {
+        self.variable(name.into(), SessionValue::Text(value.into()))
+    }
+
+    /// Set the variable `name` to the number `value`, for variables such as
+    /// `max_execution_time` that don't take strings.
+    ///
+    /// Panics if `name` is not made of ASCII letters, digits and underscores.
+    pub fn set_number<N: Into<String>>(self, name: N, value: i64) -> MysqlSessionConfig {
+        self.variable(name.into(), SessionValue::Number(value))
+    }
+
+    /// `sql_mode`, e.g. `TRADITIONAL` or `STRICT_ALL_TABLES,NO_ZERO_DATE`.
+    pub fn sql_mode<S: Into<String>>(self, sql_mode: S) -> MysqlSessionConfig {
+        self.set("sql_mode", sql_mode)
+    }
+
+    /// `time_zone`, e.g. `+00:00` or, with the time zone tables loaded, `UTC`.
+    pub fn time_zone<S: Into<String>>(self, time_zone: S) -> MysqlSessionConfig {
+        self.set("time_zone", time_zone)
+    }
+
+    /// `wait_timeout`, how long the server keeps the connection open while it
+    /// is idle, in whole seconds.
+    pub fn wait_timeout(self, timeout: Duration) -> MysqlSessionConfig {
+        let seconds = timeout.as_secs().clamp(1, i64::MAX as u64);
+        self.set_number("wait_timeout", seconds as i64)
+    }
+
+    fn variable(mut self, name: String, value: SessionValue) -> MysqlSessionConfig {
+        assert!(
+            !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'),
+            "invalid session variable name: {:?}",
+            name
+        );
+        self.variables.insert(name, value);
+        self
+    }
+
+    // The `SET SESSION` statement applying the variables, `None` if there are none
+    fn statement(&self) -> Option<String> {
+        if self.variables.is_empty() {
+            return None;
+        }
+        let assignments = self
+            .variables
+            .iter()
+            .map(|(name, value)| match *value {
+                SessionValue::Text(ref text) => format!(
+                    "SESSION {} = '{}'",
+                    name,
+                    text.replace('\\', "\\\\").replace('\'', "''")
+                ),
+                SessionValue::Number(number) => format!("SESSION {} = {}", name, number),
+            })
+            .collect::<Vec<_>>();
+        Some(format!("SET {}", assignments.join(", ")))
+    }
+}
+
+impl DatabaseBuilder<MysqlConnection> {
+    /// Apply `config` to every connection the database checks out, before it
+    /// is used; see `MysqlSessionConfig`.
+    ///
+    /// Costs a round trip per checkout. A failing `SET` fails the checkout
+    /// with `AsyncError::Checkout`.
+    pub fn session_variables(self, config: MysqlSessionConfig) -> DatabaseBuilder<MysqlConnection> {
+        match config.statement() {
+            Some(statement) => self.on_acquire(move |conn| conn.batch_execute(&statement)),
+            None => self,
+        }
+    }
+}
+
+impl StatementTimeout for MysqlConnection {
+    // `max_execution_time` only limits read only `SELECT` statements; it is a session
+    // setting, so the previous value is put back whatever `f` returns
+    fn with_statement_timeout<R, E, F>(&self, timeout: Duration, f: F) -> Result<R, E>
+    where
+        E: From<DieselError>,
+        F: FnOnce(&Self) -> Result<R, E>,
+    {
+        let previous = diesel::select(sql::<Unsigned<BigInt>>("@@SESSION.max_execution_time"))
+            .get_result::<u64>(self)?;
+        self.batch_execute(&format!(
+            "SET SESSION max_execution_time = {}",
+            statement_timeout::millis(timeout)
+        ))?;
+
+        let result = f(self);
+        self.batch_execute(&format!("SET SESSION max_execution_time = {}", previous))?;
+        result
+    }
+}
+
+impl TruncateTables for MysqlConnection {
+    // `TRUNCATE` refuses tables that foreign keys refer to unless the checks are off;
+    // it also commits, so the tables are emptied one by one rather than in a transaction
+    fn truncate_all(&self, except: &[&str]) -> QueryResult<Vec<String>> {
+        let tables = sql::<Text>(
+            "SELECT TABLE_NAME FROM information_schema.TABLES \
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_TYPE = 'BASE TABLE' \
+             ORDER BY TABLE_NAME",
+        )
+        .load::<String>(self)?;
+        let schema = diesel::select(sql::<Text>("DATABASE()")).get_result::<String>(self)?;
+        let tables: Vec<String> = tables
+            .into_iter()
+            .filter(|table| !truncate::is_kept(except, &schema, table))
+            .collect();
+
+        let checks = diesel::select(sql::<Unsigned<BigInt>>("@@SESSION.foreign_key_checks"))
+            .get_result::<u64>(self)?;
+        self.batch_execute("SET SESSION foreign_key_checks = 0")?;
+        let result = tables.iter().try_for_each(|table| {
+            self.batch_execute(&format!("TRUNCATE TABLE {}", quote_identifier(table)))
+        });
+        self.batch_execute(&format!("SET SESSION foreign_key_checks = {}", checks))?;
+        result.map(|_| tables)
+    }
+}
+
+impl TempDatabaseBackend for MysqlConnection {
+    fn create_database(base_url: &str, name: &str) -> Result<String, AsyncError<DieselError>> {
+        let conn = MysqlConnection::establish(base_url).map_err(AsyncError::Connect)?;
+        conn.batch_execute(&format!("CREATE DATABASE {}", quote_identifier(name)))
+            .map_err(AsyncError::Error)?;
+        Ok(temp_database::with_database(base_url, name))
+    }
+
+    // Unlike Postgres, MySQL drops a database its connections are still using
+    fn drop_database(base_url: &str, name: &str) -> Result<(), AsyncError<DieselError>> {
+        let conn = MysqlConnection::establish(base_url).map_err(AsyncError::Connect)?;
+        conn.batch_execute(&format!(
+            "DROP DATABASE IF EXISTS {}",
+            quote_identifier(name)
+        ))
+        .map_err(AsyncError::Error)
+    }
+}
+
+#[cfg(feature = "testcontainers")]
+impl crate::ContainerBackend for MysqlConnection {
+    const IMAGE: &'static str = "mysql:8";
+    const PORT: u16 = 3306;
+
+    fn environment() -> Vec<(String, String)> {
+        vec![
+            ("MYSQL_ROOT_PASSWORD".to_string(), "mysql".to_string()),
+            ("MYSQL_DATABASE".to_string(), "test".to_string()),
+        ]
+    }
+
+    fn url(host: &str, port: u16) -> String {
+        format!("mysql://root:mysql@{}:{}/test", host, port)
+    }
+}
+
+impl Database<MysqlConnection> {
+    /// Run `f` in a transaction, running it again in a new one as `policy`
+    /// allows whenever InnoDB reports a deadlock (error 1213) or a lock wait
+    /// timeout (1205), which are to be expected under contention.
+    ///
+    /// Every attempt is rolled back in full before the next, so `f` must not
+    /// have effects outside the database it cannot repeat.
+    pub async fn transaction_retrying<R, Func>(
+        &self,
+        policy: RetryPolicy,
+        f: Func,
+    ) -> Result<R, AsyncError<DieselError>>
+    where
+        R: 'static + Send,
+        Func: 'static + FnMut(&MysqlConnection) -> QueryResult<R> + Send,
+    {
+        let f = Arc::new(Mutex::new(f));
+        retry::retrying(&policy, is_lock_conflict, || {
+            let f = f.clone();
+            self.transaction(move |conn| (f.lock().unwrap())(conn))
+        })
+        .await
+    }
+}
+
+impl Database<MysqlConnection> {
+    /// Run `sql`, a `LOAD DATA LOCAL INFILE` statement, with `source` as the
+    /// file, returning the number of rows loaded. The file name in `sql` is
+    /// ignored.
+    ///
+    /// `source` is read on the current task and handed to a blocking thread
+    /// through a bounded buffer, so a large file never sits in memory at
+    /// once. A read error aborts the load, which fails with the error's
+    /// message. Runs on a connection of its own, established outside the pool
+    /// and closed afterwards; needs the database URL, see
+    /// `DatabaseBuilder::database_url`, and `local_infile` enabled on the
+    /// server.
+    pub async fn load_data_async<R>(
+        &self,
+        sql: &str,
+        source: R,
+    ) -> Result<u64, AsyncError<DieselError>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (tx, mut rx) = mpsc::channel(INPUT_BUFFER);
+        let load = self.load_data(sql, std::iter::from_fn(move || rx.blocking_recv()));
+        let (_, loaded) = future::join(stream::feed_input("LOAD DATA", source, tx), load).await;
+        loaded
+    }
+
+    /// Like `load_data_async`, with the file made of the chunks `data`
+    /// yields, such as one CSV line per item, produced on the blocking thread.
+    ///
+    /// If `data` panics, the load is aborted and the call fails with
+    /// `AsyncError::Panicked`.
+    pub async fn load_data_iter_async<I>(
+        &self,
+        sql: &str,
+        data: I,
+    ) -> Result<u64, AsyncError<DieselError>>
+    where
+        I: IntoIterator,
+        I::IntoIter: 'static + Send,
+        I::Item: Into<Vec<u8>>,
+    {
+        self.load_data(sql, data.into_iter().map(|chunk| Ok(chunk.into())))
+            .await
+    }
+
+    /// Like `batch_execute_async`, but reports how each statement in `sql`
+    /// went: the rows it affected (or returned), or the error it failed with.
+    ///
+    /// MySQL stops at the first statement that fails, so an error is always
+    /// the last item, and statements after it were not run.
+    ///
+    /// diesel doesn't expose its connections' libmysqlclient handles, so this
+    /// runs on a connection of its own, like `load_data_async`: established
+    /// from the database URL outside the pool and closed afterwards. It is
+    /// not part of any transaction or `AsyncConnectionGuard` session of the
+    /// caller, doesn't see their uncommitted changes, and the pool's
+    /// connection customizers and hooks, such as `MysqlSessionConfig`, are
+    /// not applied to it. Needs the database URL, see
+    /// `DatabaseBuilder::database_url`.
+    pub async fn batch_execute_results_async(
+        &self,
+        sql: &str,
+    ) -> Result<Vec<QueryResult<u64>>, AsyncError<DieselError>> {
+        let sql = sql.to_string();
+        self.run_raw(move |conn| Ok(conn.execute_batch(&sql))).await
+    }
+
+    async fn load_data<I>(&self, sql: &str, data: I) -> Result<u64, AsyncError<DieselError>>
+    where
+        I: 'static + Send + Iterator<Item = Result<Vec<u8>, String>>,
+    {
+        let sql = sql.to_string();
+        self.run_raw(move |conn| conn.load_data(&sql, data)).await
+    }
+
+    // Runs `f` on a connection established for it outside the pool, with none of the
+    // pool's session setup
+    async fn run_raw<R, Func>(&self, f: Func) -> Result<R, AsyncError<DieselError>>
+    where
+        R: 'static + Send,
+        Func: 'static + FnOnce(&RawConnection) -> QueryResult<R> + Send,
+    {
+        let in_flight = self.admit()?;
+        let url = self.database_url()?;
+
+        self.spawn_job(Priority::Normal, move || {
+            let _in_flight = in_flight;
+            let conn = RawConnection::establish(&url).map_err(AsyncError::Connect)?;
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&conn))) {
+                Ok(result) => result.map_err(AsyncError::Error),
+                Err(payload) => Err(AsyncError::Panicked(panic_message(payload))),
+            }
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+}
+
+fn is_lock_conflict(err: &AsyncError<DieselError>) -> bool {
+    matches!(
+        err.class(),
+        DatabaseErrorClass::Deadlock | DatabaseErrorClass::LockTimeout
+    )
+}
+
+// Quotes `name` as an SQL identifier
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
@@ -0,0 +1,148 @@
+use crate::{classify, trace, AsyncError, Database};
+use diesel::Connection;
+use opentelemetry::{
+    global,
+    trace::{Span, SpanKind, Status, Tracer},
+    Context, KeyValue,
+};
+use std::{
+    borrow::Cow,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// How a `Database` reports its calls as OpenTelemetry spans, with the
+/// `otel` feature.
+///
+/// Every `run`, `transaction` and `*_async` call becomes a client span of the
+/// current context, following the database semantic conventions:
+/// `db.system`, `db.statement` and `db.operation` when the statement is
+/// known, and the pool's name, size and connections in use.
+#[derive(Clone, Debug, Default)]
+pub struct OtelConfig {
+    pool_name: Option<Cow<'static, str>>,
+    raw_statements: bool,
+}
+
+impl OtelConfig {
+    pub fn new() -> OtelConfig {
+        OtelConfig::default()
+    }
+
+    /// Reported as `db.client.connection.pool.name`.
+    pub fn pool_name<S: Into<Cow<'static, str>>>(mut self, name: S) -> OtelConfig {
+        self.pool_name = Some(name.into());
+        self
+    }
+
+    /// Report statements as they are, instead of with their literals and
+    /// bind values replaced by `?` (default false).
+    pub fn raw_statements(mut self, raw: bool) -> OtelConfig {
+        self.raw_statements = raw;
+        self
+    }
+}
+
+// Records a finished call as a span that started `elapsed` ago
+pub(crate) fn record_span<Conn, R, E>(
+    db: &Database<Conn>,
+    config: &OtelConfig,
+    elapsed: Duration,
+    tag: Option<&str>,
+    statement: Option<&str>,
+    result: &Result<R, AsyncError<E>>,
+) where
+    Conn: 'static + Connection,
+    E: 'static + fmt::Debug,
+{
+    let system = db_system::<Conn>();
+    let mut attributes = vec![KeyValue::new("db.system", system)];
+
+    let operation = statement.and_then(|sql| sql.split_whitespace().next());
+    if let Some(sql) = statement {
+        let sql = if config.raw_statements {
+            sql.to_string()
+        } else {
+            sanitize(sql)
+        };
+        attributes.push(KeyValue::new("db.statement", sql));
+    }
+    if let Some(operation) = operation {
+        attributes.push(KeyValue::new("db.operation", operation.to_uppercase()));
+    }
+    if let Some(ref name) = config.pool_name {
+        attributes.push(KeyValue::new(
+            "db.client.connection.pool.name",
+            name.to_string(),
+        ));
+    }
+    if let Some(pool) = db.pool() {
+        let state = pool.state();
+        attributes.push(KeyValue::new(
+            "db.client.connection.max",
+            i64::from(pool.max_size()),
+        ));
+        attributes.push(KeyValue::new(
+            "db.client.connection.count.used",
+            i64::from(state.connections - state.idle_connections),
+        ));
+    }
+
+    let end = SystemTime::now();
+    let name = tag
+        .map(str::to_string)
+        .or_else(|| operation.map(str::to_uppercase))
+        .unwrap_or_else(|| system.to_string());
+    let tracer = global::tracer("actix-threadpool-diesel");
+    let mut span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Client)
+        .with_start_time(end - elapsed)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &Context::current());
+
+    if let Err(ref err) = *result {
+        let class = classify::class_of(err).as_str();
+        span.set_attribute(KeyValue::new("error.type", class));
+        span.set_status(Status::error(class));
+    }
+    span.end_with_timestamp(end);
+}
+
+fn db_system<Conn: Connection>() -> &'static str {
+    match std::any::type_name::<Conn::Backend>().rsplit("::").next() {
+        Some("Pg") => "postgresql",
+        Some("Mysql") => "mysql",
+        Some("Sqlite") => "sqlite",
+        _ => "other_sql",
+    }
+}
+
+// Replaces string and number literals with `?` and drops the bind values that
+// `debug_query` appends, keeping the statement's shape
+fn sanitize(sql: &str) -> String {
+    let sql = trace::redact_binds(sql);
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut in_word = false;
+
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            // A doubled quote is an escaped quote inside the literal
+            while let Some(c) = chars.next() {
+                if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                    break;
+                }
+            }
+            out.push('?');
+            in_word = false;
+        } else if c.is_ascii_digit() && !in_word {
+            while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+            out.push('?');
+        } else {
+            in_word = c.is_alphanumeric() || c == '_' || c == '$' || c == '"';
+            out.push(c);
+        }
+    }
+    out
+}
@@ -0,0 +1,147 @@
+use crate::{AsyncConnection, AsyncError};
+use diesel::{
+    backend::Backend,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    query_dsl::{methods::LoadQuery, RunQueryDsl},
+    result::{Error as DieselError, QueryResult},
+    serialize::ToSql,
+    sql_types::{BigInt, HasSqlType},
+    Connection,
+};
+
+/// Adds `paginate` to queries.
+pub trait Paginate: Sized {
+    /// Restrict the query to page `page` (counting from 1) of `per_page` rows.
+    fn paginate(self, page: i64, per_page: i64) -> Paginated<Self>;
+}
+
+/// A query restricted to one page, created by `Paginate::paginate`.
+#[derive(Debug, Clone, Copy)]
+pub struct Paginated<T> {
+    query: T,
+    page: i64,
+    per_page: i64,
+}
+
+/// One page of results together with the size of the whole result set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of rows across all pages
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+// `SELECT COUNT(*)` over the unpaginated query; public only because it appears
+// in `load_page_async`'s bounds
+#[derive(Debug, Clone, Copy)]
+pub struct CountQuery<T>(T);
+
+impl<T: Query> Paginate for T {
+    fn paginate(self, page: i64, per_page: i64) -> Paginated<Self> {
+        assert!(page > 0, "pages are numbered from 1");
+        assert!(per_page > 0, "per_page must be positive");
+        Paginated {
+            query: self,
+            page,
+            per_page,
+        }
+    }
+}
+
+impl<T> Paginated<T> {
+    /// Load the page and count the total number of rows, both on the same
+    /// checked out connection.
+    pub async fn load_page_async<U, Conn, AsyncConn>(
+        self,
+        asc: &AsyncConn,
+    ) -> Result<Page<U>, AsyncError<DieselError>>
+    where
+        U: 'static + Send,
+        T: 'static + Clone + Send,
+        Conn: 'static + Connection,
+        AsyncConn: AsyncConnection<Conn>,
+        Self: LoadQuery<Conn, U>,
+        CountQuery<T>: LoadQuery<Conn, i64>,
+    {
+        let (page, per_page) = (self.page, self.per_page);
+        asc.run(move |conn| {
+            let total = CountQuery(self.query.clone()).get_result(conn)?;
+            let items = self.load(conn)?;
+            Ok(Page {
+                items,
+                total,
+                page,
+                per_page,
+            })
+        })
+        .await
+    }
+
+    fn offset(&self) -> i64 {
+        (self.page - 1) * self.per_page
+    }
+}
+
+impl<T> Page<T> {
+    pub fn total_pages(&self) -> i64 {
+        (self.total + self.per_page - 1) / self.per_page
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.page < self.total_pages()
+    }
+}
+
+impl<T: Query> Query for Paginated<T> {
+    type SqlType = T::SqlType;
+}
+
+impl<T> QueryId for Paginated<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, Conn> RunQueryDsl<Conn> for Paginated<T> {}
+
+impl<T, DB> QueryFragment<DB> for Paginated<T>
+where
+    DB: Backend + HasSqlType<BigInt>,
+    T: QueryFragment<DB>,
+    i64: ToSql<BigInt, DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        out.push_sql("SELECT * FROM (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(") AS paginated LIMIT ");
+        out.push_bind_param::<BigInt, _>(&self.per_page)?;
+        out.push_sql(" OFFSET ");
+        out.push_bind_param::<BigInt, _>(&self.offset())?;
+        Ok(())
+    }
+}
+
+impl<T> Query for CountQuery<T> {
+    type SqlType = BigInt;
+}
+
+impl<T> QueryId for CountQuery<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, Conn> RunQueryDsl<Conn> for CountQuery<T> {}
+
+impl<T, DB> QueryFragment<DB> for CountQuery<T>
+where
+    DB: Backend,
+    T: QueryFragment<DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        out.push_sql("SELECT COUNT(*) FROM (");
+        self.0.walk_ast(out.reborrow())?;
+        out.push_sql(") AS counted");
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{
+    checkout::Checkout, libpq::quote_identifier, limiter::Priority, statement_timeout,
+    temp_database, trace, truncate, AsyncConnection, AsyncError, AsyncTransaction, Database,
+    DatabaseBuilder, SessionLabel, StatementTimeout, TempDatabaseBackend, TruncateTables,
+};
+use diesel::{
+    connection::{SimpleConnection, TransactionManager},
+    debug_query,
+    dsl::sql,
+    pg::{Pg, PgConnection, TransactionBuilder},
+    r2d2::{CustomizeConnection, Error as R2D2Error},
+    result::{DatabaseErrorKind, Error as DieselError, QueryResult},
+    sql_types::{Bool, Integer, Text},
+    Connection, RunQueryDsl,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Where `ApplicationName` also keeps the name it sets, for `clear_label` to go back to
+const APPLICATION_NAME: &str = "actix_threadpool_diesel.application_name";
+
+/// An r2d2 connection customizer setting `application_name` on every
+/// connection the pool opens, so the service's load can be told apart in
+/// server-side views like `pg_stat_activity`.
+///
+/// Add it with `DatabaseBuilder::application_name`, or to a pool of your own
+/// with r2d2's `Builder::connection_customizer`. A `with_connection_label`
+/// label replaces the name until the connection goes back to the pool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApplicationName {
+    name: String,
+}
+
+/// Transaction isolation levels accepted by `AsyncTransactionBuilder::isolation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Configures the isolation level and access mode of a transaction, mirroring
+/// diesel's `TransactionBuilder`. Created by `Database::transaction_builder`.
+pub struct AsyncTransactionBuilder<'a> {
+    db: &'a Database<PgConnection>,
+    options: TransactionOptions,
+}
+
+// Plain data so it can be moved onto the blocking thread and applied there
+#[derive(Clone, Copy, Default)]
+struct TransactionOptions {
+    isolation: Option<IsolationLevel>,
+    read_only: Option<bool>,
+    deferrable: Option<bool>,
+    defer_constraints: bool,
+}
+
+// Progress of a cancellable query, shared between the caller and the blocking thread
+enum QueryState {
+    Pending,
+    Running(i32),
+    Finished,
+    // Dropped before it started, so it must not start
+    Cancelled,
+}
+
+// Cancels the backend's current statement if dropped before being disarmed
+struct CancelOnDrop {
+    db: Database<PgConnection>,
+    state: Arc<Mutex<QueryState>>,
+    armed: bool,
+}
+
+impl ApplicationName {
+    pub fn new<S: Into<String>>(name: S) -> ApplicationName {
+        ApplicationName { name: name.into() }
+    }
+
+    /// `service version`, e.g. from `env!("CARGO_PKG_NAME")` and
+    /// `env!("CARGO_PKG_VERSION")`.
+    pub fn service(service: &str, version: &str) -> ApplicationName {
+        ApplicationName::new(format!("{} {}", service, version))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl CustomizeConnection<PgConnection, R2D2Error> for ApplicationName {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), R2D2Error> {
+        let name = quote_literal(&self.name);
+        conn.batch_execute(&format!(
+            "SET application_name = {name}; SET {setting} = {name}",
+            name = name,
+            setting = APPLICATION_NAME
+        ))
+        .map_err(R2D2Error::QueryError)
+    }
+}
+
+impl SessionLabel for PgConnection {
+    // Postgres truncates `application_name` to 63 bytes
+    fn set_label(&self, label: &str) -> QueryResult<()> {
+        self.batch_execute(&format!("SET application_name = {}", quote_literal(label)))
+    }
+
+    // `RESET` goes back to the name the connection was opened with, not one set by
+    // `ApplicationName` afterwards
+    fn clear_label(&self) -> QueryResult<()> {
+        self.batch_execute(&format!(
+            "RESET application_name; \
+             SELECT set_config('application_name', current_setting('{setting}', true), false) \
+             WHERE current_setting('{setting}', true) IS NOT NULL",
+            setting = APPLICATION_NAME
+        ))
+    }
+}
+
+impl DatabaseBuilder<PgConnection> {
+    /// Set `application_name` to `name` on every connection the pool created
+    /// by `connect` opens; see `ApplicationName`.
+    pub fn application_name<S: Into<String>>(self, name: S) -> DatabaseBuilder<PgConnection> {
+        self.connection_customizer(ApplicationName::new(name))
+    }
+}
+
+impl StatementTimeout for PgConnection {
+    // `SET LOCAL` only lasts until the end of a transaction, so `f` runs in one, or in
+    // a savepoint when already inside a transaction
+    fn with_statement_timeout<R, E, F>(&self, timeout: Duration, f: F) -> Result<R, E>
+    where
+        E: From<DieselError>,
+        F: FnOnce(&Self) -> Result<R, E>,
+    {
+        self.transaction(|| {
+            // Releasing a savepoint keeps its settings for the rest of the transaction
+            let depth =
+                TransactionManager::<Self>::get_transaction_depth(self.transaction_manager());
+            let previous = if depth > 1 {
+                Some(
+                    diesel::select(sql::<Text>("current_setting('statement_timeout')"))
+                        .get_result::<String>(self)?,
+                )
+            } else {
+                None
+            };
+
+            self.batch_execute(&format!(
+                "SET LOCAL statement_timeout = {}",
+                statement_timeout::millis(timeout)
+            ))?;
+            let result = f(self)?;
+            if let Some(previous) = previous {
+                self.batch_execute(&format!(
+                    "SET LOCAL statement_timeout = {}",
+                    quote_literal(&previous)
+                ))?;
+            }
+            Ok(result)
+        })
+    }
+}
+
+impl TruncateTables for PgConnection {
+    // The tables of the schemas on the search path, emptied by one `TRUNCATE` so
+    // foreign keys between them are no obstacle; they are named `schema.table`
+    fn truncate_all(&self, except: &[&str]) -> QueryResult<Vec<String>> {
+        let tables = sql::<(Text, Text)>(
+            "SELECT table_schema::text, table_name::text FROM information_schema.tables \
+             WHERE table_type = 'BASE TABLE' AND table_schema = ANY (current_schemas(false)) \
+             ORDER BY table_schema, table_name",
+        )
+        .load::<(String, String)>(self)?;
+        let tables: Vec<(String, String)> = tables
+            .into_iter()
+            .filter(|(schema, table)| !truncate::is_kept(except, schema, table))
+            .collect();
+        if tables.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let quoted: Vec<String> = tables
+            .iter()
+            .map(|(schema, table)| {
+                format!("{}.{}", quote_identifier(schema), quote_identifier(table))
+            })
+            .collect();
+        self.batch_execute(&format!(
+            "TRUNCATE TABLE {} RESTART IDENTITY",
+            quoted.join(", ")
+        ))?;
+        Ok(tables
+            .into_iter()
+            .map(|(schema, table)| format!("{}.{}", schema, table))
+            .collect())
+    }
+}
+
+impl TempDatabaseBackend for PgConnection {
+    fn create_database(base_url: &str, name: &str) -> Result<String, AsyncError<DieselError>> {
+        let conn = PgConnection::establish(base_url).map_err(AsyncError::Connect)?;
+        conn.batch_execute(&format!("CREATE DATABASE {}", quote_identifier(name)))
+            .map_err(AsyncError::Error)?;
+        Ok(temp_database::with_database(base_url, name))
+    }
+
+    // `DROP DATABASE` fails while anyone is connected, so the connections are ended
+    // first, once no new ones are allowed; each statement is sent on its own, as
+    // `DROP DATABASE` can't run in the implicit transaction of several
+    fn drop_database(base_url: &str, name: &str) -> Result<(), AsyncError<DieselError>> {
+        let conn = PgConnection::establish(base_url).map_err(AsyncError::Connect)?;
+        let database = quote_identifier(name);
+        conn.batch_execute(&format!(
+            "ALTER DATABASE {} ALLOW_CONNECTIONS false",
+            database
+        ))
+        .and_then(|_| {
+            conn.batch_execute(&format!(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = {}",
+                quote_literal(name)
+            ))
+        })
+        .and_then(|_| conn.batch_execute(&format!("DROP DATABASE IF EXISTS {}", database)))
+        .map_err(AsyncError::Error)
+    }
+}
+
+#[cfg(feature = "testcontainers")]
+impl crate::ContainerBackend for PgConnection {
+    const IMAGE: &'static str = "postgres:16-alpine";
+    const PORT: u16 = 5432;
+
+    fn environment() -> Vec<(String, String)> {
+        vec![("POSTGRES_PASSWORD".to_string(), "postgres".to_string())]
+    }
+
+    fn url(host: &str, port: u16) -> String {
+        format!("postgres://postgres:postgres@{}:{}/postgres", host, port)
+    }
+}
+
+impl Database<PgConnection> {
+    pub fn transaction_builder(&self) -> AsyncTransactionBuilder<'_> {
+        AsyncTransactionBuilder {
+            db: self,
+            options: TransactionOptions::default(),
+        }
+    }
+
+    /// Run `f` in a `SERIALIZABLE` transaction, running it again (up to
+    /// `max_retries` more times) whenever Postgres reports a serialization
+    /// failure (SQLSTATE 40001).
+    pub async fn transaction_serializable<R, Func>(
+        &self,
+        max_retries: u32,
+        mut f: Func,
+    ) -> Result<R, AsyncError<DieselError>>
+    where
+        R: 'static + Send,
+        Func: 'static + FnMut(&PgConnection) -> QueryResult<R> + Send,
+    {
+        self.run(move |conn| {
+            let mut retries = 0;
+            loop {
+                match conn.build_transaction().serializable().run(|| f(conn)) {
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _))
+                        if retries < max_retries =>
+                    {
+                        retries += 1;
+                    }
+                    result => return result,
+                }
+            }
+        })
+        .await
+    }
+
+    /// `COMMIT PREPARED`: commit the transaction prepared as `gid` with
+    /// `AsyncTransaction::prepare_transaction`.
+    pub async fn commit_prepared(&self, gid: &str) -> Result<(), AsyncError<DieselError>> {
+        let sql = format!("COMMIT PREPARED {}", quote_literal(gid));
+        self.run(move |conn| conn.batch_execute(&sql)).await
+    }
+
+    /// `ROLLBACK PREPARED`: discard the transaction prepared as `gid`.
+    pub async fn rollback_prepared(&self, gid: &str) -> Result<(), AsyncError<DieselError>> {
+        let sql = format!("ROLLBACK PREPARED {}", quote_literal(gid));
+        self.run(move |conn| conn.batch_execute(&sql)).await
+    }
+
+    /// Like `run`, but dropping the returned future before it completes asks
+    /// the server to cancel the running statement (`pg_cancel_backend`); a
+    /// call dropped while still queued never runs `f`.
+    ///
+    /// Cancellation is best effort: the request is sent over a second pooled
+    /// connection, from a job of the database's executor.
+    pub async fn run_cancellable<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&PgConnection) -> Result<R, E> + Send,
+    {
+        let state = Arc::new(Mutex::new(QueryState::Pending));
+        let mut guard = CancelOnDrop {
+            db: self.clone(),
+            state: state.clone(),
+            armed: true,
+        };
+
+        let run = self.dispatch_checkout(Priority::Normal, move |conn| {
+            if let QueryState::Cancelled = *state.lock().unwrap() {
+                return Ok(None);
+            }
+            let pid = backend_pid(conn)?;
+            {
+                let mut state = state.lock().unwrap();
+                if let QueryState::Cancelled = *state {
+                    return Ok(None);
+                }
+                *state = QueryState::Running(pid);
+            }
+
+            let result = f(conn);
+            *state.lock().unwrap() = QueryState::Finished;
+            result.map(Some)
+        });
+        let result = trace::instrument("run", trace::no_rows, run).await;
+
+        guard.armed = false;
+        result?.ok_or(AsyncError::Canceled)
+    }
+}
+
+impl AsyncTransaction<PgConnection> {
+    /// `PREPARE TRANSACTION`: end the transaction, keeping its work on the
+    /// server under `gid` until `Database::commit_prepared` or
+    /// `rollback_prepared`, possibly from another connection, settles it.
+    ///
+    /// Needs `max_prepared_transactions` above zero on the server. If
+    /// preparing fails, the transaction is rolled back.
+    pub async fn prepare_transaction(mut self, gid: &str) -> Result<(), AsyncError<DieselError>> {
+        let prepare = format!("PREPARE TRANSACTION {}", quote_literal(gid));
+        self.finish(move |conn| {
+            let prepared = conn.batch_execute(&prepare);
+            // The session has left the transaction either way; a COMMIT outside one
+            // only warns, and brings diesel's transaction depth back to 0
+            conn.transaction_manager().commit_transaction(conn)?;
+            prepared
+        })
+        .await
+    }
+}
+
+impl<'a> AsyncTransactionBuilder<'a> {
+    pub fn isolation(mut self, level: IsolationLevel) -> Self {
+        self.options.isolation = Some(level);
+        self
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.options.read_only = Some(true);
+        self
+    }
+
+    pub fn read_write(mut self) -> Self {
+        self.options.read_only = Some(false);
+        self
+    }
+
+    /// Only has an effect for serializable, read only transactions.
+    pub fn deferrable(mut self) -> Self {
+        self.options.deferrable = Some(true);
+        self
+    }
+
+    pub fn not_deferrable(mut self) -> Self {
+        self.options.deferrable = Some(false);
+        self
+    }
+
+    /// Issue `SET CONSTRAINTS ALL DEFERRED` right after `BEGIN`, so deferrable
+    /// constraints (e.g. cyclic foreign keys) are only checked at commit.
+    pub fn defer_constraints(mut self) -> Self {
+        self.options.defer_constraints = true;
+        self
+    }
+
+    /// Run `f` in a transaction with these settings, committing if it returns
+    /// `Ok` and rolling back otherwise.
+    pub async fn run<R, E, Func>(self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&PgConnection) -> Result<R, E> + Send,
+    {
+        let options = self.options;
+        self.db
+            .run(move |conn| {
+                options.apply(conn.build_transaction()).run(|| {
+                    options.set_constraints(conn)?;
+                    f(conn)
+                })
+            })
+            .await
+    }
+
+    /// Open an `AsyncTransaction` with these settings.
+    pub async fn begin(self) -> Result<AsyncTransaction<PgConnection>, AsyncError<DieselError>> {
+        let options = self.options;
+        self.db
+            .begin_with(move |conn| {
+                let sql =
+                    debug_query::<Pg, _>(&options.apply(conn.build_transaction())).to_string();
+                conn.transaction_manager()
+                    .begin_transaction_sql(conn, &sql)?;
+                options.set_constraints(conn)
+            })
+            .await
+    }
+}
+
+impl TransactionOptions {
+    // Statements to run once the transaction has begun
+    fn set_constraints(&self, conn: &PgConnection) -> QueryResult<()> {
+        if self.defer_constraints {
+            conn.batch_execute("SET CONSTRAINTS ALL DEFERRED")?;
+        }
+        Ok(())
+    }
+
+    fn apply<'c>(&self, mut builder: TransactionBuilder<'c>) -> TransactionBuilder<'c> {
+        builder = match self.isolation {
+            Some(IsolationLevel::ReadCommitted) => builder.read_committed(),
+            Some(IsolationLevel::RepeatableRead) => builder.repeatable_read(),
+            Some(IsolationLevel::Serializable) => builder.serializable(),
+            None => builder,
+        };
+        builder = match self.read_only {
+            Some(true) => builder.read_only(),
+            Some(false) => builder.read_write(),
+            None => builder,
+        };
+        match self.deferrable {
+            Some(true) => builder.deferrable(),
+            Some(false) => builder.not_deferrable(),
+            None => builder,
+        }
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        // A query still queued is stopped from starting instead
+        {
+            let mut state = self.state.lock().unwrap();
+            match *state {
+                QueryState::Pending => {
+                    *state = QueryState::Cancelled;
+                    return;
+                }
+                QueryState::Running(_) => {}
+                QueryState::Finished | QueryState::Cancelled => return,
+            }
+        }
+
+        let pool = match self.db.checkout_pool::<DieselError>() {
+            Ok(pool) => pool,
+            Err(_) => return,
+        };
+        let state = self.state.clone();
+        self.db.spawn_detached(move || {
+            // Check out before locking: the query thread must be able to mark itself
+            // finished while we wait, or a pool of one would deadlock
+            let conn = match pool.get() {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+
+            // Holding the lock keeps the original connection from being handed to a new
+            // query before the cancel request has been sent
+            let state = state.lock().unwrap();
+            if let QueryState::Running(pid) = *state {
+                let _ = diesel::select(sql::<Bool>(&format!("pg_cancel_backend({})", pid)))
+                    .get_result::<bool>(&*conn);
+            }
+        });
+    }
+}
+
+// The PID of the server process behind `conn`, read once per pooled connection
+fn backend_pid(conn: &Checkout<PgConnection>) -> QueryResult<i32> {
+    if let Some(&pid) = conn.session().backend_pid.get() {
+        return Ok(pid);
+    }
+    let pid = diesel::select(sql::<Integer>("pg_backend_pid()")).get_result(&**conn)?;
+    Ok(*conn.session().backend_pid.get_or_init(|| pid))
+}
+
+// Quotes `value` as an SQL string literal
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
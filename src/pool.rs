@@ -0,0 +1,49 @@
+use crate::AsyncError;
+use async_trait::async_trait;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection,
+};
+use std::fmt;
+use tokio::task;
+
+/// A pool of connections that the async methods can check out of.
+///
+/// Implemented for r2d2's `Pool`, which checks out on the blocking thread
+/// running the query, and (with the `bb8` feature) for bb8's `Pool`, which
+/// waits for a free connection without holding up a thread.
+#[async_trait]
+pub trait AsyncPool: Send + Sync {
+    type Connection: 'static + Connection;
+
+    /// Check out a connection and run `f` with it on a blocking thread.
+    async fn with_connection<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Self::Connection) -> Result<R, AsyncError<E>> + Send;
+}
+
+#[async_trait]
+impl<Conn> AsyncPool for Pool<ConnectionManager<Conn>>
+where
+    Conn: 'static + Connection,
+{
+    type Connection = Conn;
+
+    #[inline]
+    async fn with_connection<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, AsyncError<E>> + Send,
+    {
+        let self_ = self.clone();
+        task::spawn_blocking(move || {
+            let conn = self_.get().map_err(|e| AsyncError::Checkout(e.into()))?;
+            f(&*conn)
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+}
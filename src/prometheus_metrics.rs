@@ -0,0 +1,218 @@
+use crate::{classify, stats::ACQUIRE_BUCKETS_MS, AsyncError, Database};
+use diesel::Connection;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry,
+};
+use std::{fmt, time::Duration};
+
+// Updated as calls finish, once the database is registered with a `Registry`
+pub(crate) struct QueryCollectors {
+    duration: Histogram,
+    errors: IntCounterVec,
+    // Observed at checkout, by the database's `CheckoutStats`
+    pub(crate) acquire_wait: Histogram,
+}
+
+// Reads the pool's statistics whenever the registry is gathered
+struct PoolCollector<Conn>
+where
+    Conn: 'static + Connection,
+{
+    db: Database<Conn>,
+    checkouts: IntCounter,
+    checkout_errors: IntCounter,
+    checkout_wait: GaugeVec,
+    errors: IntCounterVec,
+    connections: IntGaugeVec,
+    max_size: IntGauge,
+    saturation: Gauge,
+}
+
+impl QueryCollectors {
+    pub(crate) fn observe<R, E>(&self, elapsed: Duration, result: &Result<R, AsyncError<E>>)
+    where
+        E: 'static + fmt::Debug,
+    {
+        self.duration.observe(elapsed.as_secs_f64());
+        if let Err(ref err) = *result {
+            self.errors
+                .with_label_values(&[classify::class_of(err).as_str()])
+                .inc();
+        }
+    }
+}
+
+impl<Conn> Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Register the database's metrics with `registry`, labelled
+    /// `database="<name>"` so several databases can share one registry:
+    ///
+    /// - `db_pool_checkouts_total` and `db_pool_checkout_errors_total`
+    /// - `db_pool_checkout_wait_seconds`, by `quantile`, over recent checkouts
+    /// - `db_pool_acquire_wait_seconds`, a histogram of the time from a call
+    ///   being made to it holding a connection
+    /// - `db_pool_connections`, by `state` (`idle` or `in_use`), with
+    ///   `db_pool_max_size` and `db_pool_saturation` (in use over max size)
+    /// - `db_query_duration_seconds`, a histogram of `run`, `transaction` and
+    ///   `*_async` calls, checkout included
+    /// - `db_query_errors_total`, by error `class`
+    /// - `db_errors_total`, by `category` as in `ErrorCounts`
+    ///
+    /// A database can only be registered once, even if registering fails.
+    pub fn register_metrics(&self, registry: &Registry, name: &str) -> prometheus::Result<()> {
+        let opts = |metric: &str, help: &str| Opts::new(metric, help).const_label("database", name);
+
+        let queries = QueryCollectors {
+            duration: Histogram::with_opts(HistogramOpts::from(opts(
+                "db_query_duration_seconds",
+                "Time taken by database calls, checkout included",
+            )))?,
+            errors: IntCounterVec::new(
+                opts(
+                    "db_query_errors_total",
+                    "Failed database calls by error class",
+                ),
+                &["class"],
+            )?,
+            acquire_wait: Histogram::with_opts(
+                HistogramOpts::from(opts(
+                    "db_pool_acquire_wait_seconds",
+                    "Time from a database call being made to it holding a connection",
+                ))
+                .buckets(
+                    ACQUIRE_BUCKETS_MS
+                        .iter()
+                        .map(|&ms| ms as f64 / 1000.0)
+                        .collect(),
+                ),
+            )?,
+        };
+        let pool = PoolCollector {
+            db: self.clone(),
+            checkouts: IntCounter::with_opts(opts(
+                "db_pool_checkouts_total",
+                "Successful connection checkouts",
+            ))?,
+            checkout_errors: IntCounter::with_opts(opts(
+                "db_pool_checkout_errors_total",
+                "Failed connection checkouts",
+            ))?,
+            checkout_wait: GaugeVec::new(
+                opts(
+                    "db_pool_checkout_wait_seconds",
+                    "Connection checkout wait percentiles over recent checkouts",
+                ),
+                &["quantile"],
+            )?,
+            errors: IntCounterVec::new(
+                opts("db_errors_total", "Failed database calls by category"),
+                &["category"],
+            )?,
+            connections: IntGaugeVec::new(
+                opts("db_pool_connections", "Open connections by state"),
+                &["state"],
+            )?,
+            max_size: IntGauge::with_opts(opts(
+                "db_pool_max_size",
+                "Maximum number of connections in the pool",
+            ))?,
+            saturation: Gauge::with_opts(opts(
+                "db_pool_saturation",
+                "Connections in use as a fraction of the maximum",
+            ))?,
+        };
+
+        let (duration, errors, acquire_wait) = (
+            queries.duration.clone(),
+            queries.errors.clone(),
+            queries.acquire_wait.clone(),
+        );
+        if self.set_query_collectors(queries).is_err() {
+            return Err(prometheus::Error::Msg(
+                "database already registered".to_string(),
+            ));
+        }
+        registry.register(Box::new(duration))?;
+        registry.register(Box::new(errors))?;
+        registry.register(Box::new(acquire_wait))?;
+        registry.register(Box::new(pool))?;
+        Ok(())
+    }
+}
+
+impl<Conn> Collector for PoolCollector<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.checkouts.desc();
+        descs.extend(self.checkout_errors.desc());
+        descs.extend(self.checkout_wait.desc());
+        descs.extend(self.errors.desc());
+        descs.extend(self.connections.desc());
+        descs.extend(self.max_size.desc());
+        descs.extend(self.saturation.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = self.db.stats();
+        let max_size = self.db.pool().map_or(0, |pool| pool.max_size());
+
+        // The counters can only go up; catch them up with the totals
+        self.checkouts
+            .inc_by(stats.checkouts.saturating_sub(self.checkouts.get()));
+        self.checkout_errors.inc_by(
+            stats
+                .checkout_errors
+                .saturating_sub(self.checkout_errors.get()),
+        );
+        for (quantile, wait) in [
+            ("0.5", stats.wait_p50),
+            ("0.9", stats.wait_p90),
+            ("0.99", stats.wait_p99),
+        ] {
+            self.checkout_wait
+                .with_label_values(&[quantile])
+                .set(wait.as_secs_f64());
+        }
+        let errors = stats.errors;
+        for (category, total) in [
+            ("checkout", errors.checkout),
+            ("canceled", errors.canceled),
+            ("timeout", errors.timeout),
+            ("serialization", errors.serialization),
+            ("constraint", errors.constraint),
+            ("other", errors.other),
+        ] {
+            let counter = self.errors.with_label_values(&[category]);
+            counter.inc_by(total.saturating_sub(counter.get()));
+        }
+        self.connections
+            .with_label_values(&["idle"])
+            .set(stats.idle.into());
+        self.connections
+            .with_label_values(&["in_use"])
+            .set(stats.in_use.into());
+        self.max_size.set(max_size.into());
+        self.saturation.set(if max_size == 0 {
+            0.0
+        } else {
+            f64::from(stats.in_use) / f64::from(max_size)
+        });
+
+        let mut families = self.checkouts.collect();
+        families.extend(self.checkout_errors.collect());
+        families.extend(self.checkout_wait.collect());
+        families.extend(self.errors.collect());
+        families.extend(self.connections.collect());
+        families.extend(self.max_size.collect());
+        families.extend(self.saturation.collect());
+        families
+    }
+}
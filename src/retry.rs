@@ -0,0 +1,107 @@
+use crate::AsyncError;
+use diesel::result::Error as DieselError;
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tokio::time;
+
+/// How often and how patiently to retry an operation that failed.
+///
+/// Waits grow exponentially from the initial backoff up to the maximum; with
+/// jitter (the default) each wait is randomly shortened by up to half so
+/// callers that failed together don't retry in lockstep.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Try at most `max_attempts` times in total, waiting 10ms after the
+    /// first failure and at most 1s between attempts.
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        assert!(max_attempts > 0, "max_attempts must be positive");
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+        }
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        assert!(initial <= max, "initial backoff exceeds the maximum");
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    // Whether another attempt is allowed after `attempt` (counting from 1) failed
+    pub(crate) fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    // How long to wait after `attempt` (counting from 1) failed
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+
+        if self.jitter {
+            // Any fresh `RandomState` is randomly keyed, which is all the randomness needed
+            let random = RandomState::new().build_hasher().finish();
+            let half = delay / 2;
+            half + half.mul_f64((random % 1024) as f64 / 1024.0)
+        } else {
+            delay
+        }
+    }
+}
+
+// Makes attempts until one succeeds, fails with an error `retryable` rejects, or
+// `policy` allows no more, waiting between them as `policy` says
+pub(crate) async fn retrying<R, A, Fut, P>(
+    policy: &RetryPolicy,
+    retryable: P,
+    mut attempt: A,
+) -> Result<R, AsyncError<DieselError>>
+where
+    A: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, AsyncError<DieselError>>>,
+    P: Fn(&AsyncError<DieselError>) -> bool,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(err) if retryable(&err) && policy.should_retry(attempts) => {
+                let delay = policy.delay(attempts);
+                log::warn!(
+                    "retrying in {:?} after attempt {} of {} failed: {}",
+                    delay,
+                    attempts,
+                    policy.max_attempts(),
+                    err
+                );
+                time::sleep(delay).await;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
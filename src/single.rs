@@ -0,0 +1,112 @@
+use crate::{run_guarded, trace, AsyncConnection, AsyncError, AsyncSimpleConnection};
+use async_trait::async_trait;
+use diesel::{result::Error as DieselError, Connection};
+use std::{fmt, sync::Arc};
+use tokio::{sync::Mutex, task};
+
+/// One connection, without a pool, usable from async code.
+///
+/// Calls wait their turn for the connection instead of blocking a thread, and
+/// every call sees the same session. Suits CLIs, tests and SQLite, where a pool
+/// adds nothing or would open the database several times. Clones share the
+/// connection.
+pub struct AsyncSingleConnection<Conn> {
+    conn: Arc<Mutex<Conn>>,
+}
+
+impl<Conn> AsyncSingleConnection<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    pub fn new(conn: Conn) -> AsyncSingleConnection<Conn> {
+        AsyncSingleConnection {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Connect to `database_url` on a blocking thread.
+    pub async fn establish(
+        database_url: &str,
+    ) -> Result<AsyncSingleConnection<Conn>, AsyncError<DieselError>> {
+        let database_url = database_url.to_string();
+        let conn = task::spawn_blocking(move || Conn::establish(&database_url))
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+            .map_err(AsyncError::Connect)?;
+        Ok(AsyncSingleConnection::new(conn))
+    }
+
+    async fn with_conn<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let conn = self.conn.clone().lock_owned().await;
+        task::spawn_blocking(move || run_guarded(&*conn, f))
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+    }
+}
+
+impl<Conn> Clone for AsyncSingleConnection<Conn> {
+    fn clone(&self) -> Self {
+        AsyncSingleConnection {
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for AsyncSingleConnection<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncSingleConnection").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncSingleConnection<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        let query = query.to_string();
+        trace::instrument(
+            "batch_execute",
+            trace::no_rows,
+            self.with_conn(move |conn| conn.batch_execute(&query)),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncSingleConnection<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        trace::instrument("run", trace::no_rows, self.with_conn(f)).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        trace::instrument(
+            "transaction",
+            trace::no_rows,
+            self.with_conn(|conn| conn.transaction::<R, E, _>(|| f(conn))),
+        )
+        .await
+    }
+}
@@ -0,0 +1,458 @@
+use crate::{
+    libsqlite3::RawConnection, limiter::Priority, panic_message, truncate, AsyncError, Database,
+    DatabaseBuilder, TempDatabaseBackend, TruncateTables,
+};
+use diesel::{
+    connection::SimpleConnection,
+    dsl::sql,
+    r2d2::{CustomizeConnection, Error as R2D2Error},
+    result::{Error as DieselError, QueryResult},
+    sql_types::Text,
+    sqlite::SqliteConnection,
+    Connection, RunQueryDsl,
+};
+use std::{
+    env, fmt, fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// Numbers the in-memory databases created by `connect_in_memory`
+static IN_MEMORY_DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Settings applied to every connection of a pooled SQLite database, as an
+/// r2d2 connection customizer.
+///
+/// Without them, pooled connections to one file fail with `database is
+/// locked` as soon as two of them write at once. The defaults turn on
+/// write-ahead logging, so readers don't block the writer, and wait up to 5
+/// seconds for a lock instead of failing at once; they also enforce foreign
+/// keys and use `synchronous = NORMAL`, which is safe with WAL.
+///
+/// Add it with `DatabaseBuilder::sqlite_config`, or to a pool of your own
+/// with r2d2's `Builder::connection_customizer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SqlitePoolConfig {
+    wal: bool,
+    busy_timeout: Duration,
+    foreign_keys: bool,
+    synchronous: Synchronous,
+    // Set with `pragma`, in order
+    pragmas: Vec<(String, PragmaValue)>,
+}
+
+/// Values of SQLite's `synchronous` setting: how often it waits for writes
+/// to reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// Values of SQLite's `temp_store` setting: where temporary tables and
+/// indices are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempStore {
+    /// As SQLite was compiled, normally on disk
+    Default,
+    File,
+    Memory,
+}
+
+/// The size of SQLite's page cache, per connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheSize {
+    Pages(u32),
+    Kibibytes(u32),
+}
+
+/// The value of a pragma set with `SqlitePoolConfig::pragma`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PragmaValue {
+    Integer(i64),
+    Bool(bool),
+    /// Quoted as a string literal
+    Text(String),
+}
+
+/// How far a `Database::backup_to` has got, in pages of the source database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// Pages still to copy
+    pub remaining: u32,
+    /// Pages in the source database
+    pub total: u32,
+}
+
+// Keeps an in-memory database alive for as long as its pool, which holds its
+// customizers; it is gone as soon as its last connection closes
+struct KeepAlive {
+    _conn: Mutex<RawConnection>,
+    customizer: Option<Box<dyn CustomizeConnection<SqliteConnection, R2D2Error>>>,
+}
+
+impl Default for SqlitePoolConfig {
+    fn default() -> SqlitePoolConfig {
+        SqlitePoolConfig {
+            wal: true,
+            busy_timeout: Duration::from_secs(5),
+            foreign_keys: true,
+            synchronous: Synchronous::Normal,
+            pragmas: Vec::new(),
+        }
+    }
+}
+
+impl SqlitePoolConfig {
+    pub fn new() -> SqlitePoolConfig {
+        SqlitePoolConfig::default()
+    }
+
+    /// Use `journal_mode = WAL` (default true). The mode is stored in the
+    /// database file, so turning this off leaves it as it was.
+    pub fn wal(mut self, wal: bool) -> SqlitePoolConfig {
+        self.wal = wal;
+        self
+    }
+
+    /// How long a statement waits for a lock held by another connection
+    /// before failing with `database is locked` (default 5 seconds).
+    pub fn busy_timeout(mut self, timeout: Duration) -> SqlitePoolConfig {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Enforce foreign key constraints (default true).
+    pub fn foreign_keys(mut self, foreign_keys: bool) -> SqlitePoolConfig {
+        self.foreign_keys = foreign_keys;
+        self
+    }
+
+    /// Default `Synchronous::Normal`.
+    pub fn synchronous(mut self, synchronous: Synchronous) -> SqlitePoolConfig {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// `cache_size`, how much of the database each connection keeps in
+    /// memory (SQLite's default is 2MiB).
+    pub fn cache_size(self, cache_size: CacheSize) -> SqlitePoolConfig {
+        // Negative sizes are in KiB
+        let value = match cache_size {
+            CacheSize::Pages(pages) => i64::from(pages),
+            CacheSize::Kibibytes(kib) => -i64::from(kib),
+        };
+        self.pragma("cache_size", value)
+    }
+
+    /// `mmap_size`, how many bytes of the database file to read through
+    /// memory-mapped I/O (SQLite's default is 0, none).
+    pub fn mmap_size(self, bytes: u64) -> SqlitePoolConfig {
+        self.pragma("mmap_size", bytes.min(i64::MAX as u64) as i64)
+    }
+
+    pub fn temp_store(self, temp_store: TempStore) -> SqlitePoolConfig {
+        self.pragma("temp_store", temp_store.as_str())
+    }
+
+    /// Set any other pragma `name` to `value`, after the settings above and
+    /// before `journal_mode`. Setting one twice keeps the last value.
+    ///
+    /// Panics if `name` is not made of ASCII letters, digits and
+    /// underscores, optionally after a schema name and a dot.
+    pub fn pragma<N, V>(mut self, name: N, value: V) -> SqlitePoolConfig
+    where
+        N: Into<String>,
+        V: Into<PragmaValue>,
+    {
+        let name = name.into();
+        let is_identifier = |part: &str| {
+            !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        };
+        assert!(
+            name.splitn(2, '.').all(is_identifier),
+            "invalid pragma name: {:?}",
+            name
+        );
+
+        let value = value.into();
+        match self.pragmas.iter_mut().find(|(set, _)| *set == name) {
+            Some((_, set)) => *set = value,
+            None => self.pragmas.push((name, value)),
+        }
+        self
+    }
+
+    // The pragmas setting these up; `busy_timeout` comes first, so the others wait
+    // for locks too
+    fn pragmas(&self) -> String {
+        let mut pragmas = format!(
+            "PRAGMA busy_timeout = {}; PRAGMA foreign_keys = {}; PRAGMA synchronous = {};",
+            self.busy_timeout.as_millis(),
+            if self.foreign_keys { "ON" } else { "OFF" },
+            self.synchronous.as_str(),
+        );
+        for (name, value) in &self.pragmas {
+            let value = match *value {
+                PragmaValue::Integer(n) => n.to_string(),
+                PragmaValue::Bool(b) => if b { "ON" } else { "OFF" }.to_string(),
+                PragmaValue::Text(ref text) => format!("'{}'", text.replace('\'', "''")),
+            };
+            pragmas.push_str(&format!(" PRAGMA {} = {};", name, value));
+        }
+        if self.wal {
+            pragmas.push_str(" PRAGMA journal_mode = WAL;");
+        }
+        pragmas
+    }
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+impl TempStore {
+    fn as_str(self) -> &'static str {
+        match self {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        }
+    }
+}
+
+impl From<i64> for PragmaValue {
+    fn from(n: i64) -> PragmaValue {
+        PragmaValue::Integer(n)
+    }
+}
+
+impl From<bool> for PragmaValue {
+    fn from(b: bool) -> PragmaValue {
+        PragmaValue::Bool(b)
+    }
+}
+
+impl From<&str> for PragmaValue {
+    fn from(text: &str) -> PragmaValue {
+        PragmaValue::Text(text.to_string())
+    }
+}
+
+impl From<String> for PragmaValue {
+    fn from(text: String) -> PragmaValue {
+        PragmaValue::Text(text)
+    }
+}
+
+impl CustomizeConnection<SqliteConnection, R2D2Error> for SqlitePoolConfig {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), R2D2Error> {
+        conn.batch_execute(&self.pragmas())
+            .map_err(R2D2Error::QueryError)
+    }
+}
+
+impl CustomizeConnection<SqliteConnection, R2D2Error> for KeepAlive {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), R2D2Error> {
+        match self.customizer {
+            Some(ref customizer) => customizer.on_acquire(conn),
+            None => Ok(()),
+        }
+    }
+
+    fn on_release(&self, conn: SqliteConnection) {
+        if let Some(ref customizer) = self.customizer {
+            customizer.on_release(conn);
+        }
+    }
+}
+
+impl fmt::Debug for KeepAlive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeepAlive")
+            .field("customizer", &self.customizer)
+            .finish()
+    }
+}
+
+impl TruncateTables for SqliteConnection {
+    // SQLite has no `TRUNCATE` nor information_schema: the tables are listed from
+    // `sqlite_master` and emptied in a transaction, with foreign keys checked at its end
+    // only; `sqlite_sequence` holds the `AUTOINCREMENT` counters
+    fn truncate_all(&self, except: &[&str]) -> QueryResult<Vec<String>> {
+        self.transaction(|| {
+            let tables = sql::<Text>(
+                "SELECT name FROM sqlite_master \
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )
+            .load::<String>(self)?;
+            let tables: Vec<String> = tables
+                .into_iter()
+                .filter(|table| !truncate::is_kept(except, "main", table))
+                .collect();
+            // Only created along with the first `AUTOINCREMENT` table
+            let has_sequences = !sql::<Text>(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'sqlite_sequence'",
+            )
+            .load::<String>(self)?
+            .is_empty();
+
+            self.batch_execute("PRAGMA defer_foreign_keys = ON")?;
+            for table in &tables {
+                self.batch_execute(&format!("DELETE FROM {}", quote_identifier(table)))?;
+                if has_sequences {
+                    self.batch_execute(&format!(
+                        "DELETE FROM sqlite_sequence WHERE name = '{}'",
+                        table.replace('\'', "''")
+                    ))?;
+                }
+            }
+            Ok(tables)
+        })
+    }
+}
+
+impl TempDatabaseBackend for SqliteConnection {
+    // The file is created by the pool's first connection
+    fn create_database(base_url: &str, name: &str) -> Result<String, AsyncError<DieselError>> {
+        Ok(temp_file(base_url, name).to_string_lossy().into_owned())
+    }
+
+    // Along with the journal files SQLite keeps next to the database
+    fn drop_database(base_url: &str, name: &str) -> Result<(), AsyncError<DieselError>> {
+        let path = temp_file(base_url, name);
+        for suffix in &["", "-wal", "-shm", "-journal"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            match fs::remove_file(&file) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("failed to remove {}: {}", Path::new(&file).display(), err)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseBuilder<SqliteConnection> {
+    /// Create a pool for a new, empty in-memory database and build the
+    /// database on it, for tests wanting a throwaway database behind the
+    /// whole pooled API.
+    ///
+    /// Every connection of the pool opens the same database, through SQLite's
+    /// `memdb` VFS, so they see each other's writes and wait for each other's
+    /// locks as with a file. The database lives as long as the pool. Needs
+    /// SQLite 3.36 or later built with URI file names enabled, as most are.
+    pub async fn connect_in_memory(
+        mut self,
+    ) -> Result<Database<SqliteConnection>, AsyncError<DieselError>> {
+        let database_url = format!(
+            "file:/actix-threadpool-diesel-{}?vfs=memdb",
+            IN_MEMORY_DATABASES.fetch_add(1, Ordering::Relaxed)
+        );
+        let conn = RawConnection::establish(&database_url).map_err(AsyncError::Connect)?;
+        let keep_alive = KeepAlive {
+            _conn: Mutex::new(conn),
+            customizer: self.connection_customizer.take(),
+        };
+        self.connection_customizer(keep_alive)
+            .connect(database_url)
+            .await
+    }
+
+    /// Apply `config` to every connection the pool created by `connect`
+    /// opens; see `SqlitePoolConfig`.
+    pub fn sqlite_config(self, config: SqlitePoolConfig) -> DatabaseBuilder<SqliteConnection> {
+        self.connection_customizer(config)
+    }
+
+    /// Run every call but reads on one dedicated connection, one at a time,
+    /// while reads use the rest of the pool (default off). Reads are the
+    /// `AsyncRunQueryDsl` calls returning rows, such as `load_async` and
+    /// `get_result_async`; `run`, `transaction`, `execute_async` and
+    /// `batch_execute_async` count as writes.
+    ///
+    /// SQLite allows a single writer at a time; queued in the crate instead
+    /// of in SQLite, writes don't fail with `database is locked`, whatever the
+    /// busy timeout. The connection is checked out by the first write and kept
+    /// until shutdown, so the pool needs room for one more. `begin` and
+    /// `acquire` wait for the writer's turn and hold it until the transaction
+    /// or guard is gone, so a write made on the database meanwhile waits for
+    /// them rather than going through them.
+    pub fn single_writer(mut self, single_writer: bool) -> DatabaseBuilder<SqliteConnection> {
+        self.single_writer = single_writer;
+        self
+    }
+}
+
+impl Database<SqliteConnection> {
+    /// Create a pool for a new, empty in-memory database with the default
+    /// settings; see `DatabaseBuilder::connect_in_memory`.
+    pub async fn connect_in_memory() -> Result<Database<SqliteConnection>, AsyncError<DieselError>>
+    {
+        Database::builder().connect_in_memory().await
+    }
+
+    /// Copy the live database to the file at `path`, replacing its contents,
+    /// with SQLite's online backup API.
+    ///
+    /// Pages are copied a few hundred at a time, so writers are only held up
+    /// for a step; if another connection writes meanwhile, the copy starts
+    /// over, and the result is a consistent snapshot. `progress` is called on
+    /// the blocking thread after every step; if it panics, the backup stops
+    /// and fails with `AsyncError::Panicked`. Runs on a connection of its own;
+    /// needs the database URL, see `DatabaseBuilder::database_url`, and a
+    /// file database, as `:memory:` opens a new empty one.
+    pub async fn backup_to<P, F>(&self, path: P, progress: F) -> Result<(), AsyncError<DieselError>>
+    where
+        P: AsRef<Path>,
+        F: 'static + FnMut(BackupProgress) + Send,
+    {
+        let dest = path.as_ref().to_string_lossy().into_owned();
+        let in_flight = self.admit()?;
+        let url = self.database_url()?;
+
+        self.spawn_job(Priority::Normal, move || {
+            let _in_flight = in_flight;
+            let source = RawConnection::establish(&url).map_err(AsyncError::Connect)?;
+            let dest = RawConnection::establish(&dest).map_err(AsyncError::Connect)?;
+            match panic::catch_unwind(AssertUnwindSafe(|| source.backup_to(&dest, progress))) {
+                Ok(result) => result.map_err(AsyncError::Error),
+                Err(payload) => Err(AsyncError::Panicked(panic_message(payload))),
+            }
+        })
+        .await
+        .map_err(|_| AsyncError::Canceled)?
+    }
+}
+
+// Where `TempDatabase` keeps the database `name`: in the directory `base_url`, or
+// the system's temporary directory
+fn temp_file(base_url: &str, name: &str) -> PathBuf {
+    let dir = if base_url.is_empty() {
+        env::temp_dir()
+    } else {
+        PathBuf::from(base_url)
+    };
+    dir.join(format!("{}.sqlite3", name))
+}
+
+// Quotes `name` as an SQL identifier
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
@@ -0,0 +1,24 @@
+use diesel::{result::Error as DieselError, Connection};
+use std::time::Duration;
+
+/// Connections whose statements the server can cut short after a time limit,
+/// such as with Postgres's `statement_timeout` or MySQL's
+/// `max_execution_time`.
+///
+/// Used by `AsyncConnection::run_with_statement_timeout`. Unlike
+/// `run_with_timeout`, the statement stops on the server too, and the
+/// connection is free again as soon as it does.
+pub trait StatementTimeout: Connection {
+    /// Run `f` with its statements limited to `timeout`, restoring the
+    /// previous limit afterwards.
+    fn with_statement_timeout<R, E, F>(&self, timeout: Duration, f: F) -> Result<R, E>
+    where
+        E: From<DieselError>,
+        F: FnOnce(&Self) -> Result<R, E>;
+}
+
+// The limit in milliseconds, as the backends take it; never 0, which turns it off
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+pub(crate) fn millis(timeout: Duration) -> u128 {
+    timeout.as_millis().max(1)
+}
@@ -0,0 +1,271 @@
+use crate::{classify, AsyncError, DatabaseErrorClass};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "prometheus")]
+use {prometheus::Histogram, std::sync::OnceLock};
+
+// Checkout waits kept for the percentiles
+const WAIT_SAMPLES: usize = 1024;
+
+// Upper bounds of the acquire wait histogram's buckets, in milliseconds
+pub(crate) const ACQUIRE_BUCKETS_MS: [u64; 13] =
+    [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+thread_local! {
+    // When the call running on this blocking thread was made, read at checkout
+    static CALLED_AT: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A snapshot of a database's pool, returned by `Database::stats`.
+///
+/// Serializes the wait percentiles as fractional milliseconds, e.g.
+/// `"wait_p50_ms": 0.21`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PoolStats {
+    /// Open connections not checked out
+    pub idle: u32,
+    /// Connections checked out
+    pub in_use: u32,
+    /// Successful checkouts since the database was built
+    pub checkouts: u64,
+    /// Failed checkouts, including failed `on_acquire` hooks, since the
+    /// database was built
+    pub checkout_errors: u64,
+    /// Checkout wait percentiles over the most recent checkouts
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "wait_p50_ms", serialize_with = "serialize_millis")
+    )]
+    pub wait_p50: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "wait_p90_ms", serialize_with = "serialize_millis")
+    )]
+    pub wait_p90: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "wait_p99_ms", serialize_with = "serialize_millis")
+    )]
+    pub wait_p99: Duration,
+    /// Time from a call being made to it holding a connection, queueing for
+    /// the concurrency limit and a blocking thread included
+    pub acquire_wait: WaitHistogram,
+    /// Failed `run`, `transaction` and `*_async` calls since the database
+    /// was built, by category
+    pub errors: ErrorCounts,
+}
+
+/// Failed calls by category, to tell an exhausted pool from bad data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ErrorCounts {
+    /// No connection could be checked out
+    pub checkout: u64,
+    /// The blocking task went away before finishing
+    pub canceled: u64,
+    /// Client side timeouts and deadlines, statement and lock timeouts
+    pub timeout: u64,
+    /// Serialization failures and deadlocks
+    pub serialization: u64,
+    /// Unique, foreign key, not null and check constraint violations
+    pub constraint: u64,
+    pub other: u64,
+}
+
+/// Counts of waits by duration, as a Prometheus histogram has them.
+///
+/// Serializes the bounds and sum as fractional milliseconds, e.g.
+/// `{"buckets": [[1.0, 12], [2.0, 15]], "count": 16, "sum_ms": 21.5}`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WaitHistogram {
+    /// Upper bounds, with the number of waits up to each, so counts only grow
+    /// from one bucket to the next
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_buckets"))]
+    pub buckets: Vec<(Duration, u64)>,
+    /// Number of waits, including those longer than the last bound
+    pub count: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "sum_ms", serialize_with = "serialize_millis")
+    )]
+    pub sum: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct CheckoutStats {
+    checkouts: AtomicU64,
+    errors: AtomicU64,
+    waits: Mutex<VecDeque<Duration>>,
+    // Not cumulative; the last counts waits beyond every bound
+    acquire_waits: [AtomicU64; ACQUIRE_BUCKETS_MS.len() + 1],
+    acquire_nanos: AtomicU64,
+    // Indexed like the fields of `ErrorCounts`
+    errors_by_category: [AtomicU64; 6],
+    #[cfg(feature = "prometheus")]
+    acquire_histogram: OnceLock<Histogram>,
+}
+
+impl CheckoutStats {
+    pub(crate) fn record(&self, wait: Duration, ok: bool) {
+        if ok {
+            self.checkouts.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut waits = self.waits.lock().unwrap();
+        if waits.len() == WAIT_SAMPLES {
+            waits.pop_front();
+        }
+        waits.push_back(wait);
+    }
+
+    pub(crate) fn record_error<E>(&self, err: &AsyncError<E>)
+    where
+        E: 'static + fmt::Debug,
+    {
+        let category = match (err, classify::class_of(err)) {
+            (AsyncError::Checkout(_), _) => 0,
+            (AsyncError::Canceled, _) => 1,
+            (_, DatabaseErrorClass::Timeout | DatabaseErrorClass::LockTimeout) => 2,
+            (_, DatabaseErrorClass::SerializationFailure | DatabaseErrorClass::Deadlock) => 3,
+            (
+                _,
+                DatabaseErrorClass::UniqueViolation
+                | DatabaseErrorClass::ForeignKeyViolation
+                | DatabaseErrorClass::NotNullViolation
+                | DatabaseErrorClass::CheckViolation,
+            ) => 4,
+            _ => 5,
+        };
+        self.errors_by_category[category].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records how long the call on this thread took to get its connection
+    pub(crate) fn record_acquire(&self) {
+        let wait = match CALLED_AT.with(Cell::get) {
+            Some(called_at) => called_at.elapsed(),
+            None => return,
+        };
+
+        let bucket = ACQUIRE_BUCKETS_MS
+            .iter()
+            .position(|&ms| wait <= Duration::from_millis(ms))
+            .unwrap_or(ACQUIRE_BUCKETS_MS.len());
+        self.acquire_waits[bucket].fetch_add(1, Ordering::Relaxed);
+        self.acquire_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        if let Some(histogram) = self.acquire_histogram.get() {
+            histogram.observe(wait.as_secs_f64());
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn set_acquire_histogram(&self, histogram: Histogram) {
+        let _ = self.acquire_histogram.set(histogram);
+    }
+
+    pub(crate) fn snapshot(&self, state: Option<r2d2::State>) -> PoolStats {
+        let mut waits: Vec<_> = self.waits.lock().unwrap().iter().copied().collect();
+        waits.sort_unstable();
+        // Nearest rank, so a high percentile of few samples is their maximum
+        let percentile = |p: usize| match waits.len() {
+            0 => Duration::default(),
+            len => waits[(len * p).div_ceil(100) - 1],
+        };
+
+        let (idle, in_use) = state.map_or((0, 0), |state| {
+            (
+                state.idle_connections,
+                state.connections - state.idle_connections,
+            )
+        });
+
+        PoolStats {
+            idle,
+            in_use,
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            checkout_errors: self.errors.load(Ordering::Relaxed),
+            wait_p50: percentile(50),
+            wait_p90: percentile(90),
+            wait_p99: percentile(99),
+            acquire_wait: self.acquire_snapshot(),
+            errors: self.error_snapshot(),
+        }
+    }
+
+    fn error_snapshot(&self) -> ErrorCounts {
+        let count = |category: usize| self.errors_by_category[category].load(Ordering::Relaxed);
+        ErrorCounts {
+            checkout: count(0),
+            canceled: count(1),
+            timeout: count(2),
+            serialization: count(3),
+            constraint: count(4),
+            other: count(5),
+        }
+    }
+
+    fn acquire_snapshot(&self) -> WaitHistogram {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(ACQUIRE_BUCKETS_MS.len());
+        for (i, waits) in self.acquire_waits.iter().enumerate() {
+            count += waits.load(Ordering::Relaxed);
+            if let Some(&ms) = ACQUIRE_BUCKETS_MS.get(i) {
+                buckets.push((Duration::from_millis(ms), count));
+            }
+        }
+
+        WaitHistogram {
+            buckets,
+            count,
+            sum: Duration::from_nanos(self.acquire_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_millis<S: serde::Serializer>(
+    wait: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(wait.as_secs_f64() * 1000.0)
+}
+
+// Wraps `job` so a checkout it makes can tell how long ago the call was made;
+// called on the task, like `label::labeled`
+pub(crate) fn called_at<F, R>(called_at: Instant, job: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    move || {
+        CALLED_AT.with(|cell| cell.set(Some(called_at)));
+        let result = job();
+        CALLED_AT.with(|cell| cell.set(None));
+        result
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_buckets<S: serde::Serializer>(
+    buckets: &[(Duration, u64)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        buckets
+            .iter()
+            .map(|&(bound, count)| (bound.as_secs_f64() * 1000.0, count)),
+    )
+}
@@ -0,0 +1,99 @@
+use crate::AsyncError;
+use diesel::result::Error as DieselError;
+use futures::Stream;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+
+// How many rows may be waiting for the consumer before the blocking side pauses
+#[cfg(feature = "postgres")]
+pub(crate) const STREAM_BUFFER: usize = 64;
+
+// Bytes read from an input source per chunk handed to the blocking side
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+const INPUT_CHUNK: usize = 64 * 1024;
+
+// How many chunks of input may wait for the blocking side before reading pauses
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+pub(crate) const INPUT_BUFFER: usize = 8;
+
+type Feed<'a> = Pin<Box<dyn Future<Output = Result<(), AsyncError<DieselError>>> + Send + 'a>>;
+
+/// The results of a query, produced by `AsyncRunQueryDsl::load_chunked_async`
+/// (one chunk per item), `Database::load_cursor_async` (one row per item) and
+/// `Database::copy_out_async` (the `COPY` output).
+///
+/// Items are handed over through a bounded channel; while the consumer lags
+/// behind, the producing side waits. A failed query ends the stream with its
+/// error.
+pub struct LoadStream<'a, U> {
+    feed: Option<Feed<'a>>,
+    rows: mpsc::Receiver<U>,
+    error: Option<AsyncError<DieselError>>,
+}
+
+impl<'a, U> LoadStream<'a, U> {
+    pub(crate) fn new(feed: Feed<'a>, rows: mpsc::Receiver<U>) -> LoadStream<'a, U> {
+        LoadStream {
+            feed: Some(feed),
+            rows,
+            error: None,
+        }
+    }
+}
+
+impl<'a, U> Stream for LoadStream<'a, U> {
+    type Item = Result<U, AsyncError<DieselError>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Drive the blocking job; nothing is queued on the executor until this is polled
+        if let Some(ref mut feed) = this.feed {
+            if let Poll::Ready(result) = feed.as_mut().poll(cx) {
+                this.feed = None;
+                this.error = result.err();
+            }
+        }
+
+        match this.rows.poll_recv(cx) {
+            Poll::Ready(Some(row)) => Poll::Ready(Some(Ok(row))),
+            // The sender is gone, but the job's result may not have arrived yet
+            Poll::Ready(None) if this.feed.is_some() => Poll::Pending,
+            Poll::Ready(None) => Poll::Ready(this.error.take().map(Err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Reads `source` into chunks for a bulk load until it ends, fails, or the load stops
+// taking them; `what` names the load in the error a failed read ends it with
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+pub(crate) async fn feed_input<R>(
+    what: &str,
+    mut source: R,
+    tx: mpsc::Sender<Result<Vec<u8>, String>>,
+) where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut chunk = vec![0; INPUT_CHUNK];
+        let chunk = match source.read(&mut chunk).await {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(err) => Err(format!("reading {} data failed: {}", what, err)),
+        };
+        let failed = chunk.is_err();
+        if tx.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
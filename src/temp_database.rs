@@ -0,0 +1,177 @@
+use crate::{AsyncError, Database, DatabaseBuilder};
+use diesel::{result::Error as DieselError, Connection};
+use std::{
+    fmt,
+    ops::Deref,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+// Makes the names of databases created by one process unique
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections whose backend can create and drop whole databases, for
+/// `TempDatabase`.
+pub trait TempDatabaseBackend: Connection {
+    /// Create the database `name` next to the one `base_url` points to,
+    /// returning the URL to connect to it.
+    fn create_database(base_url: &str, name: &str) -> Result<String, AsyncError<DieselError>>;
+
+    /// Drop the database `name` made by `create_database`, even if
+    /// connections to it are still open.
+    fn drop_database(base_url: &str, name: &str) -> Result<(), AsyncError<DieselError>>;
+}
+
+/// A database of its own for one test, so integration tests can run in
+/// parallel without seeing each other's data.
+///
+/// `create` makes a uniquely named database on the server `base_url` points
+/// to (for SQLite, a file in the directory `base_url` names, or the system's
+/// temporary directory when it is empty) and a pool connected to it, which
+/// the `TempDatabase` derefs to. The database is dropped by `close`, or else
+/// when the `TempDatabase` is dropped; `Drop` blocks the thread while it
+/// does, so prefer `close` in async code.
+pub struct TempDatabase<Conn>
+where
+    Conn: 'static + TempDatabaseBackend,
+{
+    db: Database<Conn>,
+    base_url: String,
+    name: String,
+    url: String,
+    dropped: bool,
+}
+
+impl<Conn> TempDatabase<Conn>
+where
+    Conn: 'static + TempDatabaseBackend,
+{
+    /// Create a database next to `base_url` and a pool connected to it with
+    /// the default `PoolConfig`.
+    pub async fn create(base_url: &str) -> Result<TempDatabase<Conn>, AsyncError<DieselError>> {
+        TempDatabase::create_with(base_url, Database::builder()).await
+    }
+
+    /// Like `create`, connecting with `builder`.
+    pub async fn create_with(
+        base_url: &str,
+        builder: DatabaseBuilder<Conn>,
+    ) -> Result<TempDatabase<Conn>, AsyncError<DieselError>> {
+        let base_url = base_url.to_string();
+        let name = unique_name();
+        let url = {
+            let (base_url, name) = (base_url.clone(), name.clone());
+            task::spawn_blocking(move || Conn::create_database(&base_url, &name))
+                .await
+                .map_err(|_| AsyncError::Canceled)??
+        };
+
+        let db = match builder.connect(url.clone()).await {
+            Ok(db) => db,
+            Err(err) => {
+                let (base_url, name) = (base_url.clone(), name.clone());
+                let _ = task::spawn_blocking(move || Conn::drop_database(&base_url, &name)).await;
+                return Err(err);
+            }
+        };
+        Ok(TempDatabase {
+            db,
+            base_url,
+            name,
+            url,
+            dropped: false,
+        })
+    }
+
+    /// The pool connected to the database.
+    pub fn database(&self) -> &Database<Conn> {
+        &self.db
+    }
+
+    /// The name of the database, or of the SQLite file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The URL the pool connects to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Shut the pool down, giving in-flight calls a moment to finish, and
+    /// drop the database.
+    pub async fn close(mut self) -> Result<(), AsyncError<DieselError>> {
+        let _ = self.db.shutdown(Duration::from_secs(1)).await;
+        self.dropped = true;
+        let (base_url, name) = (self.base_url.clone(), self.name.clone());
+        task::spawn_blocking(move || Conn::drop_database(&base_url, &name))
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+    }
+}
+
+impl<Conn> Deref for TempDatabase<Conn>
+where
+    Conn: 'static + TempDatabaseBackend,
+{
+    type Target = Database<Conn>;
+
+    fn deref(&self) -> &Database<Conn> {
+        &self.db
+    }
+}
+
+impl<Conn> fmt::Debug for TempDatabase<Conn>
+where
+    Conn: 'static + TempDatabaseBackend,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TempDatabase")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<Conn> Drop for TempDatabase<Conn>
+where
+    Conn: 'static + TempDatabaseBackend,
+{
+    fn drop(&mut self) {
+        if self.dropped {
+            return;
+        }
+        self.db.close_now();
+        if let Err(err) = Conn::drop_database(&self.base_url, &self.name) {
+            log::warn!("failed to drop temporary database {}: {}", self.name, err);
+        }
+    }
+}
+
+// Lowercase, and short enough for Postgres's 63 and MySQL's 64 byte identifiers
+fn unique_name() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos());
+    format!(
+        "actix_threadpool_diesel_{}_{}_{}",
+        process::id(),
+        nanos,
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// `url` with its path, the database name, replaced by `name`
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+pub(crate) fn with_database(url: &str, name: &str) -> String {
+    let (base, query) = match url.find('?') {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let authority = base.find("://").map_or(0, |i| i + 3);
+    let path = base[authority..]
+        .find('/')
+        .map_or(base.len(), |i| authority + i);
+    format!("{}/{}{}", &base[..path], name, query)
+}
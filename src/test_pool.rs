@@ -0,0 +1,88 @@
+use crate::{AsyncConnection, AsyncError, AsyncSimpleConnection, AsyncSingleConnection};
+use async_trait::async_trait;
+use diesel::{result::Error as DieselError, Connection};
+use std::fmt;
+
+/// One connection inside a transaction that is never committed, for tests.
+///
+/// Every call runs on the same connection, in the transaction begun when the
+/// pool was created; `transaction` calls become savepoints. Nothing is ever
+/// committed, so a test leaves no data behind and tests don't see each
+/// other's. Clones share the connection and its transaction, which is rolled
+/// back when the last of them is dropped.
+pub struct TestPool<Conn> {
+    conn: AsyncSingleConnection<Conn>,
+}
+
+impl<Conn> TestPool<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    /// Connect to `database_url` and begin the test transaction.
+    pub async fn establish(database_url: &str) -> Result<TestPool<Conn>, AsyncError<DieselError>> {
+        TestPool::begin(AsyncSingleConnection::establish(database_url).await?).await
+    }
+
+    /// Begin the test transaction on `conn`.
+    pub async fn new(conn: Conn) -> Result<TestPool<Conn>, AsyncError<DieselError>> {
+        TestPool::begin(AsyncSingleConnection::new(conn)).await
+    }
+
+    async fn begin(
+        conn: AsyncSingleConnection<Conn>,
+    ) -> Result<TestPool<Conn>, AsyncError<DieselError>> {
+        conn.run(|conn| conn.begin_test_transaction()).await?;
+        Ok(TestPool { conn })
+    }
+}
+
+impl<Conn> Clone for TestPool<Conn> {
+    fn clone(&self) -> Self {
+        TestPool {
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for TestPool<Conn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestPool").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for TestPool<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        self.conn.batch_execute_async(query).await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for TestPool<Conn>
+where
+    Conn: 'static + Connection + Send,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.conn.run(f).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        self.conn.transaction(f).await
+    }
+}
@@ -0,0 +1,214 @@
+use crate::limiter::{Limiter, Priority};
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+use tokio::sync::oneshot;
+
+// Named jobs show up in `ThreadPool::running` while a worker runs them
+type Job = (Option<Arc<str>>, Box<dyn FnOnce() + Send>);
+
+/// A fixed set of threads dedicated to database work.
+///
+/// Cloning a `ThreadPool` is cheap and yields a handle to the same threads;
+/// the threads exit once every handle is dropped and the queue is drained.
+#[derive(Clone)]
+pub struct ThreadPool {
+    inner: Arc<Inner>,
+}
+
+pub struct ThreadPoolBuilder {
+    size: usize,
+    queue_size: Option<usize>,
+    thread_name_prefix: String,
+    stack_size: Option<usize>,
+}
+
+// Dropped when the last `ThreadPool` handle goes away; the workers only hold `Shared`
+struct Inner {
+    shared: Arc<Shared>,
+    slots: Option<Arc<Limiter>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+    // Indexed by worker, with the name of the job each one is running
+    running: Mutex<Vec<(String, Option<Arc<str>>)>>,
+}
+
+struct State {
+    // Indexed by `Priority as usize`
+    jobs: [VecDeque<Job>; 3],
+    shutdown: bool,
+}
+
+/// The job was dropped before it produced a value.
+#[derive(Debug)]
+pub(crate) struct Canceled;
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::builder().size(size).build()
+    }
+
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size: 4,
+            queue_size: None,
+            thread_name_prefix: "db-worker".to_string(),
+            stack_size: None,
+        }
+    }
+
+    /// The workers running a named job, with the job's name, such as the tag
+    /// of the query it runs, to find out which work is holding the threads.
+    pub fn running(&self) -> Vec<(String, Arc<str>)> {
+        let running = self.inner.shared.running.lock().unwrap();
+        running
+            .iter()
+            .filter_map(|(thread, job)| Some((thread.clone(), job.clone()?)))
+            .collect()
+    }
+
+    pub(crate) async fn spawn<F, R>(
+        &self,
+        priority: Priority,
+        name: Option<Arc<str>>,
+        f: F,
+    ) -> Result<R, Canceled>
+    where
+        F: 'static + FnOnce() -> R + Send,
+        R: 'static + Send,
+    {
+        // Wait for room in the queue; the slot is given back once a worker picks the job up
+        let slot = match self.inner.slots {
+            Some(ref slots) => Some(slots.acquire(priority).await),
+            None => None,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.push(
+            priority,
+            (
+                name,
+                Box::new(move || {
+                    drop(slot);
+                    let _ = tx.send(f());
+                }),
+            ),
+        );
+
+        rx.await.map_err(|_| Canceled)
+    }
+
+    // Queue a job without waiting for a slot, for cleanup that cannot be awaited
+    pub(crate) fn execute<F>(&self, f: F)
+    where
+        F: 'static + FnOnce() + Send,
+    {
+        self.push(Priority::High, (None, Box::new(f)));
+    }
+
+    fn push(&self, priority: Priority, job: Job) {
+        {
+            let mut state = self.inner.shared.state.lock().unwrap();
+            state.jobs[priority as usize].push_back(job);
+        }
+        self.inner.shared.available.notify_one();
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Number of worker threads (default 4).
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        assert!(size > 0, "thread pool size must be positive");
+        self.size = size;
+        self
+    }
+
+    /// Maximum number of jobs waiting for a worker; callers beyond that wait
+    /// asynchronously for a free slot (default unbounded).
+    pub fn queue_size(mut self, queue_size: usize) -> ThreadPoolBuilder {
+        assert!(queue_size > 0, "thread pool queue size must be positive");
+        self.queue_size = Some(queue_size);
+        self
+    }
+
+    /// Workers are named `{prefix}-{n}` (default `db-worker`).
+    pub fn thread_name_prefix<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.thread_name_prefix = prefix.into();
+        self
+    }
+
+    /// Stack size of each worker in bytes (default: the platform default).
+    pub fn stack_size(mut self, stack_size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn build(self) -> ThreadPool {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: Default::default(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            running: Mutex::new(
+                (0..self.size)
+                    .map(|n| (format!("{}-{}", self.thread_name_prefix, n), None))
+                    .collect(),
+            ),
+        });
+
+        for n in 0..self.size {
+            let shared = shared.clone();
+            let name = shared.running.lock().unwrap()[n].0.clone();
+            let mut builder = thread::Builder::new().name(name);
+            if let Some(stack_size) = self.stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            builder
+                .spawn(move || work(&shared, n))
+                .expect("failed to spawn thread pool worker");
+        }
+
+        ThreadPool {
+            inner: Arc::new(Inner {
+                shared,
+                slots: self.queue_size.map(Limiter::new),
+            }),
+        }
+    }
+}
+
+fn work(shared: &Shared, n: usize) {
+    loop {
+        let (name, job) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(job) = state.jobs.iter_mut().rev().find_map(VecDeque::pop_front) {
+                    break job;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = shared.available.wait(state).unwrap();
+            }
+        };
+
+        shared.running.lock().unwrap()[n].1 = name;
+        // A panicking job drops its result sender, which the caller sees as `Canceled`
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        shared.running.lock().unwrap()[n].1 = None;
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+    }
+}
@@ -0,0 +1,105 @@
+use crate::AsyncError;
+use std::{fmt, future::Future, sync::Arc};
+#[cfg(feature = "tracing")]
+use {
+    crate::classify,
+    std::time::Instant,
+    tracing::{field, Instrument},
+};
+
+// Runs `fut`; with the `tracing` feature, in a span for `op` that records how long it
+// took, the rows `rows` counts in its result and the class of its error
+pub(crate) async fn instrument<T, E, F>(
+    op: &'static str,
+    rows: fn(&T) -> Option<usize>,
+    fut: F,
+) -> Result<T, AsyncError<E>>
+where
+    E: 'static + fmt::Debug,
+    F: Future<Output = Result<T, AsyncError<E>>>,
+{
+    #[cfg(feature = "tracing")]
+    {
+        let statement = statement();
+        let span = tracing::debug_span!(
+            "db",
+            op,
+            statement = statement.as_deref().map(redact_binds),
+            elapsed_ms = field::Empty,
+            rows = field::Empty,
+            error.class = field::Empty,
+        );
+        let start = Instant::now();
+        let result = fut.instrument(span.clone()).await;
+
+        span.record("elapsed_ms", start.elapsed().as_secs_f64() * 1000.0);
+        match result {
+            Ok(ref value) => {
+                if let Some(rows) = rows(value) {
+                    span.record("rows", rows);
+                }
+            }
+            Err(ref err) => {
+                span.record("error.class", field::debug(classify::class_of(err)));
+            }
+        }
+        result
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (op, rows);
+        fut.await
+    }
+}
+
+tokio::task_local! {
+    // The SQL being run, when known, for the slow query log and spans
+    static STATEMENT: Arc<str>;
+
+    // The tag of the call being made, naming its tasks and audit events
+    static TAG: Arc<str>;
+}
+
+/// Run `fut` with `sql` as the statement of the `run`, `transaction` and
+/// `*_async` calls it makes, for the slow query log, interceptors and spans.
+///
+/// Pass a query's `debug_query` to have diesel render its SQL, with the bind
+/// values appended. Those are always dropped from `tracing` spans, and
+/// elsewhere unless the database was built with
+/// `DatabaseBuilder::redact_bind_values(false)`.
+pub async fn with_sql<S, F>(sql: S, fut: F) -> F::Output
+where
+    S: fmt::Display,
+    F: Future,
+{
+    STATEMENT.scope(sql.to_string().into(), fut).await
+}
+
+pub(crate) fn statement() -> Option<Arc<str>> {
+    STATEMENT.try_with(Clone::clone).ok()
+}
+
+/// Run `fut` with `tag` naming the calls it makes, as `Database::run_tagged`
+/// does for its own call, in blocking task names and audit events.
+pub async fn with_tag<F, T>(tag: T, fut: F) -> F::Output
+where
+    F: Future,
+    T: Into<Arc<str>>,
+{
+    TAG.scope(tag.into(), fut).await
+}
+
+pub(crate) fn tag() -> Option<Arc<str>> {
+    TAG.try_with(Clone::clone).ok()
+}
+
+// Drops the bind values `debug_query` appends to the SQL
+pub(crate) fn redact_binds(sql: &str) -> &str {
+    sql.split(" -- binds:").next().unwrap_or(sql)
+}
+
+// For operations whose result says nothing about rows
+pub(crate) fn no_rows<T>(_: &T) -> Option<usize> {
+    None
+}
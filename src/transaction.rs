@@ -0,0 +1,374 @@
+use crate::{
+    audit, checkout::Checkout, database::InFlight, deadline, limiter::Priority, run_guarded, trace,
+    writer::WriteLock, AsyncConnection, AsyncError, AsyncSimpleConnection, Database,
+};
+use async_trait::async_trait;
+use diesel::{connection::TransactionManager, result::Error as DieselError, Connection};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// A transaction held open across awaits on one pooled connection.
+///
+/// Created by `Database::begin`. Every `run` executes on the same
+/// connection; nested `transaction` calls become savepoints. The transaction
+/// is rolled back if the handle is dropped without calling `commit`.
+pub struct AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    db: Database<Conn>,
+    state: Option<Arc<TxState<Conn>>>,
+    in_flight: Option<InFlight>,
+    // The writer's turn, held until the transaction has ended on a single-writer database
+    write_lock: Option<WriteLock<Conn>>,
+}
+
+/// A savepoint within an `AsyncTransaction`, created by `savepoint`.
+///
+/// Savepoints nest: only the innermost one may be released or rolled back.
+/// Dropping it without doing either rolls back to it.
+pub struct AsyncSavepoint<'a, Conn>
+where
+    Conn: 'static + Connection,
+{
+    tx: &'a AsyncTransaction<Conn>,
+    depth: u32,
+    done: bool,
+}
+
+struct TxState<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: Mutex<TxConn<Conn>>,
+    // Depth of the outermost savepoint dropped without release or rollback (0 if none);
+    // rolled back to by the next job, so it always happens before later work
+    rollback_to: AtomicU32,
+}
+
+// Rolls the transaction back when dropped while still open; the last reference is
+// always dropped on a blocking thread
+struct TxConn<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: Checkout<Conn>,
+    open: bool,
+}
+
+impl<Conn> Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub async fn begin(&self) -> Result<AsyncTransaction<Conn>, AsyncError<DieselError>> {
+        self.begin_with(|conn| conn.transaction_manager().begin_transaction(conn))
+            .await
+    }
+
+    // Check out a connection and open a transaction on it with `begin`
+    pub(crate) async fn begin_with<Func>(
+        &self,
+        begin: Func,
+    ) -> Result<AsyncTransaction<Conn>, AsyncError<DieselError>>
+    where
+        Func: 'static + FnOnce(&Conn) -> Result<(), DieselError> + Send,
+    {
+        let write_lock = self.write_lock().await;
+        let in_flight = self.admit()?;
+        let pool = self.checkout_pool()?;
+
+        let conn = self
+            .spawn_job(Priority::Normal, move || {
+                let conn = pool.get().map_err(AsyncError::Checkout)?;
+                begin(&*conn).map_err(AsyncError::Error)?;
+                Ok(TxConn { conn, open: true })
+            })
+            .await
+            .map_err(|_| AsyncError::Canceled)??;
+
+        Ok(AsyncTransaction {
+            db: self.clone(),
+            state: Some(Arc::new(TxState {
+                conn: Mutex::new(conn),
+                rollback_to: AtomicU32::new(0),
+            })),
+            in_flight: Some(in_flight),
+            write_lock,
+        })
+    }
+}
+
+impl<Conn> AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub async fn commit(mut self) -> Result<(), AsyncError<DieselError>> {
+        let commit = async move {
+            self.db.offer_audit();
+            self.finish(|conn| conn.transaction_manager().commit_transaction(conn))
+                .await
+        };
+        audit::audited("commit", trace::no_rows, commit).await
+    }
+
+    pub async fn rollback(mut self) -> Result<(), AsyncError<DieselError>> {
+        self.finish(|conn| conn.transaction_manager().rollback_transaction(conn))
+            .await
+    }
+
+    /// Issue `SAVEPOINT`; work done after this can be undone on its own with
+    /// `AsyncSavepoint::rollback` while keeping the rest of the transaction.
+    pub async fn savepoint(&self) -> Result<AsyncSavepoint<'_, Conn>, AsyncError<DieselError>> {
+        let depth = self
+            .with_conn(|conn| {
+                let manager = conn.transaction_manager();
+                manager.begin_transaction(conn)?;
+                Ok(manager.get_transaction_depth())
+            })
+            .await?;
+
+        Ok(AsyncSavepoint {
+            tx: self,
+            depth,
+            done: false,
+        })
+    }
+
+    // Ends the transaction with `f`, which leaves diesel's transaction depth at 0 when
+    // it succeeds. A failed `COMMIT` leaves the depth as it was, so the transaction
+    // stays open to be rolled back on drop, before the connection goes back to the pool
+    pub(crate) async fn finish<Func>(&mut self, f: Func) -> Result<(), AsyncError<DieselError>>
+    where
+        Func: 'static + FnOnce(&Conn) -> Result<(), DieselError> + Send,
+    {
+        let state = self.state().clone();
+        self.db
+            .spawn_job(Priority::Normal, move || {
+                let mut tx = state.lock().map_err(AsyncError::Error)?;
+                let result = f(&*tx.conn).map_err(AsyncError::Error);
+                if result.is_ok() {
+                    tx.open = false;
+                }
+                result
+            })
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+    }
+
+    async fn with_conn<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let state = self.state().clone();
+        let start = Instant::now();
+        self.db.start_call(None)?;
+        let result = deadline::enforced(async {
+            self.db
+                .spawn_job(Priority::Normal, move || {
+                    let tx = state.lock().map_err(|e| AsyncError::Error(E::from(e)))?;
+                    if !tx.open {
+                        return Err(AsyncError::Closed);
+                    }
+                    run_guarded(&*tx.conn, f)
+                })
+                .await
+                .map_err(|_| AsyncError::Canceled)?
+        })
+        .await;
+        self.db.finish_call(start, None, &result);
+        result
+    }
+
+    fn state(&self) -> &Arc<TxState<Conn>> {
+        self.state.as_ref().expect("transaction already finished")
+    }
+}
+
+impl<'a, Conn> AsyncSavepoint<'a, Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// `RELEASE SAVEPOINT`, keeping the work done since it was created.
+    pub async fn release(mut self) -> Result<(), AsyncError<DieselError>> {
+        self.done = true;
+        let depth = self.depth;
+        self.tx
+            .with_conn(move |conn| {
+                innermost(conn, depth)?;
+                conn.transaction_manager().commit_transaction(conn)
+            })
+            .await
+    }
+
+    /// `ROLLBACK TO SAVEPOINT`, undoing the work done since it was created.
+    pub async fn rollback(mut self) -> Result<(), AsyncError<DieselError>> {
+        self.done = true;
+        let depth = self.depth;
+        self.tx
+            .with_conn(move |conn| {
+                innermost(conn, depth)?;
+                conn.transaction_manager().rollback_transaction(conn)
+            })
+            .await
+    }
+}
+
+fn innermost<Conn: Connection>(conn: &Conn, depth: u32) -> Result<(), DieselError> {
+    if conn.transaction_manager().get_transaction_depth() == depth {
+        Ok(())
+    } else {
+        Err(DieselError::QueryBuilderError(
+            "savepoint is not the innermost one".into(),
+        ))
+    }
+}
+
+impl<'a, Conn> Drop for AsyncSavepoint<'a, Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let depth = self.depth;
+        let _ = self.tx.state().rollback_to.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |pending| {
+                if pending == 0 || depth < pending {
+                    Some(depth)
+                } else {
+                    None
+                }
+            },
+        );
+    }
+}
+
+impl<Conn> TxState<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Lock the connection, first rolling back any savepoints that were dropped
+    fn lock(&self) -> Result<MutexGuard<'_, TxConn<Conn>>, DieselError> {
+        let tx = self.conn.lock().unwrap();
+        let depth = self.rollback_to.swap(0, Ordering::SeqCst);
+        if depth > 0 && tx.open {
+            // Also rolls back any savepoints still open inside it
+            let manager = tx.conn.transaction_manager();
+            while manager.get_transaction_depth() >= depth {
+                manager.rollback_transaction(&*tx.conn)?;
+            }
+        }
+        Ok(tx)
+    }
+}
+
+impl<Conn> Drop for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        let tx = self.state.take();
+        let in_flight = self.in_flight.take();
+        let write_lock = self.write_lock.take();
+        // Any rollback is blocking work; keep it off the async thread
+        self.db.spawn_detached(move || {
+            drop(tx);
+            drop(in_flight);
+            drop(write_lock);
+        });
+    }
+}
+
+impl<Conn> Drop for TxConn<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Rolls back every level diesel still counts, savepoints included; Postgres has
+    // already ended a transaction whose `COMMIT` failed, and only warns on `ROLLBACK`
+    fn drop(&mut self) {
+        if self.open {
+            let manager = self.conn.transaction_manager();
+            while manager.get_transaction_depth() > 0 {
+                if let Err(err) = manager.rollback_transaction(&*self.conn) {
+                    log::warn!("rolling back a dropped transaction failed: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> Result<(), AsyncError<DieselError>> {
+        let sql = query.to_string();
+        trace::instrument(
+            "batch_execute",
+            trace::no_rows,
+            trace::with_sql(query, self.with_conn(move |conn| conn.batch_execute(&sql))),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        trace::instrument("run", trace::no_rows, self.with_conn(f)).await
+    }
+
+    #[inline]
+    async fn transaction<R, E, Func>(&self, f: Func) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let transaction = trace::instrument(
+            "transaction",
+            trace::no_rows,
+            self.with_conn(|conn| conn.transaction::<R, E, _>(|| f(conn))),
+        );
+        audit::audited("transaction", trace::no_rows, transaction).await
+    }
+
+    // As a deadline, so the timeout is enforced inside the call and counted in its stats
+    async fn run_with_timeout<R, E, Func>(
+        &self,
+        timeout: Duration,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + From<DieselError> + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Conn) -> Result<R, E> + Send,
+    {
+        let call = deadline::with_deadline(timeout, self.with_conn(f));
+        trace::instrument("run", trace::no_rows, call).await
+    }
+}
@@ -0,0 +1,22 @@
+use diesel::{result::QueryResult, Connection};
+
+// Where diesel records the migrations it ran, which is kept like the schema itself
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+const MIGRATIONS_TABLE: &str = "__diesel_schema_migrations";
+
+/// Connections that can empty every table of their database at once, for
+/// `AsyncConnection::truncate_all`.
+pub trait TruncateTables: Connection {
+    /// Empty every table except those named in `except` and diesel's
+    /// `__diesel_schema_migrations`, returning the tables emptied.
+    fn truncate_all(&self, except: &[&str]) -> QueryResult<Vec<String>>;
+}
+
+// Whether the table `schema.table` is in `except`, by its name or its qualified name
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+pub(crate) fn is_kept(except: &[&str], schema: &str, table: &str) -> bool {
+    table == MIGRATIONS_TABLE
+        || except
+            .iter()
+            .any(|name| *name == table || name.split_once('.') == Some((schema, table)))
+}
@@ -0,0 +1,121 @@
+use crate::{checkout::Checkout, deadline, limiter::Priority, run_guarded, AsyncError, Database};
+use diesel::{connection::TransactionManager, Connection};
+use std::{
+    fmt,
+    future::Future,
+    sync::{self, Arc},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+tokio::task_local! {
+    // Set for the calls that only read, which a single-writer database runs on the pool
+    static READ: ();
+}
+
+// The one connection a single-writer database runs every call but reads on: checked
+// out of the pool by the first write and kept until shutdown. Writes wait their turn
+// for it asynchronously, so they never contend for the database's write lock
+pub(crate) struct Writer<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: Arc<Mutex<Option<Checkout<Conn>>>>,
+}
+
+impl<Conn> Writer<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new() -> Writer<Conn> {
+        Writer {
+            conn: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // The connection, if a write checked it out and no write is using it
+    pub(crate) fn take(&self) -> Option<Checkout<Conn>> {
+        self.conn.try_lock().ok()?.take()
+    }
+
+    // Waits for the writer's turn, held by a transaction or guard on a connection of its
+    // own so no write runs on the writer meanwhile
+    pub(crate) async fn lock(&self) -> WriteLock<Conn> {
+        WriteLock {
+            _turn: sync::Mutex::new(self.conn.clone().lock_owned().await),
+        }
+    }
+}
+
+// Holds the writer's turn until dropped. The guard sits behind a mutex of its own so the
+// lock is Sync, as the transactions and guards holding it must be, while the connection
+// it guards is only Send
+pub(crate) struct WriteLock<Conn>
+where
+    Conn: 'static + Connection,
+{
+    _turn: sync::Mutex<OwnedMutexGuard<Option<Checkout<Conn>>>>,
+}
+
+// Runs `fut` as a read, whose calls a single-writer database runs on the pool
+pub(crate) async fn read<F: Future>(fut: F) -> F::Output {
+    READ.scope((), fut).await
+}
+
+pub(crate) fn is_read() -> bool {
+    READ.try_with(|_| ()).is_ok()
+}
+
+impl<Conn> Clone for Writer<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        Writer {
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<Conn> Database<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) async fn run_on_writer<R, E, Func>(
+        &self,
+        writer: Writer<Conn>,
+        priority: Priority,
+        f: Func,
+    ) -> Result<R, AsyncError<E>>
+    where
+        R: 'static + Send,
+        E: 'static + fmt::Debug + Send,
+        Func: 'static + FnOnce(&Checkout<Conn>) -> Result<R, E> + Send,
+    {
+        let in_flight = self.admit()?;
+        let pool = self.checkout_pool()?;
+        deadline::enforced(async {
+            let mut conn = writer.conn.lock_owned().await;
+            self.spawn_job(priority, move || {
+                let _in_flight = in_flight;
+                if conn.is_none() {
+                    *conn = Some(pool.get().map_err(AsyncError::Checkout)?);
+                }
+                let checkout = conn.as_ref().unwrap();
+                let result = run_guarded(&**checkout, |_| f(checkout));
+                // A call that left a transaction open can't share the connection;
+                // dropping the checkout rolls it back
+                let open = conn.as_ref().is_some_and(|conn| {
+                    TransactionManager::<Conn>::get_transaction_depth(conn.transaction_manager())
+                        > 0
+                });
+                if open {
+                    *conn = None;
+                }
+                result
+            })
+            .await
+            .map_err(|_| AsyncError::Canceled)?
+        })
+        .await
+    }
+}
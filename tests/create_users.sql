@@ -1 +1 @@
-CREATE table users (id uuid);
+CREATE TABLE IF NOT EXISTS users (id uuid);
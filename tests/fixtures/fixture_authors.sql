@@ -0,0 +1 @@
+INSERT INTO fixture_authors (id, name) VALUES ('1d7c3a0e-5b4f-4c2e-8a9d-0e6f2b1c3d02', 'O''Brien');
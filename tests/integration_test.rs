@@ -1,3 +1,6 @@
+// diesel 1.x derives (used by `table!`) emit non-local impls
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 
@@ -7,7 +10,15 @@ use diesel::{
     r2d2::{ConnectionManager, Pool},
     sql_query,
 };
-use std::error::Error;
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use uuid::Uuid;
 
 // Schema
@@ -17,8 +28,15 @@ table! {
     }
 }
 
-#[actix_rt::test]
-async fn test_db_ops() -> Result<(), Box<dyn Error>> {
+#[cfg(feature = "mysql")]
+table! {
+    mysql_items (id) {
+        id -> Unsigned<BigInt>,
+        name -> Text,
+    }
+}
+
+async fn setup() -> Result<Pool<ConnectionManager<PgConnection>>, Box<dyn Error>> {
     let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
     let pool = Pool::builder().build(manager)?;
 
@@ -26,6 +44,13 @@ async fn test_db_ops() -> Result<(), Box<dyn Error>> {
         .execute_async(&pool)
         .await;
 
+    Ok(pool)
+}
+
+#[tokio::test]
+async fn test_db_ops() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+
     // Add
     println!("add a user");
     diesel::insert_into(users::table)
@@ -41,3 +66,4153 @@ async fn test_db_ops() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_current_thread_runtime() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+
+    pool.batch_execute_async("SELECT 1").await?;
+
+    let num_users: i64 = pool
+        .run(|conn| users::table.count().get_result(conn))
+        .await?;
+    assert!(num_users >= 0);
+
+    let inserted = pool
+        .transaction(|conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(Uuid::new_v4()))
+                .execute(conn)
+        })
+        .await?;
+    assert_eq!(inserted, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dedicated_thread_pool() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let db = Database::builder()
+        .thread_pool(ThreadPool::builder().size(1).queue_size(1).build())
+        .build(pool);
+
+    let tasks: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                diesel::insert_into(users::table)
+                    .values(users::id.eq(Uuid::new_v4()))
+                    .execute_async(&db)
+                    .await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        assert_eq!(task.await??, 1);
+    }
+
+    let num_users: i64 = users::table.count().get_result_async(&db).await?;
+    assert!(num_users >= 4);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_max_concurrent_queries() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let db = Database::builder().max_concurrent_queries(2).build(pool);
+
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let db = db.clone();
+            let running = running.clone();
+            let peak = peak.clone();
+            tokio::spawn(async move {
+                db.run(move |conn| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    let result = sql_query("SELECT pg_sleep(0.02)").execute(conn);
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    result
+                })
+                .await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await??;
+    }
+
+    assert!(peak.load(Ordering::SeqCst) <= 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_with_timeout() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+
+    let result = pool
+        .run_with_timeout(Duration::from_millis(50), |conn| {
+            sql_query("SELECT pg_sleep(1)").execute(conn)
+        })
+        .await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+
+    let inserted = diesel::insert_into(users::table)
+        .values(users::id.eq(Uuid::new_v4()))
+        .execute_async_timeout(&pool, Duration::from_secs(5))
+        .await?;
+    assert_eq!(inserted, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_database_run_with_timeout_counted() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+
+    let result = db
+        .run_with_timeout(Duration::from_millis(50), |conn| {
+            sql_query("SELECT pg_sleep(1)").execute(conn)
+        })
+        .await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+    assert_eq!(db.stats().errors.timeout, 1);
+
+    let done = db
+        .run_with_timeout(Duration::from_secs(5), |conn| {
+            sql_query("SELECT 1").execute(conn)
+        })
+        .await?;
+    assert_eq!(done, 1);
+    assert_eq!(db.stats().errors.timeout, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_held_run_with_timeout_counted() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let slow = |conn: &PgConnection| sql_query("SELECT pg_sleep(1)").execute(conn);
+
+    let conn = db.acquire().await?;
+    let result = conn.run_with_timeout(Duration::from_millis(50), slow).await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+    assert_eq!(db.stats().errors.timeout, 1);
+    drop(conn);
+
+    let tx = db.begin().await?;
+    let result = tx.run_with_timeout(Duration::from_millis(50), slow).await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+    assert_eq!(db.stats().errors.timeout, 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_run_with_statement_timeout() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let db = Database::new(setup().await?);
+    let conn = db.acquire().await?;
+    let setting = || diesel::select(sql::<Text>("current_setting('statement_timeout')"));
+
+    let err = conn
+        .run_with_statement_timeout(Duration::from_millis(20), |conn| {
+            sql_query("SELECT pg_sleep(1)").execute(conn)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::Timeout);
+    assert_eq!(setting().get_result_async::<String>(&conn).await?, "0");
+
+    let limit = conn
+        .run_with_statement_timeout(Duration::from_secs(5), move |conn| {
+            setting().get_result::<String>(conn)
+        })
+        .await?;
+    assert_eq!(limit, "5s");
+    drop(conn);
+
+    // Inside a transaction, the limit ends with the call too
+    let tx = db.begin().await?;
+    tx.run_with_statement_timeout(Duration::from_secs(5), |conn| {
+        sql_query("SELECT 1").execute(conn)
+    })
+    .await?;
+    assert_eq!(setting().get_result_async::<String>(&tx).await?, "0");
+    tx.rollback().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_panic_in_transaction() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = Pool::builder().max_size(1).build(manager)?;
+
+    let id = Uuid::new_v4();
+    let result = pool
+        .transaction(move |conn| -> QueryResult<()> {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(id))
+                .execute(conn)?;
+            panic!("boom");
+        })
+        .await;
+    match result {
+        Err(AsyncError::Panicked(msg)) => assert_eq!(msg, "boom"),
+        other => panic!("expected a panic error, got {:?}", other),
+    }
+
+    // The only connection in the pool must have been rolled back
+    let found: i64 = users::table
+        .filter(users::id.eq(id))
+        .count()
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(found, 0);
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_run_cancellable() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    let db = Database::new(setup().await?);
+
+    // Later calls on a connection reuse the backend PID read by the first
+    for _ in 0..2 {
+        let rows = db
+            .run_cancellable(|conn| sql_query("SELECT 1").execute(conn))
+            .await?;
+        assert_eq!(rows, 1);
+    }
+
+    let slow = db.run_cancellable(|conn| sql_query("SELECT pg_sleep(5.123)").execute(conn));
+    assert!(tokio::time::timeout(Duration::from_millis(200), slow)
+        .await
+        .is_err());
+
+    for _ in 0..50 {
+        let active: i64 = diesel::select(sql::<BigInt>(
+            "(SELECT count(*) FROM pg_stat_activity \
+             WHERE state = 'active' AND query = 'SELECT pg_sleep(5.123)')",
+        ))
+        .get_result_async(&db)
+        .await?;
+        if active == 0 {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("the dropped query is still running")
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_run_cancellable_queued() -> Result<(), Box<dyn Error>> {
+    use std::sync::atomic::AtomicBool;
+
+    let db = Database::<PgConnection>::builder()
+        .thread_pool(ThreadPool::new(1))
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    let busy = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.run(|conn| sql_query("SELECT pg_sleep(0.3)").execute(conn))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Dropped while queued behind it, so it never starts
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = ran.clone();
+    let queued = db.run_cancellable(move |_| {
+        flag.store(true, Ordering::SeqCst);
+        Ok::<_, diesel::result::Error>(())
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(50), queued)
+        .await
+        .is_err());
+
+    busy.await??;
+    db.run(|conn| sql_query("SELECT 1").execute(conn)).await?;
+    assert!(!ran.load(Ordering::SeqCst));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_listen() -> Result<(), Box<dyn Error>> {
+    use futures::StreamExt;
+
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+    let mut notifications = db.listen("cache_events").await?;
+
+    db.batch_execute_async("NOTIFY cache_events, 'users:1'")
+        .await?;
+    db.batch_execute_async("NOTIFY other_events, 'ignored'")
+        .await?;
+    db.batch_execute_async("SELECT pg_notify('cache_events', 'users:2')")
+        .await?;
+
+    let mut payloads = Vec::new();
+    for _ in 0..2 {
+        let notification = tokio::time::timeout(Duration::from_secs(5), notifications.next())
+            .await?
+            .ok_or("notifications ended")?;
+        assert_eq!(notification.channel, "cache_events");
+        payloads.push(notification.payload);
+    }
+    assert_eq!(payloads, ["users:1", "users:2"]);
+
+    // Without a URL there is nothing to listen with
+    let db = Database::new(setup().await?);
+    assert!(matches!(
+        db.listen("cache_events").await,
+        Err(AsyncError::Connect(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_advisory_lock() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let key = 0x5eed_1e55;
+
+    let held = db.advisory_lock(key).await?;
+    assert_eq!(held.key(), key);
+    assert!(db.try_advisory_lock(key).await?.is_none());
+
+    // A waiter that gives up releases the lock once it finally gets it
+    let waiter = with_deadline(Duration::from_millis(100), db.advisory_lock(key)).await;
+    assert!(matches!(waiter, Err(AsyncError::Timeout)));
+    held.unlock().await?;
+
+    // Dropping the guard releases the lock in the background
+    let mut guard = None;
+    for _ in 0..50 {
+        guard = db.try_advisory_lock(key).await?;
+        if guard.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    drop(guard.ok_or("lock never released")?);
+    for _ in 0..50 {
+        if let Some(guard) = db.try_advisory_lock(key).await? {
+            return Ok(guard.unlock().await?);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("dropped guard never released the lock")
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_copy_in() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+
+    let ids: Vec<_> = (0..1000).map(|_| Uuid::new_v4()).collect();
+    let csv: String = ids.iter().map(|id| format!("{}\n", id)).collect();
+    let copied = db
+        .copy_in_async(
+            "COPY users (id) FROM STDIN WITH (FORMAT csv)",
+            csv.as_bytes(),
+        )
+        .await?;
+    assert_eq!(copied, 1000);
+    let found: i64 = users::table
+        .filter(users::id.eq_any(ids))
+        .count()
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(found, 1000);
+
+    // Bad data fails the whole copy
+    let id = Uuid::new_v4();
+    let csv = format!("{}\nnot-a-uuid\n", id);
+    let result = db
+        .copy_in_async(
+            "COPY users (id) FROM STDIN WITH (FORMAT csv)",
+            csv.as_bytes(),
+        )
+        .await;
+    assert!(matches!(result, Err(AsyncError::Error(_))));
+    let found: i64 = users::table
+        .filter(users::id.eq(id))
+        .count()
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(found, 0);
+
+    // So does failing to read the data
+    struct Broken;
+
+    impl tokio::io::AsyncRead for Broken {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::other("disk on fire")))
+        }
+    }
+
+    let result = db
+        .copy_in_async("COPY users (id) FROM STDIN WITH (FORMAT csv)", Broken)
+        .await;
+    assert!(
+        matches!(result, Err(AsyncError::Error(ref err)) if err.to_string().contains("disk on fire"))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_copy_out() -> Result<(), Box<dyn Error>> {
+    use futures::TryStreamExt;
+
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+    let mut ids: Vec<_> = (0..3).map(|_| Uuid::new_v4()).collect();
+    ids.sort();
+    diesel::insert_into(users::table)
+        .values(ids.iter().map(|id| users::id.eq(*id)).collect::<Vec<_>>())
+        .execute_async(&db)
+        .await?;
+
+    let list: Vec<_> = ids.iter().map(|id| format!("'{}'", id)).collect();
+    let sql = format!(
+        "COPY (SELECT id FROM users WHERE id IN ({}) ORDER BY id) TO STDOUT",
+        list.join(", ")
+    );
+    let rows: Vec<_> = db.copy_out_async(&sql).try_collect().await?;
+    let expected: Vec<_> = ids.iter().map(|id| format!("{}\n", id)).collect();
+    assert_eq!(rows, expected);
+
+    let result: Result<Vec<_>, _> = db
+        .copy_out_async("COPY no_such_table TO STDOUT")
+        .try_collect()
+        .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_large_object() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let db = Database::new(setup().await?);
+    // Spans several chunks
+    let blob: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+
+    let tx = db.begin().await?;
+    let oid = tx.create_large_object().await?;
+    let mut writer = tx.write_large_object(oid).await?;
+    writer.write_all(&blob).await?;
+    writer.shutdown().await?;
+    drop(writer);
+    tx.commit().await?;
+
+    let tx = db.begin().await?;
+    let mut read = Vec::new();
+    tx.read_large_object(oid)
+        .await?
+        .read_to_end(&mut read)
+        .await?;
+    assert_eq!(read, blob);
+    tx.unlink_large_object(oid).await?;
+    tx.commit().await?;
+
+    let tx = db.begin().await?;
+    assert!(tx.read_large_object(oid).await.is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_load_cursor() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Integer};
+    use futures::{StreamExt, TryStreamExt};
+
+    let db = Database::new(setup().await?);
+    let mut ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    ids.sort();
+    diesel::insert_into(users::table)
+        .values(ids.iter().map(|id| users::id.eq(*id)).collect::<Vec<_>>())
+        .execute_async(&db)
+        .await?;
+
+    let query = users::table
+        .select(users::id)
+        .filter(users::id.eq_any(ids.clone()))
+        .order(users::id);
+    let rows: Vec<_> = db
+        .load_cursor_async::<Uuid, _>(query.clone(), 2)
+        .try_collect()
+        .await?;
+    assert_eq!(rows, ids);
+
+    // Dropping the stream early gives the connection back
+    let first: Vec<_> = db
+        .load_cursor_async::<Uuid, _>(query.clone(), 2)
+        .take(1)
+        .try_collect()
+        .await?;
+    assert_eq!(first, ids[..1]);
+    assert_eq!(query.load_async::<Uuid>(&db).await?, ids);
+
+    let mut rows = db.load_cursor_async::<i32, _>(diesel::select(sql::<Integer>("1 / 0")), 2);
+    assert!(matches!(rows.next().await, Some(Err(AsyncError::Error(_)))));
+    assert!(rows.next().await.is_none());
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_two_phase_commit() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let db = Database::new(setup().await?);
+    let count = |id: Uuid| users::table.filter(users::id.eq(id)).count();
+    let insert = |id: Uuid| diesel::insert_into(users::table).values(users::id.eq(id));
+    let enabled: String =
+        diesel::select(sql::<Text>("current_setting('max_prepared_transactions')"))
+            .get_result_async(&db)
+            .await?;
+
+    let id = Uuid::new_v4();
+    let gid = format!("test-{}", id);
+    let tx = db.begin().await?;
+    insert(id).execute_async(&tx).await?;
+    if enabled == "0" {
+        // Preparing fails and rolls back, leaving the connection usable
+        assert!(tx.prepare_transaction(&gid).await.is_err());
+        assert_eq!(count(id).get_result_async::<i64>(&db).await?, 0);
+        db.transaction(|conn| sql_query("SELECT 1").execute(conn))
+            .await?;
+        return Ok(());
+    }
+    tx.prepare_transaction(&gid).await?;
+
+    // Prepared work is invisible until committed, and survives its session
+    assert_eq!(count(id).get_result_async::<i64>(&db).await?, 0);
+    db.transaction(|conn| sql_query("SELECT 1").execute(conn))
+        .await?;
+    db.commit_prepared(&gid).await?;
+    assert_eq!(count(id).get_result_async::<i64>(&db).await?, 1);
+    assert!(db.commit_prepared(&gid).await.is_err());
+
+    let id = Uuid::new_v4();
+    let gid = format!("it's {}", id);
+    let tx = db.begin().await?;
+    insert(id).execute_async(&tx).await?;
+    tx.prepare_transaction(&gid).await?;
+    db.rollback_prepared(&gid).await?;
+    assert_eq!(count(id).get_result_async::<i64>(&db).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_run_with_priority() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let db = Database::builder().max_concurrent_queries(1).build(pool);
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let busy = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.run(|conn| sql_query("SELECT pg_sleep(0.2)").execute(conn))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut tasks = Vec::new();
+    for priority in [Priority::Low, Priority::High] {
+        let db = db.clone();
+        let order = order.clone();
+        tasks.push(tokio::spawn(async move {
+            db.run_with_priority(priority, move |_| -> QueryResult<()> {
+                order.lock().unwrap().push(priority);
+                Ok(())
+            })
+            .await
+        }));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    busy.await??;
+    for task in tasks {
+        task.await??;
+    }
+
+    assert_eq!(*order.lock().unwrap(), vec![Priority::High, Priority::Low]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+
+    let slow = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.run(|conn| sql_query("SELECT pg_sleep(0.1)").execute(conn))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    db.shutdown(Duration::from_secs(5)).await?;
+    slow.await??;
+
+    assert!(db.is_closed());
+    assert!(db.pool().is_none());
+    let result = users::table.count().get_result_async::<i64>(&db).await;
+    assert!(matches!(result, Err(AsyncError::Closed)));
+
+    let db = Database::new(setup().await?);
+    let slow = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.run(|conn| sql_query("SELECT pg_sleep(0.2)").execute(conn))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let result = db.shutdown(Duration::from_millis(10)).await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+    slow.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_thread_pool_naming() -> Result<(), Box<dyn Error>> {
+    let db = Database::builder()
+        .thread_pool(
+            ThreadPool::builder()
+                .size(1)
+                .thread_name_prefix("test-db")
+                .stack_size(4 * 1024 * 1024)
+                .build(),
+        )
+        .build(setup().await?);
+
+    let name = db
+        .run(|_| -> QueryResult<_> { Ok(std::thread::current().name().map(String::from)) })
+        .await?;
+    assert_eq!(name.as_deref(), Some("test-db-0"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_thread_pool_running() -> Result<(), Box<dyn Error>> {
+    let pool = ThreadPool::builder()
+        .size(2)
+        .thread_name_prefix("test-db")
+        .build();
+    let db = Database::builder()
+        .thread_pool(pool.clone())
+        .build(setup().await?);
+
+    let workers = pool.clone();
+    let running = db
+        .run_tagged("nightly report", move |_| -> QueryResult<_> {
+            Ok(workers.running())
+        })
+        .await?;
+    assert_eq!(running.len(), 1);
+    assert!(running[0].0.starts_with("test-db-"));
+    assert_eq!(&*running[0].1, "db: nightly report");
+
+    let workers = pool.clone();
+    let running = db
+        .run(move |_| -> QueryResult<_> { Ok(workers.running()) })
+        .await?;
+    assert_eq!(&*running[0].1, "db");
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-rt")]
+#[test]
+fn test_arbiter_pool() -> Result<(), Box<dyn Error>> {
+    use diesel::{connection::TransactionManager, sql_types::Integer};
+
+    #[derive(QueryableByName)]
+    struct Pid {
+        #[sql_type = "Integer"]
+        pid: i32,
+    }
+    let pid = |conn: &PgConnection| {
+        sql_query("SELECT pg_backend_pid() AS pid")
+            .get_result::<Pid>(conn)
+            .map(|row| row.pid)
+    };
+    let in_use = |pool: &Pool<ConnectionManager<PgConnection>>| {
+        let state = pool.state();
+        state.connections - state.idle_connections
+    };
+
+    actix_rt::System::new().block_on(async {
+        let pool = setup().await?;
+        let arbiters = ArbiterPool::new(2);
+        let db = Database::builder()
+            .arbiters(arbiters.clone())
+            .build(pool.clone());
+
+        // Calls made one after another run on the same arbiter and its connection
+        let first = db.run(pid).await?;
+        assert_eq!(db.run(pid).await?, first);
+        assert_eq!(in_use(&pool), 1);
+
+        // Concurrent calls are spread over the arbiters, each keeping a connection
+        let calls: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                actix_rt::spawn(async move {
+                    db.run(move |conn| {
+                        conn.execute("SELECT pg_sleep(0.05)")?;
+                        pid(conn)
+                    })
+                    .await
+                })
+            })
+            .collect();
+        let mut pids = Vec::new();
+        for call in calls {
+            pids.push(call.await??);
+        }
+        pids.sort_unstable();
+        pids.dedup();
+        assert_eq!(pids.len(), 2);
+        assert_eq!(in_use(&pool), 2);
+        assert_eq!(arbiters.queued(), vec![0, 0]);
+
+        // The hooks run around every call, though the connection is kept
+        let acquired = Arc::new(AtomicUsize::new(0));
+        let released = Arc::new(AtomicUsize::new(0));
+        let (acquired_, released_) = (acquired.clone(), released.clone());
+        let hooked = Database::builder()
+            .arbiters(ArbiterPool::new(1))
+            .on_acquire(move |_| {
+                acquired_.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .on_release(move |_| {
+                released_.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .build(pool.clone());
+        let first = hooked.run(pid).await?;
+        assert_eq!(hooked.run(pid).await?, first);
+        assert_eq!(acquired.load(Ordering::SeqCst), 2);
+        assert_eq!(released.load(Ordering::SeqCst), 2);
+        drop(hooked);
+
+        // A connection left in a transaction is rolled back and given back to the pool
+        db.run(|conn| conn.transaction_manager().begin_transaction(conn))
+            .await?;
+        let depth = db
+            .run(|conn| -> QueryResult<_> {
+                Ok(TransactionManager::<PgConnection>::get_transaction_depth(
+                    conn.transaction_manager(),
+                ))
+            })
+            .await?;
+        assert_eq!(depth, 0);
+
+        // A panicking call doesn't take its arbiter down
+        let err = db
+            .run(|_| -> QueryResult<()> { panic!("boom") })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AsyncError::Panicked(_)));
+        db.run(pid).await?;
+
+        // The arbiters close their connections once the database is dropped
+        drop(db);
+        for _ in 0..50 {
+            if in_use(&pool) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(in_use(&pool), 0);
+
+        Ok(())
+    })
+}
+
+#[tokio::test]
+async fn test_async_transaction() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let count = |id: Uuid| users::table.filter(users::id.eq(id)).count();
+
+    // Committed work is visible afterwards
+    let id = Uuid::new_v4();
+    let tx = db.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&tx)
+        .await?;
+    tokio::task::yield_now().await;
+    assert_eq!(count(id).get_result_async::<i64>(&tx).await?, 1);
+    tx.commit().await?;
+    assert_eq!(count(id).get_result_async::<i64>(&db).await?, 1);
+
+    // Rolled back work is not
+    let id = Uuid::new_v4();
+    let tx = db.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&tx)
+        .await?;
+    tx.rollback().await?;
+    assert_eq!(count(id).get_result_async::<i64>(&db).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_transaction_rollback_on_drop() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let db = Database::new(Pool::builder().max_size(1).build(manager)?);
+
+    let id = Uuid::new_v4();
+    let tx = db.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&tx)
+        .await?;
+    drop(tx);
+
+    // The only connection is handed back once the rollback has run
+    let found: i64 = users::table
+        .filter(users::id.eq(id))
+        .count()
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(found, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_transaction_failed_commit() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::SimpleConnection;
+
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let db = Database::new(Pool::builder().max_size(1).build(manager)?);
+
+    // The foreign key is only checked at `COMMIT`, which fails
+    let tx = db.begin().await?;
+    tx.batch_execute_async(
+        "CREATE TEMP TABLE parents (id int PRIMARY KEY) ON COMMIT DROP;
+         CREATE TEMP TABLE children (
+             parent_id int REFERENCES parents DEFERRABLE INITIALLY DEFERRED
+         ) ON COMMIT DROP;
+         INSERT INTO children VALUES (1);",
+    )
+    .await?;
+    assert!(tx.commit().await.is_err());
+
+    // The only connection came back out of the transaction
+    let tx = db.begin().await?;
+    tx.transaction(|conn| conn.batch_execute("SELECT 1"))
+        .await?;
+    tx.commit().await?;
+    db.transaction(|conn| conn.batch_execute("SELECT 1"))
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_savepoints() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+    let insert = |id: Uuid| diesel::insert_into(users::table).values(users::id.eq(id));
+
+    let tx = db.begin().await?;
+    insert(ids[0]).execute_async(&tx).await?;
+
+    let savepoint = tx.savepoint().await?;
+    insert(ids[1]).execute_async(&tx).await?;
+    savepoint.rollback().await?;
+
+    let savepoint = tx.savepoint().await?;
+    insert(ids[2]).execute_async(&tx).await?;
+    savepoint.release().await?;
+
+    let savepoint = tx.savepoint().await?;
+    insert(ids[3]).execute_async(&tx).await?;
+    drop(savepoint);
+
+    tx.commit().await?;
+
+    let found: Vec<Uuid> = users::table
+        .select(users::id)
+        .filter(users::id.eq_any(ids.clone()))
+        .load_async(&db)
+        .await?;
+    assert!(found.contains(&ids[0]));
+    assert!(!found.contains(&ids[1]));
+    assert!(found.contains(&ids[2]));
+    assert!(!found.contains(&ids[3]));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_transaction_builder() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let db = Database::new(setup().await?);
+    let settings = || {
+        diesel::select((
+            sql::<Text>("current_setting('transaction_isolation')"),
+            sql::<Text>("current_setting('transaction_read_only')"),
+        ))
+    };
+
+    let (isolation, read_only): (String, String) = db
+        .transaction_builder()
+        .isolation(IsolationLevel::Serializable)
+        .read_only()
+        .run(move |conn| settings().get_result(conn))
+        .await?;
+    assert_eq!(isolation, "serializable");
+    assert_eq!(read_only, "on");
+
+    let tx = db
+        .transaction_builder()
+        .isolation(IsolationLevel::RepeatableRead)
+        .begin()
+        .await?;
+    let (isolation, read_only): (String, String) = settings().get_result_async(&tx).await?;
+    assert_eq!(isolation, "repeatable read");
+    assert_eq!(read_only, "off");
+    tx.commit().await?;
+
+    let result = db
+        .transaction_builder()
+        .read_only()
+        .run(|conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(Uuid::new_v4()))
+                .execute(conn)
+        })
+        .await;
+    assert!(matches!(result, Err(AsyncError::Error(_))));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_defer_constraints() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::SimpleConnection;
+
+    let db = Database::new(setup().await?);
+    // The child row is inserted before its parent, which only passes a deferred check
+    let insert_cycle = |conn: &PgConnection| {
+        conn.batch_execute(
+            "CREATE TEMP TABLE parents (id int PRIMARY KEY) ON COMMIT DROP;
+             CREATE TEMP TABLE children (
+                 parent_id int REFERENCES parents DEFERRABLE INITIALLY IMMEDIATE
+             ) ON COMMIT DROP;
+             INSERT INTO children VALUES (1);
+             INSERT INTO parents VALUES (1);",
+        )
+    };
+
+    db.transaction_builder()
+        .defer_constraints()
+        .run(insert_cycle)
+        .await?;
+
+    let tx = db.transaction_builder().defer_constraints().begin().await?;
+    tx.run(insert_cycle).await?;
+    tx.commit().await?;
+
+    let result = db.transaction_builder().run(insert_cycle).await;
+    assert!(matches!(result, Err(AsyncError::Error(_))));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_defer_constraints_violated() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::SimpleConnection;
+
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let db = Database::new(Pool::builder().max_size(1).build(manager)?);
+    // The child row never gets its parent, which the deferred check finds at `COMMIT`
+    let insert_orphan = |conn: &PgConnection| {
+        conn.batch_execute(
+            "CREATE TEMP TABLE parents (id int PRIMARY KEY) ON COMMIT DROP;
+             CREATE TEMP TABLE children (
+                 parent_id int REFERENCES parents DEFERRABLE INITIALLY IMMEDIATE
+             ) ON COMMIT DROP;
+             INSERT INTO children VALUES (1);",
+        )
+    };
+
+    let tx = db.transaction_builder().defer_constraints().begin().await?;
+    tx.run(insert_orphan).await?;
+    let err = tx.commit().await.unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::ForeignKeyViolation);
+
+    let err = db
+        .transaction_builder()
+        .defer_constraints()
+        .run(insert_orphan)
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::ForeignKeyViolation);
+
+    // The pool's only connection is still usable for transactions
+    let tx = db.begin().await?;
+    tx.commit().await?;
+    db.transaction(|conn| conn.batch_execute("SELECT 1"))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_transaction_serializable_retry() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let id = Uuid::new_v4();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let count = move || users::table.filter(users::id.eq(id)).count();
+
+    let counter = attempts.clone();
+    db.transaction_serializable(3, move |conn| {
+        let seen: i64 = count().get_result(conn)?;
+
+        // On the first attempt a concurrent serializable transaction makes the same
+        // decision and commits first, so this one has to fail
+        if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+            let side = PgConnection::establish("postgres://postgres@localhost").unwrap();
+            side.build_transaction().serializable().run(|| {
+                count().get_result::<i64>(&side)?;
+                diesel::insert_into(users::table)
+                    .values(users::id.eq(id))
+                    .execute(&side)
+            })?;
+        }
+
+        if seen == 0 {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(id))
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(count().get_result_async::<i64>(&db).await?, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acquire_reuses_connection() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let db = Database::new(setup().await?);
+    let conn = db.acquire().await?;
+
+    conn.batch_execute_async("SET application_name = 'guard_test'")
+        .await?;
+    tokio::task::yield_now().await;
+
+    let name: String = diesel::select(sql::<Text>("current_setting('application_name')"))
+        .get_result_async(&conn)
+        .await?;
+    assert_eq!(name, "guard_test");
+
+    conn.batch_execute_async("RESET application_name").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_with_pinned() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let db = Database::new(setup().await?);
+    let application_name = || diesel::select(sql::<Text>("current_setting('application_name')"));
+
+    let name = db
+        .with_pinned(async {
+            db.batch_execute_async("SET application_name = 'pinned_test'")
+                .await?;
+            let name: String = application_name().get_result_async(&db).await?;
+            db.batch_execute_async("RESET application_name").await?;
+            Ok::<_, AsyncError<diesel::result::Error>>(name)
+        })
+        .await??;
+    assert_eq!(name, "pinned_test");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_batch() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+
+    type Query = Box<dyn FnOnce(&PgConnection) -> QueryResult<i64> + Send>;
+    let queries: Vec<Query> = vec![
+        Box::new(move |conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(id))
+                .execute(conn)
+                .map(|n| n as i64)
+        }),
+        Box::new(move |conn| {
+            users::table
+                .filter(users::id.eq(id))
+                .count()
+                .get_result(conn)
+        }),
+        Box::new(|conn| {
+            sql_query("SELECT * FROM no_such_table")
+                .execute(conn)
+                .map(|n| n as i64)
+        }),
+    ];
+
+    let results = pool.run_batch(queries).await?;
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().ok(), Some(&1));
+    assert_eq!(results[1].as_ref().ok(), Some(&1));
+    assert!(results[2].is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_isolated() -> Result<(), Box<dyn Error>> {
+    use diesel::{connection::SimpleConnection, dsl::sql, sql_types::Integer};
+
+    setup().await?;
+    let url = "postgres://postgres@localhost";
+    let manager = ConnectionManager::<PgConnection>::new(url);
+    let db = Database::builder()
+        .database_url(url)
+        .build(Pool::builder().max_size(1).build(manager)?);
+    let backend_pid = || diesel::select(sql::<Integer>("pg_backend_pid()"));
+
+    let pooled: i32 = backend_pid().get_result_async(&db).await?;
+    let isolated: i32 = db
+        .run_isolated(move |conn| backend_pid().get_result(conn))
+        .await?;
+    assert_ne!(pooled, isolated);
+
+    let name = format!("isolated_{}", Uuid::new_v4().to_simple());
+    db.run_isolated(move |conn| {
+        conn.batch_execute(&format!("CREATE DATABASE {}", name))?;
+        conn.batch_execute(&format!("DROP DATABASE {}", name))
+    })
+    .await?;
+
+    let db = Database::new(db.pool().unwrap());
+    let result = db.run_isolated(|_| -> QueryResult<()> { Ok(()) }).await;
+    assert!(matches!(result, Err(AsyncError::Connect(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_with_raw() -> Result<(), Box<dyn Error>> {
+    #[derive(Debug, PartialEq)]
+    enum ReportError {
+        Query,
+        Empty,
+    }
+
+    let db = Database::new(setup().await?);
+
+    // `build_transaction` only exists on `PgConnection`
+    let count = db
+        .with_raw(|conn: &PgConnection| {
+            conn.build_transaction()
+                .read_only()
+                .run(|| users::table.count().get_result::<i64>(conn))
+                .map_err(|_| ReportError::Query)
+        })
+        .await
+        .unwrap();
+    assert!(count >= 0);
+
+    let result = db.with_raw(|_| Err::<(), _>(ReportError::Empty)).await;
+    assert!(matches!(result, Err(AsyncError::Error(ReportError::Empty))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_chunked_async() -> Result<(), Box<dyn Error>> {
+    use futures::StreamExt;
+
+    let pool = setup().await?;
+    let mut ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    let values: Vec<_> = ids.iter().map(|id| users::id.eq(*id)).collect();
+    diesel::insert_into(users::table)
+        .values(values)
+        .execute_async(&pool)
+        .await?;
+    ids.sort();
+
+    let mut chunks = users::table
+        .select(users::id)
+        .filter(users::id.eq_any(ids.clone()))
+        .order(users::id)
+        .load_chunked_async::<Uuid>(&pool, 2);
+    let mut sizes = Vec::new();
+    let mut seen = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        sizes.push(chunk.len());
+        seen.extend(chunk);
+    }
+    assert_eq!(sizes, vec![2, 2, 1]);
+    assert_eq!(seen, ids);
+
+    let mut chunks = users::table
+        .select(users::id)
+        .load_chunked_async::<Uuid>(&pool, 0);
+    let err = chunks.next().await.unwrap().unwrap_err();
+    assert!(matches!(
+        err,
+        AsyncError::Error(diesel::result::Error::QueryBuilderError(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_paginate() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let mut ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    let values: Vec<_> = ids.iter().map(|id| users::id.eq(*id)).collect();
+    diesel::insert_into(users::table)
+        .values(values)
+        .execute_async(&pool)
+        .await?;
+    ids.sort();
+
+    let query = || {
+        users::table
+            .select(users::id)
+            .filter(users::id.eq_any(ids.clone()))
+            .order(users::id)
+    };
+
+    let page: Page<Uuid> = query().paginate(2, 2).load_page_async(&pool).await?;
+    assert_eq!(page.items, ids[2..4].to_vec());
+    assert_eq!(page.total, 5);
+    assert_eq!(page.total_pages(), 3);
+    assert!(page.has_next());
+
+    let page: Page<Uuid> = query().paginate(4, 2).load_page_async(&pool).await?;
+    assert!(page.items.is_empty());
+    assert_eq!(page.total, 5);
+    assert!(!page.has_next());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keyset_pagination() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let mut ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    let values: Vec<_> = ids.iter().map(|id| users::id.eq(*id)).collect();
+    diesel::insert_into(users::table)
+        .values(values)
+        .execute_async(&pool)
+        .await?;
+    ids.sort();
+
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let page: KeysetPage<Uuid> = users::table
+            .select(users::id)
+            .filter(users::id.eq_any(ids.clone()))
+            .keyset(users::id)
+            .after(cursor)
+            .limit(2)
+            .load_keyset_async(&pool, |id: &Uuid| *id)
+            .await?;
+        pages += 1;
+        seen.extend(page.items);
+        cursor = page.next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(seen, ids);
+
+    let result: Result<KeysetPage<Uuid>, _> = users::table
+        .select(users::id)
+        .keyset(users::id)
+        .after(Some(Cursor::from("not hex".to_string())))
+        .load_keyset_async(&pool, |id: &Uuid| *id)
+        .await;
+    assert!(matches!(
+        result,
+        Err(AsyncError::Error(
+            diesel::result::Error::DeserializationError(_)
+        ))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_optional_async() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+    let find = move || users::table.select(users::id).filter(users::id.eq(id));
+
+    assert_eq!(find().get_optional_async::<Uuid>(&pool).await?, None);
+
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+    assert_eq!(find().get_optional_async::<Uuid>(&pool).await?, Some(id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exists_async() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+
+    assert!(!users::table.find(id).exists_async(&pool).await?);
+
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+    assert!(users::table.find(id).exists_async(&pool).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_count_async() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    let values: Vec<_> = ids.iter().map(|id| users::id.eq(*id)).collect();
+    diesel::insert_into(users::table)
+        .values(values)
+        .execute_async(&pool)
+        .await?;
+
+    let count = users::table
+        .filter(users::id.eq_any(ids))
+        .count_async(&pool)
+        .await?;
+    assert_eq!(count, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_insert_batched_async() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    let values: Vec<_> = ids.iter().map(|id| users::id.eq(*id)).collect();
+
+    let err = pool
+        .insert_batched_async(users::table, values.clone(), 0)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        AsyncError::Error(diesel::result::Error::QueryBuilderError(_))
+    ));
+
+    let inserted = pool.insert_batched_async(users::table, values, 2).await?;
+    assert_eq!(inserted, 5);
+
+    let count = users::table
+        .filter(users::id.eq_any(ids))
+        .count_async(&pool)
+        .await?;
+    assert_eq!(count, 5);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sql_query_with_binds() -> Result<(), Box<dyn Error>> {
+    use diesel::sql_types::{BigInt, Text};
+
+    #[derive(QueryableByName)]
+    struct Row {
+        #[sql_type = "Text"]
+        label: String,
+        #[sql_type = "BigInt"]
+        total: i64,
+    }
+
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+
+    let rows: Vec<Row> =
+        sql_query("SELECT $1 AS label, COUNT(*) AS total FROM users WHERE id = $2")
+            .bind::<Text, _>("match")
+            .bind::<diesel::sql_types::Uuid, _>(id)
+            .load_async(&pool)
+            .await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].label, "match");
+    assert_eq!(rows[0].total, 1);
+
+    let row: Option<Row> = sql_query("SELECT 'none' AS label, 0::int8 AS total WHERE false")
+        .get_optional_async(&pool)
+        .await?;
+    assert!(row.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_first_optional_async() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+    let find = move || users::table.select(users::id).filter(users::id.eq(id));
+
+    assert_eq!(find().first_optional_async::<Uuid>(&pool).await?, None);
+
+    diesel::insert_into(users::table)
+        .values(vec![users::id.eq(id), users::id.eq(id)])
+        .execute_async(&pool)
+        .await?;
+    assert_eq!(find().first_optional_async::<Uuid>(&pool).await?, Some(id));
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_error_class() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::SimpleConnection;
+
+    let db = Database::new(setup().await?);
+    let conn = db.acquire().await?;
+    conn.batch_execute_async(
+        "CREATE TEMPORARY TABLE classified (id integer UNIQUE, name text NOT NULL)",
+    )
+    .await?;
+    conn.batch_execute_async("INSERT INTO classified VALUES (1, 'one')")
+        .await?;
+
+    let err = conn
+        .batch_execute_async("INSERT INTO classified VALUES (1, 'again')")
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::UniqueViolation);
+    assert!(!err.is_transient());
+    assert_eq!(err.table_name(), Some("classified"));
+    assert_eq!(err.details(), Some("Key (id)=(1) already exists."));
+
+    let err = conn
+        .batch_execute_async("INSERT INTO classified VALUES (2, NULL)")
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::NotNullViolation);
+    assert_eq!(err.column_name(), Some("name"));
+
+    let err = conn
+        .batch_execute_async(
+            "DO $$ BEGIN RAISE EXCEPTION 'out of stock' USING HINT = 'order less'; END $$",
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.database_message(), Some("out of stock"));
+    assert_eq!(err.hint(), Some("order less"));
+    assert_eq!(err.details(), None);
+
+    let err = conn
+        .run(|conn| {
+            conn.batch_execute("SET statement_timeout = 10")?;
+            let result = conn.batch_execute("SELECT pg_sleep(1)");
+            conn.batch_execute("RESET statement_timeout")?;
+            result
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::Timeout);
+    assert!(err.is_transient());
+
+    let err = users::table
+        .find(Uuid::new_v4())
+        .select(users::id)
+        .get_result_async::<Uuid>(&conn)
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::NotFound);
+
+    for (code, class) in [
+        ("40P01", DatabaseErrorClass::Deadlock),
+        ("55P03", DatabaseErrorClass::LockTimeout),
+        ("57P01", DatabaseErrorClass::ConnectionLost),
+        ("23514", DatabaseErrorClass::CheckViolation),
+        ("P0001", DatabaseErrorClass::Other),
+    ] {
+        assert_eq!(
+            DatabaseErrorClass::of_sqlstate(code),
+            class,
+            "SQLSTATE {}",
+            code
+        );
+    }
+    assert_eq!(
+        DatabaseErrorClass::of_mysql_errno(1213),
+        DatabaseErrorClass::Deadlock
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_optional_custom_error() -> Result<(), Box<dyn Error>> {
+    // Both preludes export an `OptionalExtension`
+    use actix_threadpool_diesel::OptionalExtension;
+
+    #[derive(Debug)]
+    enum AppError {
+        Db(diesel::result::Error),
+        Invalid,
+    }
+
+    impl From<diesel::result::Error> for AppError {
+        fn from(err: diesel::result::Error) -> AppError {
+            AppError::Db(err)
+        }
+    }
+
+    impl IsNotFound for AppError {
+        fn is_not_found(&self) -> bool {
+            matches!(*self, AppError::Db(ref err) if err.is_not_found())
+        }
+    }
+
+    let pool = setup().await?;
+    let id = Uuid::new_v4();
+
+    let found = pool
+        .run(move |conn| -> Result<Uuid, AppError> {
+            Ok(users::table.find(id).select(users::id).get_result(conn)?)
+        })
+        .await
+        .optional()
+        .unwrap();
+    assert!(found.is_none());
+
+    let result = pool
+        .run(|_| Err::<Uuid, _>(AppError::Invalid))
+        .await
+        .optional();
+    assert!(matches!(result, Err(AsyncError::Error(AppError::Invalid))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_constraint_violations() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let conn = db.acquire().await?;
+    conn.batch_execute_async(
+        "CREATE TEMPORARY TABLE parents (id integer CONSTRAINT parents_pk PRIMARY KEY);
+         CREATE TEMPORARY TABLE children (
+             parent integer CONSTRAINT children_parent_fk REFERENCES parents (id)
+         );
+         INSERT INTO parents VALUES (1);",
+    )
+    .await?;
+
+    let err = conn
+        .batch_execute_async("INSERT INTO parents VALUES (1)")
+        .await
+        .unwrap_err();
+    assert!(err.is_unique_violation());
+    assert_eq!(err.unique_violation_constraint(), Some("parents_pk"));
+    assert_eq!(err.foreign_key_violation_constraint(), None);
+
+    let err = conn
+        .batch_execute_async("INSERT INTO children VALUES (2)")
+        .await
+        .unwrap_err();
+    assert!(err.is_foreign_key_violation());
+    assert_eq!(
+        err.foreign_key_violation_constraint(),
+        Some("children_parent_fk")
+    );
+    assert_eq!(err.unique_violation_constraint(), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checkout_retry() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = Pool::builder()
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(50))
+        .build(manager)?;
+
+    let hold = |db: Database<PgConnection>| async move {
+        let conn = db.acquire().await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            drop(conn);
+        });
+    };
+
+    let db = Database::new(pool.clone());
+    hold(db.clone()).await;
+    match db.batch_execute_async("SELECT 1").await {
+        Err(AsyncError::Checkout(err)) => {
+            assert!(err.downcast_ref::<diesel::r2d2::PoolError>().is_some())
+        }
+        result => panic!("expected a checkout error, got {:?}", result),
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let db = Database::builder()
+        .checkout_retry(
+            RetryPolicy::new(10).backoff(Duration::from_millis(20), Duration::from_millis(100)),
+        )
+        .build(pool);
+    hold(db.clone()).await;
+    db.batch_execute_async("SELECT 1").await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[test]
+fn test_response_error() {
+    use actix_web::{http::StatusCode, ResponseError};
+
+    let status = |err: AsyncError<diesel::result::Error>| err.status_code();
+    assert_eq!(
+        status(AsyncError::Error(diesel::result::Error::NotFound)),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(status(AsyncError::Timeout), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(status(AsyncError::Closed), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        status(AsyncError::Error(
+            diesel::result::Error::RollbackTransaction
+        )),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_error_body() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+    let conn = db.acquire().await?;
+    conn.batch_execute_async(
+        "CREATE TEMPORARY TABLE bodies (id integer CONSTRAINT bodies_pk PRIMARY KEY);
+         INSERT INTO bodies VALUES (1);",
+    )
+    .await?;
+
+    let err = conn
+        .batch_execute_async("INSERT INTO bodies VALUES (1)")
+        .await
+        .unwrap_err();
+    let json = serde_json::to_value(&err)?;
+    assert_eq!(json["kind"], "unique_violation");
+    assert_eq!(json["constraint"], "bodies_pk");
+    assert!(json["message"].as_str().unwrap().contains("duplicate key"));
+
+    let json = serde_json::to_value(AsyncError::<diesel::result::Error>::Timeout)?;
+    assert_eq!(
+        json,
+        serde_json::json!({ "kind": "timeout", "message": "operation timed out" })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_error_conversions() {
+    use diesel::result::Error as DieselError;
+
+    #[derive(Debug, PartialEq)]
+    enum AppError {
+        NotFound,
+        Other,
+    }
+
+    let to_app = |err: DieselError| match err {
+        DieselError::NotFound => AppError::NotFound,
+        _ => AppError::Other,
+    };
+
+    let err = AsyncError::Error(DieselError::NotFound).map(to_app);
+    assert_eq!(err.into_inner(), Some(AppError::NotFound));
+
+    let err = AsyncError::<DieselError>::Timeout.map(to_app);
+    assert!(matches!(err, AsyncError::Timeout));
+    assert_eq!(err.into_inner(), None);
+
+    let err: DieselError = AsyncError::Error(DieselError::NotFound).into();
+    assert_eq!(err, DieselError::NotFound);
+
+    let err: DieselError = AsyncError::<DieselError>::Closed.into();
+    match err {
+        DieselError::DatabaseError(_, info) => assert_eq!(info.message(), "database is shut down"),
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    // Converting keeps whether the failure is worth retrying
+    let transient =
+        |err: AsyncError<DieselError>| AsyncError::Error(DieselError::from(err)).is_transient();
+    assert!(!transient(AsyncError::Panicked("boom".to_string())));
+    assert!(!transient(AsyncError::Closed));
+    assert!(!transient(AsyncError::Rejected("read only".to_string())));
+    assert!(!transient(AsyncError::Canceled));
+    assert!(transient(AsyncError::Timeout));
+    assert!(transient(AsyncError::Checkout(CheckoutError::new(
+        "pool exhausted"
+    ))));
+}
+
+#[test]
+fn test_custom_checkout_error() {
+    #[derive(Debug)]
+    struct Exhausted;
+
+    impl fmt::Display for Exhausted {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("pool exhausted")
+        }
+    }
+
+    impl Error for Exhausted {}
+
+    let err = AsyncError::<diesel::result::Error>::Checkout(CheckoutError::new(Exhausted));
+    assert_eq!(err.to_string(), "pool exhausted");
+    assert!(err.is_transient());
+    match err {
+        AsyncError::Checkout(err) => assert!(err.downcast_ref::<Exhausted>().is_some()),
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn test_error_context() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+
+    let err = db
+        .run_tagged("missing table", |conn| {
+            sql_query("SELECT * FROM no_such_table").execute(conn)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.query_tag(), Some("missing table"));
+    assert_eq!(err.query_attempts(), Some(1));
+    assert!(err.query_elapsed().is_some());
+    assert!(err
+        .to_string()
+        .starts_with("query `missing table` failed after"));
+    assert!(err.to_string().contains("no_such_table"));
+    assert!(err.source().is_some());
+    assert!(matches!(err.into_error(), AsyncError::Error(_)));
+
+    let err = users::table
+        .find(Uuid::new_v4())
+        .select(users::id)
+        .get_result_async::<Uuid>(&db)
+        .await
+        .context("find user")
+        .unwrap_err();
+    assert_eq!(err.to_string(), "query `find user` failed: NotFound");
+    assert_eq!(err.error().class(), DatabaseErrorClass::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_retrying() -> Result<(), Box<dyn Error>> {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    let pool = setup().await?;
+    let policy = RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(5));
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let value = pool
+        .run_retrying(policy.clone(), move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(DieselError::DatabaseError(
+                    DatabaseErrorKind::SerializationFailure,
+                    Box::new("could not serialize access".to_string()),
+                ))
+            } else {
+                Ok(42)
+            }
+        })
+        .await?;
+    assert_eq!(value, 42);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let result = pool
+        .run_retrying(policy, move |_| -> QueryResult<()> {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(DieselError::NotFound)
+        })
+        .await;
+    assert!(matches!(
+        result,
+        Err(AsyncError::Error(DieselError::NotFound))
+    ));
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "bb8")]
+#[tokio::test]
+async fn test_bb8_pool() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let manager = Bb8ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = bb8::Pool::builder().max_size(2).build(manager).await?;
+
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+
+    let found: Option<Uuid> = users::table
+        .find(id)
+        .select(users::id)
+        .get_optional_async(&pool)
+        .await?;
+    assert_eq!(found, Some(id));
+
+    // More queries than connections wait for one to come back
+    let counts = futures::future::try_join_all(
+        (0..6).map(|_| users::table.count().get_result_async::<i64>(&pool)),
+    )
+    .await?;
+    assert!(counts.iter().all(|&count| count > 0));
+
+    Ok(())
+}
+
+#[cfg(feature = "deadpool")]
+#[tokio::test]
+async fn test_deadpool() -> Result<(), Box<dyn Error>> {
+    use deadpool_diesel::{Manager, Pool, Runtime};
+
+    setup().await?;
+    let manager = Manager::<PgConnection>::new("postgres://postgres@localhost", Runtime::Tokio1);
+    let pool = Pool::builder(manager).max_size(2).build()?;
+
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+
+    let found: Option<Uuid> = users::table
+        .find(id)
+        .select(users::id)
+        .get_optional_async(&pool)
+        .await?;
+    assert_eq!(found, Some(id));
+
+    let inserted = pool
+        .transaction(move |conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(Uuid::new_v4()))
+                .execute(conn)
+        })
+        .await?;
+    assert_eq!(inserted, 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "mobc")]
+#[tokio::test]
+async fn test_mobc_pool() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let manager = MobcConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = mobc::Pool::builder().max_open(2).build(manager);
+
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+
+    let found: Option<Uuid> = users::table
+        .find(id)
+        .select(users::id)
+        .get_optional_async(&pool)
+        .await?;
+    assert_eq!(found, Some(id));
+
+    let counts = futures::future::try_join_all(
+        (0..6).map(|_| users::table.count().get_result_async::<i64>(&pool)),
+    )
+    .await?;
+    assert!(counts.iter().all(|&count| count > 0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_single_connection() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let conn =
+        AsyncSingleConnection::<PgConnection>::establish("postgres://postgres@localhost").await?;
+
+    // Session state carries over between calls
+    sql_query("CREATE TEMPORARY TABLE single_scratch (n INT)")
+        .execute_async(&conn)
+        .await?;
+    sql_query("INSERT INTO single_scratch VALUES (1), (2)")
+        .execute_async(&conn)
+        .await?;
+
+    // Concurrent callers take turns
+    let (a, b) = tokio::join!(
+        users::table.count().get_result_async::<i64>(&conn),
+        conn.transaction(|conn| {
+            sql_query("DELETE FROM single_scratch WHERE n = 1").execute(conn)
+        })
+    );
+    assert!(a? >= 0);
+    assert_eq!(b?, 1);
+
+    let err = AsyncSingleConnection::<PgConnection>::establish("postgres://nobody@localhost:1")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Connect(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_database_connect() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+    let num_users: i64 = users::table.count().get_result_async(&db).await?;
+    assert!(num_users >= 0);
+
+    // Fails up front rather than at the first query
+    let err = Database::<PgConnection>::builder()
+        .max_concurrent_queries(1)
+        .connect("postgres://nobody@localhost:1")
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, AsyncError::Connect(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_config() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let config = PoolConfig::new()
+        .max_size(1)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_millis(100))
+        .idle_timeout(None);
+    let db = Database::<PgConnection>::builder()
+        .pool_config(config)
+        .connect("postgres://postgres@localhost")
+        .await?;
+    assert_eq!(db.pool().unwrap().max_size(), 1);
+
+    // With the only connection held, a checkout gives up after the timeout
+    let guard = db.acquire().await?;
+    let start = std::time::Instant::now();
+    let err = users::table
+        .count()
+        .get_result_async::<i64>(&db)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Checkout(_)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(guard);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_warm_up() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(3).min_idle(Some(0)))
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    db.warm_up(5).await?;
+    let state = db.pool().unwrap().state();
+    assert_eq!(state.connections, 3);
+    assert_eq!(state.idle_connections, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stats() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .pool_config(
+            PoolConfig::new()
+                .max_size(1)
+                .min_idle(Some(0))
+                .connection_timeout(Duration::from_millis(100)),
+        )
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    for _ in 0..3 {
+        users::table.count().get_result_async::<i64>(&db).await?;
+    }
+
+    let guard = db.acquire().await?;
+    assert!(users::table
+        .count()
+        .get_result_async::<i64>(&db)
+        .await
+        .is_err());
+
+    let stats = db.stats();
+    assert_eq!(stats.in_use, 1);
+    assert_eq!(stats.idle, 0);
+    assert_eq!(stats.checkouts, 4);
+    assert_eq!(stats.checkout_errors, 1);
+    // The failed checkout waited out the timeout
+    assert!(stats.wait_p99 >= Duration::from_millis(100));
+    assert!(stats.wait_p50 <= stats.wait_p90 && stats.wait_p90 <= stats.wait_p99);
+
+    drop(guard);
+    assert_eq!(db.stats().idle, 1);
+
+    assert!(sql_query("SELECT * FROM no_such_table")
+        .execute_async(&db)
+        .await
+        .is_err());
+    let sleep = sql_query("SELECT pg_sleep(0.5)").execute_async(&db);
+    assert!(with_deadline(Duration::from_millis(50), sleep)
+        .await
+        .is_err());
+    assert_eq!(
+        db.stats().errors,
+        ErrorCounts {
+            checkout: 1,
+            timeout: 1,
+            other: 1,
+            ..ErrorCounts::default()
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acquire_wait() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(1).min_idle(Some(0)))
+        .connect("postgres://postgres@localhost")
+        .await?;
+    users::table.count().get_result_async::<i64>(&db).await?;
+
+    // A call made while the only connection is held waits for it
+    let guard = db.acquire().await?;
+    let starved = tokio::spawn({
+        let db = db.clone();
+        async move { users::table.count().get_result_async::<i64>(&db).await }
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    drop(guard);
+    starved.await??;
+
+    let histogram = db.stats().acquire_wait;
+    // The initial call, the guard and the starved call
+    assert_eq!(histogram.count, 3);
+    assert!(histogram.sum >= Duration::from_millis(250));
+    assert!(histogram
+        .buckets
+        .windows(2)
+        .all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1));
+    let (_, within_250ms) = histogram.buckets[7];
+    assert_eq!(within_250ms, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_check() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    pool.ping_async().await?;
+    assert!(pool.is_healthy(Duration::from_secs(5)).await);
+
+    let db = Database::new(pool);
+    assert!(db.is_healthy(Duration::from_secs(5)).await);
+    db.shutdown(Duration::from_secs(1)).await?;
+    assert!(matches!(db.ping_async().await, Err(AsyncError::Closed)));
+    assert!(!db.is_healthy(Duration::from_secs(5)).await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_hooks() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::SimpleConnection;
+
+    setup().await?;
+    let released = Arc::new(AtomicUsize::new(0));
+    let released_ = released.clone();
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(1))
+        .on_acquire(|conn| conn.batch_execute("SET application_name = 'hooked'"))
+        .on_release(move |conn| {
+            released_.fetch_add(1, Ordering::SeqCst);
+            conn.batch_execute("RESET application_name")
+        })
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    #[derive(QueryableByName)]
+    struct Setting {
+        #[sql_type = "diesel::sql_types::Text"]
+        application_name: String,
+    }
+
+    let setting: Setting = sql_query("SHOW application_name")
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(setting.application_name, "hooked");
+    assert_eq!(released.load(Ordering::SeqCst), 1);
+
+    // The release hook ran before the pool handed the connection out directly
+    let pool = db.pool().unwrap();
+    let setting: Setting = sql_query("SHOW application_name")
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(setting.application_name, "");
+
+    let guard = db.acquire().await?;
+    drop(guard);
+    db.shutdown(Duration::from_secs(5)).await?;
+    assert_eq!(released.load(Ordering::SeqCst), 2);
+
+    // A failing acquire hook fails the checkout
+    let db = Database::<PgConnection>::builder()
+        .on_acquire(|conn| conn.batch_execute("SET no_such_setting = 1"))
+        .connect("postgres://postgres@localhost")
+        .await?;
+    let err = db.ping_async().await.unwrap_err();
+    assert!(matches!(err, AsyncError::Checkout(_)));
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_db_extractor() -> Result<(), Box<dyn Error>> {
+    use actix_web::{test::TestRequest, web, FromRequest};
+
+    let db = Database::new(setup().await?);
+
+    let req = TestRequest::default()
+        .app_data(db.clone())
+        .to_http_request();
+    let extracted = Db::<PgConnection>::extract(&req).await?;
+    let num_users: i64 = users::table.count().get_result_async(&extracted).await?;
+    assert!(num_users >= 0);
+
+    let req = TestRequest::default()
+        .app_data(web::Data::new(db))
+        .to_http_request();
+    let extracted = Db::<PgConnection>::extract(&req).await?;
+    extracted.ping_async().await?;
+
+    let req = TestRequest::default().to_http_request();
+    assert!(Db::<PgConnection>::extract(&req).await.is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_transactional_middleware() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    let db = Database::new(setup().await?);
+
+    async fn insert(
+        tx: Tx<PgConnection>,
+        path: web::Path<(String, u16)>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        let (id, status) = path.into_inner();
+        let id: Uuid = id.parse().unwrap();
+        diesel::insert_into(users::table)
+            .values(users::id.eq(id))
+            .execute_async(&tx)
+            .await?;
+        Ok(HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish())
+    }
+
+    let app = init_service(
+        App::new()
+            .app_data(db.clone())
+            .wrap(Transactional::<PgConnection>::new())
+            .route("/{id}/{status}", web::post().to(insert)),
+    )
+    .await;
+
+    let (committed, rolled_back) = (Uuid::new_v4(), Uuid::new_v4());
+    let uri = format!("/{}/201", committed);
+    let res = call_service(&app, TestRequest::post().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), 201);
+    let uri = format!("/{}/500", rolled_back);
+    let res = call_service(&app, TestRequest::post().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), 500);
+
+    let found: Vec<Uuid> = users::table
+        .filter(users::id.eq_any(vec![committed, rolled_back]))
+        .select(users::id)
+        .load_async(&db)
+        .await?;
+    assert_eq!(found, vec![committed]);
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_db_health_handler() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App,
+    };
+
+    let db = Database::new(setup().await?);
+    let app = init_service(
+        App::new()
+            .app_data(db.clone())
+            .route("/health", web::get().to(db_health_handler::<PgConnection>)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(res.status(), 200);
+    let report: serde_json::Value = read_body_json(res).await;
+    assert_eq!(report["healthy"], true);
+    assert!(report["pool"]["checkouts"].as_u64().unwrap() >= 1);
+    assert!(report["pool"]["wait_p99_ms"].is_number());
+
+    db.shutdown(Duration::from_secs(1)).await?;
+    let res = call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(res.status(), 503);
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_database_config() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    setup().await?;
+    let db_config = DatabaseConfig::<PgConnection>::new(
+        "postgres://postgres@localhost",
+        PoolConfig::new().max_size(2),
+    )
+    .health_route("/ready");
+
+    async fn count(
+        db: Db<PgConnection>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        let count: i64 = users::table.count().get_result_async(&db).await?;
+        Ok(HttpResponse::Ok().body(count.to_string()))
+    }
+
+    let app = init_service(
+        App::new()
+            .configure(|cfg| db_config.configure(cfg))
+            .route("/count", web::get().to(count)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/ready").to_request()).await;
+    assert_eq!(res.status(), 200);
+    let res = call_service(&app, TestRequest::get().uri("/count").to_request()).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(db_config.database().pool().unwrap().max_size(), 2);
+
+    db_config
+        .database()
+        .shutdown(Duration::from_secs(1))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_database_config_serve() -> Result<(), Box<dyn Error>> {
+    use actix_web::{App, HttpServer};
+
+    setup().await?;
+    let db_config = DatabaseConfig::<PgConnection>::new(
+        "postgres://postgres@localhost",
+        PoolConfig::new().max_size(2),
+    )
+    .health_route("/ready")
+    .shutdown_grace(Duration::from_secs(1));
+
+    let app_config = db_config.clone();
+    let server = HttpServer::new(move || {
+        let app_config = app_config.clone();
+        App::new().configure(move |cfg| app_config.configure(cfg))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind("127.0.0.1:0")?
+    .run();
+
+    let handle = server.handle();
+    tokio::spawn(async move { handle.stop(true).await });
+    db_config.serve(server).await?;
+
+    assert!(db_config.database().is_closed());
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_database_registry() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpRequest, HttpResponse,
+    };
+
+    setup().await?;
+    let registry = DatabaseRegistry::new()
+        .register(
+            "primary",
+            Database::<PgConnection>::connect("postgres://postgres@localhost").await?,
+        )
+        .register(
+            "analytics",
+            Database::<PgConnection>::connect("postgres://postgres@localhost").await?,
+        );
+    assert!(registry.get::<PgConnection>("primary").is_some());
+    assert!(registry.get::<PgConnection>("missing").is_none());
+
+    async fn count(
+        req: HttpRequest,
+        name: web::Path<String>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let db = Db::<PgConnection>::named(&req, &name)?;
+        let count: i64 = users::table
+            .count()
+            .get_result_async(&db)
+            .await
+            .map_err(actix_web::Error::from)?;
+        Ok(HttpResponse::Ok().body(count.to_string()))
+    }
+
+    let app = init_service(
+        App::new()
+            .app_data(registry.clone())
+            .route("/{name}/count", web::get().to(count)),
+    )
+    .await;
+
+    let res = call_service(
+        &app,
+        TestRequest::get().uri("/analytics/count").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    assert!(std::str::from_utf8(&read_body(res).await)?
+        .parse::<i64>()
+        .is_ok());
+    let res = call_service(&app, TestRequest::get().uri("/missing/count").to_request()).await;
+    assert_eq!(res.status(), 500);
+
+    Ok(())
+}
+
+#[cfg(all(feature = "actix-web", feature = "postgres"))]
+#[tokio::test]
+async fn test_transactional_set_local() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        dev::Payload,
+        error::ErrorUnauthorized,
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, FromRequest, HttpRequest, HttpResponse,
+    };
+    use futures::future::{ready, Ready};
+
+    struct UserId(String);
+
+    impl fmt::Display for UserId {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl FromRequest for UserId {
+        type Error = actix_web::Error;
+        type Future = Ready<Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let user = req
+                .headers()
+                .get("x-user-id")
+                .and_then(|value| value.to_str().ok())
+                .map(|value| UserId(value.to_string()));
+            ready(user.ok_or_else(|| ErrorUnauthorized("unauthorized")))
+        }
+    }
+
+    #[derive(QueryableByName)]
+    struct Setting {
+        #[sql_type = "diesel::sql_types::Text"]
+        user_id: String,
+    }
+
+    async fn whoami(
+        tx: Tx<PgConnection>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        let setting: Setting = sql_query("SELECT current_setting('app.user_id') AS user_id")
+            .get_result_async(&tx)
+            .await?;
+        Ok(HttpResponse::Ok().body(setting.user_id))
+    }
+
+    let db = Database::new(setup().await?);
+    let app = init_service(
+        App::new()
+            .app_data(db.clone())
+            .wrap(Transactional::<PgConnection>::new().set_local::<UserId>("app.user_id"))
+            .route("/whoami", web::get().to(whoami)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("x-user-id", "o'brien"))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(read_body(res).await, "o'brien");
+
+    let req = TestRequest::get().uri("/whoami").to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), 401);
+
+    for name in [
+        "",
+        "app.",
+        "1app.user_id",
+        "app.user_id = 1; RESET ALL; SET x",
+    ] {
+        let set = std::panic::catch_unwind(|| {
+            Transactional::<PgConnection>::new().set_local::<UserId>(name);
+        });
+        assert!(set.is_err(), "{:?} accepted", name);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_connection_label() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .label_connections()
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    async fn application_name(db: &Database<PgConnection>) -> Result<String, Box<dyn Error>> {
+        Ok(
+            diesel::select(sql::<Text>("current_setting('application_name')"))
+                .get_result_async(db)
+                .await?,
+        )
+    }
+
+    let name = with_connection_label("billing 42", application_name(&db)).await?;
+    assert_eq!(name, "billing 42");
+
+    let name: String = with_connection_label(
+        "billing 43",
+        db.transaction(|conn| {
+            diesel::select(sql::<Text>("current_setting('application_name')")).get_result(conn)
+        }),
+    )
+    .await?;
+    assert_eq!(name, "billing 43");
+
+    // Cleared before the connection went back to the pool
+    assert_eq!(application_name(&db).await?, "");
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_application_name() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .application_name(ApplicationName::service("billing", "1.2.3").name())
+        .label_connections()
+        .pool_config(PoolConfig::new().max_size(1))
+        .connect("postgres://postgres@localhost")
+        .await?;
+    let application_name = || diesel::select(sql::<Text>("current_setting('application_name')"));
+
+    assert_eq!(
+        application_name().get_result_async::<String>(&db).await?,
+        "billing 1.2.3"
+    );
+    let name = with_connection_label(
+        "invoices",
+        application_name().get_result_async::<String>(&db),
+    );
+    assert_eq!(name.await?, "invoices");
+    // The label gives way to the service's name again
+    assert_eq!(
+        application_name().get_result_async::<String>(&db).await?,
+        "billing 1.2.3"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deadline() -> Result<(), Box<dyn Error>> {
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+    let sleep = |conn: &PgConnection| sql_query("SELECT pg_sleep(0.5)").execute(conn);
+
+    let result = with_deadline(Duration::from_millis(100), db.run(sleep)).await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+
+    let result = with_deadline(Duration::from_millis(100), async {
+        let tx = db.begin().await?;
+        tx.run(sleep).await
+    })
+    .await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+
+    // An inner deadline can't extend the outer one
+    let result = with_deadline(
+        Duration::from_millis(100),
+        with_deadline(Duration::from_secs(5), db.run(sleep)),
+    )
+    .await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+
+    with_deadline(Duration::from_secs(5), db.run(sleep)).await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing_spans() -> Result<(), Box<dyn Error>> {
+    use std::sync::Mutex;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    type SpanFields = Vec<(String, String)>;
+
+    // Collects the fields recorded on every span, by span
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<SpanFields>>>);
+
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_string(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = vec![("id".to_string(), id.into_u64().to_string())];
+            attrs.record(&mut Fields(&mut fields));
+            spans.push(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            let mut spans = self.0.lock().unwrap();
+            let id = id.into_u64().to_string();
+            if let Some(fields) = spans.iter_mut().find(|fields| fields[0].1 == id) {
+                values.record(&mut Fields(fields));
+            }
+        }
+    }
+
+    let recorder = Recorder::default();
+    let _guard = tracing_subscriber::registry()
+        .with(recorder.clone())
+        .set_default();
+
+    let pool = setup().await?;
+    let db = Database::new(pool);
+    users::table
+        .select(users::id)
+        .load_async::<Uuid>(&db)
+        .await?;
+    let result = sql_query("SELECT pg_sleep(0.5)")
+        .execute_async_timeout(&db, Duration::from_millis(50))
+        .await;
+    assert!(matches!(result, Err(AsyncError::Timeout)));
+
+    let spans = recorder.0.lock().unwrap().clone();
+    let field = |span: &SpanFields, name: &str| {
+        span.iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.clone())
+    };
+    let ops: Vec<_> = spans.iter().filter_map(|span| field(span, "op")).collect();
+    assert!(ops.contains(&"load".to_string()));
+    assert!(ops.contains(&"run".to_string()));
+
+    let load = spans
+        .iter()
+        .find(|span| field(span, "op").as_deref() == Some("load"))
+        .unwrap();
+    assert!(field(load, "rows").is_some());
+    assert!(field(load, "elapsed_ms").is_some());
+
+    let failed = spans
+        .iter()
+        .rfind(|span| field(span, "op").as_deref() == Some("execute"))
+        .unwrap();
+    assert_eq!(field(failed, "error.class").as_deref(), Some("Timeout"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slow_query_log() -> Result<(), Box<dyn Error>> {
+    use std::sync::Mutex;
+
+    // Keeps the warnings logged while the tests run
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    log::set_logger(&CAPTURE).map_err(|err| err.to_string())?;
+    log::set_max_level(log::LevelFilter::Warn);
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .slow_query_threshold(Duration::from_millis(100))
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    let sleep = sql_query("SELECT pg_sleep(0.2)");
+    let sql = diesel::debug_query::<diesel::pg::Pg, _>(&sleep).to_string();
+    db.run_tagged(sql, move |conn| sleep.execute(conn)).await?;
+    db.run_tagged("quick lookup", |conn| sql_query("SELECT 1").execute(conn))
+        .await?;
+
+    let logged = CAPTURE.0.lock().unwrap();
+    assert!(logged
+        .iter()
+        .any(|line| line.starts_with("slow query") && line.contains("pg_sleep(0.2)")));
+    assert!(!logged.iter().any(|line| line.contains("quick lookup")));
+
+    Ok(())
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn test_prometheus_metrics() -> Result<(), Box<dyn Error>> {
+    use prometheus::{proto::MetricFamily, Registry};
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(4))
+        .connect("postgres://postgres@localhost")
+        .await?;
+    let registry = Registry::new();
+    db.register_metrics(&registry, "primary")?;
+    assert!(db.register_metrics(&Registry::new(), "again").is_err());
+
+    users::table.count().get_result_async::<i64>(&db).await?;
+    let result = sql_query("SELECT * FROM no_such_table")
+        .execute_async(&db)
+        .await;
+    assert!(result.is_err());
+
+    let families = registry.gather();
+    let family = |name: &str| -> Result<&MetricFamily, Box<dyn Error>> {
+        Ok(families
+            .iter()
+            .find(|family| family.get_name() == name)
+            .ok_or(format!("no {}", name))?)
+    };
+
+    let metric = &family("db_pool_checkouts_total")?.get_metric()[0];
+    assert_eq!(metric.get_label()[0].get_value(), "primary");
+    assert!(metric.get_counter().get_value() >= 2.0);
+    let queries = &family("db_query_duration_seconds")?.get_metric()[0];
+    assert_eq!(queries.get_histogram().get_sample_count(), 2);
+    let errors = &family("db_query_errors_total")?.get_metric()[0];
+    assert_eq!(errors.get_counter().get_value(), 1.0);
+    assert!(errors
+        .get_label()
+        .iter()
+        .any(|label| label.get_name() == "class" && label.get_value() == "other"));
+    let max_size = &family("db_pool_max_size")?.get_metric()[0];
+    assert_eq!(max_size.get_gauge().get_value(), 4.0);
+    family("db_pool_saturation")?;
+    assert_eq!(
+        family("db_pool_checkout_wait_seconds")?.get_metric().len(),
+        3
+    );
+    let categories = family("db_errors_total")?.get_metric();
+    assert!(categories.iter().any(|metric| {
+        metric.get_counter().get_value() == 1.0
+            && metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == "category" && label.get_value() == "other")
+    }));
+    let acquire_wait = &family("db_pool_acquire_wait_seconds")?.get_metric()[0];
+    assert_eq!(acquire_wait.get_histogram().get_sample_count(), 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+#[tokio::test]
+async fn test_otel_spans() -> Result<(), Box<dyn Error>> {
+    use opentelemetry::{trace::Status, Value};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    opentelemetry::global::set_tracer_provider(provider);
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .otel(OtelConfig::new().pool_name("primary"))
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    db.batch_execute_async("SELECT 'secret', 42 WHERE 1 = 1")
+        .await?;
+    let result = db
+        .run_tagged("missing table", |conn| {
+            sql_query("SELECT * FROM no_such_table").execute(conn)
+        })
+        .await;
+    assert!(result.is_err());
+
+    let spans = exporter.get_finished_spans()?;
+    let attribute = |span: &SpanData, key: &str| {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    };
+
+    let batch = spans
+        .iter()
+        .find(|span| span.name == "SELECT")
+        .ok_or("no span for the batch")?;
+    assert_eq!(
+        attribute(batch, "db.system"),
+        Some(Value::from("postgresql"))
+    );
+    assert_eq!(
+        attribute(batch, "db.statement"),
+        Some(Value::from("SELECT ?, ? WHERE ? = ?"))
+    );
+    assert_eq!(
+        attribute(batch, "db.operation"),
+        Some(Value::from("SELECT"))
+    );
+    assert_eq!(
+        attribute(batch, "db.client.connection.pool.name"),
+        Some(Value::from("primary"))
+    );
+
+    let failed = spans
+        .iter()
+        .find(|span| span.name == "missing table")
+        .ok_or("no span for the failed call")?;
+    assert_eq!(failed.status, Status::error("other"));
+    assert_eq!(attribute(failed, "error.type"), Some(Value::from("other")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_interceptor() -> Result<(), Box<dyn Error>> {
+    use std::sync::Mutex;
+
+    type Call = (Option<String>, Result<(), DatabaseErrorClass>);
+
+    // Refuses DROP statements and records every call that ran
+    #[derive(Clone, Default)]
+    struct Policy(Arc<Mutex<Vec<Call>>>);
+
+    impl QueryInterceptor for Policy {
+        fn before_query(&self, call: &QueryCall<'_>) -> Result<(), String> {
+            match call.statement() {
+                Some(sql) if sql.to_uppercase().starts_with("DROP") => {
+                    Err("DROP is not allowed".to_string())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn after_query(
+            &self,
+            call: &QueryCall<'_>,
+            result: Result<(), DatabaseErrorClass>,
+            _: Duration,
+        ) {
+            let tag = call.tag().map(str::to_string);
+            self.0.lock().unwrap().push((tag, result));
+        }
+    }
+
+    setup().await?;
+    let policy = Policy::default();
+    let db = Database::<PgConnection>::builder()
+        .interceptor(policy.clone())
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    users::table.count().get_result_async::<i64>(&db).await?;
+    db.run_tagged("missing table", |conn| {
+        sql_query("SELECT * FROM no_such_table").execute(conn)
+    })
+    .await
+    .unwrap_err();
+    let result = db.batch_execute_async("DROP TABLE users").await;
+    assert!(matches!(result, Err(AsyncError::Rejected(_))));
+
+    let tx = db.begin().await?;
+    let result = tx.batch_execute_async("drop table users").await;
+    assert!(matches!(result, Err(AsyncError::Rejected(_))));
+    tx.rollback().await?;
+
+    let calls = policy.0.lock().unwrap().clone();
+    assert_eq!(
+        calls,
+        vec![
+            (None, Ok(())),
+            (
+                Some("missing table".to_string()),
+                Err(DatabaseErrorClass::Other)
+            ),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_with_sql() -> Result<(), Box<dyn Error>> {
+    use std::sync::Mutex;
+
+    // Records the statement of every call that ran
+    #[derive(Clone, Default)]
+    struct Statements(Arc<Mutex<Vec<Option<String>>>>);
+
+    impl QueryInterceptor for Statements {
+        fn after_query(
+            &self,
+            call: &QueryCall<'_>,
+            _: Result<(), DatabaseErrorClass>,
+            _: Duration,
+        ) {
+            let statement = call.statement().map(str::to_string);
+            self.0.lock().unwrap().push(statement);
+        }
+    }
+
+    let pool = setup().await?;
+    let (redacted, raw) = (Statements::default(), Statements::default());
+    let db = Database::<PgConnection>::builder()
+        .interceptor(redacted.clone())
+        .build(pool.clone());
+    let raw_db = Database::<PgConnection>::builder()
+        .interceptor(raw.clone())
+        .redact_bind_values(false)
+        .build(pool);
+
+    let user_id = Uuid::new_v4();
+    let query = users::table.select(users::id).filter(users::id.eq(user_id));
+    let sql = diesel::debug_query::<diesel::pg::Pg, _>(&query);
+    with_sql(&sql, query.load_async::<Uuid>(&db)).await?;
+    with_sql(&sql, query.load_async::<Uuid>(&raw_db)).await?;
+    users::table.count().get_result_async::<i64>(&db).await?;
+
+    let redacted = redacted.0.lock().unwrap().clone();
+    let raw = raw.0.lock().unwrap().clone();
+    let expected = r#"SELECT "users"."id" FROM "users" WHERE "users"."id" = $1"#;
+    assert_eq!(redacted, vec![Some(expected.to_string()), None]);
+    assert_eq!(
+        raw,
+        vec![Some(format!("{} -- binds: [{}]", expected, user_id))]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_sink() -> Result<(), Box<dyn Error>> {
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<AuditEvent>>>);
+
+    impl AuditSink for Events {
+        fn record(&self, event: AuditEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    let events = Events::default();
+    let db = Database::<PgConnection>::builder()
+        .audit_sink(events.clone())
+        .build(setup().await?);
+
+    let insert = diesel::insert_into(users::table).values(users::id.eq(Uuid::new_v4()));
+    with_actor("alice", with_tag("create user", insert.execute_async(&db))).await?;
+    users::table.count().get_result_async::<i64>(&db).await?;
+    db.transaction(|conn| sql_query("SELECT 1").execute(conn))
+        .await?;
+    let tx = db.begin().await?;
+    diesel::delete(users::table.filter(users::id.eq(Uuid::new_v4())))
+        .execute_async(&tx)
+        .await?;
+    tx.commit().await?;
+
+    let events = events.0.lock().unwrap().clone();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| {
+            (
+                event.operation,
+                event.tag.as_deref(),
+                event.rows_affected,
+                event.actor.as_deref(),
+                event.error,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("execute", Some("create user"), Some(1), Some("alice"), None),
+            ("transaction", None, None, None, None),
+            ("execute", None, Some(0), None, None),
+            ("commit", None, None, None, None),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_metrics() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(setup().await?);
+
+    let (result, metrics) = QueryMetrics::collect(async {
+        for _ in 0..3 {
+            users::table.count().get_result_async::<i64>(&db).await?;
+        }
+        db.run(|conn| sql_query("SELECT pg_sleep(0.05)").execute(conn))
+            .await
+    })
+    .await;
+    result?;
+
+    assert_eq!(metrics.queries, 4);
+    assert!(metrics.slowest >= Duration::from_millis(50));
+    assert!(metrics.total >= metrics.slowest);
+
+    // Work outside `collect` isn't counted anywhere
+    db.ping_async().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_db_metrics_middleware() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    async fn n_plus_one(
+        db: Db<PgConnection>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        for _ in 0..5 {
+            db.ping_async().await?;
+        }
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    let db = Database::new(setup().await?);
+    let app = init_service(
+        App::new()
+            .app_data(db)
+            .wrap(DbMetrics)
+            .route("/", web::get().to(n_plus_one)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    let header = |name| res.headers().get(name).unwrap().to_str().unwrap();
+    assert_eq!(header("x-db-queries"), "5");
+    let time: f64 = header("x-db-time").parse()?;
+    let slowest: f64 = header("x-db-slowest").parse()?;
+    assert!(time >= slowest);
+    assert_eq!(
+        res.response()
+            .extensions()
+            .get::<QueryMetrics>()
+            .unwrap()
+            .queries,
+        5
+    );
+
+    Ok(())
+}
+
+#[cfg(all(feature = "actix-web", feature = "prometheus"))]
+#[tokio::test]
+async fn test_prometheus_handler() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+    use prometheus::Registry;
+
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+    let registry = Registry::new();
+    db.register_metrics(&registry, "primary")?;
+
+    let app = init_service(
+        App::new()
+            .app_data(registry)
+            .route("/metrics", web::get().to(prometheus_handler)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+    assert_eq!(res.status(), 200);
+    let body = read_body(res).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("db_pool_connections{database=\"primary\",state=\"idle\"}"));
+
+    Ok(())
+}
+
+#[cfg(feature = "actix-web")]
+#[tokio::test]
+async fn test_db_deadline_middleware() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    setup().await?;
+    let db = Database::<PgConnection>::connect("postgres://postgres@localhost").await?;
+
+    async fn sleep(
+        db: Db<PgConnection>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        db.run(|conn| sql_query("SELECT pg_sleep(0.5)").execute(conn))
+            .await?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    let app = init_service(
+        App::new()
+            .app_data(db)
+            .service(
+                web::resource("/fast")
+                    .wrap(DbDeadline::new(Duration::from_millis(100)))
+                    .route(web::get().to(sleep)),
+            )
+            .route("/slow", web::get().to(sleep)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/fast").to_request()).await;
+    assert_eq!(res.status(), 503);
+    let res = call_service(&app, TestRequest::get().uri("/slow").to_request()).await;
+    assert_eq!(res.status(), 200);
+
+    Ok(())
+}
+
+#[cfg(all(feature = "actix-web", feature = "postgres"))]
+#[tokio::test]
+async fn test_label_connections_middleware() -> Result<(), Box<dyn Error>> {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+    use diesel::{dsl::sql, sql_types::Text};
+
+    setup().await?;
+    let db = Database::<PgConnection>::builder()
+        .label_connections()
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    async fn application_name(
+        db: Db<PgConnection>,
+    ) -> Result<HttpResponse, AsyncError<diesel::result::Error>> {
+        let name: String = diesel::select(sql::<Text>("current_setting('application_name')"))
+            .get_result_async(&db)
+            .await?;
+        Ok(HttpResponse::Ok().body(name))
+    }
+
+    let app = init_service(
+        App::new()
+            .app_data(db)
+            .wrap(LabelConnections::new("billing"))
+            .route("/", web::get().to(application_name)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/")
+        .insert_header(("x-request-id", "abc123"))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(read_body(res).await, "billing abc123");
+
+    let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    let request_id = res
+        .headers()
+        .get("x-request-id")
+        .ok_or("no request id")?
+        .to_str()?
+        .to_string();
+    assert_eq!(read_body(res).await, format!("billing {}", request_id));
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_pool_config() -> Result<(), Box<dyn Error>> {
+    use diesel::{
+        dsl::sql,
+        sql_types::{Integer, Text},
+        sqlite::SqliteConnection,
+    };
+
+    let path = std::env::temp_dir().join(format!("{}.db", Uuid::new_v4()));
+    let db = Database::<SqliteConnection>::builder()
+        .sqlite_config(SqlitePoolConfig::new().busy_timeout(Duration::from_secs(2)))
+        .connect(path.to_str().unwrap())
+        .await?;
+
+    let journal_mode: String = diesel::select(sql::<Text>("journal_mode FROM pragma_journal_mode"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(journal_mode, "wal");
+    let busy_timeout: i32 = diesel::select(sql::<Integer>("timeout FROM pragma_busy_timeout"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(busy_timeout, 2000);
+    let foreign_keys: i32 = diesel::select(sql::<Integer>("foreign_keys FROM pragma_foreign_keys"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(foreign_keys, 1);
+
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_single_writer() -> Result<(), Box<dyn Error>> {
+    use diesel::{
+        connection::TransactionManager, dsl::sql, sql_types::BigInt, sqlite::SqliteConnection,
+    };
+
+    let path = std::env::temp_dir().join(format!("{}.db", Uuid::new_v4()));
+    let db = Database::<SqliteConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(4))
+        .sqlite_config(SqlitePoolConfig::new())
+        .single_writer(true)
+        .connect(path.to_str().unwrap())
+        .await?;
+    db.batch_execute_async("CREATE TABLE counters (n INTEGER NOT NULL)")
+        .await?;
+    db.warm_up(3).await?;
+
+    // Concurrent read-then-write transactions would otherwise fail at once
+    // with `database is locked`
+    let writes = (0..20).map(|_| {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.transaction(|conn| {
+                diesel::select(sql::<BigInt>("COUNT(*) FROM counters")).get_result::<i64>(conn)?;
+                diesel::sql_query("INSERT INTO counters (n) VALUES (1)").execute(conn)?;
+                // Holds the write lock a while
+                std::thread::sleep(Duration::from_millis(5));
+                Ok::<_, diesel::result::Error>(())
+            })
+            .await
+        })
+    });
+    for write in futures::future::join_all(writes).await {
+        write??;
+    }
+    diesel::sql_query("INSERT INTO counters (n) VALUES (1)")
+        .execute_async(&db)
+        .await?;
+
+    // So do writes made with `run` and through `begin`, side by side with the others
+    let runs = (0..10).map(|_| {
+        let db = db.clone();
+        tokio::spawn(async move {
+            db.run(|conn| {
+                conn.transaction(|| {
+                    diesel::select(sql::<BigInt>("COUNT(*) FROM counters"))
+                        .get_result::<i64>(conn)?;
+                    std::thread::sleep(Duration::from_millis(5));
+                    diesel::sql_query("INSERT INTO counters (n) VALUES (1)").execute(conn)
+                })
+            })
+            .await
+        })
+    });
+    let begins = (0..10).map(|_| {
+        let db = db.clone();
+        tokio::spawn(async move {
+            let tx = db.begin().await?;
+            diesel::select(sql::<BigInt>("COUNT(*) FROM counters"))
+                .get_result_async::<i64>(&tx)
+                .await?;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            diesel::sql_query("INSERT INTO counters (n) VALUES (1)")
+                .execute_async(&tx)
+                .await?;
+            tx.commit().await
+        })
+    });
+    for run in futures::future::join_all(runs).await {
+        run??;
+    }
+    for begin in futures::future::join_all(begins).await {
+        begin??;
+    }
+
+    // A write leaving a transaction open has it rolled back, not left for the next write
+    db.run(|conn| {
+        conn.transaction_manager().begin_transaction(conn)?;
+        diesel::sql_query("INSERT INTO counters (n) VALUES (1)").execute(conn)
+    })
+    .await?;
+    diesel::sql_query("INSERT INTO counters (n) VALUES (1)")
+        .execute_async(&db)
+        .await?;
+
+    let count: i64 = diesel::select(sql::<BigInt>("COUNT(*) FROM counters"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(count, 42);
+
+    db.shutdown(Duration::from_secs(1)).await?;
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_backup() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt, sqlite::SqliteConnection};
+
+    let path = std::env::temp_dir().join(format!("{}.db", Uuid::new_v4()));
+    let backup = std::env::temp_dir().join(format!("{}.db", Uuid::new_v4()));
+    let db = Database::<SqliteConnection>::builder()
+        .sqlite_config(SqlitePoolConfig::new())
+        .connect(path.to_str().unwrap())
+        .await?;
+    db.batch_execute_async(
+        "CREATE TABLE notes (body TEXT NOT NULL);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+         INSERT INTO notes SELECT printf('note %d', i) FROM n",
+    )
+    .await?;
+
+    let steps = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = steps.clone();
+    db.backup_to(&backup, move |progress| {
+        recorded.lock().unwrap().push(progress)
+    })
+    .await?;
+    let steps = steps.lock().unwrap().clone();
+    assert!(!steps.is_empty());
+    assert_eq!(steps.last().unwrap().remaining, 0);
+    assert!(steps.last().unwrap().total > 0);
+
+    // A panicking callback stops the backup without leaving either database locked
+    let err = db
+        .backup_to(&backup, |_| panic!("progress failed"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Panicked(ref msg) if msg == "progress failed"));
+    db.batch_execute_async("INSERT INTO notes VALUES ('after')")
+        .await?;
+    db.batch_execute_async("DELETE FROM notes WHERE body = 'after'")
+        .await?;
+    db.backup_to(&backup, |_| {}).await?;
+
+    let copy = Database::<SqliteConnection>::connect(backup.to_str().unwrap()).await?;
+    let count: i64 = diesel::select(sql::<BigInt>("COUNT(*) FROM notes"))
+        .get_result_async(&copy)
+        .await?;
+    assert_eq!(count, 2000);
+
+    drop((db, copy));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&backup);
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_in_memory() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt, sqlite::SqliteConnection};
+
+    let db = Database::<SqliteConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(4))
+        .sqlite_config(SqlitePoolConfig::new())
+        .connect_in_memory()
+        .await?;
+    db.batch_execute_async("CREATE TABLE notes (body TEXT NOT NULL)")
+        .await?;
+    db.warm_up(4).await?;
+
+    // Every connection sees the table
+    let inserts = (0..8).map(|i| {
+        diesel::sql_query(format!("INSERT INTO notes (body) VALUES ('note {}')", i))
+            .execute_async(&db)
+    });
+    futures::future::try_join_all(inserts).await?;
+    let count: i64 = diesel::select(sql::<BigInt>("COUNT(*) FROM notes"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(count, 8);
+
+    // Each call makes a database of its own
+    let other = Database::<SqliteConnection>::connect_in_memory().await?;
+    let tables: i64 = diesel::select(sql::<BigInt>("COUNT(*) FROM sqlite_master"))
+        .get_result_async(&other)
+        .await?;
+    assert_eq!(tables, 0);
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_pragmas() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt, sqlite::SqliteConnection};
+
+    let config = SqlitePoolConfig::new()
+        .cache_size(CacheSize::Kibibytes(8192))
+        .mmap_size(1 << 20)
+        .temp_store(TempStore::Memory)
+        .pragma("recursive_triggers", true)
+        .pragma("main.user_version", 7)
+        .pragma("main.user_version", 8);
+    let db = Database::<SqliteConnection>::builder()
+        .sqlite_config(config)
+        .connect_in_memory()
+        .await?;
+
+    let pragma = |name: &str| {
+        diesel::select(sql::<BigInt>(&format!("* FROM pragma_{}", name)))
+            .get_result_async::<i64>(&db)
+    };
+    assert_eq!(pragma("cache_size").await?, -8192);
+    assert_eq!(pragma("temp_store").await?, 2);
+    assert_eq!(pragma("recursive_triggers").await?, 1);
+    assert_eq!(pragma("user_version").await?, 8);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_test_pool() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+    let test_pool = TestPool::<PgConnection>::establish("postgres://postgres@localhost").await?;
+
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&test_pool)
+        .await?;
+    let found = test_pool
+        .transaction(move |conn| users::table.find(id).count().get_result::<i64>(conn))
+        .await?;
+    assert_eq!(found, 1);
+
+    // Never committed, so other connections don't see the row, even once it is dropped
+    let elsewhere: i64 = users::table
+        .find(id)
+        .count()
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(elsewhere, 0);
+    drop(test_pool);
+    let elsewhere: i64 = users::table
+        .find(id)
+        .count()
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(elsewhere, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dyn_async_pool() -> Result<(), Box<dyn Error>> {
+    #[derive(Debug)]
+    enum AppError {
+        Db(diesel::result::Error),
+        Invalid,
+    }
+
+    impl From<diesel::result::Error> for AppError {
+        fn from(err: diesel::result::Error) -> AppError {
+            AppError::Db(err)
+        }
+    }
+
+    // What a service would hold
+    let db: Arc<dyn AsyncDatabase<PgConnection>> = Arc::new(Database::new(setup().await?));
+    let pool = DynAsyncPool::from(db);
+
+    let id = Uuid::new_v4();
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&pool)
+        .await?;
+    let found: Uuid = users::table
+        .find(id)
+        .select(users::id)
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(found, id);
+
+    // The caller's error comes back as it was, and still rolls the transaction back
+    let other = Uuid::new_v4();
+    let result = pool
+        .transaction(move |conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(other))
+                .execute(conn)?;
+            Err::<(), _>(AppError::Invalid)
+        })
+        .await;
+    assert!(matches!(result, Err(AsyncError::Error(AppError::Invalid))));
+    let result = pool
+        .run(move |conn| -> Result<Uuid, AppError> {
+            Ok(users::table
+                .find(other)
+                .select(users::id)
+                .get_result(conn)?)
+        })
+        .await;
+    assert!(matches!(
+        result,
+        Err(AsyncError::Error(AppError::Db(
+            diesel::result::Error::NotFound
+        )))
+    ));
+
+    pool.batch_execute_async("SELECT 1").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_database() -> Result<(), Box<dyn Error>> {
+    // A handler under test, written against the traits
+    async fn rename_user<A>(db: &A, id: Uuid) -> Result<usize, AsyncError<diesel::result::Error>>
+    where
+        A: AsyncConnection<PgConnection> + Send + Sync,
+    {
+        let query = diesel::update(users::table.find(id)).set(users::id.eq(Uuid::new_v4()));
+        let sql = diesel::debug_query::<diesel::pg::Pg, _>(&query).to_string();
+        with_tag("rename_user", with_sql(sql, query.execute_async(db))).await
+    }
+
+    let mock = MockDatabase::<PgConnection>::new();
+    mock.returns(1usize)
+        .fails(diesel::result::Error::NotFound)
+        .returns(vec![Uuid::nil()]);
+
+    assert_eq!(rename_user(&mock, Uuid::new_v4()).await?, 1);
+    let err = rename_user(&mock, Uuid::new_v4()).await.unwrap_err();
+    assert!(matches!(
+        err,
+        AsyncError::Error(diesel::result::Error::NotFound)
+    ));
+    let ids: Vec<Uuid> = users::table.select(users::id).load_async(&mock).await?;
+    assert_eq!(ids, vec![Uuid::nil()]);
+    mock.batch_execute_async("TRUNCATE users").await?;
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 4);
+    assert_eq!(calls[0].operation, "run");
+    assert_eq!(calls[0].tag.as_deref(), Some("rename_user"));
+    assert!(calls[0]
+        .sql
+        .as_deref()
+        .unwrap()
+        .starts_with("UPDATE \"users\""));
+    assert_eq!(calls[2].sql, None);
+    assert_eq!(calls[3].sql.as_deref(), Some("TRUNCATE users"));
+    assert_eq!(mock.pending(), 0);
+
+    // Usable where a trait object is expected too
+    let _: Arc<dyn AsyncDatabase<PgConnection>> = Arc::new(mock);
+    Ok(())
+}
+
+#[cfg(feature = "fixtures")]
+#[tokio::test]
+async fn test_fixtures() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    // Nothing outlives the test transaction, tables included
+    let db = TestPool::<PgConnection>::establish("postgres://postgres@localhost").await?;
+    db.batch_execute_async(
+        "CREATE TABLE fixture_authors (id UUID PRIMARY KEY, name TEXT NOT NULL);
+         CREATE TABLE fixture_books (
+             id SERIAL PRIMARY KEY,
+             author_id UUID NOT NULL REFERENCES fixture_authors,
+             title TEXT NOT NULL,
+             published BOOLEAN NOT NULL,
+             pages INT
+         );",
+    )
+    .await?;
+
+    let fixtures = Fixtures::new()
+        .dir("tests/fixtures")?
+        .depends_on("fixture_books", "fixture_authors");
+    assert_eq!(fixtures.load(&db).await?, 3);
+
+    let authors: i64 = diesel::select(sql::<BigInt>("COUNT(*) FROM fixture_authors"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(authors, 2);
+    let published: i64 = diesel::select(sql::<BigInt>(
+        "COUNT(*) FROM fixture_books WHERE published AND pages IS NULL",
+    ))
+    .get_result_async(&db)
+    .await?;
+    assert_eq!(published, 1);
+
+    let err = Fixtures::new().yaml("fixture_books: [[1, 2]]").unwrap_err();
+    assert!(matches!(err, FixtureError::Invalid(_)));
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_temp_database() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    let base_url = "postgres://postgres@localhost";
+    let first = TempDatabase::<PgConnection>::create(base_url).await?;
+    let second = TempDatabase::<PgConnection>::create(base_url).await?;
+    assert_ne!(first.name(), second.name());
+
+    // Each has tables of its own
+    first
+        .batch_execute_async(include_str!("./create_users.sql"))
+        .await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(Uuid::new_v4()))
+        .execute_async(&*first)
+        .await?;
+    let count: i64 = users::table.count().get_result_async(&*first).await?;
+    assert_eq!(count, 1);
+    let missing = users::table.count().get_result_async::<i64>(&*second).await;
+    assert!(missing.is_err());
+
+    // Dropped by `close`, or else on `Drop`, even with connections open
+    let count_databases = |name: String| {
+        diesel::select(sql::<BigInt>(&format!(
+            "COUNT(*) FROM pg_database WHERE datname = '{}'",
+            name
+        )))
+    };
+    let admin = Database::<PgConnection>::connect(base_url).await?;
+    let (first_name, second_name) = (first.name().to_string(), second.name().to_string());
+    first.close().await?;
+    let found: i64 = count_databases(first_name).get_result_async(&admin).await?;
+    assert_eq!(found, 0);
+    let pool = second.database().clone();
+    drop(second);
+    assert!(pool.is_closed());
+    let found: i64 = count_databases(second_name)
+        .get_result_async(&admin)
+        .await?;
+    assert_eq!(found, 0);
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_temp_database() -> Result<(), Box<dyn Error>> {
+    use diesel::sqlite::SqliteConnection;
+
+    let temp = TempDatabase::<SqliteConnection>::create("").await?;
+    temp.batch_execute_async("CREATE TABLE notes (body TEXT NOT NULL)")
+        .await?;
+    let path = std::path::PathBuf::from(temp.url());
+    assert!(path.starts_with(std::env::temp_dir()));
+    assert!(path.exists());
+
+    temp.close().await?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// Needs a Docker daemon
+#[cfg(all(feature = "testcontainers", feature = "postgres"))]
+#[tokio::test]
+async fn test_postgres_container() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    let container = TestContainer::<PgConnection>::new()
+        .migrations("tests/migrations")
+        .database_builder(Database::builder().pool_config(PoolConfig::new().max_size(2)))
+        .start()
+        .await?;
+
+    container
+        .batch_execute_async("INSERT INTO notes (body) VALUES ('migrated')")
+        .await?;
+    let versions: i64 = diesel::select(sql::<BigInt>(
+        "COUNT(*) FROM __diesel_schema_migrations WHERE version = '20210101000000'",
+    ))
+    .get_result_async(&*container)
+    .await?;
+    assert_eq!(versions, 1);
+
+    container.stop().await;
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_truncate_all() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    let temp = TempDatabase::<PgConnection>::create("postgres://postgres@localhost").await?;
+    temp.batch_execute_async(
+        "CREATE TABLE authors (id SERIAL PRIMARY KEY, name TEXT NOT NULL); \
+         CREATE TABLE books (id SERIAL PRIMARY KEY, author_id INTEGER NOT NULL REFERENCES authors); \
+         CREATE TABLE countries (code TEXT PRIMARY KEY); \
+         INSERT INTO authors (name) VALUES ('a'), ('b'); \
+         INSERT INTO books (author_id) VALUES (1), (2); \
+         INSERT INTO countries VALUES ('fr')",
+    )
+    .await?;
+
+    // Referenced tables are emptied along with those referring to them
+    let truncated = temp.truncate_all(&["countries"]).await?;
+    assert_eq!(truncated, vec!["public.authors", "public.books"]);
+    let count = |table: &str| diesel::select(sql::<BigInt>(&format!("COUNT(*) FROM {}", table)));
+    assert_eq!(count("books").get_result_async::<i64>(&*temp).await?, 0);
+    assert_eq!(count("countries").get_result_async::<i64>(&*temp).await?, 1);
+
+    // Identities start over
+    temp.batch_execute_async("INSERT INTO authors (name) VALUES ('c')")
+        .await?;
+    let id: i32 = diesel::select(sql::<diesel::sql_types::Integer>("MAX(id) FROM authors"))
+        .get_result_async(&*temp)
+        .await?;
+    assert_eq!(id, 1);
+
+    temp.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fault_injector() -> Result<(), Box<dyn Error>> {
+    let pool = setup().await?;
+
+    // A lost connection rolls the transaction back
+    let id = Uuid::new_v4();
+    let disconnecting = FaultInjector::new(pool.clone()).disconnects(1.0);
+    let err = disconnecting
+        .transaction(move |conn| {
+            diesel::insert_into(users::table)
+                .values(users::id.eq(id))
+                .execute(conn)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::ConnectionLost);
+    let found: i64 = users::table
+        .find(id)
+        .count()
+        .get_result_async(&pool)
+        .await?;
+    assert_eq!(found, 0);
+
+    // The same seed fails the same calls, which retrying gets past
+    let failures = |seed| {
+        let flaky = FaultInjector::new(pool.clone())
+            .seed(seed)
+            .checkout_failures(0.5);
+        async move {
+            let mut failed = Vec::new();
+            for _ in 0..16 {
+                failed.push(flaky.batch_execute_async("SELECT 1").await.is_err());
+            }
+            failed
+        }
+    };
+    let failed = failures(7).await;
+    assert_eq!(failed, failures(7).await);
+    assert!(failed.contains(&true) && failed.contains(&false));
+    let flaky = FaultInjector::new(pool.clone())
+        .seed(7)
+        .checkout_failures(0.5);
+    let policy = RetryPolicy::new(16).backoff(Duration::from_millis(1), Duration::from_millis(1));
+    let one: i32 = flaky
+        .run_retrying(policy, |conn| {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("1")).get_result(conn)
+        })
+        .await?;
+    assert_eq!(one, 1);
+
+    // Latency trips timeouts
+    let slow = FaultInjector::new(pool).latency(1.0, Duration::from_millis(200));
+    let err = slow
+        .run_with_timeout(Duration::from_millis(50), |_| {
+            Ok::<_, diesel::result::Error>(())
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Timeout));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_guard_test_transaction() -> Result<(), Box<dyn Error>> {
+    use diesel::connection::TransactionManager;
+
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(1))
+        .connect("postgres://postgres@localhost")
+        .await?;
+    let _ = db
+        .batch_execute_async(include_str!("./create_users.sql"))
+        .await;
+
+    let id = Uuid::new_v4();
+    let guard = db.acquire().await?;
+    guard.begin_test_transaction_async().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(id))
+        .execute_async(&guard)
+        .await?;
+    let found: i64 = users::table
+        .find(id)
+        .count()
+        .get_result_async(&guard)
+        .await?;
+    assert_eq!(found, 1);
+    drop(guard);
+
+    // The only connection comes back rolled back and out of the transaction
+    let depth = db
+        .run(|conn| {
+            Ok::<_, diesel::result::Error>(
+                TransactionManager::<PgConnection>::get_transaction_depth(
+                    conn.transaction_manager(),
+                ),
+            )
+        })
+        .await?;
+    assert_eq!(depth, 0);
+    let found: i64 = users::table.find(id).count().get_result_async(&db).await?;
+    assert_eq!(found, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_test() -> Result<(), Box<dyn Error>> {
+    let db = Database::<PgConnection>::builder()
+        .pool_config(PoolConfig::new().max_size(2))
+        .connect("postgres://postgres@localhost")
+        .await?;
+
+    let report = LoadTest::new()
+        .concurrency(8)
+        .queries(200)
+        .duration(Duration::from_secs(30))
+        .statement("SELECT pg_sleep(0.001)")
+        .run(&db)
+        .await;
+    assert_eq!(report.queries, 200);
+    assert_eq!(report.errors, 0);
+    assert_eq!(report.error_rate(), 0.0);
+    assert!(report.throughput() > 0.0);
+    assert!(report.latency_p50 <= report.latency_p99);
+    // More callers than connections, so calls queue for one
+    assert_eq!(report.checkout_wait.count, 200);
+    assert!(report.mean_checkout_wait() > Duration::ZERO);
+
+    // A failing statement shows in the error rate
+    let report = LoadTest::new()
+        .concurrency(2)
+        .queries(10)
+        .statement("SELECT * FROM no_such_table")
+        .run(&db)
+        .await;
+    assert_eq!(report.error_rate(), 1.0);
+    assert_eq!(report.errors_by_category.other, 10);
+
+    Ok(())
+}
+
+// A MySQL server with `local_infile` enabled
+#[cfg(feature = "mysql")]
+fn mysql_url() -> String {
+    std::env::var("MYSQL_DATABASE_URL")
+        .unwrap_or_else(|_| "mysql://root@127.0.0.1:3306/test".to_string())
+}
+
+// A database on `mysql_url` with an empty `table` of ids and unique names
+#[cfg(feature = "mysql")]
+async fn mysql_setup(
+    table: &str,
+) -> Result<Database<diesel::mysql::MysqlConnection>, Box<dyn Error>> {
+    let db = Database::connect(mysql_url()).await?;
+    db.batch_execute_async(&format!("DROP TABLE IF EXISTS {}", table))
+        .await?;
+    db.batch_execute_async(&format!(
+        "CREATE TABLE {} (id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY, name VARCHAR(64) NOT NULL UNIQUE)",
+        table
+    ))
+    .await?;
+    Ok(db)
+}
+
+#[cfg(feature = "mysql")]
+#[tokio::test]
+async fn test_mysql_transaction_retrying() -> Result<(), Box<dyn Error>> {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    let db = mysql_setup("retried").await?;
+    let policy = RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(1));
+
+    // Attempts ending in a deadlock are rolled back and run again
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counted = attempts.clone();
+    let id = db
+        .transaction_retrying(policy.clone(), move |conn| {
+            diesel::insert_into(mysql_items::table)
+                .values(mysql_items::name.eq("retried"))
+                .execute(conn)?;
+            if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(DieselError::DatabaseError(
+                    DatabaseErrorKind::__Unknown,
+                    Box::new(
+                        "Deadlock found when trying to get lock; try restarting transaction"
+                            .to_string(),
+                    ),
+                ));
+            }
+            mysql_items::table
+                .select(mysql_items::id)
+                .first::<u64>(conn)
+        })
+        .await?;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    let names: Vec<String> = mysql_items::table
+        .select(mysql_items::name)
+        .load_async(&db)
+        .await?;
+    assert_eq!(names, vec!["retried"]);
+    assert!(id > 0);
+
+    // Other errors are not retried
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counted = attempts.clone();
+    let err = db
+        .transaction_retrying(policy, move |conn| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            diesel::insert_into(mysql_items::table)
+                .values(mysql_items::name.eq("retried"))
+                .execute(conn)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), DatabaseErrorClass::UniqueViolation);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "mysql")]
+#[tokio::test]
+async fn test_mysql_insert_and_get_id() -> Result<(), Box<dyn Error>> {
+    let db = mysql_setup("inserted_ids").await?;
+    db.batch_execute_async("INSERT INTO inserted_ids (name) VALUES ('first')")
+        .await?;
+
+    let id = sql_query("INSERT INTO inserted_ids (name) VALUES ('second')")
+        .insert_and_get_id_async(&db)
+        .await?;
+    let name: String = sql_query(format!("SELECT name FROM inserted_ids WHERE id = {}", id))
+        .load_async::<Named>(&db)
+        .await?
+        .remove(0)
+        .name;
+    assert_eq!(name, "second");
+
+    Ok(())
+}
+
+#[cfg(feature = "mysql")]
+#[derive(QueryableByName)]
+struct Named {
+    #[sql_type = "diesel::sql_types::Text"]
+    name: String,
+}
+
+#[cfg(feature = "mysql")]
+#[tokio::test]
+async fn test_mysql_load_data() -> Result<(), Box<dyn Error>> {
+    let db = mysql_setup("loaded").await?;
+    let load = "LOAD DATA LOCAL INFILE 'ignored' INTO TABLE loaded \
+                FIELDS TERMINATED BY ',' (id, name)";
+
+    let loaded = db
+        .load_data_iter_async(load, vec!["1,one\n", "2,two\n"])
+        .await?;
+    assert_eq!(loaded, 2);
+    let loaded = db.load_data_async(load, &b"3,three\n"[..]).await?;
+    assert_eq!(loaded, 1);
+
+    // A panicking source aborts the load without unwinding through libmysqlclient
+    let mut chunks = 0;
+    let err = db
+        .load_data_iter_async(
+            load,
+            std::iter::from_fn(move || {
+                chunks += 1;
+                assert!(chunks < 2, "source failed");
+                Some("4,four\n")
+            }),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Panicked(ref msg) if msg.contains("source failed")));
+
+    // Connection options the crate can't apply are refused
+    let with_options = Database::<diesel::mysql::MysqlConnection>::builder()
+        .database_url(format!("{}?ssl_mode=required", mysql_url()))
+        .build(db.pool().unwrap());
+    let err = with_options
+        .load_data_iter_async(load, vec!["5,five\n"])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AsyncError::Connect(_)));
+
+    Ok(())
+}
+
+#[cfg(feature = "mysql")]
+#[tokio::test]
+async fn test_mysql_batch_execute_results() -> Result<(), Box<dyn Error>> {
+    let db = mysql_setup("batched").await?;
+
+    let results = db
+        .batch_execute_results_async(
+            "INSERT INTO batched (name) VALUES ('a'), ('b'); \
+             SELECT * FROM batched; \
+             INSERT INTO batched (name) VALUES ('a'); \
+             INSERT INTO batched (name) VALUES ('c')",
+        )
+        .await?;
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().ok(), Some(&2));
+    assert_eq!(results[1].as_ref().ok(), Some(&2));
+    let err = AsyncError::Error(results.into_iter().nth(2).unwrap().unwrap_err());
+    assert_eq!(err.class(), DatabaseErrorClass::UniqueViolation);
+    assert_eq!(err.details(), Some("MySQL error 1062"));
+
+    // Outside any transaction of the caller
+    let tx = db.begin().await?;
+    tx.batch_execute_async("INSERT INTO batched (name) VALUES ('d')")
+        .await?;
+    let results = db
+        .batch_execute_results_async("SELECT * FROM batched WHERE name = 'd'")
+        .await?;
+    assert_eq!(results[0].as_ref().ok(), Some(&0));
+    tx.rollback().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "mysql")]
+#[tokio::test]
+async fn test_mysql_session_variables() -> Result<(), Box<dyn Error>> {
+    use diesel::{dsl::sql, sql_types::BigInt};
+
+    let db = Database::builder()
+        .session_variables(
+            MysqlSessionConfig::new()
+                .set_number("max_execution_time", 1234)
+                .set("time_zone", "+02:00"),
+        )
+        .connect(mysql_url())
+        .await?;
+
+    let timeout: i64 = diesel::select(sql::<BigInt>("@@SESSION.max_execution_time"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(timeout, 1234);
+    let zone: String = diesel::select(sql::<diesel::sql_types::Text>("@@SESSION.time_zone"))
+        .get_result_async(&db)
+        .await?;
+    assert_eq!(zone, "+02:00");
+
+    Ok(())
+}